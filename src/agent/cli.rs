@@ -1,16 +1,22 @@
-use clap::Parser;
-use std::fs;
+use clap::{Parser, Subcommand};
+use std::{fs, sync::Arc};
+use tokio_rustls::rustls::ClientConfig;
 
-use crate::{
-  protocol::auth,
-  utils::util::{get_random_uuid, random_str},
+use super::{
+  enroll::EnrollArgs,
+  state::{Identity, StateDir},
+  tls::client_config,
 };
+use crate::utils::util::{get_random_uuid, random_str};
 use anyhow::Result;
 use log::{error, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(version = crate::VERSION)]
 struct Cli {
+  #[command(subcommand)]
+  command: Option<Command>,
+
   /// Connect to controller with Websocket URL. This option will disable discovery.
   ///
   /// Url should be in the format of `ws://<host>:<port>` or `wss://<host>:<port>`.
@@ -41,10 +47,83 @@ struct Cli {
   #[clap(long, env = "MXA_ENFORCE_AUTH")]
   enforce_auth: bool,
 
+  /// Trust on first use: pin the first controller that authenticates and refuse controllers with another key.
+  ///
+  /// Controllers in the trusted controllers list are always accepted. Clear the pin with `mxa trust unpin`.
+  #[clap(long, env = "MXA_TOFU")]
+  tofu: bool,
+
   /// A list of trusted controllers.
   /// Each controller should be sha256 hash of controller's public key.
+  ///
+  /// If the list is not empty, other controllers are refused even without `--enforce-auth`.
   #[clap(long, env = "MXA_TRUSTED_CONTROLLERS")]
   trusted_controllers: Vec<String>,
+
+  /// CA certificate file the controller's certificate must be issued by, for `wss://` connections.
+  ///
+  /// Without it, controllers are verified against the public web PKI.
+  #[clap(long, env = "MXA_CA_CERT")]
+  ca_cert: Option<String>,
+
+  /// Client certificate file to present to controllers that require mutual TLS. Requires `--ca-cert`.
+  ///
+  /// Its common name must be this host's id. The controller issues it through its `agent-cert` API.
+  #[clap(long, env = "MXA_TLS_CERT", requires = "ca_cert", requires = "tls_key")]
+  tls_cert: Option<String>,
+
+  /// Private key file of the client certificate
+  #[clap(long, env = "MXA_TLS_KEY", requires = "tls_cert")]
+  tls_key: Option<String>,
+
+  /// Directory to keep the agent's identity, trusted controllers and undelivered task results in
+  #[clap(long, env = "MXA_STATE_DIR", default_value = "/var/lib/mxa", global = true)]
+  state_dir: String,
+
+  /// Enroll with the controller before connecting, unless the stored identity is enrolled already.
+  ///
+  /// The controller has to approve the enrollment, unless a bootstrap token is given. The operator approves it by the
+  /// key fingerprint the agent logs.
+  #[clap(long, env = "MXA_ENROLL")]
+  enroll: bool,
+
+  /// One-time token minted by the controller that enrolls the agent without approval. Implies `--enroll`.
+  ///
+  /// Only sent over HTTPS, over plain HTTP the agent waits for approval instead.
+  #[clap(long, env = "MXA_BOOTSTRAP_TOKEN")]
+  bootstrap_token: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Show or rotate the identity stored in the state directory
+  Identity {
+    #[command(subcommand)]
+    action: IdentityAction,
+  },
+  /// Manage the controllers trusted by fingerprint, in addition to `--trusted-controllers`
+  Trust {
+    #[command(subcommand)]
+    action: TrustAction,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum IdentityAction {
+  /// Print the public key, its fingerprint and what else is stored
+  Show,
+  /// Replace the keypair with a new one. An enrolled agent has to enroll again.
+  Rotate,
+}
+
+#[derive(Subcommand, Debug)]
+enum TrustAction {
+  /// Trust the controller with this fingerprint, the SHA-256 of its public key
+  Add { fingerprint: String },
+  /// Stop trusting the controller with this fingerprint
+  Remove { fingerprint: String },
+  /// Forget the controller pinned on first use, so the next one to authenticate is pinned instead
+  Unpin,
 }
 
 #[derive(Debug, Clone)]
@@ -54,8 +133,17 @@ pub(crate) struct StartupArgs {
   pub session_id: String,
   pub envs: Vec<String>,
   pub enforce_auth: bool,
+  /// Pin the first controller that authenticates
+  pub tofu: bool,
   pub key_pair: (String, String),
   pub trusted_controllers: Vec<String>,
+  /// TLS configuration for `wss://` connections, if the controller's CA is pinned
+  pub tls_config: Option<Arc<ClientConfig>>,
+  /// Set if the agent has to enroll before connecting
+  pub enroll: Option<EnrollArgs>,
+  /// Renew the client certificate of the stored identity when it is about to expire
+  pub renew_client_cert: bool,
+  pub state_dir: StateDir,
 }
 
 pub async fn main() -> Result<()> {
@@ -67,6 +155,13 @@ pub async fn main() -> Result<()> {
     return script_main(script_path).await;
   }
 
+  let state_dir = StateDir::new(&cli.state_dir);
+  match cli.command {
+    Some(Command::Identity { action }) => return identity_main(&state_dir, action).await,
+    Some(Command::Trust { action }) => return trust_main(&state_dir, action).await,
+    None => {}
+  }
+
   info!("MetalX Agent - Launching");
 
   #[cfg(unix)]
@@ -85,7 +180,10 @@ pub async fn main() -> Result<()> {
     }
   });
 
-  let session_id = random_str(16);
+  let session_id = state_dir.session_id().await.unwrap_or_else(|err| {
+    warn!("Failed to keep the session id, a new one is used on every launch: {err}");
+    random_str(16)
+  });
   info!("Host ID: {host_id}");
   info!("Session ID: {session_id}");
   let envs = std::env::vars()
@@ -93,32 +191,147 @@ pub async fn main() -> Result<()> {
     .map(|(k, v)| format!("{k}={v}"))
     .collect::<Vec<_>>();
 
+  let stored = state_dir.identity()?;
+  let enroll = (cli.enroll || cli.bootstrap_token.is_some()) && !stored.as_ref().is_some_and(|i| i.enrolled);
+  // Only the certificate enrollment issued to the stored key can be renewed
+  let renew_client_cert = cli.tls_cert.is_none() && cli.public_key.is_none();
+
+  // Files given on the command line take precedence over the stored identity.
+  let ca_cert = match &cli.ca_cert {
+    Some(ca_cert) => Some(fs::read_to_string(ca_cert)?),
+    None => stored.as_ref().and_then(|i| i.ca_cert.clone()),
+  };
+  let client_cert = match (&cli.tls_cert, &cli.tls_key) {
+    (Some(cert), Some(key)) => Some((fs::read_to_string(cert)?, fs::read_to_string(key)?)),
+    _ => stored.as_ref().and_then(|i| i.client_cert()).map(|(cert, key)| (cert.to_string(), key.to_string())),
+  };
+  let tls_config = match &ca_cert {
+    Some(ca_cert) => {
+      let config = client_config(ca_cert, client_cert.as_ref().map(|(c, k)| (c.as_str(), k.as_str())))?;
+      Some(Arc::new(config))
+    }
+    None => None,
+  };
+
+  let key_pair = match (cli.public_key, cli.private_key) {
+    (Some(public_key), Some(private_key)) => (public_key, private_key),
+    (None, None) => {
+      let identity = match stored {
+        Some(identity) => {
+          info!("Using the identity stored in {}", state_dir.path().display());
+          identity
+        }
+        None => {
+          let identity = Identity::generate();
+          match state_dir.save_identity(&identity).await {
+            Ok(()) => info!("Generated a new identity, stored in {}", state_dir.path().display()),
+            Err(err) => {
+              warn!("Failed to store the generated identity, a new one is generated on every launch: {err}");
+              info!("Public Key: {}", identity.public_key);
+            }
+          }
+          identity
+        }
+      };
+      (identity.public_key, identity.private_key)
+    }
+    _ => {
+      error!("Both public and private keys must be provided or neither.");
+      return Ok(());
+    }
+  };
+
+  let mut trusted_controllers = cli.trusted_controllers;
+  trusted_controllers.extend(state_dir.trusted_controllers()?);
+
   let startup_args = StartupArgs {
     ws_url: cli.ws_url.clone(),
     host_id: host_id.clone(),
     session_id: session_id.clone(),
     envs: envs.clone(),
     enforce_auth: cli.enforce_auth,
-    key_pair: {
-      if cli.public_key.is_some() && cli.private_key.is_some() {
-        (cli.public_key.unwrap(), cli.private_key.unwrap())
-      } else if cli.public_key.is_none() && cli.private_key.is_none() {
-        let kp = auth::generate_keypair_str();
-        warn!("No public or private key provided, generating a new keypair. Please save it for future use.");
-        info!("Public Key: {}", kp.0);
-        info!("Private Key: {}", kp.1);
-        kp
-      } else {
-        error!("Both public and private keys must be provided or neither.");
-        return Ok(());
-      }
-    },
-    trusted_controllers: cli.trusted_controllers,
+    tofu: cli.tofu,
+    key_pair,
+    trusted_controllers,
+    tls_config,
+    enroll: enroll.then_some(EnrollArgs {
+      bootstrap_token: cli.bootstrap_token,
+    }),
+    renew_client_cert,
+    state_dir,
   };
 
   super::net::start_agent(startup_args).await
 }
 
+async fn identity_main(state_dir: &StateDir, action: IdentityAction) -> Result<()> {
+  match action {
+    IdentityAction::Show => {
+      let Some(identity) = state_dir.identity()? else {
+        println!("No identity stored in {}", state_dir.path().display());
+        return Ok(());
+      };
+      println!("State directory:  {}", state_dir.path().display());
+      println!("Public key:       {}", identity.public_key);
+      println!("Fingerprint:      {}", identity.fingerprint()?);
+      println!("Enrolled:         {}", if identity.enrolled { "yes" } else { "no" });
+      println!(
+        "Client cert:      {}",
+        if identity.client_cert().is_some() { "yes" } else { "no" }
+      );
+      println!(
+        "Pinned CA:        {}",
+        if identity.ca_cert.is_some() { "yes" } else { "no" }
+      );
+      if let Some(controller) = state_dir.controller()? {
+        println!(
+          "Pinned:           {} at {} since {}",
+          controller.fingerprint, controller.url, controller.first_seen
+        );
+      }
+      for fingerprint in state_dir.trusted_controllers()? {
+        println!("Trusted:          {fingerprint}");
+      }
+      println!("Pending results:  {}", state_dir.pending_results_count());
+    }
+    IdentityAction::Rotate => {
+      let mut identity = Identity::generate();
+      // The pinned CA stays valid, but the controller only knows the previous key.
+      identity.ca_cert = state_dir.identity()?.and_then(|i| i.ca_cert);
+      state_dir.save_identity(&identity).await?;
+      println!("Public key:  {}", identity.public_key);
+      println!("Fingerprint: {}", identity.fingerprint()?);
+      println!("Controllers that require enrollment have to enroll the new key, start the agent with `--enroll`.");
+    }
+  }
+  Ok(())
+}
+
+async fn trust_main(state_dir: &StateDir, action: TrustAction) -> Result<()> {
+  let mut trusted = state_dir.trusted_controllers()?;
+  match action {
+    TrustAction::Add { fingerprint } => {
+      let fingerprint = fingerprint.to_lowercase();
+      if !trusted.contains(&fingerprint) {
+        trusted.push(fingerprint);
+      }
+      state_dir.save_trusted_controllers(&trusted).await
+    }
+    TrustAction::Remove { fingerprint } => {
+      let fingerprint = fingerprint.to_lowercase();
+      trusted.retain(|f| *f != fingerprint);
+      state_dir.save_trusted_controllers(&trusted).await
+    }
+    TrustAction::Unpin => {
+      match state_dir.clear_controller()? {
+        Some(pin) => println!("Unpinned controller {} at {}", pin.fingerprint, pin.url),
+        None => println!("No controller is pinned"),
+      }
+      Ok(())
+    }
+  }
+}
+
 async fn script_main(script: String) -> Result<()> {
   let content = match fs::read_to_string(script) {
     Ok(content) => content,
@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use reqwest::{Client, StatusCode, header};
+use url::Url;
+
+use super::{cli::StartupArgs, net::tls_config, state::Identity, tls::client_config};
+use crate::{
+  discovery::Endpoint,
+  protocol::{
+    auth::AuthRequest,
+    enrollment::{ENROLL_PATH, EnrollRequest, EnrollResponse, EnrollStatus},
+    handshake::CONNECT_AGENT_AUTH_HEADER_KEY,
+  },
+  utils::{
+    cert::{generate_client_csr, needs_renewal},
+    hash::sha2_256_for_str,
+    util::safe_sleep,
+  },
+};
+
+/// How long to wait before asking again while an operator has not approved the enrollment yet.
+const PENDING_POLL_INTERVAL: u64 = 10_000;
+
+/// How to enroll, for agents that have not yet.
+#[derive(Debug, Clone)]
+pub(crate) struct EnrollArgs {
+  pub bootstrap_token: Option<String>,
+}
+
+/// URL of the enrollment endpoint of the controller reached at `ws_url`.
+fn enroll_url(ws_url: &Url) -> Result<Url> {
+  let mut url = ws_url.clone();
+  let scheme = if ws_url.scheme() == "wss" { "https" } else { "http" };
+  url.set_scheme(scheme).map_err(|_| anyhow::anyhow!("Unsupported controller URL: {ws_url}"))?;
+  url.set_path(ENROLL_PATH);
+  url.set_query(None);
+  Ok(url)
+}
+
+/// Sign `body` with `private_key` and post it to the enrollment endpoint at `url`. Returns `None` if the controller
+/// does not accept enrollments.
+async fn send(
+  client: &Client, url: &Url, body: &mut EnrollRequest, private_key: &str,
+) -> Result<Option<EnrollResponse>> {
+  let auth = AuthRequest::new_with_privkey_string(private_key)?;
+  body.sign(&auth, private_key)?;
+  let resp = client
+    .post(url.clone())
+    .header(CONNECT_AGENT_AUTH_HEADER_KEY, auth.encode())
+    .header(header::CONTENT_TYPE, "application/json")
+    .body(serde_json::to_vec(&body)?)
+    .send()
+    .await?;
+  if resp.status() == StatusCode::NOT_FOUND {
+    return Ok(None);
+  }
+  Ok(Some(serde_json::from_slice(&resp.bytes().await?)?))
+}
+
+/// Enroll the agent's key with the controller at `endpoint` and store it as the agent's identity.
+///
+/// While the enrollment waits for an operator, the controller is asked again periodically. Returns `None` if the
+/// agent is shut down meanwhile, and an error if the enrollment is rejected.
+pub(crate) async fn enroll(args: &StartupArgs, enroll: &EnrollArgs, endpoint: &Endpoint) -> Result<Option<Identity>> {
+  let url = enroll_url(&endpoint.url)?;
+  let mut client = reqwest::Client::builder();
+  if let Some(tls_config) = tls_config(args, endpoint)? {
+    client = client.use_preconfigured_tls((*tls_config).clone());
+  }
+  let client = client.build()?;
+  // The key of the client certificate never leaves the agent, only a request to sign it does.
+  let csr = if url.scheme() == "https" {
+    Some(generate_client_csr(&args.host_id)?)
+  } else {
+    None
+  };
+  // The controller refuses bootstrap tokens over plain HTTP, where anyone on the path could redeem them first
+  let token = match &enroll.bootstrap_token {
+    Some(_) if url.scheme() != "https" => {
+      warn!("Not sending the bootstrap token over plain HTTP, waiting for approval by an operator instead");
+      None
+    }
+    token => token.clone(),
+  };
+  let mut body = EnrollRequest {
+    host_id: args.host_id.clone(),
+    token,
+    csr: csr.as_ref().map(|(csr, _)| csr.clone()),
+    signature: String::new(),
+  };
+  let (public_key, private_key) = args.key_pair.clone();
+  info!(
+    "Enrolling with controller at {url}, key fingerprint {}",
+    sha2_256_for_str(&public_key)?
+  );
+  loop {
+    let resp = match send(&client, &url, &mut body, &private_key).await {
+      Ok(Some(resp)) => Ok(resp),
+      Ok(None) => anyhow::bail!("Controller at {url} does not accept enrollments"),
+      Err(err) => Err(err),
+    };
+    match resp {
+      Ok(EnrollResponse {
+        status: Some(EnrollStatus::Approved),
+        cert,
+        ca_cert,
+        ..
+      }) => {
+        let identity = Identity {
+          public_key,
+          private_key,
+          enrolled: true,
+          tls_key: cert.as_ref().and(csr.map(|(_, key)| key)),
+          tls_cert: cert,
+          ca_cert,
+        };
+        args.state_dir.save_identity(&identity).await?;
+        info!(
+          "Enrolled with controller, identity saved to {}",
+          args.state_dir.path().display()
+        );
+        return Ok(Some(identity));
+      }
+      Ok(EnrollResponse {
+        status: Some(EnrollStatus::Rejected),
+        ..
+      }) => anyhow::bail!("Enrollment was rejected by the controller"),
+      Ok(EnrollResponse {
+        status: Some(EnrollStatus::Pending),
+        ..
+      }) => info!("Enrollment is waiting for approval by an operator"),
+      Ok(EnrollResponse { reason, .. }) => warn!("Enrollment failed: {reason:?}"),
+      Err(err) => error!("Failed to enroll with controller: {err}"),
+    }
+    if safe_sleep(PENDING_POLL_INTERVAL).await {
+      debug!("Enrollment interrupted");
+      return Ok(None);
+    }
+  }
+}
+
+/// Whether the client certificate of the stored identity has to be renewed before connecting. Certificates given on
+/// the command line are left alone.
+pub(crate) fn client_cert_expiring(args: &StartupArgs) -> bool {
+  args.renew_client_cert &&
+    args
+      .state_dir
+      .identity()
+      .ok()
+      .flatten()
+      .and_then(|identity| identity.tls_cert)
+      .is_some_and(|cert| needs_renewal(&cert))
+}
+
+/// Have the controller at `endpoint` issue a client certificate for a new key to the enrolled agent, and use it from
+/// now on.
+///
+/// The controller trusts the enrolled key, so the request does not present the current certificate, which may have
+/// expired already.
+pub(crate) async fn renew_client_cert(args: &mut StartupArgs, endpoint: &Endpoint) -> Result<()> {
+  let Some(mut identity) = args.state_dir.identity()? else {
+    anyhow::bail!("No identity stored in {}", args.state_dir.path().display());
+  };
+  let Some(ca_cert) = &identity.ca_cert else {
+    anyhow::bail!("No CA certificate stored with the client certificate");
+  };
+  let url = enroll_url(&endpoint.url)?;
+  if url.scheme() != "https" {
+    anyhow::bail!("Client certificates are only renewed over HTTPS, not at {url}");
+  }
+  let client = Client::builder().use_preconfigured_tls(client_config(ca_cert, None)?).build()?;
+  let (csr, tls_key) = generate_client_csr(&args.host_id)?;
+  let mut body = EnrollRequest {
+    host_id: args.host_id.clone(),
+    token: None,
+    csr: Some(csr),
+    signature: String::new(),
+  };
+  match send(&client, &url, &mut body, &identity.private_key).await? {
+    Some(EnrollResponse {
+      status: Some(EnrollStatus::Approved),
+      cert: Some(cert),
+      ca_cert,
+      ..
+    }) => {
+      identity.tls_cert = Some(cert);
+      identity.tls_key = Some(tls_key);
+      identity.ca_cert = ca_cert.or(identity.ca_cert);
+      args.state_dir.save_identity(&identity).await?;
+      info!("Renewed the client certificate with controller at {url}");
+      apply(args, &identity)
+    }
+    Some(EnrollResponse { status, reason, .. }) => {
+      anyhow::bail!("Controller did not renew the client certificate: {status:?} {reason:?}")
+    }
+    None => anyhow::bail!("Controller at {url} does not accept enrollments"),
+  }
+}
+
+/// Use the enrolled identity to connect from now on.
+pub(crate) fn apply(args: &mut StartupArgs, identity: &Identity) -> Result<()> {
+  args.key_pair = (identity.public_key.clone(), identity.private_key.clone());
+  if let (Some(ca_cert), Some(client_cert)) = (&identity.ca_cert, identity.client_cert()) {
+    args.tls_config = Some(Arc::new(client_config(ca_cert, Some(client_cert))?));
+  }
+  Ok(())
+}
@@ -6,10 +6,10 @@ use crate::{
 };
 use anyhow::Result;
 
-use super::RequestHandler;
+use super::{RequestHandler, TaskContext};
 
 impl RequestHandler<CommandExecutionResponse> for CommandExecutionRequest {
-  async fn handle(&self) -> Result<CommandExecutionResponse, ErrorResponse> {
+  async fn handle(&self, _ctx: &TaskContext) -> Result<CommandExecutionResponse, ErrorResponse> {
     let Ok((code, stdout, stderr)) = (if self.use_shell.unwrap_or(true) {
       execute_shell(&self.command, self.use_script_file.unwrap_or(false)).await
     } else {
@@ -1,49 +1,77 @@
-use std::{fs::OpenOptions, io::Write as _};
+use std::path::Path;
 
 use crate::{
   protocol::messaging::{
-    ErrorResponse, FileDownloadParams, FileDownloadResult, FileOperationResponse, FileReadParams, FileReadResult,
-    FileTransferRequest, FileUploadParams, FileUploadResult, FileWriteParams, FileWriteResult,
+    DirDownloadParams, DirTransferResult, DirUploadParams, ErrorResponse, FileDownloadParams, FileDownloadResult,
+    FileOperationResponse, FileReadParams, FileReadResult, FileTransferRequest, FileUploadParams, FileUploadResult,
+    FileWriteParams, FileWriteResult, ImageWriteParams, ImageWriteResult,
   },
   utils::{
-    hash::xxh3_for_file,
-    util::{download_file, upload_file},
+    archive::{download_dir, upload_dir},
+    download::{DownloadOptions, download_file},
+    fs::{FileAttrs, WriteOptions, write_file},
+    hash::{HashAlgorithm, hash_eq, hashes_for_file, xxh3_for_file},
+    image::{ImageOptions, write_image},
+    util::upload_file,
   },
 };
 use anyhow::Result;
-use log::warn;
+use log::{info, warn};
 
-use super::RequestHandler;
+use super::{RequestHandler, TaskContext};
 
 impl RequestHandler<FileDownloadResult> for FileDownloadParams {
-  async fn handle(&self) -> Result<FileDownloadResult, ErrorResponse> {
-    match download_file(&self.src_url, &self.dest_path).await {
+  async fn handle(&self, ctx: &TaskContext) -> Result<FileDownloadResult, ErrorResponse> {
+    let algorithm = self.hash_algorithm.unwrap_or_default();
+    if self.skip_existing.unwrap_or(true) &&
+      let Some(expected) = &self.expected_hash &&
+      let Ok(mut hashes) = hashes_for_file(&self.dest_path, &[algorithm]).await &&
+      let Some(hash) = hashes.remove(&algorithm) &&
+      hash_eq(algorithm, &hash, expected)
+    {
+      info!("'{}' already has hash {hash}, skipping download", self.dest_path);
+      return Ok(FileDownloadResult {
+        ok: true,
+        hash: Some(hash),
+        reason: None,
+        skipped: true,
+      });
+    }
+    let opts = DownloadOptions {
+      expected_hash: self.expected_hash.clone(),
+      hash_algorithm: algorithm,
+      resume: self.resume.unwrap_or(true),
+      segments: self.segments.unwrap_or(1),
+    };
+    match download_file(&self.src_url, &self.dest_path, &opts, |p| ctx.report_progress(p)).await {
       Ok(hash) => Ok(FileDownloadResult {
         ok: true,
         hash: Some(hash),
+        reason: None,
+        skipped: false,
       }),
       Err(err) => {
         warn!(
           "Failed to download file from '{}' to '{}': {}",
           self.src_url, self.dest_path, err
         );
-        Ok(FileDownloadResult { ok: false, hash: None })
+        Ok(FileDownloadResult {
+          ok: false,
+          hash: None,
+          reason: Some(err.to_string()),
+          skipped: false,
+        })
       }
     }
   }
 }
 
 impl RequestHandler<FileUploadResult> for FileUploadParams {
-  async fn handle(&self) -> Result<FileUploadResult, ErrorResponse> {
-    match upload_file(&self.src_path, &self.dest_url).await {
-      Ok(_) => Ok(FileUploadResult {
+  async fn handle(&self, ctx: &TaskContext) -> Result<FileUploadResult, ErrorResponse> {
+    match upload_file(&self.dest_url, &self.src_path, |p| ctx.report_progress(p)).await {
+      Ok(hash) => Ok(FileUploadResult {
         ok: true,
-        hash: xxh3_for_file(&self.src_path)
-          .await
-          .inspect_err(|err| {
-            warn!("Failed to calculate hash for file '{}': {}", self.src_path, err);
-          })
-          .ok(),
+        hash: Some(hash),
       }),
       Err(err) => {
         warn!(
@@ -57,7 +85,7 @@ impl RequestHandler<FileUploadResult> for FileUploadParams {
 }
 
 impl RequestHandler<FileReadResult> for FileReadParams {
-  async fn handle(&self) -> Result<FileReadResult, ErrorResponse> {
+  async fn handle(&self, _ctx: &TaskContext) -> Result<FileReadResult, ErrorResponse> {
     match std::fs::read(&self.src_path) {
       Ok(content) => {
         if let Some(size_limit) = self.size_limit &&
@@ -90,32 +118,211 @@ impl RequestHandler<FileReadResult> for FileReadParams {
 }
 
 impl RequestHandler<FileWriteResult> for FileWriteParams {
-  async fn handle(&self) -> Result<FileWriteResult, ErrorResponse> {
-    let f = OpenOptions::new().write(true).create(true).truncate(true).open(&self.dest_path);
-    match f {
-      Ok(mut file) => {
-        if let Err(err) = file.write_all(self.content.as_bytes()) {
-          warn!("Failed to write to file '{}': {}", self.dest_path, err);
-          return Ok(FileWriteResult { ok: false });
-        }
-        Ok(FileWriteResult { ok: true })
+  async fn handle(&self, _ctx: &TaskContext) -> Result<FileWriteResult, ErrorResponse> {
+    if let Some(expected) = &self.expected_hash {
+      let current = xxh3_for_file(&self.dest_path).await.ok();
+      if !current.as_ref().is_some_and(|hash| hash_eq(HashAlgorithm::Xxh3, hash, expected)) {
+        warn!(
+          "File '{}' has changed, expected hash {} but found {:?}",
+          self.dest_path, expected, current
+        );
+        return Ok(FileWriteResult {
+          ok: false,
+          hash: None,
+          current_hash: current,
+          backup_path: None,
+        });
+      }
+    }
+    let opts = WriteOptions {
+      atomic: self.atomic.unwrap_or(true),
+      append: self.append.unwrap_or(false),
+      create_parents: self.create_parents.unwrap_or(false),
+      backup: self.backup.unwrap_or(false),
+      attrs: FileAttrs {
+        mode: self.mode,
+        owner: self.owner.clone(),
+        group: self.group.clone(),
+      },
+    };
+    match write_file(Path::new(&self.dest_path), self.content.as_bytes(), &opts).await {
+      Ok(backup) => Ok(FileWriteResult {
+        ok: true,
+        hash: xxh3_for_file(&self.dest_path)
+          .await
+          .inspect_err(|err| {
+            warn!("Failed to calculate hash for file '{}': {}", self.dest_path, err);
+          })
+          .ok(),
+        current_hash: None,
+        backup_path: backup.map(|p| p.to_string_lossy().to_string()),
+      }),
+      Err(err) => {
+        warn!("Failed to write to file '{}': {}", self.dest_path, err);
+        Ok(FileWriteResult {
+          ok: false,
+          hash: None,
+          current_hash: None,
+          backup_path: None,
+        })
+      }
+    }
+  }
+}
+
+impl RequestHandler<ImageWriteResult> for ImageWriteParams {
+  async fn handle(&self, ctx: &TaskContext) -> Result<ImageWriteResult, ErrorResponse> {
+    let opts = ImageOptions {
+      compression: self.compression,
+      expected_hash: self.expected_hash.clone(),
+      hash_algorithm: self.hash_algorithm.unwrap_or_default(),
+    };
+    match write_image(&self.src_url, Path::new(&self.dest_path), &opts, |p| {
+      ctx.report_progress(p)
+    })
+    .await
+    {
+      Ok(outcome) => Ok(ImageWriteResult {
+        ok: true,
+        written: outcome.written,
+        hash: Some(outcome.hash),
+        image_hash: Some(outcome.image_hash),
+        reason: None,
+      }),
+      Err(err) => {
+        warn!(
+          "Failed to write image from '{}' to '{}': {}",
+          self.src_url, self.dest_path, err
+        );
+        Ok(ImageWriteResult {
+          ok: false,
+          written: 0,
+          hash: None,
+          image_hash: None,
+          reason: Some(err.to_string()),
+        })
       }
+    }
+  }
+}
+
+impl RequestHandler<DirTransferResult> for DirDownloadParams {
+  async fn handle(&self, ctx: &TaskContext) -> Result<DirTransferResult, ErrorResponse> {
+    #[cfg(unix)]
+    let is_root = nix::unistd::geteuid().is_root();
+    #[cfg(not(unix))]
+    let is_root = false;
+    match download_dir(
+      &self.src_url,
+      &self.dest_path,
+      self.compression,
+      self.preserve_owner.unwrap_or(is_root),
+      |p| ctx.report_progress(p),
+    )
+    .await
+    {
+      Ok(size) => Ok(DirTransferResult {
+        ok: true,
+        size,
+        reason: None,
+      }),
+      Err(err) => {
+        warn!(
+          "Failed to extract archive from '{}' into '{}': {}",
+          self.src_url, self.dest_path, err
+        );
+        Ok(DirTransferResult {
+          ok: false,
+          size: 0,
+          reason: Some(err.to_string()),
+        })
+      }
+    }
+  }
+}
+
+impl RequestHandler<DirTransferResult> for DirUploadParams {
+  async fn handle(&self, ctx: &TaskContext) -> Result<DirTransferResult, ErrorResponse> {
+    match upload_dir(
+      &self.dest_url,
+      &self.src_path,
+      self.compression.unwrap_or_default(),
+      |p| ctx.report_progress(p),
+    )
+    .await
+    {
+      Ok(size) => Ok(DirTransferResult {
+        ok: true,
+        size,
+        reason: None,
+      }),
       Err(err) => {
-        warn!("Failed to open file '{}': {}", self.dest_path, err);
-        Ok(FileWriteResult { ok: false })
+        warn!(
+          "Failed to upload archive of '{}' to '{}': {}",
+          self.src_path, self.dest_url, err
+        );
+        Ok(DirTransferResult {
+          ok: false,
+          size: 0,
+          reason: Some(err.to_string()),
+        })
       }
     }
   }
 }
 
 impl RequestHandler<FileOperationResponse> for FileTransferRequest {
-  async fn handle(&self) -> Result<FileOperationResponse, ErrorResponse> {
+  async fn handle(&self, ctx: &TaskContext) -> Result<FileOperationResponse, ErrorResponse> {
     let r = match self {
-      FileTransferRequest::Download(params) => params.handle().await?.into(),
-      FileTransferRequest::Upload(params) => params.handle().await?.into(),
-      FileTransferRequest::Read(params) => params.handle().await?.into(),
-      FileTransferRequest::Write(params) => params.handle().await?.into(),
+      FileTransferRequest::Download(params) => params.handle(ctx).await?.into(),
+      FileTransferRequest::Upload(params) => params.handle(ctx).await?.into(),
+      FileTransferRequest::Read(params) => params.handle(ctx).await?.into(),
+      FileTransferRequest::Write(params) => params.handle(ctx).await?.into(),
+      FileTransferRequest::WriteImage(params) => params.handle(ctx).await?.into(),
+      FileTransferRequest::DownloadDir(params) => FileOperationResponse::DownloadDir(params.handle(ctx).await?),
+      FileTransferRequest::UploadDir(params) => FileOperationResponse::UploadDir(params.handle(ctx).await?),
     };
     Ok(r)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use axum::{Router, body::Bytes, extract::State, routing::put};
+  use tokio::{net::TcpListener, sync::mpsc};
+
+  use super::*;
+  use crate::utils::testing::TempPath;
+
+  #[tokio::test]
+  async fn test_upload_file() {
+    let received = Arc::new(Mutex::new(None));
+    let app = Router::new()
+      .route(
+        "/upload/{name}",
+        put(
+          |State(received): State<Arc<Mutex<Option<Bytes>>>>, body: Bytes| async move {
+            *received.lock().unwrap() = Some(body);
+          },
+        ),
+      )
+      .with_state(received.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let path = TempPath::new("mxlite-upload");
+    std::fs::write(&path, b"uploaded content").unwrap();
+    let params = FileUploadParams {
+      src_path: path.to_string_lossy().to_string(),
+      dest_url: format!("http://{addr}/upload/file"),
+    };
+    let (tx, _rx) = mpsc::channel(8);
+    let result = params.handle(&TaskContext::new(1, tx)).await.unwrap();
+
+    assert!(result.ok);
+    assert_eq!(received.lock().unwrap().as_deref(), Some(&b"uploaded content"[..]));
+  }
+}
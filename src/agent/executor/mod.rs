@@ -2,43 +2,98 @@ mod cmd_task;
 mod file_task;
 mod script_task;
 
-use log::warn;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use log::{error, warn};
 
 use crate::protocol::messaging::{
-  AgentResponse, AgentResponsePayload, ControllerRequest, ControllerRequestPayload, ErrorResponse, Status,
+  AgentResponse, AgentResponsePayload, ControllerRequest, ControllerRequestPayload, ErrorResponse,
+  Message as ProtocolMessage, Status, TransferProgress,
+};
+
+use crate::agent::{
+  net::{MessageSend as _, MessageSender},
+  state::PendingResults,
 };
 
-use crate::agent::net::{MessageSend as _, MessageSender};
+/// State of a request being handled, used to send partial responses before the final one.
+struct TaskContext {
+  id: u32,
+  tx: MessageSender,
+  partials: AtomicU32,
+}
+
+impl TaskContext {
+  fn new(id: u32, tx: MessageSender) -> Self {
+    TaskContext {
+      id,
+      tx,
+      partials: AtomicU32::new(0),
+    }
+  }
+
+  fn report_progress(&self, progress: TransferProgress) {
+    let seq = self.partials.fetch_add(1, Ordering::Relaxed) + 1;
+    self.tx.send_msg(AgentResponse {
+      id: self.id,
+      status: Status::PartialOk(seq),
+      payload: progress.into(),
+    });
+  }
+
+  /// Status of the final response, depending on whether partial responses were sent before it.
+  fn final_status(&self, ok: bool) -> Status {
+    match (self.partials.load(Ordering::Relaxed), ok) {
+      (0, true) => Status::Ok,
+      (0, false) => Status::Error,
+      (n, true) => Status::Finished(n + 1),
+      (n, false) => Status::FailFast(n + 1),
+    }
+  }
+}
 
 trait RequestHandler<T> {
-  async fn handle(&self) -> Result<T, ErrorResponse>;
+  async fn handle(&self, ctx: &TaskContext) -> Result<T, ErrorResponse>;
 }
 
 impl RequestHandler<AgentResponsePayload> for ControllerRequest {
-  async fn handle(&self) -> Result<AgentResponsePayload, ErrorResponse> {
+  async fn handle(&self, ctx: &TaskContext) -> Result<AgentResponsePayload, ErrorResponse> {
     let r = match &self.payload {
-      ControllerRequestPayload::CommandExecutionRequest(req) => req.handle().await?.into(),
-      ControllerRequestPayload::ScriptEvalRequest(req) => req.handle().await?.into(),
-      ControllerRequestPayload::FileTransferRequest(req) => req.handle().await?.into(),
+      ControllerRequestPayload::CommandExecutionRequest(req) => req.handle(ctx).await?.into(),
+      ControllerRequestPayload::ScriptEvalRequest(req) => req.handle(ctx).await?.into(),
+      ControllerRequestPayload::FileTransferRequest(req) => req.handle(ctx).await?.into(),
     };
     Ok(r)
   }
 }
 
-pub(crate) async fn handle_event(request: ControllerRequest, tx: MessageSender) {
-  match request.handle().await {
-    Ok(payload) => tx.send_msg(AgentResponse {
+/// Handle `request` and send its final response, or keep the response in `pending` if the connection is gone.
+pub(crate) async fn handle_event(request: ControllerRequest, tx: MessageSender, pending: PendingResults) {
+  let ctx = TaskContext::new(request.id, tx.clone());
+  let response = match request.handle(&ctx).await {
+    Ok(payload) => AgentResponse {
       id: request.id,
-      status: Status::Ok,
+      status: ctx.final_status(true),
       payload,
-    }),
+    },
     Err(err) => {
       warn!("Failed to handle request: {err:?}");
-      tx.send_msg(AgentResponse {
+      AgentResponse {
         id: request.id,
-        status: Status::Error,
+        status: ctx.final_status(false),
         payload: err.into(),
-      })
+      }
     }
   };
+  let msg = match String::try_from(ProtocolMessage::from(response.clone())) {
+    Ok(msg) => msg,
+    Err(err) => {
+      error!("Failed to serialize response of task {}: {err}", request.id);
+      return;
+    }
+  };
+  // Unlike partial responses, the final one waits for room in the queue rather than being dropped.
+  if tx.send(msg.into()).await.is_err() {
+    pending.store(&response).await;
+  }
 }
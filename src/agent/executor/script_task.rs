@@ -2,13 +2,13 @@ use anyhow::Result;
 
 use crate::protocol::messaging::{ErrorResponse, ScriptEvalRequest, ScriptEvalResponse};
 
-use super::RequestHandler;
+use super::{RequestHandler, TaskContext};
 
 const ERR_SCRIPT_CONTEXT: &str = "ERR_SCRIPT_CONTEXT";
 const ERR_SCRIPT_EVAL: &str = "ERR_SCRIPT_EVAL";
 
 impl RequestHandler<ScriptEvalResponse> for ScriptEvalRequest {
-  async fn handle(&self) -> Result<ScriptEvalResponse, ErrorResponse> {
+  async fn handle(&self, _ctx: &TaskContext) -> Result<ScriptEvalResponse, ErrorResponse> {
     let Ok(ctx) = crate::script::ExecutorContext::try_new() else {
       return Err(ErrorResponse {
         code: ERR_SCRIPT_CONTEXT.to_string(),
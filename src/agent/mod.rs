@@ -1,4 +1,7 @@
 pub mod cli;
+pub mod enroll;
 pub mod executor;
 pub mod net;
+pub mod state;
+pub mod tls;
 pub mod utils;
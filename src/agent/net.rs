@@ -1,16 +1,23 @@
-use std::str::FromStr;
+use std::{collections::VecDeque, str::FromStr, sync::Arc};
 
-use super::cli::StartupArgs;
+use super::{
+  cli::StartupArgs,
+  state::{ControllerRecord, PendingResults},
+  tls::pinned_cert_config,
+};
 use crate::{
-  discovery::discover_controller_once,
+  discovery::{Endpoint, discover_controller_once},
   protocol::{
     auth::AuthRequest,
-    handshake::{CONNECT_AGENT_AUTH_HEADER_KEY, CONNECT_HANDSHAKE_HEADER_KEY, ConnectHandshake},
+    handshake::{
+      CONNECT_AGENT_AUTH_HEADER_KEY, CONNECT_CONTROLLER_AUTH_HEADER_KEY, CONNECT_HANDSHAKE_HEADER_KEY, ConnectHandshake,
+    },
     messaging::{AgentResponse, Message as ProtocolMessage, PROTOCOL_VERSION},
   },
   system_info::{self},
   utils::{
     hash::sha2_256_for_str,
+    http::set_controller_tls,
     retry::{Retry, RetryResult, async_with_retry},
     util::safe_sleep,
   },
@@ -27,8 +34,9 @@ use crate::utils::signal::ctrl_c;
 use anyhow::{Result, anyhow};
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, error, info, trace, warn};
+use tokio_rustls::rustls::ClientConfig;
 use tokio_tungstenite::{
-  MaybeTlsStream, WebSocketStream, connect_async_with_config,
+  Connector, MaybeTlsStream, WebSocketStream, client_async_tls_with_config,
   tungstenite::{
     client::IntoClientRequest,
     handshake::client::Response,
@@ -82,15 +90,43 @@ enum BreakLoopReason {
   Continue,
 }
 
-pub(crate) async fn start_agent(args: StartupArgs) -> Result<()> {
+pub(crate) async fn start_agent(mut args: StartupArgs) -> Result<()> {
+  // Controllers left to fail over to, in the order discovery ranked them
+  let mut candidates = VecDeque::new();
   loop {
-    let Some(ws_url) = get_ws_url(&args).await else {
-      warn!("No controller URL found");
+    if candidates.is_empty() {
+      // Discovery only gives up when the agent is shut down
+      let Some(ws_urls) = get_ws_urls(&args).await else {
+        info!("Exiting...");
+        break;
+      };
+      candidates = ws_urls.into();
+    }
+    let Some(ws_url) = candidates.pop_front() else {
       continue;
     };
+    if let Some(enroll) = args.enroll.take() {
+      if ws_url.is_link_local() {
+        anyhow::bail!("Cannot enroll through the link-local address of {ws_url}, set the controller URL explicitly");
+      }
+      let Some(identity) = super::enroll::enroll(&args, &enroll, &ws_url).await? else {
+        info!("Exiting...");
+        break;
+      };
+      super::enroll::apply(&mut args, &identity)?;
+    } else if super::enroll::client_cert_expiring(&args) &&
+      let Err(err) = super::enroll::renew_client_cert(&mut args, &ws_url).await
+    {
+      warn!("Failed to renew the client certificate: {err}");
+    }
     info!("Connecting to controller websocket: {}", &ws_url);
+    if ws_url.is_link_local() {
+      warn!("{ws_url} is a link-local address, file tasks will not work over it");
+    }
 
-    match async_with_retry(async || handle_connect(&args, &ws_url).await, 5).await {
+    // The last candidate is retried a while before discovering again, the others are given up on at once
+    let retries = if candidates.is_empty() { 5 } else { 1 };
+    match async_with_retry(async || handle_connect(&args, &ws_url).await, retries).await {
       RetryResult::Break => {
         info!("Exiting...");
         break;
@@ -98,22 +134,41 @@ pub(crate) async fn start_agent(args: StartupArgs) -> Result<()> {
       RetryResult::Return(should_break) => {
         if should_break {
           break;
-        } else {
-          continue;
         }
       }
       RetryResult::NoResult => {
-        warn!("Failed to connect to controller");
+        warn!("Failed to connect to controller {ws_url}");
       }
     }
-    safe_sleep(5000).await;
+    if let Some(next) = candidates.front() {
+      info!("Failing over to controller {next}");
+      continue;
+    }
+    if safe_sleep(5000).await {
+      info!("Exiting...");
+      break;
+    }
   }
   Ok(())
 }
 
-async fn discover_controller() -> Vec<Url> {
+/// Fingerprints of the controllers whose discovery responses are accepted. Empty if any controller is.
+fn discovery_trust(args: &StartupArgs) -> Vec<String> {
+  let mut trusted = args.trusted_controllers.clone();
+  if args.tofu {
+    match args.state_dir.controller() {
+      Ok(Some(pin)) => trusted.push(pin.fingerprint),
+      Ok(None) => {}
+      Err(err) => warn!("Failed to read pinned controller: {err}"),
+    }
+  }
+  trusted
+}
+
+async fn discover_controller(args: &StartupArgs) -> Vec<Endpoint> {
+  let trusted = discovery_trust(args);
   loop {
-    match discover_controller_once().await {
+    match discover_controller_once(&trusted).await {
       Ok(r) => return r,
       Err(e) => {
         error!("Failed to discover controller: {e}");
@@ -125,14 +180,15 @@ async fn discover_controller() -> Vec<Url> {
   }
 }
 
-async fn get_ws_url(args: &StartupArgs) -> Option<Url> {
+/// Controllers to connect to, in order of preference.
+async fn get_ws_urls(args: &StartupArgs) -> Option<Vec<Endpoint>> {
   if let Some(ws_url) = args.ws_url.as_ref() {
     info!("Using controller URL from environment variable: {ws_url}");
-    Some(Url::from_str(ws_url.as_str()).unwrap())
+    Some(vec![Endpoint::new(Url::from_str(ws_url.as_str()).unwrap())])
   } else {
     info!("Discovering controller URL...");
     let controllers = select! {
-      r = discover_controller() => r,
+      r = discover_controller(args) => r,
       _ = ctrl_c() => {
         info!("Canceling discovery and exit");
         return None;
@@ -142,86 +198,184 @@ async fn get_ws_url(args: &StartupArgs) -> Option<Url> {
       warn!("No controller discovered");
       None
     } else {
-      Some(controllers[0].clone())
+      Some(controllers)
     }
   }
 }
 
+/// Returns the challenge the controller has to sign along with the connection.
 async fn connect_to(
-  args: &StartupArgs, ws_url: &Url,
-) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response)> {
+  args: &StartupArgs, endpoint: &Endpoint,
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response, Vec<u8>)> {
+  let ws_url = &endpoint.url;
   let mut req = ws_url.as_str().into_client_request()?;
   let headers = req.headers_mut();
-  headers.insert(
-    CONNECT_HANDSHAKE_HEADER_KEY,
-    (ConnectHandshake {
-      version: PROTOCOL_VERSION,
-      controller_url: ws_url.clone(),
-      host_id: args.host_id.clone(),
-      session_id: args.session_id.clone(),
-      envs: args.envs.clone(),
-      system_info: system_info::collect_info(),
-    })
-    .to_string()
-    .parse()?,
-  );
-  handle_pre_auth(args, headers)?;
+  let handshake = ConnectHandshake {
+    version: PROTOCOL_VERSION,
+    controller_url: ws_url.clone(),
+    host_id: args.host_id.clone(),
+    session_id: args.session_id.clone(),
+    envs: args.envs.clone(),
+    system_info: system_info::collect_info(),
+  };
+  headers.insert(CONNECT_HANDSHAKE_HEADER_KEY, handshake.to_string().parse()?);
+  let auth = handle_pre_auth(args, headers)?;
+  let challenge = handshake.controller_challenge(&auth.nonce);
 
-  connect_async_with_config(req.clone(), Some(WebSocketConfig { ..Default::default() }), false)
-    .await
-    .map_err(|e| {
-      error!("Failed to connect to controller: {e}");
-      anyhow!(e)
-    })
+  let connector = tls_config(args, endpoint)?.map(Connector::Rustls);
+  // Connect the socket here, link-local addresses need the scope of the interface the controller was discovered on
+  let stream = TcpStream::connect(&*endpoint.socket_addrs().await?).await?;
+  client_async_tls_with_config(
+    req.clone(),
+    stream,
+    Some(WebSocketConfig { ..Default::default() }),
+    connector,
+  )
+  .await
+  .map(|(ws, resp)| (ws, resp, challenge))
+  .map_err(|e| {
+    error!("Failed to connect to controller: {e}");
+    anyhow!(e)
+  })
 }
 
-fn handle_pre_auth(args: &StartupArgs, headers: &mut http::HeaderMap) -> Result<()> {
+/// TLS configuration to reach `endpoint` with: the pinned CA if there is one, else the certificate a trusted
+/// controller advertised in a signed discovery response. Without either, the certificate has to pass the usual checks.
+pub(super) fn tls_config(args: &StartupArgs, endpoint: &Endpoint) -> Result<Option<Arc<ClientConfig>>> {
+  if let Some(tls_config) = &args.tls_config {
+    return Ok(Some(tls_config.clone()));
+  }
+  match &endpoint.cert_fingerprint {
+    Some(fingerprint) => Ok(Some(Arc::new(pinned_cert_config(fingerprint)?))),
+    None => Ok(None),
+  }
+}
+
+fn handle_pre_auth(args: &StartupArgs, headers: &mut http::HeaderMap) -> Result<AuthRequest> {
   let (_, privkey) = &args.key_pair;
   let sign = AuthRequest::new_with_privkey_string(privkey)?;
   headers.insert(CONNECT_AGENT_AUTH_HEADER_KEY, sign.encode().parse()?);
-  Ok(())
+  Ok(sign)
 }
 
-fn handle_post_auth(args: &StartupArgs, resp: &Response) -> bool {
+/// Outcome of checking the controller's signature on the handshake response.
+enum ControllerAuth {
+  Rejected,
+  /// The controller did not sign the response, which is allowed unless authentication is enforced
+  Unsigned,
+  /// Accepted controller, with the fingerprint of its key
+  Verified(String),
+}
+
+/// Check that the controller signed `challenge` with an accepted key, and with the key it was discovered with.
+///
+/// A non-empty list of trusted controllers accepts only those, and the pinned one in TOFU mode, even without
+/// `--enforce-auth`.
+fn handle_post_auth(args: &StartupArgs, endpoint: &Endpoint, resp: &Response, challenge: &[u8]) -> ControllerAuth {
   let headers = resp.headers();
-  let Some(auth_header) = headers.get(CONNECT_AGENT_AUTH_HEADER_KEY) else {
+  let Some(auth_header) = headers.get(CONNECT_CONTROLLER_AUTH_HEADER_KEY) else {
     warn!("No authentication header found in response");
-    return !args.enforce_auth;
+    let required = args.enforce_auth || args.tofu || !args.trusted_controllers.is_empty();
+    return if required || endpoint.fingerprint.is_some() {
+      ControllerAuth::Rejected
+    } else {
+      ControllerAuth::Unsigned
+    };
   };
   let Ok(header_val) = auth_header.to_str() else {
     error!("Failed to convert authentication header to string");
-    return false;
+    return ControllerAuth::Rejected;
   };
   let Ok(auth_req) = AuthRequest::decode(header_val) else {
     error!("Failed to decode authentication header");
-    return false;
+    return ControllerAuth::Rejected;
   };
-  if !auth_req.verify() {
+  if !auth_req.verify_challenge(challenge) {
     error!("Authentication failed, controller is not trusted");
-    return false;
+    return ControllerAuth::Rejected;
   }
-  let pubkey = auth_req.encoded_pubkey();
-  if args.enforce_auth {
-    let Ok(hashed) = sha2_256_for_str(&pubkey) else {
-      error!("Failed to hash public key");
-      return false;
-    };
-    return args.trusted_controllers.contains(&hashed);
+  let Ok(hashed) = sha2_256_for_str(&auth_req.encoded_pubkey()) else {
+    error!("Failed to hash public key");
+    return ControllerAuth::Rejected;
+  };
+  if let Some(fingerprint) = &endpoint.fingerprint &&
+    *fingerprint != hashed
+  {
+    error!("Controller key {hashed} does not match the key {fingerprint} it was discovered with");
+    return ControllerAuth::Rejected;
+  }
+  if args.trusted_controllers.contains(&hashed) {
+    return ControllerAuth::Verified(hashed);
+  }
+  if args.tofu {
+    match args.state_dir.controller() {
+      Ok(Some(pin)) if pin.fingerprint != hashed => {
+        error!(
+          "Controller key {hashed} does not match the key {} pinned at {}. Run `mxa trust unpin` if it was replaced",
+          pin.fingerprint, pin.url
+        );
+        return ControllerAuth::Rejected;
+      }
+      Ok(Some(_)) => return ControllerAuth::Verified(hashed),
+      Ok(None) => {}
+      Err(err) => {
+        error!("Failed to read pinned controller: {err}");
+        return ControllerAuth::Rejected;
+      }
+    }
+  }
+  if args.enforce_auth || !args.trusted_controllers.is_empty() {
+    error!("Controller key {hashed} is not trusted");
+    return ControllerAuth::Rejected;
+  }
+  ControllerAuth::Verified(hashed)
+}
+
+/// Pin the first controller the agent authenticated in TOFU mode, it is the only controller trusted afterwards.
+///
+/// Nothing is recorded without `--tofu`, as controllers are then accepted unsigned or with any key, and a record would
+/// pin one of them once TOFU is turned on.
+async fn pin_controller(args: &StartupArgs, fingerprint: String, ws_url: &Url) {
+  if !args.tofu {
+    return;
+  }
+  match args.state_dir.controller() {
+    // Any other key was rejected by `handle_post_auth`
+    Ok(Some(_)) => {}
+    Ok(None) => {
+      let record = ControllerRecord::new(fingerprint, ws_url.to_string());
+      match args.state_dir.save_controller(&record).await {
+        Ok(()) => info!("Pinned controller {} at {}", record.fingerprint, record.url),
+        Err(err) => warn!("Failed to pin controller: {err}"),
+      }
+    }
+    Err(err) => warn!("Failed to read pinned controller: {err}"),
   }
-  true
 }
 
 /// Returns a boolean indicating whether the loop should be break
 /// If `None` is returned, it means a error occurred and the loop should continue after sleep
-async fn handle_connect(args: &StartupArgs, ws_url: &Url) -> Retry<bool> {
-  match connect_to(args, ws_url).await {
-    Ok((ws, resp)) => {
-      if !handle_post_auth(args, &resp) {
-        error!("Authentication failed, exiting");
-        return Retry::Return(false);
-      }
+async fn handle_connect(args: &StartupArgs, endpoint: &Endpoint) -> Retry<bool> {
+  match connect_to(args, endpoint).await {
+    Ok((ws, resp, challenge)) => {
+      let fingerprint = match handle_post_auth(args, endpoint, &resp, &challenge) {
+        ControllerAuth::Rejected => {
+          error!("Authentication of the controller failed");
+          return Retry::Return(false);
+        }
+        ControllerAuth::Unsigned => None,
+        ControllerAuth::Verified(fingerprint) => {
+          pin_controller(args, fingerprint.clone(), &endpoint.url).await;
+          Some(fingerprint)
+        }
+      };
       info!("Connected to controller");
-      match handle_conn(ws).await {
+      // File URLs of the controller are reached like the controller itself
+      match tls_config(args, endpoint) {
+        Ok(config) => set_controller_tls(&endpoint.url, config),
+        Err(err) => warn!("Failed to set up file transfers with the controller: {err}"),
+      }
+      match handle_conn(ws, args.state_dir.pending_results(fingerprint.as_deref())).await {
         Err(e) => {
           error!("Failed to handle connection: {e}");
           Retry::RetryImmediate
@@ -246,11 +400,18 @@ async fn handle_connect(args: &StartupArgs, ws_url: &Url) -> Retry<bool> {
   }
 }
 
-async fn handle_conn(ws: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Result<BreakLoopReason> {
+async fn handle_conn(
+  ws: WebSocketStream<MaybeTlsStream<TcpStream>>, pending: PendingResults,
+) -> Result<BreakLoopReason> {
   let (mut tx, mut rx) = ws.split();
   let (tx_tx, mut tx_rx) = mpsc::channel::<Message>(16);
   debug!("Websocket connected to controller. Begin to handle message loop");
-  loop {
+  tokio::spawn({
+    let pending = pending.clone();
+    let tx_tx = tx_tx.clone();
+    async move { pending.resend(tx_tx).await }
+  });
+  let exit = loop {
     select! {
       _ = ctrl_c() => {
         info!("Shutting down websocket connection");
@@ -265,7 +426,7 @@ async fn handle_conn(ws: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Result<B
         }
       }
       msg = rx.next() => {
-        match handle_ws_message(msg, tx_tx.clone()).await {
+        match handle_ws_message(msg, tx_tx.clone(), &pending).await {
           Ok(BreakLoopReason::LostConnection) => {
             error!("Lost connection to controller");
             break Ok(BreakLoopReason::LostConnection);
@@ -290,15 +451,26 @@ async fn handle_conn(ws: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Result<B
         }
       }
     }
+  };
+  // Keep the results that were queued but not sent before the connection ended.
+  tx_rx.close();
+  while let Ok(msg) = tx_rx.try_recv() {
+    if let Message::Text(text) = msg &&
+      let Ok(ProtocolMessage::AgentResponse(response)) = ProtocolMessage::try_from(text.as_str()) &&
+      !response.status.is_partial()
+    {
+      pending.store(&response).await;
+    }
   }
+  exit
 }
 
 async fn handle_ws_message(
-  event: Option<Result<Message, tokio_tungstenite::tungstenite::Error>>, tx: Sender<Message>,
+  event: Option<Result<Message, tokio_tungstenite::tungstenite::Error>>, tx: Sender<Message>, pending: &PendingResults,
 ) -> Result<BreakLoopReason> {
   if let Some(event) = event {
     match event {
-      Ok(ws_msg) => match handle_msg(ws_msg, tx).await {
+      Ok(ws_msg) => match handle_msg(ws_msg, tx, pending).await {
         Ok(c) => Ok(c),
         Err(e) => {
           error!("Failed to handle message: {e}");
@@ -315,11 +487,11 @@ async fn handle_ws_message(
   }
 }
 
-async fn handle_msg(msg: Message, tx: Sender<Message>) -> Result<BreakLoopReason> {
+async fn handle_msg(msg: Message, tx: Sender<Message>, pending: &PendingResults) -> Result<BreakLoopReason> {
   match msg {
     Message::Text(msg) => {
       trace!("Received text message from controller");
-      handle_text_msg(msg, tx);
+      handle_text_msg(msg, tx, pending);
     }
     Message::Binary(_) => {
       warn!("Received binary message from controller, which is not supported");
@@ -341,11 +513,12 @@ async fn handle_msg(msg: Message, tx: Sender<Message>) -> Result<BreakLoopReason
   Ok(BreakLoopReason::Continue)
 }
 
-fn handle_text_msg(msg: String, tx: Sender<Message>) {
+fn handle_text_msg(msg: String, tx: Sender<Message>, pending: &PendingResults) {
   match ProtocolMessage::try_from(msg.as_str()) {
     Ok(ProtocolMessage::ControllerRequest(request)) => {
       info!("Received event: {request:?}");
-      tokio::spawn(async move { handle_event(request, tx).await });
+      let pending = pending.clone();
+      tokio::spawn(async move { handle_event(request, tx, pending).await });
     }
     Ok(_) => {
       warn!("Received unsupported message type, ignoring: {msg}");
@@ -0,0 +1,345 @@
+use std::{
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::fs;
+
+use crate::{
+  agent::net::MessageSender,
+  protocol::{
+    auth,
+    messaging::{AgentResponse, Message as ProtocolMessage},
+  },
+  utils::{
+    fs::{FileAttrs, WriteOptions, apply_attrs, write_file},
+    hash::sha2_256_for_str,
+    util::random_str,
+  },
+};
+
+const IDENTITY_FILE: &str = "identity.json";
+const TRUSTED_CONTROLLERS_FILE: &str = "trusted-controllers.json";
+const CONTROLLER_FILE: &str = "controller.json";
+const SESSION_FILE: &str = "session.json";
+const PENDING_RESULTS_DIR: &str = "pending-results";
+/// Age after which a kept result is not sent anymore
+const PENDING_RESULT_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Results kept for a controller, the oldest are dropped beyond
+const MAX_PENDING_RESULTS: usize = 256;
+
+/// Keys the agent authenticates with, and the client certificate it was issued when it enrolled.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Identity {
+  pub public_key: String,
+  pub private_key: String,
+  /// Whether the controller accepted the key through enrollment
+  #[serde(default)]
+  pub enrolled: bool,
+  /// PEM encoded client certificate, if the controller requires mutual TLS
+  pub tls_cert: Option<String>,
+  /// PEM encoded private key of the client certificate
+  pub tls_key: Option<String>,
+  /// PEM encoded CA certificate of the controller
+  pub ca_cert: Option<String>,
+}
+
+impl Identity {
+  pub fn generate() -> Self {
+    let (public_key, private_key) = auth::generate_keypair_str();
+    Identity {
+      public_key,
+      private_key,
+      enrolled: false,
+      tls_cert: None,
+      tls_key: None,
+      ca_cert: None,
+    }
+  }
+
+  /// PEM encoded client certificate and key, if the identity has them.
+  pub fn client_cert(&self) -> Option<(&str, &str)> { Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?)) }
+
+  /// The hash controllers and operators know this key by.
+  pub fn fingerprint(&self) -> Result<String> { Ok(sha2_256_for_str(&self.public_key)?) }
+}
+
+/// The controller pinned in TOFU mode, the first one the agent authenticated.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ControllerRecord {
+  /// SHA-256 of the controller's public key
+  pub fingerprint: String,
+  pub url: String,
+  /// Seconds since the Unix epoch
+  pub first_seen: u64,
+}
+
+impl ControllerRecord {
+  pub fn new(fingerprint: String, url: String) -> Self {
+    ControllerRecord {
+      fingerprint,
+      url,
+      first_seen: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+    }
+  }
+}
+
+/// Directory the agent keeps its state in between launches. Only its owner may read it.
+#[derive(Clone, Debug)]
+pub(crate) struct StateDir {
+  path: PathBuf,
+}
+
+impl StateDir {
+  pub fn new(path: impl Into<PathBuf>) -> Self { StateDir { path: path.into() } }
+
+  pub fn path(&self) -> &Path { &self.path }
+
+  fn read<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+    let path = self.path.join(name);
+    match std::fs::read_to_string(&path) {
+      Ok(content) => {
+        Ok(Some(serde_json::from_str(&content).map_err(|err| {
+          anyhow::anyhow!("Failed to parse {}: {err}", path.display())
+        })?))
+      }
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(err) => anyhow::bail!("Failed to read {}: {err}", path.display()),
+    }
+  }
+
+  async fn create_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path).await?;
+    apply_attrs(
+      path,
+      &FileAttrs {
+        mode: Some(0o700),
+        ..Default::default()
+      },
+    )
+    .await
+  }
+
+  /// Replace `path` atomically with `value`, readable by the owner only.
+  async fn write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let opts = WriteOptions {
+      atomic: true,
+      attrs: FileAttrs {
+        mode: Some(0o600),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    write_file(path, &serde_json::to_vec_pretty(value)?, &opts)
+      .await
+      .map_err(|err| anyhow::anyhow!("Failed to write {}: {err}", path.display()))?;
+    Ok(())
+  }
+
+  async fn save<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+    Self::create_dir(&self.path).await?;
+    Self::write(&self.path.join(name), value).await
+  }
+
+  pub fn identity(&self) -> Result<Option<Identity>> { self.read(IDENTITY_FILE) }
+
+  pub async fn save_identity(&self, identity: &Identity) -> Result<()> { self.save(IDENTITY_FILE, identity).await }
+
+  /// Fingerprints of the controllers trusted in addition to those given on the command line.
+  pub fn trusted_controllers(&self) -> Result<Vec<String>> {
+    Ok(self.read(TRUSTED_CONTROLLERS_FILE)?.unwrap_or_default())
+  }
+
+  pub async fn save_trusted_controllers(&self, fingerprints: &[String]) -> Result<()> {
+    self.save(TRUSTED_CONTROLLERS_FILE, &fingerprints).await
+  }
+
+  pub fn controller(&self) -> Result<Option<ControllerRecord>> { self.read(CONTROLLER_FILE) }
+
+  pub async fn save_controller(&self, record: &ControllerRecord) -> Result<()> {
+    self.save(CONTROLLER_FILE, record).await
+  }
+
+  /// Remove the controller record, returning it.
+  pub fn clear_controller(&self) -> Result<Option<ControllerRecord>> {
+    let record = self.controller()?;
+    if record.is_some() {
+      std::fs::remove_file(self.path.join(CONTROLLER_FILE))?;
+    }
+    Ok(record)
+  }
+
+  /// Id of the agent's session, the same across launches so that the controller recognizes the results kept for it.
+  pub async fn session_id(&self) -> Result<String> {
+    if let Some(session_id) = self.read(SESSION_FILE)? {
+      return Ok(session_id);
+    }
+    let session_id = random_str(16);
+    self.save(SESSION_FILE, &session_id).await?;
+    Ok(session_id)
+  }
+
+  /// Results kept for the controller with the key `fingerprint`. Results of tasks sent by a controller that did not
+  /// authenticate itself are not kept, they could not be told apart from those of another controller.
+  pub fn pending_results(&self, fingerprint: Option<&str>) -> PendingResults {
+    PendingResults {
+      dir: fingerprint.map(|fingerprint| self.path.join(PENDING_RESULTS_DIR).join(fingerprint)),
+    }
+  }
+
+  /// Number of results waiting to be sent, to all controllers.
+  pub fn pending_results_count(&self) -> usize {
+    std::fs::read_dir(self.path.join(PENDING_RESULTS_DIR)).map_or(0, |entries| {
+      entries.flatten().map(|e| PendingResults { dir: Some(e.path()) }.count()).sum()
+    })
+  }
+}
+
+/// Final responses of tasks that completed while the agent was disconnected, sent once it reconnects to the same
+/// controller. At most [`MAX_PENDING_RESULTS`] are kept, for up to [`PENDING_RESULT_TTL`].
+#[derive(Clone, Debug)]
+pub(crate) struct PendingResults {
+  dir: Option<PathBuf>,
+}
+
+impl PendingResults {
+  /// Keep `response` until it can be sent. Failures are logged, the response is lost then.
+  pub async fn store(&self, response: &AgentResponse) {
+    let Some(dir) = &self.dir else {
+      warn!(
+        "Dropping the result of task {}, the controller did not authenticate itself",
+        response.id
+      );
+      return;
+    };
+    for path in Self::entries(dir).await.into_iter().skip(MAX_PENDING_RESULTS - 1) {
+      warn!("Dropping the kept result {}, too many are waiting", path.display());
+      let _ = fs::remove_file(&path).await;
+    }
+    let path = dir.join(format!("{}.json", response.id));
+    let r = match StateDir::create_dir(dir).await {
+      Ok(()) => StateDir::write(&path, response).await,
+      Err(err) => Err(err),
+    };
+    match r {
+      Ok(()) => info!("Kept the result of task {} to send once reconnected", response.id),
+      Err(err) => warn!("Failed to keep the result of task {}: {err}", response.id),
+    }
+  }
+
+  /// Number of responses waiting to be sent.
+  pub fn count(&self) -> usize {
+    let Some(dir) = &self.dir else {
+      return 0;
+    };
+    std::fs::read_dir(dir).map_or(0, |entries| {
+      entries
+        .filter(|e| e.as_ref().is_ok_and(|e| e.path().extension().is_some_and(|ext| ext == "json")))
+        .count()
+    })
+  }
+
+  /// Responses kept in `dir`, newest first. Expired ones are removed.
+  async fn entries(dir: &Path) -> Vec<PathBuf> {
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+      return vec![];
+    };
+    let mut kept = vec![];
+    while let Ok(Some(entry)) = entries.next_entry().await {
+      let path = entry.path();
+      if path.extension().is_none_or(|ext| ext != "json") {
+        continue;
+      }
+      let age = entry.metadata().await.ok().and_then(|m| m.modified().ok()).and_then(|t| t.elapsed().ok());
+      match age {
+        Some(age) if age > PENDING_RESULT_TTL => {
+          info!("Dropping the kept result {}, it expired", path.display());
+          let _ = fs::remove_file(&path).await;
+        }
+        age => kept.push((age.unwrap_or_default(), path)),
+      }
+    }
+    kept.sort_by_key(|(age, _)| *age);
+    kept.into_iter().map(|(_, path)| path).collect()
+  }
+
+  /// Send the kept responses through `tx`, oldest first, removing each once it is queued.
+  pub async fn resend(&self, tx: MessageSender) {
+    let Some(dir) = &self.dir else {
+      return;
+    };
+    for path in Self::entries(dir).await.into_iter().rev() {
+      let response = match fs::read(&path)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(serde_json::from_slice::<AgentResponse>(&content)?))
+      {
+        Ok(response) => response,
+        Err(err) => {
+          warn!("Dropping unreadable pending result {}: {err}", path.display());
+          let _ = fs::remove_file(&path).await;
+          continue;
+        }
+      };
+      let id = response.id;
+      let Ok(msg) = String::try_from(ProtocolMessage::from(response)) else {
+        continue;
+      };
+      if tx.send(msg.into()).await.is_err() {
+        debug!("Connection closed while sending pending results");
+        return;
+      }
+      info!("Sent the kept result of task {id}");
+      if let Err(err) = fs::remove_file(&path).await {
+        warn!("Failed to remove pending result {}: {err}", path.display());
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    protocol::messaging::{ScriptEvalResponse, Status},
+    utils::testing::TempPath,
+  };
+
+  #[tokio::test]
+  async fn test_state_dir() {
+    let dir = TempPath::new("mxa-state");
+    let state = StateDir::new(dir.to_path_buf());
+    assert!(state.identity().unwrap().is_none());
+    let identity = Identity::generate();
+    state.save_identity(&identity).await.unwrap();
+    assert_eq!(state.identity().unwrap().unwrap().public_key, identity.public_key);
+    let session_id = state.session_id().await.unwrap();
+    assert_eq!(state.session_id().await.unwrap(), session_id);
+
+    let pending = state.pending_results(Some("a"));
+    pending
+      .store(&AgentResponse {
+        id: 1,
+        status: Status::Ok,
+        payload: ScriptEvalResponse {
+          ok: true,
+          result: String::new(),
+        }
+        .into(),
+      })
+      .await;
+    assert_eq!(pending.count(), 1);
+    assert_eq!(state.pending_results_count(), 1);
+    // Only the controller the task came from gets the result
+    let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+    state.pending_results(Some("b")).resend(tx).await;
+    assert!(rx.recv().await.is_none());
+    let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+    pending.resend(tx).await;
+    assert!(rx.recv().await.is_some());
+    assert_eq!(pending.count(), 0);
+  }
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio_rustls::rustls::{
+  CertificateError, ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+  client::{
+    WebPkiServerVerifier,
+    danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+  },
+  crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+  pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject as _},
+};
+
+use crate::utils::hash::sha2_256_for_bytes;
+
+/// Accepts only controllers with a certificate issued by a pinned CA.
+///
+/// The CA is private to the controller, so the certificate is not required to name the address it was reached at.
+/// Discovered controllers are reached by IP, which the generated certificates do not list.
+#[derive(Debug)]
+struct PinnedCaVerifier {
+  inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for PinnedCaVerifier {
+  fn verify_server_cert(
+    &self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], server_name: &ServerName<'_>,
+    ocsp_response: &[u8], now: UnixTime,
+  ) -> Result<ServerCertVerified, Error> {
+    match self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+      Err(Error::InvalidCertificate(
+        CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+      )) => Ok(ServerCertVerified::assertion()),
+      r => r,
+    }
+  }
+
+  fn verify_tls12_signature(
+    &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, Error> {
+    self.inner.verify_tls12_signature(message, cert, dss)
+  }
+
+  fn verify_tls13_signature(
+    &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, Error> {
+    self.inner.verify_tls13_signature(message, cert, dss)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> { self.inner.supported_verify_schemes() }
+}
+
+/// Accepts only the controller certificate with the SHA-256 fingerprint advertised by discovery.
+///
+/// Used when no CA is pinned, for controllers with self-signed certificates. The certificate is not checked otherwise,
+/// so the fingerprint must come from a discovery response signed by a trusted controller.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+  fingerprint: String,
+  provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+  fn verify_server_cert(
+    &self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8], _now: UnixTime,
+  ) -> Result<ServerCertVerified, Error> {
+    match sha2_256_for_bytes(end_entity) {
+      Ok(fingerprint) if fingerprint.eq_ignore_ascii_case(&self.fingerprint) => Ok(ServerCertVerified::assertion()),
+      _ => Err(Error::InvalidCertificate(
+        CertificateError::ApplicationVerificationFailure,
+      )),
+    }
+  }
+
+  fn verify_tls12_signature(
+    &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, Error> {
+    verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn verify_tls13_signature(
+    &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, Error> {
+    verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.provider.signature_verification_algorithms.supported_schemes()
+  }
+}
+
+/// Build the TLS configuration to connect to a controller whose certificate has the SHA-256 `fingerprint`.
+pub fn pinned_cert_config(fingerprint: &str) -> Result<ClientConfig> {
+  let provider = Arc::new(ring::default_provider());
+  let verifier = PinnedCertVerifier {
+    fingerprint: fingerprint.to_string(),
+    provider: provider.clone(),
+  };
+  Ok(
+    ClientConfig::builder_with_provider(provider)
+      .with_safe_default_protocol_versions()?
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(verifier))
+      .with_no_client_auth(),
+  )
+}
+
+/// Build the TLS configuration to connect to the controller with.
+///
+/// `ca_cert` pins the CA the controller certificate must be issued by. `identity` is the PEM encoded client
+/// certificate and key to present when the controller requires mutual TLS.
+pub fn client_config(ca_cert: &str, identity: Option<(&str, &str)>) -> Result<ClientConfig> {
+  let provider = Arc::new(ring::default_provider());
+  let mut roots = RootCertStore::empty();
+  roots.add(CertificateDer::from_pem_slice(ca_cert.as_bytes())?)?;
+  let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+  let builder = ClientConfig::builder_with_provider(provider)
+    .with_safe_default_protocol_versions()?
+    .dangerous()
+    .with_custom_certificate_verifier(Arc::new(PinnedCaVerifier { inner: verifier }));
+  Ok(match identity {
+    Some((cert, key)) => builder.with_client_auth_cert(
+      CertificateDer::pem_slice_iter(cert.as_bytes()).collect::<Result<_, _>>()?,
+      PrivateKeyDer::from_pem_slice(key.as_bytes())?,
+    )?,
+    None => builder.with_no_client_auth(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::cert::{cert_fingerprint, generate_ca_cert};
+
+  #[test]
+  fn test_pinned_cert_verifier() {
+    let (cert, _) = generate_ca_cert().unwrap();
+    let (other, _) = generate_ca_cert().unwrap();
+    let der = CertificateDer::from_pem_slice(cert.as_bytes()).unwrap();
+    let server_name = ServerName::try_from("192.0.2.1").unwrap();
+    let verify = |fingerprint: String| {
+      PinnedCertVerifier {
+        fingerprint,
+        provider: Arc::new(ring::default_provider()),
+      }
+      .verify_server_cert(&der, &[], &server_name, &[], UnixTime::now())
+    };
+    assert!(verify(cert_fingerprint(&cert).unwrap()).is_ok());
+    assert!(verify(cert_fingerprint(&cert).unwrap().to_uppercase()).is_ok());
+    assert!(verify(cert_fingerprint(&other).unwrap()).is_err());
+    assert!(verify(String::new()).is_err());
+  }
+}
@@ -0,0 +1,209 @@
+use std::{collections::HashMap, fmt::Display, net::SocketAddr, path::Path, sync::Arc};
+
+use anyhow::Result;
+use axum::http::Method;
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+
+/// What an API token is allowed to do.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+  /// Everything, including the scopes below
+  #[serde(rename = "admin")]
+  Admin,
+  /// Read hosts, tasks, results and published files
+  #[serde(rename = "read-only")]
+  ReadOnly,
+  #[serde(rename = "task:exec")]
+  TaskExec,
+  #[serde(rename = "task:file")]
+  TaskFile,
+  #[serde(rename = "task:script")]
+  TaskScript,
+  #[serde(rename = "file-map:write")]
+  FileMapWrite,
+  #[serde(rename = "fs:read")]
+  FsRead,
+}
+
+impl Scope {
+  /// Scope needed for `method` on `path`, relative to `/api`.
+  ///
+  /// Changes that have no scope of their own, like starting discovery, need `admin`.
+  pub fn required_for(method: &Method, path: &str) -> Scope {
+    let read = method == Method::GET || method == Method::HEAD;
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    match (segments.next(), segments.next()) {
+      (Some("fs"), _) => Scope::FsRead,
+      (Some("audit"), _) => Scope::Admin,
+      (Some("task"), Some("exec")) => Scope::TaskExec,
+      (Some("task"), Some("file")) => Scope::TaskFile,
+      (Some("task"), Some("script")) => Scope::TaskScript,
+      // Files received from file tasks
+      (Some("uploads"), _) => Scope::TaskFile,
+      (Some("file-map"), _) if !read => Scope::FileMapWrite,
+      _ if read => Scope::ReadOnly,
+      _ => Scope::Admin,
+    }
+  }
+}
+
+/// Tokens declared in a YAML file:
+///
+/// ```yaml
+/// tokens:
+///   - name: ci
+///     token: 0123456789abcdef
+///     scopes: [read-only, task:exec]
+///     hosts: # optional, all labels must match
+///       role: web
+/// labels: # labels of hosts, by host id
+///   3d1219c7-c4c5-404a-aa1f-6d2a48adfda4:
+///     role: web
+/// ```
+///
+/// Hosts are only labelled here, what agents report about themselves is not trusted. A token limited to some labels
+/// cannot target hosts without labels.
+#[derive(Deserialize)]
+struct ApiTokenConfig {
+  #[serde(default)]
+  tokens: Vec<TokenEntry>,
+  #[serde(default)]
+  labels: HashMap<String, Labels>,
+}
+
+type Labels = HashMap<String, String>;
+
+#[derive(Deserialize)]
+struct TokenEntry {
+  name: String,
+  token: String,
+  scopes: Vec<Scope>,
+  #[serde(default)]
+  hosts: Labels,
+}
+
+/// Who is calling the API and what they may do.
+#[derive(Clone, Debug)]
+pub struct Caller {
+  pub name: String,
+  /// Address the request came from
+  pub remote_addr: Option<SocketAddr>,
+  scopes: Vec<Scope>,
+  /// Labels a host must have to be targeted. Empty if any host may be.
+  hosts: Labels,
+  /// Labels of hosts by host id, from the token config
+  labels: Arc<HashMap<String, Labels>>,
+}
+
+impl Caller {
+  fn admin(name: &str) -> Self {
+    Caller {
+      name: name.to_string(),
+      remote_addr: None,
+      scopes: vec![Scope::Admin],
+      hosts: HashMap::new(),
+      labels: Arc::default(),
+    }
+  }
+
+  pub fn allows(&self, scope: Scope) -> bool { self.scopes.iter().any(|s| *s == scope || *s == Scope::Admin) }
+
+  /// Whether the caller may act on, or read about, the host `host_id`.
+  pub fn may_target(&self, host_id: &str) -> bool {
+    if self.hosts.is_empty() {
+      return true;
+    }
+    let Some(labels) = self.labels.get(host_id) else {
+      return false;
+    };
+    self.hosts.iter().all(|(k, v)| labels.get(k) == Some(v))
+  }
+}
+
+impl Display for Caller {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "token {}", self.name) }
+}
+
+/// The tokens accepted by the API. Without any, the API is open to everyone.
+pub struct ApiTokens {
+  /// Callers by the SHA-256 of their token, so lookups do not leak tokens through timing
+  tokens: HashMap<[u8; 32], Caller>,
+}
+
+impl ApiTokens {
+  /// Load the tokens declared in `config`, plus the legacy API key with every scope.
+  pub fn load(config: Option<&Path>, apikey: Option<&str>) -> Result<Self> {
+    let mut tokens = HashMap::new();
+    if let Some(path) = config {
+      let content = std::fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read API tokens from {}: {err}", path.display()))?;
+      let config: ApiTokenConfig = serde_yml::from_str(&content)?;
+      let labels = Arc::new(config.labels);
+      for entry in config.tokens {
+        let caller = Caller {
+          name: entry.name,
+          remote_addr: None,
+          scopes: entry.scopes,
+          hosts: entry.hosts,
+          labels: labels.clone(),
+        };
+        if let Some(previous) = tokens.insert(digest(&entry.token), caller) {
+          anyhow::bail!("Token {} is declared twice", previous.name);
+        }
+      }
+    }
+    if let Some(key) = apikey {
+      tokens.insert(digest(key), Caller::admin("apikey"));
+    }
+    Ok(ApiTokens { tokens })
+  }
+
+  pub fn is_empty(&self) -> bool { self.tokens.is_empty() }
+
+  /// The caller presenting `authorization`, an `Authorization` header value.
+  ///
+  /// Returns `None` for unknown tokens, or an anonymous admin if no tokens are configured.
+  pub fn authenticate(&self, authorization: Option<&[u8]>) -> Option<Caller> {
+    if self.is_empty() {
+      return Some(Caller::admin("anonymous"));
+    }
+    let token = authorization?.strip_prefix(b"Bearer ")?;
+    self.tokens.get(&digest(token)).cloned()
+  }
+}
+
+fn digest(token: impl AsRef<[u8]>) -> [u8; 32] { Sha256::digest(token.as_ref()).into() }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_required_scope() {
+    assert_eq!(Scope::required_for(&Method::GET, "/list"), Scope::ReadOnly);
+    assert_eq!(Scope::required_for(&Method::GET, "/fs/read"), Scope::FsRead);
+    assert_eq!(Scope::required_for(&Method::POST, "/task/exec/"), Scope::TaskExec);
+    assert_eq!(Scope::required_for(&Method::GET, "/file-map"), Scope::ReadOnly);
+    assert_eq!(Scope::required_for(&Method::DELETE, "/file-map"), Scope::FileMapWrite);
+    assert_eq!(Scope::required_for(&Method::POST, "/discovery"), Scope::Admin);
+    assert_eq!(Scope::required_for(&Method::GET, "/audit"), Scope::Admin);
+    assert_eq!(Scope::required_for(&Method::GET, "/uploads/file"), Scope::TaskFile);
+  }
+
+  #[test]
+  fn test_may_target() {
+    let config: ApiTokenConfig =
+      serde_yml::from_str("tokens: []\nlabels:\n  web-1: {role: web, env: prod}\n  db-1: {role: db}\n").unwrap();
+    let caller = Caller {
+      hosts: HashMap::from([("role".to_string(), "web".to_string())]),
+      labels: Arc::new(config.labels),
+      ..Caller::admin("ci")
+    };
+    assert!(caller.may_target("web-1"));
+    assert!(!caller.may_target("db-1"));
+    // Unlabelled hosts are out of reach
+    assert!(!caller.may_target("unknown"));
+    assert!(Caller::admin("apikey").may_target("unknown"));
+  }
+}
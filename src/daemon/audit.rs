@@ -0,0 +1,362 @@
+use std::{
+  collections::VecDeque,
+  fs::{File, OpenOptions},
+  io::Write as _,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+  io::{AsyncBufReadExt as _, BufReader},
+  sync::{mpsc, oneshot},
+};
+
+use crate::{
+  daemon::api_token::Caller,
+  protocol::messaging::{ControllerRequestPayload, Status},
+};
+
+/// What happened.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum AuditEvent {
+  /// A task was sent to a host
+  TaskSent {
+    payload: ControllerRequestPayload,
+  },
+  /// A task was not sent to a host, because the caller may not target it or it could not be sent
+  TaskRejected {
+    reason: String,
+    payload: Option<ControllerRequestPayload>,
+  },
+  /// An API request was refused, its token lacks the scope of the route
+  RequestDenied {
+    method: String,
+    path: String,
+  },
+  /// A host returned the final response of a task
+  TaskCompleted {
+    status: Status,
+    code: Option<i32>,
+  },
+  FileMapAdded {
+    name: String,
+    path: String,
+    isdir: bool,
+  },
+  FileMapRemoved {
+    name: String,
+  },
+  /// Discovery was started or stopped
+  DiscoveryChanged {
+    start: bool,
+    ok: bool,
+  },
+  /// A client certificate was issued for the host
+  AgentCertIssued,
+  BootstrapTokenMinted {
+    expires: u64,
+  },
+  /// The host's key was enrolled, automatically if it presented a bootstrap token
+  AgentEnrolled {
+    pubkey: String,
+    auto: bool,
+  },
+  EnrollmentRejected,
+  AgentRevoked,
+}
+
+impl AuditEvent {
+  pub fn name(&self) -> &'static str {
+    match self {
+      AuditEvent::TaskSent { .. } => "task-sent",
+      AuditEvent::TaskRejected { .. } => "task-rejected",
+      AuditEvent::RequestDenied { .. } => "request-denied",
+      AuditEvent::TaskCompleted { .. } => "task-completed",
+      AuditEvent::FileMapAdded { .. } => "file-map-added",
+      AuditEvent::FileMapRemoved { .. } => "file-map-removed",
+      AuditEvent::DiscoveryChanged { .. } => "discovery-changed",
+      AuditEvent::AgentCertIssued => "agent-cert-issued",
+      AuditEvent::BootstrapTokenMinted { .. } => "bootstrap-token-minted",
+      AuditEvent::AgentEnrolled { .. } => "agent-enrolled",
+      AuditEvent::EnrollmentRejected => "enrollment-rejected",
+      AuditEvent::AgentRevoked => "agent-revoked",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+  /// Seconds since the Unix epoch
+  pub timestamp: u64,
+  /// Name of the API token that caused the event
+  pub token: Option<String>,
+  /// Address of the API client, or of the agent for task results
+  pub client: Option<SocketAddr>,
+  /// Config file the change was loaded from
+  pub config: Option<String>,
+  pub host: Option<String>,
+  pub task_id: Option<u32>,
+  #[serde(flatten)]
+  pub event: AuditEvent,
+}
+
+impl AuditRecord {
+  pub fn new(event: AuditEvent) -> Self {
+    AuditRecord {
+      timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+      token: None,
+      client: None,
+      config: None,
+      host: None,
+      task_id: None,
+      event,
+    }
+  }
+
+  pub fn by(mut self, caller: &Caller) -> Self {
+    self.token = Some(caller.name.clone());
+    self.client = caller.remote_addr;
+    self
+  }
+
+  pub fn client(mut self, client: Option<SocketAddr>) -> Self {
+    self.client = client;
+    self
+  }
+
+  pub fn config(mut self, path: &Path) -> Self {
+    self.config = Some(path.display().to_string());
+    self
+  }
+
+  pub fn host(mut self, host: &str) -> Self {
+    self.host = Some(host.to_string());
+    self
+  }
+
+  pub fn task(mut self, task_id: u32) -> Self {
+    self.task_id = Some(task_id);
+    self
+  }
+}
+
+/// Conditions a record must meet to be returned by [`AuditLog::query`].
+#[derive(Deserialize, Default)]
+pub struct AuditFilter {
+  pub host: Option<String>,
+  pub token: Option<String>,
+  pub event: Option<String>,
+  pub task_id: Option<u32>,
+  /// Earliest timestamp, inclusive
+  pub since: Option<u64>,
+  /// Latest timestamp, inclusive
+  pub until: Option<u64>,
+  /// Return only the latest records, at most [`MAX_QUERY_RECORDS`]
+  pub limit: Option<usize>,
+}
+
+/// Most records returned by a query.
+pub const MAX_QUERY_RECORDS: usize = 10_000;
+
+impl AuditFilter {
+  fn matches(&self, record: &AuditRecord) -> bool {
+    self.host.as_ref().is_none_or(|h| record.host.as_ref() == Some(h)) &&
+      self.token.as_ref().is_none_or(|t| record.token.as_ref() == Some(t)) &&
+      self.event.as_ref().is_none_or(|e| record.event.name() == e) &&
+      self.task_id.is_none_or(|id| record.task_id == Some(id)) &&
+      self.since.is_none_or(|since| record.timestamp >= since) &&
+      self.until.is_none_or(|until| record.timestamp <= until)
+  }
+}
+
+enum WriterMessage {
+  /// A serialized record, with its trailing newline
+  Line(Vec<u8>),
+  /// Answered once the lines sent before are written
+  Flush(oneshot::Sender<()>),
+}
+
+/// Append-only record of the tasks sent to hosts and the changes made through the API, one JSON object per line.
+///
+/// Records are written by a thread of their own, so that recording from async code never waits for the disk.
+pub struct AuditLog {
+  path: PathBuf,
+  writer: mpsc::UnboundedSender<WriterMessage>,
+}
+
+impl AuditLog {
+  /// Open the log at `path`, creating it readable by the owner only, and start its writer.
+  pub fn open(path: &Path) -> Result<Self> {
+    let mut opts = OpenOptions::new();
+    opts.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    let file = opts
+      .open(path)
+      .map_err(|err| anyhow::anyhow!("Failed to open audit log {}: {err}", path.display()))?;
+    let (writer, rx) = mpsc::unbounded_channel();
+    let log_path = path.to_path_buf();
+    std::thread::Builder::new()
+      .name("audit-log".to_string())
+      .spawn(move || write_records(file, &log_path, rx))?;
+    Ok(AuditLog {
+      path: path.to_path_buf(),
+      writer,
+    })
+  }
+
+  pub fn record(&self, record: AuditRecord) {
+    debug!("Audit: {record:?}");
+    let mut line = match serde_json::to_vec(&record) {
+      Ok(line) => line,
+      Err(err) => {
+        warn!("Failed to serialize audit record: {err}");
+        return;
+      }
+    };
+    line.push(b'\n');
+    if self.writer.send(WriterMessage::Line(line)).is_err() {
+      warn!("Audit log {} writer stopped, dropping record", self.path.display());
+    }
+  }
+
+  /// Wait until the records recorded so far are written.
+  pub async fn flush(&self) {
+    let (flushed, rx) = oneshot::channel();
+    if self.writer.send(WriterMessage::Flush(flushed)).is_ok() {
+      let _ = rx.await;
+    }
+  }
+
+  /// The latest records matching `filter`, oldest first. Lines that cannot be parsed are skipped.
+  ///
+  /// Records recorded before the query are written first. The log is read line by line, only the records to return
+  /// are held in memory.
+  pub async fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>> {
+    self.flush().await;
+    let limit = filter.limit.unwrap_or(MAX_QUERY_RECORDS).min(MAX_QUERY_RECORDS);
+    if limit == 0 {
+      return Ok(vec![]);
+    }
+    let mut lines = BufReader::new(tokio::fs::File::open(&self.path).await?).lines();
+    let mut records = VecDeque::with_capacity(limit.min(1024));
+    while let Some(line) = lines.next_line().await? {
+      match serde_json::from_str::<AuditRecord>(&line) {
+        Ok(record) if filter.matches(&record) => {
+          if records.len() == limit {
+            records.pop_front();
+          }
+          records.push_back(record);
+        }
+        Ok(_) => {}
+        Err(err) => debug!("Skipping unreadable audit record: {err}"),
+      }
+    }
+    Ok(records.into())
+  }
+}
+
+/// Write the records received on `rx` to `file`, until the log is dropped.
+fn write_records(mut file: File, path: &Path, mut rx: mpsc::UnboundedReceiver<WriterMessage>) {
+  while let Some(message) = rx.blocking_recv() {
+    match message {
+      // A single write per record keeps lines whole even if the file is appended to by someone else.
+      WriterMessage::Line(line) => {
+        if let Err(err) = file.write_all(&line) {
+          warn!("Failed to write audit log {}: {err}", path.display());
+        }
+      }
+      WriterMessage::Flush(flushed) => {
+        let _ = flushed.send(());
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::TempPath;
+
+  #[test]
+  fn test_record_serialization() {
+    let record = AuditRecord::new(AuditEvent::FileMapAdded {
+      name: "img".to_string(),
+      path: "/srv/img".to_string(),
+      isdir: false,
+    })
+    .host("host")
+    .task(7);
+    let value = serde_json::to_value(&record).unwrap();
+    assert_eq!(value["event"], "file-map-added");
+    assert_eq!(value["name"], "img");
+    assert_eq!(value["task_id"], 7);
+
+    let parsed: AuditRecord = serde_json::from_value(value).unwrap();
+    assert_eq!(parsed.event.name(), "file-map-added");
+    assert_eq!(parsed.host.as_deref(), Some("host"));
+    // Records written before a field was added are still read
+    let old = r#"{"timestamp":1,"token":"ci","client":null,"host":null,"task_id":null,"event":"agent-revoked"}"#;
+    assert_eq!(
+      serde_json::from_str::<AuditRecord>(old).unwrap().event.name(),
+      "agent-revoked"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_query() {
+    let path = TempPath::new("mxd-audit");
+    let log = AuditLog::open(&path).unwrap();
+    #[cfg(unix)]
+    assert_eq!(
+      std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777,
+      0o600
+    );
+    for (timestamp, host) in [(10, "a"), (20, "b"), (30, "a"), (40, "a")] {
+      let mut record = AuditRecord::new(AuditEvent::AgentRevoked).host(host);
+      record.timestamp = timestamp;
+      log.record(record);
+    }
+    let timestamps =
+      async |filter: AuditFilter| log.query(&filter).await.unwrap().iter().map(|r| r.timestamp).collect::<Vec<_>>();
+    assert_eq!(
+      timestamps(AuditFilter {
+        host: Some("a".to_string()),
+        ..Default::default()
+      })
+      .await,
+      [10, 30, 40]
+    );
+    assert_eq!(
+      timestamps(AuditFilter {
+        host: Some("a".to_string()),
+        limit: Some(2),
+        ..Default::default()
+      })
+      .await,
+      [30, 40]
+    );
+    assert_eq!(
+      timestamps(AuditFilter {
+        since: Some(20),
+        until: Some(30),
+        ..Default::default()
+      })
+      .await,
+      [20, 30]
+    );
+    assert!(
+      timestamps(AuditFilter {
+        event: Some("task-sent".to_string()),
+        ..Default::default()
+      })
+      .await
+      .is_empty()
+    );
+  }
+}
@@ -1,12 +1,20 @@
-use std::sync::Arc;
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
 
 use crate::{
-  daemon::{discovery::DiscoveryService, server, states::AppState},
-  utils::cert::get_cert_from_file,
+  daemon::{
+    api_token::ApiTokens, audit::AuditLog, discovery::DiscoveryService, enrollment::Enrollment,
+    file_map_config::FileMapConfigWatcher, file_watcher::FileWatcher, server, states::AppState,
+  },
+  protocol::auth,
+  utils::{cert::get_cert_from_file, hash::sha2_256_for_str, util::random_str},
 };
 use anyhow::Result;
 use clap::Parser;
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
 #[derive(Parser, Debug)]
@@ -25,6 +33,19 @@ struct Cli {
   #[clap(short = 'k', long, env = "MXD_APIKEY")]
   apikey: Option<String>,
 
+  /// YAML file declaring named API tokens, each limited to some scopes and optionally to some hosts.
+  ///
+  /// The API key, if given as well, keeps working with every scope.
+  #[clap(long, env = "MXD_API_TOKENS")]
+  api_tokens: Option<String>,
+
+  /// JSON file with the keypair the controller signs its handshakes with, generated if it does not exist.
+  ///
+  /// Agents trust controllers by the SHA-256 of its public key, so it should stay the same across restarts.
+  /// A new keypair is generated on every launch if not set.
+  #[clap(long, env = "MXD_KEY_FILE")]
+  key_file: Option<String>,
+
   /// Path to static files
   #[clap(short = 's', long, env = "MXD_STATIC_PATH")]
   static_path: Option<String>,
@@ -33,6 +54,19 @@ struct Cli {
   #[clap(short = 'd', long, env = "MXD_DISCOVERY", default_value = "false")]
   disable_discovery: bool,
 
+  /// Do not advertise the controller over mDNS/DNS-SD
+  #[clap(long, env = "MXD_DISABLE_MDNS", default_value = "false")]
+  disable_mdns: bool,
+
+  /// Only let agents connect, enroll and transfer files over HTTPS, and advertise only `wss://` URLs to them. Requires
+  /// `--https`
+  #[clap(long, env = "MXD_REQUIRE_TLS", default_value = "false")]
+  require_tls: bool,
+
+  /// Preference advertised to agents that discover several controllers, lower values are tried first
+  #[clap(long, env = "MXD_DISCOVERY_PRIORITY", default_value = "0")]
+  discovery_priority: u16,
+
   /// Enable verbose logging
   #[clap(short = 'v', long, env = "MXD_VERBOSE", default_value = "false")]
   verbose: bool,
@@ -84,6 +118,76 @@ struct Cli {
   #[clap(short = 'g', long, env = "MXD_GENERATE_CERT", default_value = "false")]
   generate_cert: bool,
 
+  /// Require agents to connect over HTTPS with a client certificate issued by the CA given with `--ca-cert` and
+  /// `--ca-key`. The certificate's common name must match the agent's host id.
+  ///
+  /// Certificates for agents are issued through the `agent-cert` API.
+  #[clap(long, env = "MXD_MTLS", default_value = "false")]
+  mtls: bool,
+
+  /// Lifetime of the client certificates issued to agents, in days. Enrolled agents renew theirs once less than a
+  /// third of it is left.
+  #[clap(long, env = "MXD_CLIENT_CERT_VALIDITY", default_value = "30")]
+  client_cert_validity: u32,
+
+  /// Directory to store files uploaded by agents, in a subdirectory per host.
+  ///
+  /// Uploads are disabled if not set.
+  #[clap(short = 'u', long, env = "MXD_UPLOAD_DIR")]
+  upload_dir: Option<String>,
+
+  /// Maximum size of a single upload in bytes
+  #[clap(long, env = "MXD_UPLOAD_MAX_SIZE", default_value = "4294967296")]
+  upload_max_size: u64,
+
+  /// YAML file declaring files and directories to publish. It is reloaded when it changes.
+  #[clap(short = 'm', long, env = "MXD_FILE_MAP_CONFIG")]
+  file_map_config: Option<String>,
+
+  /// Refresh the hashes of published files as soon as they change on disk, using inotify.
+  ///
+  /// Otherwise hashes are recomputed when a changed file is requested. Linux only.
+  #[clap(long, env = "MXD_WATCH_FILES", default_value = "false")]
+  watch_files: bool,
+
+  /// Require a signed, expiring token to download from `/files`.
+  ///
+  /// URLs returned by the `relative-url` API carry such a token.
+  #[clap(long, env = "MXD_SIGN_FILE_URLS", default_value = "false")]
+  sign_file_urls: bool,
+
+  /// Secret to sign file URLs with. A random one is generated on startup if not set,
+  /// which invalidates all issued URLs on restart.
+  #[clap(long, env = "MXD_FILE_URL_SECRET")]
+  file_url_secret: Option<String>,
+
+  /// Lifetime of signed file URLs in seconds
+  #[clap(long, env = "MXD_FILE_URL_TTL", default_value = "3600")]
+  file_url_ttl: u64,
+
+  /// Append a JSON line for every task sent to a host and every change made through the API to this file
+  #[clap(long, env = "MXD_AUDIT_LOG")]
+  audit_log: Option<String>,
+
+  /// JSON file recording the agents enrolled with this controller.
+  ///
+  /// When set, agents must enroll before they can connect: with a one-time bootstrap token minted through the
+  /// `enrollment` API, or by waiting for an operator to approve them there. Only the keys of enrolled agents are
+  /// accepted afterwards.
+  #[clap(long, env = "MXD_ENROLLMENT_FILE")]
+  enrollment_file: Option<String>,
+
+  /// Directory the filesystem API may browse and read, can be given multiple times.
+  ///
+  /// Paths are resolved, symlinks included, and must stay below one of these roots.
+  /// If none is set, nothing is exposed.
+  #[clap(long = "fs-root", env = "MXD_FS_ROOTS", value_delimiter = ',')]
+  fs_roots: Vec<String>,
+
+  /// Disable the filesystem API
+  #[clap(long, env = "MXD_DISABLE_FS_API", default_value = "false")]
+  disable_fs_api: bool,
+
   /// Execute provided lua script. This option will not start server.
   #[clap(long)]
   script: Option<String>,
@@ -94,6 +198,23 @@ pub struct HttpsArgs {
   pub cert: String,
   pub key: String,
   pub port: u16,
+  /// CA that issues and verifies agent client certificates, if mutual TLS is enabled
+  pub client_ca: Option<CertAuthority>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CertAuthority {
+  pub cert: String,
+  pub key: String,
+  /// Lifetime of the certificates it issues
+  pub validity: time::Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct FileUrlSigning {
+  pub secret: String,
+  /// Lifetime of issued URLs in seconds
+  pub ttl: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -102,15 +223,50 @@ pub struct StartupArgs {
   pub http_port: u16,
   pub https_args: Option<HttpsArgs>,
   pub apikey: Option<String>,
+  /// Base64 encoded public and private key the controller signs its handshakes with
+  pub key_pair: (String, String),
+  pub api_tokens: Option<String>,
   pub static_path: Option<String>,
   pub disable_discovery: bool,
+  pub disable_mdns: bool,
+  pub discovery_priority: u16,
+  /// Agents must connect over HTTPS
+  pub require_tls: bool,
   pub detect_others: bool,
+  pub upload_dir: Option<String>,
+  pub upload_max_size: u64,
+  pub file_url_signing: Option<FileUrlSigning>,
+  /// Signs `/files/by-hash` URLs, which are only handed out through the API: with the file URL secret, or a random one
+  /// if other file URLs are not signed
+  pub by_hash_signing: FileUrlSigning,
+  pub file_map_config: Option<String>,
+  pub watch_files: bool,
+  /// Canonical roots the filesystem API is confined to. Empty if it exposes nothing.
+  pub fs_roots: Vec<PathBuf>,
+  pub disable_fs_api: bool,
+  pub audit_log: Option<String>,
+  pub enrollment_file: Option<String>,
+}
+
+impl StartupArgs {
+  /// CA of agent client certificates, if agents must authenticate with mutual TLS.
+  pub fn client_ca(&self) -> Option<&CertAuthority> { self.https_args.as_ref()?.client_ca.as_ref() }
+}
+
+#[cfg(test)]
+impl StartupArgs {
+  /// Arguments of a daemon started without any.
+  pub(crate) fn for_tests() -> Self { StartupArgs::try_from(Cli::parse_from(["mxd"])).unwrap() }
 }
 
 impl TryFrom<Cli> for StartupArgs {
   type Error = anyhow::Error;
 
   fn try_from(config: Cli) -> Result<Self, Self::Error> {
+    let file_url_signing = config.sign_file_urls.then(|| FileUrlSigning {
+      secret: config.file_url_secret.unwrap_or_else(|| random_str(32)),
+      ttl: config.file_url_ttl,
+    });
     let args = StartupArgs {
       enable_http: config.http,
       http_port: config.http_port,
@@ -118,37 +274,120 @@ impl TryFrom<Cli> for StartupArgs {
         let (cert, key) = get_cert_from_file(
           config.tls_cert,
           config.tls_key,
-          config.ca_cert,
-          config.ca_key,
+          config.ca_cert.clone(),
+          config.ca_key.clone(),
           config.generate_cert,
         )?;
+        let client_ca = if config.mtls {
+          let (Some(ca_cert), Some(ca_key)) = (config.ca_cert, config.ca_key) else {
+            anyhow::bail!("Mutual TLS requires `--ca-cert` and `--ca-key`");
+          };
+          Some(CertAuthority {
+            cert: std::fs::read_to_string(ca_cert)?,
+            key: std::fs::read_to_string(ca_key)?,
+            validity: time::Duration::days(config.client_cert_validity.into()),
+          })
+        } else {
+          None
+        };
         Some(HttpsArgs {
           cert,
           key,
           port: config.https_port,
+          client_ca,
         })
+      } else if config.mtls {
+        anyhow::bail!("Mutual TLS requires `--https`");
+      } else if config.require_tls {
+        anyhow::bail!("Requiring TLS requires `--https`");
       } else {
         None
       },
       apikey: config.apikey,
+      key_pair: load_key_pair(config.key_file.as_deref().map(Path::new))?,
+      api_tokens: config.api_tokens,
       static_path: config.static_path,
       disable_discovery: config.disable_discovery,
+      disable_mdns: config.disable_mdns,
+      discovery_priority: config.discovery_priority,
+      require_tls: config.require_tls,
       detect_others: config.detect_others,
+      upload_dir: config.upload_dir,
+      upload_max_size: config.upload_max_size,
+      file_url_signing: file_url_signing.clone(),
+      by_hash_signing: file_url_signing.unwrap_or_else(|| FileUrlSigning {
+        secret: random_str(32),
+        ttl: config.file_url_ttl,
+      }),
+      file_map_config: config.file_map_config,
+      watch_files: config.watch_files,
+      fs_roots: config
+        .fs_roots
+        .iter()
+        .map(|root| {
+          std::fs::canonicalize(root).map_err(|err| anyhow::anyhow!("Invalid filesystem API root {root}: {err}"))
+        })
+        .collect::<Result<_>>()?,
+      disable_fs_api: config.disable_fs_api,
+      audit_log: config.audit_log,
+      enrollment_file: config.enrollment_file,
     };
     Ok(args)
   }
 }
 
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+  public_key: String,
+  private_key: String,
+}
+
+/// Read the controller's keypair from `path`, or generate one and store it there, readable by the owner only.
+fn load_key_pair(path: Option<&Path>) -> Result<(String, String)> {
+  let Some(path) = path else {
+    warn!(
+      "No key file set, generating a new keypair. Agents that pinned this controller will refuse it after a restart"
+    );
+    return Ok(auth::generate_keypair_str());
+  };
+  match std::fs::read_to_string(path) {
+    Ok(content) => {
+      let key: KeyFile = serde_json::from_str(&content)
+        .map_err(|err| anyhow::anyhow!("Failed to parse key file {}: {err}", path.display()))?;
+      Ok((key.public_key, key.private_key))
+    }
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+      let (public_key, private_key) = auth::generate_keypair_str();
+      let content = serde_json::to_vec_pretty(&KeyFile {
+        public_key: public_key.clone(),
+        private_key: private_key.clone(),
+      })?;
+      let mut opts = std::fs::OpenOptions::new();
+      opts.write(true).create_new(true);
+      #[cfg(unix)]
+      std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+      opts
+        .open(path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, &content))
+        .map_err(|err| anyhow::anyhow!("Failed to write key file {}: {err}", path.display()))?;
+      info!("Generated a new keypair in {}", path.display());
+      Ok((public_key, private_key))
+    }
+    Err(err) => anyhow::bail!("Failed to read key file {}: {err}", path.display()),
+  }
+}
+
 pub async fn main() -> Result<()> {
   let cli = Cli::parse();
   crate::logger::install_logger(cli.verbose);
 
   let args = StartupArgs::try_from(cli)?;
   info!("MetalX Controller - Launching");
+  info!("Controller fingerprint: {}", sha2_256_for_str(&args.key_pair.0)?);
 
   if args.detect_others {
     info!("Detecting other controllers...");
-    match crate::discovery::discover_controller_once().await {
+    match crate::discovery::discover_controller_once(&[]).await {
       Err(crate::discovery::DiscoveryError::NoControllerFound) => {
         info!("No other controller found");
       }
@@ -168,7 +407,15 @@ pub async fn main() -> Result<()> {
     }
   }
 
-  let mut state = AppState::new(args.clone());
+  if !args.disable_fs_api && args.fs_roots.is_empty() {
+    info!("The filesystem API has no roots and exposes nothing, use --fs-root to allow directories");
+  }
+
+  let api_tokens = ApiTokens::load(args.api_tokens.as_deref().map(Path::new), args.apikey.as_deref())?;
+  if api_tokens.is_empty() {
+    warn!("No API key or tokens configured, the API is open to everyone");
+  }
+  let mut state = AppState::new(args.clone(), api_tokens);
 
   if let Some(mut ds) = DiscoveryService::new(&state) {
     ds.start()?;
@@ -176,14 +423,39 @@ pub async fn main() -> Result<()> {
     state.discovery_service = Some(Mutex::new(ds));
   }
 
+  if let Some(path) = &args.audit_log {
+    state.audit_log = Some(AuditLog::open(Path::new(path))?);
+    info!("Writing audit log to {path}");
+  }
+
+  if let Some(path) = &args.enrollment_file {
+    state.enrollment = Some(Enrollment::load(Path::new(path))?);
+    info!("Agents must enroll before connecting, enrolled agents are recorded in {path}");
+  }
+
+  if args.watch_files {
+    state.file_watcher = Some(FileWatcher::new()?);
+  }
+
   let shared_state = Arc::new(state);
 
+  if let Some(watcher) = &shared_state.file_watcher {
+    watcher.start(shared_state.clone());
+  }
+
+  if let Some(path) = &args.file_map_config {
+    let watcher = FileMapConfigWatcher::load(shared_state.clone(), path.into()).await?;
+    tokio::spawn(watcher.watch());
+  }
+
   if !args.enable_http {
     info!("HTTP server is disabled");
-    return Ok(());
-  } else if let Err(e) = server::main(shared_state).await {
+  } else if let Err(e) = server::main(shared_state.clone()).await {
     log::error!("Failed to start server: {e}");
   }
 
+  if let Some(log) = &shared_state.audit_log {
+    log.flush().await;
+  }
   Ok(())
 }
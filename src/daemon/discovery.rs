@@ -1,51 +1,96 @@
 use std::{
-  net::{IpAddr, Ipv4Addr, SocketAddr},
+  collections::HashSet,
+  io::ErrorKind,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
   str::FromStr,
+  time::Duration,
 };
 
 use crate::{
-  daemon::states::AppState,
-  protocol::discovery::{DISCOVERY_PORT, DiscoveryRequest, DiscoveryResponse, MAGIC_REQUEST, MAGIC_RESPONSE},
+  daemon::{mdns::MdnsAdvertisement, states::AppState},
+  discovery::ipv6_interfaces,
+  protocol::discovery::{DISCOVERY_MULTICAST_V6, DISCOVERY_PORT, DiscoveryRequest, DiscoveryResponse, MAGIC_REQUEST},
+  utils::cert::cert_fingerprint,
 };
 use anyhow::Result;
+use futures_util::future::join_all;
 use log::{debug, error, info, warn};
-use tokio::{net::UdpSocket, select, task::JoinHandle};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+  net::UdpSocket,
+  select,
+  task::JoinHandle,
+  time::{Instant, Interval, interval_at},
+};
 use tokio_util::sync::CancellationToken;
 
-fn get_ws_urls(port: u16) -> Result<Vec<String>> {
-  let urls = if_addrs::get_if_addrs()?
-    .iter()
-    .filter_map(|if_| {
-      if if_.is_loopback() {
-        return None;
-      }
-      let ip = if_.ip();
-      if ip.is_ipv4() {
-        Some(format!("ws://{ip}:{port}/ws"))
-      } else {
-        None
-      }
-    })
-    .collect();
-  Ok(urls)
+/// URLs agents can reach the controller at, `wss://` ones first. Link-local IPv6 addresses are only those of the
+/// interface with index `scope_id`, the one a request came in through, since agents reach them through that link
+/// only.
+fn get_ws_urls(config: &ResponseConfig, scope_id: Option<u32>) -> Result<Vec<String>> {
+  let addrs = if_addrs::get_if_addrs()?
+    .into_iter()
+    .filter(|if_| !if_.is_loopback())
+    .map(|if_| (if_.ip(), if_.index));
+  Ok(ws_urls(config, addrs, scope_id))
 }
 
-async fn recv_pack(socket: &UdpSocket, http_port: u16) -> Result<()> {
+/// [`get_ws_urls`] for the addresses of the interfaces, with their index.
+fn ws_urls(
+  config: &ResponseConfig, addrs: impl IntoIterator<Item = (IpAddr, Option<u32>)>, scope_id: Option<u32>,
+) -> Vec<String> {
+  let mut urls = vec![];
+  for (ip, index) in addrs {
+    let host = match ip {
+      IpAddr::V4(ip) => ip.to_string(),
+      IpAddr::V6(ip) if ip.is_unicast_link_local() && (scope_id.is_none() || index != scope_id) => continue,
+      IpAddr::V6(ip) => format!("[{ip}]"),
+    };
+    if let Some(https_port) = config.https_port {
+      urls.push(format!("wss://{host}:{https_port}/ws"));
+    }
+    if !config.require_tls {
+      urls.push(format!("ws://{host}:{}/ws", config.http_port));
+    }
+  }
+  urls
+}
+
+/// What the controller answers discovery requests with.
+#[derive(Clone)]
+struct ResponseConfig {
+  http_port: u16,
+  /// Port of the HTTPS service, if enabled
+  https_port: Option<u16>,
+  /// SHA-256 of the HTTPS certificate, for agents without the CA it is issued by to pin it
+  cert_fingerprint: Option<String>,
+  /// Whether only `wss://` URLs are advertised
+  require_tls: bool,
+  /// Key the responses are signed with, the one the controller authenticates to agents with
+  private_key: String,
+  /// Preference among controllers advertised to agents, lower values first
+  priority: u16,
+}
+
+async fn recv_pack(socket: &UdpSocket, config: &ResponseConfig) -> Result<()> {
   let mut buf = [0u8; 1024];
   debug!("Waiting for discovery request");
   match socket.recv_from(&mut buf).await {
     Ok((size, addr)) => {
-      info!("Received discovery request from {}:{}", addr.ip(), addr.port());
+      info!("Received discovery request from {addr}");
       let msg = std::str::from_utf8(&buf[..size])?;
       let req = DiscoveryRequest::from_str(msg)?;
       if req.magic == MAGIC_REQUEST {
-        let resp = DiscoveryResponse {
-          magic: MAGIC_RESPONSE.to_string(),
-          ws: get_ws_urls(http_port)?,
+        let scope_id = match addr {
+          SocketAddr::V6(addr) if addr.scope_id() != 0 => Some(addr.scope_id()),
+          _ => None,
         };
+        let mut resp = DiscoveryResponse::new(get_ws_urls(config, scope_id)?, config.priority);
+        resp.cert_fingerprint = config.cert_fingerprint.clone();
+        resp.sign(req.nonce, &config.private_key)?;
         let resp_str = resp.to_string();
         socket.send_to(resp_str.as_bytes(), addr).await?;
-        info!("Sent discovery response to {}:{}", addr.ip(), addr.port());
+        info!("Sent discovery response to {addr}");
       } else {
         warn!("Invalid magic: {}", req.magic);
       }
@@ -58,32 +103,158 @@ async fn recv_pack(socket: &UdpSocket, http_port: u16) -> Result<()> {
   }
 }
 
-async fn discovery_main(ct: CancellationToken, http_port: u16) {
-  match UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DISCOVERY_PORT)).await {
-    Ok(socket) => {
-      log::info!("Discovery service started at {}", socket.local_addr().unwrap());
-      loop {
-        select! {
-            _ = ct.cancelled() => {
-                info!("Discovery service stopping");
-                break;
+/// How often multicast groups are joined on interfaces that appeared since the sockets were bound
+const REJOIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interface a multicast group is joined on: its address for IPv4, its index for IPv6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Interface {
+  V4(Ipv4Addr),
+  V6(u32),
+}
+
+impl std::fmt::Display for Interface {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Interface::V4(ip) => write!(f, "interface {ip}"),
+      Interface::V6(index) => write!(f, "interface {index}"),
+    }
+  }
+}
+
+/// Memberships of a socket in a multicast group, kept up to date with the interfaces of the host.
+pub(super) struct Memberships {
+  group: IpAddr,
+  joined: HashSet<Interface>,
+}
+
+impl Memberships {
+  /// Join `group` on every multicast capable interface of `socket`'s address family.
+  pub(super) fn join(socket: &UdpSocket, group: IpAddr) -> Self {
+    let mut memberships = Memberships {
+      group,
+      joined: HashSet::new(),
+    };
+    memberships.update(socket);
+    memberships
+  }
+
+  fn interfaces(&self) -> HashSet<Interface> {
+    match self.group {
+      IpAddr::V4(_) => match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+          .iter()
+          .filter(|if_| !if_.is_loopback())
+          .filter_map(|if_| match if_.ip() {
+            IpAddr::V4(ip) => Some(Interface::V4(ip)),
+            IpAddr::V6(_) => None,
+          })
+          .collect(),
+        Err(err) => {
+          error!("Failed to list network interfaces: {err}");
+          HashSet::new()
+        }
+      },
+      IpAddr::V6(_) => ipv6_interfaces().into_iter().map(Interface::V6).collect(),
+    }
+  }
+
+  /// Join the group on interfaces that appeared since the last update. Interfaces that went away are forgotten, so
+  /// that the group is joined again if they come back.
+  pub(super) fn update(&mut self, socket: &UdpSocket) {
+    let interfaces = self.interfaces();
+    self.joined.retain(|interface| interfaces.contains(interface));
+    for interface in interfaces {
+      if self.joined.contains(&interface) {
+        continue;
+      }
+      let res = match (self.group, interface) {
+        (IpAddr::V4(group), Interface::V4(ip)) => socket.join_multicast_v4(group, ip),
+        (IpAddr::V6(group), Interface::V6(index)) => socket.join_multicast_v6(&group, index),
+        _ => continue,
+      };
+      match res {
+        // Still a member since before the interface went away and came back
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {}
+        Err(err) => {
+          warn!("Failed to join multicast group {} on {interface}: {err}", self.group);
+          continue;
+        }
+        Ok(()) => debug!("Joined multicast group {} on {interface}", self.group),
+      }
+      self.joined.insert(interface);
+    }
+  }
+}
+
+/// Timer to update multicast group memberships with, first firing after [`REJOIN_INTERVAL`].
+pub(super) fn rejoin_interval() -> Interval { interval_at(Instant::now() + REJOIN_INTERVAL, REJOIN_INTERVAL) }
+
+/// Bind the IPv6 socket and join the discovery group on every interface. The socket is IPv6 only, so it does not
+/// conflict with the IPv4 one on the same port.
+fn bind_v6() -> Result<(UdpSocket, Option<Memberships>)> {
+  let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+  socket.set_only_v6(true)?;
+  socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DISCOVERY_PORT).into())?;
+  socket.set_nonblocking(true)?;
+  let socket = UdpSocket::from_std(socket.into())?;
+  let memberships = Memberships::join(&socket, IpAddr::V6(DISCOVERY_MULTICAST_V6));
+  Ok((socket, Some(memberships)))
+}
+
+/// Answer discovery requests on `socket` until `ct` is cancelled, joining the multicast group in `memberships` on
+/// interfaces as they appear.
+async fn serve(
+  ct: &CancellationToken, socket: UdpSocket, mut memberships: Option<Memberships>, config: &ResponseConfig,
+) {
+  log::info!("Discovery service started at {}", socket.local_addr().unwrap());
+  let mut rejoin = rejoin_interval();
+  loop {
+    select! {
+        _ = ct.cancelled() => {
+            info!("Discovery service stopping");
+            break;
+        }
+        _ = rejoin.tick(), if memberships.is_some() => {
+            if let Some(memberships) = &mut memberships {
+                memberships.update(&socket);
             }
-            r = recv_pack(&socket, http_port) => {
-                if let Err(err) = r {
-                    error!("Failed to handle discovery message: {err}");
-                }
+        }
+        r = recv_pack(&socket, config) => {
+            if let Err(err) = r {
+                error!("Failed to handle discovery message: {err}");
             }
         }
-      }
     }
-    Err(e) => {
-      error!("Failed to start discovery service: {e:?}");
+  }
+}
+
+async fn discovery_main(ct: CancellationToken, config: ResponseConfig, mdns: Option<MdnsAdvertisement>) {
+  let mdns = async {
+    if let Some(mdns) = &mdns {
+      mdns.run(&ct).await;
     }
+  };
+  let mut sockets = vec![];
+  match UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DISCOVERY_PORT)).await {
+    Ok(socket) => sockets.push((socket, None)),
+    Err(e) => error!("Failed to start IPv4 discovery service: {e:?}"),
+  }
+  match bind_v6() {
+    Ok(socket) => sockets.push(socket),
+    Err(e) => warn!("Failed to start IPv6 discovery service: {e:?}"),
+  }
+  if sockets.is_empty() {
+    error!("Failed to start UDP discovery service");
   }
+  let udp = join_all(sockets.into_iter().map(|(socket, memberships)| serve(&ct, socket, memberships, &config)));
+  tokio::join!(udp, mdns);
 }
 
 pub struct DiscoveryService {
-  http_port: u16,
+  config: ResponseConfig,
+  /// Advertisement over mDNS, unless disabled
+  mdns: Option<MdnsAdvertisement>,
   join_handle: Option<JoinHandle<()>>,
   main_ct: CancellationToken,
   sub_ct: Option<CancellationToken>,
@@ -97,8 +268,36 @@ impl DiscoveryService {
       return None;
     }
 
+    let mdns = if state.startup_args.disable_mdns {
+      info!("mDNS advertisement is disabled");
+      None
+    } else {
+      match MdnsAdvertisement::new(&state.startup_args) {
+        Ok(mdns) => Some(mdns),
+        Err(err) => {
+          error!("Failed to set up mDNS advertisement: {err}");
+          None
+        }
+      }
+    };
+    let https_args = state.startup_args.https_args.as_ref();
+    let cert_fingerprint = match https_args.map(|https| cert_fingerprint(&https.cert)).transpose() {
+      Ok(cert_fingerprint) => cert_fingerprint,
+      Err(err) => {
+        error!("Failed to hash the HTTPS certificate, agents need its CA to connect over TLS: {err}");
+        None
+      }
+    };
     Some(DiscoveryService {
-      http_port: state.startup_args.http_port,
+      config: ResponseConfig {
+        http_port: state.startup_args.http_port,
+        https_port: https_args.map(|https| https.port),
+        cert_fingerprint,
+        require_tls: state.startup_args.require_tls,
+        private_key: state.startup_args.key_pair.1.clone(),
+        priority: state.startup_args.discovery_priority,
+      },
+      mdns,
       join_handle: None,
       main_ct: state.cancel_signal.clone(),
       sub_ct: None,
@@ -114,9 +313,10 @@ impl DiscoveryService {
     info!("Setting up discovery service");
     let ct = self.main_ct.child_token();
     let ct_clone = ct.clone();
-    let http_port = self.http_port;
+    let config = self.config.clone();
+    let mdns = self.mdns.clone();
     let join = tokio::spawn(async move {
-      discovery_main(ct_clone, http_port).await;
+      discovery_main(ct_clone, config, mdns).await;
     });
     self.join_handle = Some(join);
     self.sub_ct = Some(ct);
@@ -152,3 +352,46 @@ impl DiscoveryService {
 
   pub fn running(&self) -> bool { self.started }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_ws_urls() {
+    let mut config = ResponseConfig {
+      http_port: 8080,
+      https_port: None,
+      cert_fingerprint: None,
+      require_tls: false,
+      private_key: String::new(),
+      priority: 0,
+    };
+    let addrs = [
+      ("192.0.2.1".parse().unwrap(), Some(2)),
+      ("fe80::1".parse().unwrap(), Some(2)),
+      ("fe80::2".parse().unwrap(), Some(3)),
+      ("2001:db8::1".parse().unwrap(), Some(3)),
+    ];
+    assert_eq!(
+      ws_urls(&config, addrs, None),
+      ["ws://192.0.2.1:8080/ws", "ws://[2001:db8::1]:8080/ws"]
+    );
+    // Only the link-local address of the interface the request came in through
+    assert_eq!(
+      ws_urls(&config, addrs, Some(3)),
+      ["ws://192.0.2.1:8080/ws", "ws://[fe80::2]:8080/ws", "ws://[2001:db8::1]:8080/ws"]
+    );
+
+    config.https_port = Some(8443);
+    assert_eq!(
+      ws_urls(&config, addrs[..1].to_vec(), None),
+      ["wss://192.0.2.1:8443/ws", "ws://192.0.2.1:8080/ws"]
+    );
+    config.require_tls = true;
+    assert_eq!(
+      ws_urls(&config, addrs, Some(2)),
+      ["wss://192.0.2.1:8443/ws", "wss://[fe80::1]:8443/ws", "wss://[2001:db8::1]:8443/ws"]
+    );
+  }
+}
@@ -0,0 +1,276 @@
+use std::{
+  collections::BTreeMap,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{
+  fs::temp_path_for,
+  hash::sha2_256_for_str,
+  states::{StateMap, States as _},
+  util::random_str,
+};
+
+/// How long a bootstrap token stays valid if no lifetime is requested.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Most requests waiting for approval at once. Anyone can ask to enroll, so the queue must not grow without bound.
+pub const MAX_PENDING: usize = 256;
+
+/// An agent allowed to connect, identified by the public key it signs its connections with.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EnrolledAgent {
+  pub host_id: String,
+  pub pubkey: String,
+  /// Seconds since the Unix epoch
+  pub enrolled_at: u64,
+}
+
+/// An enrollment request waiting for an operator to approve or reject it.
+#[derive(Serialize, Clone, Debug)]
+pub struct PendingEnrollment {
+  pub host_id: String,
+  pub pubkey: String,
+  /// Fingerprint of `pubkey`, which the agent logs, for the operator to compare before approving
+  pub fingerprint: String,
+  pub remote_addr: Option<SocketAddr>,
+  /// Seconds since the Unix epoch
+  pub requested_at: u64,
+}
+
+/// Outcome of an enrollment request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Decision {
+  /// The key is enrolled, either now or before
+  Approved,
+  /// Queued for an operator
+  Pending,
+  /// An operator rejected this key
+  Rejected,
+  /// Too many requests are waiting for approval already
+  QueueFull,
+}
+
+/// Agents enrolled with the controller, persisted as a JSON array, and the requests waiting for approval.
+///
+/// Agents enroll by presenting a one-time bootstrap token, or by waiting for an operator to approve them through the
+/// API. Only the public keys of enrolled agents are accepted on `/ws`.
+pub struct Enrollment {
+  path: PathBuf,
+  agents: Mutex<BTreeMap<String, EnrolledAgent>>,
+  /// Requests by key fingerprint, so that a request never replaces another one for the same host id
+  pending: StateMap<String, PendingEnrollment>,
+  /// Host ids of rejected keys by fingerprint, kept until the daemon restarts
+  rejected: StateMap<String, String>,
+  /// Expiry of unused bootstrap tokens
+  tokens: StateMap<String, SystemTime>,
+}
+
+fn now_secs() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) }
+
+impl Enrollment {
+  /// Load the enrolled agents from `path`. A missing file means no agent is enrolled yet.
+  pub fn load(path: &Path) -> Result<Self> {
+    let agents: Vec<EnrolledAgent> = match std::fs::read_to_string(path) {
+      Ok(content) => serde_json::from_str(&content)
+        .map_err(|err| anyhow::anyhow!("Failed to parse enrolled agents from {}: {err}", path.display()))?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+      Err(err) => anyhow::bail!("Failed to read enrolled agents from {}: {err}", path.display()),
+    };
+    Ok(Enrollment {
+      path: path.to_path_buf(),
+      agents: Mutex::new(agents.into_iter().map(|a| (a.host_id.clone(), a)).collect()),
+      pending: StateMap::new(),
+      rejected: StateMap::new(),
+      tokens: StateMap::new(),
+    })
+  }
+
+  /// Mint a single-use bootstrap token. Returns the token and its expiry in seconds since the Unix epoch.
+  pub fn mint_token(&self, ttl: Duration) -> (String, u64) {
+    self.purge_expired();
+    let token = random_str(32);
+    let expires = SystemTime::now() + ttl;
+    self.tokens.insert(token.clone(), expires);
+    (token, expires.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()))
+  }
+
+  fn redeem(&self, token: &str) -> bool {
+    self
+      .tokens
+      .remove_if(&token.to_string(), |_| true)
+      .is_some_and(|expires| *expires > SystemTime::now())
+  }
+
+  /// Invalidate a token that has been exposed.
+  pub fn discard_token(&self, token: &str) { self.tokens.remove(&token.to_string()); }
+
+  fn purge_expired(&self) {
+    let now = SystemTime::now();
+    for token in self.tokens.list() {
+      self.tokens.remove_if(&token, |expires| *expires <= now);
+    }
+  }
+
+  /// Whether `pubkey` is the enrolled key of `host_id`.
+  pub fn is_enrolled(&self, host_id: &str, pubkey: &str) -> bool {
+    self.agents.lock().is_ok_and(|agents| agents.get(host_id).is_some_and(|a| a.pubkey == pubkey))
+  }
+
+  /// Handle a request of `host_id` to enroll `pubkey`, which it proved to hold.
+  ///
+  /// A valid bootstrap token enrolls the key at once, replacing any key enrolled before. Without one, the request
+  /// is queued for an operator unless the key is already enrolled or was rejected. A queued request is never
+  /// replaced, other keys claiming the same host id are queued next to it.
+  pub fn request(
+    &self, host_id: &str, pubkey: &str, token: Option<&str>, remote_addr: Option<SocketAddr>,
+  ) -> Result<Decision> {
+    if self.is_enrolled(host_id, pubkey) {
+      return Ok(Decision::Approved);
+    }
+    if token.is_some_and(|token| self.redeem(token)) {
+      self.enroll(host_id, pubkey)?;
+      return Ok(Decision::Approved);
+    }
+    if token.is_some() {
+      warn!("Enrollment of {host_id} presented an invalid bootstrap token");
+    }
+    let fingerprint = sha2_256_for_str(pubkey)?;
+    if self.rejected.get_arc(&fingerprint).is_some() {
+      return Ok(Decision::Rejected);
+    }
+    if self.pending.get_arc(&fingerprint).is_none() && self.pending.list().len() >= MAX_PENDING {
+      return Ok(Decision::QueueFull);
+    }
+    self.pending.try_insert_deferred_returning(fingerprint.clone(), || PendingEnrollment {
+      host_id: host_id.to_string(),
+      pubkey: pubkey.to_string(),
+      fingerprint,
+      remote_addr,
+      requested_at: now_secs(),
+    });
+    Ok(Decision::Pending)
+  }
+
+  pub fn pending(&self) -> Vec<PendingEnrollment> {
+    let mut pending = self
+      .pending
+      .list()
+      .iter()
+      .filter_map(|id| self.pending.get_arc(id).map(|p| (*p).clone()))
+      .collect::<Vec<_>>();
+    pending.sort_by_key(|p| p.requested_at);
+    pending
+  }
+
+  pub fn agents(&self) -> Vec<EnrolledAgent> {
+    self.agents.lock().map(|agents| agents.values().cloned().collect()).unwrap_or_default()
+  }
+
+  /// Enroll the pending request of `host_id` for the key with `fingerprint`. Returns `false` if there is none.
+  pub fn approve(&self, host_id: &str, fingerprint: &str) -> Result<bool> {
+    let Some(pending) = self.pending.remove_if(&fingerprint.to_string(), |p| p.host_id == host_id) else {
+      return Ok(false);
+    };
+    self.enroll(host_id, &pending.pubkey)?;
+    Ok(true)
+  }
+
+  /// Drop the pending request of `host_id` for the key with `fingerprint`, and refuse that key from now on. Returns
+  /// `false` if there is none.
+  pub fn reject(&self, host_id: &str, fingerprint: &str) -> bool {
+    if self.pending.remove_if(&fingerprint.to_string(), |p| p.host_id == host_id).is_none() {
+      return false;
+    }
+    self.rejected.insert(fingerprint.to_string(), host_id.to_string());
+    true
+  }
+
+  /// Remove the enrolled key of `host_id`. Returns `false` if it was not enrolled.
+  pub fn revoke(&self, host_id: &str) -> Result<bool> {
+    let mut agents = self.agents.lock().map_err(|_| anyhow::anyhow!("Enrolled agents are poisoned"))?;
+    if agents.remove(host_id).is_none() {
+      return Ok(false);
+    }
+    self.save(&agents)?;
+    Ok(true)
+  }
+
+  fn enroll(&self, host_id: &str, pubkey: &str) -> Result<()> {
+    let fingerprint = sha2_256_for_str(pubkey)?;
+    let mut agents = self.agents.lock().map_err(|_| anyhow::anyhow!("Enrolled agents are poisoned"))?;
+    agents.insert(
+      host_id.to_string(),
+      EnrolledAgent {
+        host_id: host_id.to_string(),
+        pubkey: pubkey.to_string(),
+        enrolled_at: now_secs(),
+      },
+    );
+    self.rejected.remove(&fingerprint);
+    self.pending.remove(&fingerprint);
+    self.save(&agents)
+  }
+
+  /// Replace the file with `agents`, atomically so a crash never leaves it half written.
+  fn save(&self, agents: &BTreeMap<String, EnrolledAgent>) -> Result<()> {
+    let content = serde_json::to_vec_pretty(&agents.values().collect::<Vec<_>>())?;
+    let tmp = temp_path_for(&self.path);
+    std::fs::write(&tmp, content).and_then(|_| std::fs::rename(&tmp, &self.path)).map_err(|err| {
+      let _ = std::fs::remove_file(&tmp);
+      anyhow::anyhow!("Failed to save enrolled agents to {}: {err}", self.path.display())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::TempPath;
+
+  #[test]
+  fn test_enrollment() {
+    let path = TempPath::new("mxd-enrollment");
+    let enrollment = Enrollment::load(&path).unwrap();
+
+    let (token, _) = enrollment.mint_token(DEFAULT_TOKEN_TTL);
+    assert_eq!(
+      enrollment.request("a", "ka", Some(&token), None).unwrap(),
+      Decision::Approved
+    );
+    // Tokens are single use
+    assert_eq!(
+      enrollment.request("b", "kb", Some(&token), None).unwrap(),
+      Decision::Pending
+    );
+    assert!(!enrollment.is_enrolled("b", "kb"));
+    // Another key claiming the same host id does not replace the pending request
+    assert_eq!(enrollment.request("b", "kx", None, None).unwrap(), Decision::Pending);
+    assert_eq!(enrollment.pending().len(), 2);
+    let fingerprint = |key| sha2_256_for_str(key).unwrap();
+    assert!(!enrollment.approve("a", &fingerprint("kb")).unwrap());
+    assert!(enrollment.approve("b", &fingerprint("kb")).unwrap());
+    assert!(enrollment.is_enrolled("b", "kb"));
+    assert!(enrollment.reject("b", &fingerprint("kx")));
+
+    assert_eq!(enrollment.request("c", "kc", None, None).unwrap(), Decision::Pending);
+    assert!(enrollment.reject("c", &fingerprint("kc")));
+    assert_eq!(enrollment.request("c", "kc", None, None).unwrap(), Decision::Rejected);
+
+    for i in 0..MAX_PENDING {
+      enrollment.request(&format!("h{i}"), &format!("k{i}"), None, None).unwrap();
+    }
+    assert_eq!(enrollment.request("d", "kd", None, None).unwrap(), Decision::QueueFull);
+
+    let reloaded = Enrollment::load(&path).unwrap();
+    assert!(reloaded.is_enrolled("a", "ka"));
+    assert!(!reloaded.is_enrolled("a", "kb"));
+    assert!(reloaded.revoke("a").unwrap());
+    assert!(!Enrollment::load(&path).unwrap().is_enrolled("a", "ka"));
+  }
+}
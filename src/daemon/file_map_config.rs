@@ -0,0 +1,213 @@
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::select;
+
+use crate::{
+  daemon::{
+    audit::{AuditEvent, AuditRecord},
+    states::SharedAppState,
+  },
+  utils::states::States as _,
+};
+
+/// How often the config file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Files and directories to publish, declared in a YAML file:
+///
+/// ```yaml
+/// maps:
+///   - name: ubuntu.img
+///     path: /srv/images/ubuntu-24.04.img
+///   - name: tools
+///     path: tools # relative to the config file
+///     isdir: true
+/// ```
+#[derive(Deserialize)]
+struct FileMapConfig {
+  #[serde(default)]
+  maps: Vec<MapEntry>,
+}
+
+#[derive(Deserialize)]
+struct MapEntry {
+  name: String,
+  path: String,
+  #[serde(default)]
+  isdir: bool,
+}
+
+/// Publishes the maps declared in a config file and keeps them in sync with it.
+///
+/// Maps added through the API are left alone: names that are already published when they first appear in the config
+/// file are skipped, so they are neither replaced nor removed by a reload. A map is only replaced when its path
+/// changes, so cached hashes survive reloads. Changes are audited along with the config file.
+pub struct FileMapConfigWatcher {
+  app: SharedAppState,
+  path: PathBuf,
+  /// Names published from the config file
+  published: HashSet<String>,
+  /// Modification time and size of the config file when it was last loaded
+  loaded: Option<(Option<SystemTime>, u64)>,
+}
+
+impl FileMapConfigWatcher {
+  /// Load `path` and publish its maps. Fails if the file cannot be read or parsed.
+  pub async fn load(app: SharedAppState, path: PathBuf) -> Result<Self> {
+    let mut watcher = FileMapConfigWatcher {
+      app,
+      path,
+      published: HashSet::new(),
+      loaded: None,
+    };
+    watcher.reload().await?;
+    Ok(watcher)
+  }
+
+  /// Reload the config file whenever it changes, until the daemon shuts down.
+  pub async fn watch(mut self) {
+    let cancel = self.app.cancel_signal.clone();
+    loop {
+      select! {
+        _ = cancel.cancelled() => break,
+        _ = tokio::time::sleep(POLL_INTERVAL) => {}
+      }
+      let stamp = tokio::fs::metadata(&self.path).await.ok().map(|m| (m.modified().ok(), m.len()));
+      if stamp.is_none() || stamp == self.loaded {
+        continue;
+      }
+      info!("File map config {} changed, reloading", self.path.display());
+      if let Err(err) = self.reload().await {
+        warn!("Failed to reload file map config {}: {err}", self.path.display());
+      }
+    }
+  }
+
+  async fn reload(&mut self) -> Result<()> {
+    let meta = tokio::fs::metadata(&self.path).await?;
+    let content = tokio::fs::read_to_string(&self.path).await?;
+    // Remember the stamp even if parsing fails, so a broken file is reported once rather than on every poll.
+    self.loaded = Some((meta.modified().ok(), meta.len()));
+    let config: FileMapConfig = serde_yml::from_str(&content)?;
+    let base = self.path.parent().unwrap_or(Path::new("."));
+
+    let mut published = HashSet::new();
+    let mut to_hash = vec![];
+    for entry in config.maps {
+      if !published.insert(entry.name.clone()) {
+        warn!("Duplicate file map {} in {}", entry.name, self.path.display());
+        continue;
+      }
+      if !self.published.contains(&entry.name) && self.app.file_map.get_arc(&entry.name).is_some() {
+        warn!(
+          "File map {} in {} is already published through the API, skipping it",
+          entry.name,
+          self.path.display()
+        );
+        published.remove(&entry.name);
+        continue;
+      }
+      let path = base.join(&entry.path);
+      let canonical = path.canonicalize().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+      if self.app.file_map.is_published(&entry.name, &canonical, entry.isdir) {
+        continue;
+      }
+      let path = path.to_string_lossy().to_string();
+      let r = if entry.isdir {
+        self.app.file_map.add_dir_map(path.clone(), entry.name.clone())
+      } else {
+        self.app.file_map.add_file_map(path.clone(), entry.name.clone())
+      };
+      match r {
+        Ok(()) => {
+          debug!("Published {} from {}", entry.name, entry.path);
+          self.app.audit(
+            AuditRecord::new(AuditEvent::FileMapAdded {
+              name: entry.name.clone(),
+              path: path.clone(),
+              isdir: entry.isdir,
+            })
+            .config(&self.path),
+          );
+          if !entry.isdir {
+            to_hash.push(entry.name);
+          }
+        }
+        Err(err) => {
+          warn!("Failed to publish {}: {err}", entry.name);
+          published.remove(&entry.name);
+        }
+      }
+    }
+    for name in self.published.difference(&published) {
+      debug!("Unpublished {name}");
+      self.app.file_map.remove(name);
+      self
+        .app
+        .audit(AuditRecord::new(AuditEvent::FileMapRemoved { name: name.clone() }).config(&self.path));
+    }
+    if let Some(watcher) = &self.app.file_watcher {
+      watcher.sync(&self.app.file_map);
+    }
+    info!("Loaded {} file maps from {}", published.len(), self.path.display());
+    self.published = published;
+
+    let app = self.app.clone();
+    tokio::spawn(async move {
+      for name in to_hash {
+        app.file_map.precompute_hashes(&name).await;
+      }
+    });
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::{
+    daemon::{api_token::ApiTokens, cli::StartupArgs, states::AppState},
+    utils::testing::TempPath,
+  };
+
+  #[tokio::test]
+  async fn test_reload_skips_api_maps() {
+    let dir = TempPath::new("mxd-file-map-config");
+    std::fs::create_dir(&dir).unwrap();
+    for name in ["api.img", "config.img"] {
+      std::fs::write(dir.join(name), name).unwrap();
+    }
+    let app = Arc::new(AppState::new(
+      StartupArgs::for_tests(),
+      ApiTokens::load(None, None).unwrap(),
+    ));
+    let api_path = dir.join("api.img").to_string_lossy().to_string();
+    app.file_map.add_file_map(api_path.clone(), "api".to_string()).unwrap();
+    let api_path = dir.join("api.img").canonicalize().unwrap().to_string_lossy().to_string();
+
+    let config = dir.join("maps.yaml");
+    std::fs::write(
+      &config,
+      "maps:\n  - name: api\n    path: config.img\n  - name: config\n    path: config.img\n",
+    )
+    .unwrap();
+    let mut watcher = FileMapConfigWatcher::load(app.clone(), config.clone()).await.unwrap();
+    assert!(app.file_map.is_published(&"api".to_string(), &api_path, false));
+    assert_eq!(watcher.published, HashSet::from(["config".to_string()]));
+
+    // Dropping the entry does not remove the API map
+    std::fs::write(&config, "maps: []\n").unwrap();
+    watcher.reload().await.unwrap();
+    assert!(app.file_map.is_published(&"api".to_string(), &api_path, false));
+    assert!(app.file_map.get_arc(&"config".to_string()).is_none());
+  }
+}
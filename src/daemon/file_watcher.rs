@@ -0,0 +1,143 @@
+use anyhow::Result;
+
+use crate::daemon::states::{SharedAppState, file_map::FileMapStorage};
+
+#[cfg(target_os = "linux")]
+use std::{
+  collections::{HashMap, HashSet},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+#[cfg(target_os = "linux")]
+use log::{debug, info, warn};
+#[cfg(target_os = "linux")]
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+#[cfg(target_os = "linux")]
+use tokio::sync::mpsc;
+
+/// Most directories watched at once. Published files in further directories are only checked when requested.
+#[cfg(target_os = "linux")]
+const MAX_WATCHED_DIRS: usize = 1024;
+
+/// Refreshes the cached hashes of published files as soon as they are rewritten or replaced.
+///
+/// Watches the directories of published files with inotify, so replacing a file with a rename is noticed as well.
+/// Watches follow the file maps through [`FileWatcher::sync`], and are dropped when their directory goes away.
+/// Without it, hashes are only recomputed when a file is requested. Only supported on Linux.
+pub struct FileWatcher {
+  #[cfg(target_os = "linux")]
+  inotify: Arc<Inotify>,
+  /// Watched directories by their watch descriptor
+  #[cfg(target_os = "linux")]
+  dirs: Arc<Mutex<HashMap<WatchDescriptor, PathBuf>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl FileWatcher {
+  pub fn new() -> Result<Self> {
+    Ok(FileWatcher {
+      inotify: Arc::new(Inotify::init(InitFlags::IN_CLOEXEC)?),
+      dirs: Arc::new(Mutex::new(HashMap::new())),
+    })
+  }
+
+  /// Start delivering change events. Events are read on a dedicated thread and hashed on the runtime.
+  pub fn start(&self, app: SharedAppState) {
+    let (tx, mut rx) = mpsc::channel::<String>(64);
+    let inotify = self.inotify.clone();
+    let dirs = self.dirs.clone();
+    std::thread::spawn(move || {
+      loop {
+        let events = match inotify.read_events() {
+          Ok(events) => events,
+          Err(nix::errno::Errno::EINTR) => continue,
+          Err(err) => {
+            warn!("Failed to read file change events: {err}");
+            break;
+          }
+        };
+        for event in events {
+          // The directory was removed, or no longer watched
+          if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            if let Ok(mut dirs) = dirs.lock() &&
+              let Some(dir) = dirs.remove(&event.wd)
+            {
+              debug!("Stopped watching {}", dir.display());
+            }
+            continue;
+          }
+          let (Some(name), Some(dir)) = (event.name, dirs.lock().ok().and_then(|d| d.get(&event.wd).cloned())) else {
+            continue;
+          };
+          if tx.blocking_send(dir.join(name).to_string_lossy().to_string()).is_err() {
+            return;
+          }
+        }
+      }
+    });
+    tokio::spawn(async move {
+      let cancel = app.cancel_signal.clone();
+      loop {
+        let path = tokio::select! {
+          _ = cancel.cancelled() => break,
+          path = rx.recv() => match path {
+            Some(path) => path,
+            None => break,
+          },
+        };
+        for name in app.file_map.names_for_path(&path) {
+          debug!("Published file {name} changed, refreshing its hashes");
+          app.file_map.precompute_hashes(&name).await;
+        }
+      }
+    });
+    info!("Watching published files for changes");
+  }
+
+  /// Watch the directories of the files published in `file_map`, and stop watching the others.
+  pub fn sync(&self, file_map: &FileMapStorage) {
+    let mut wanted: HashSet<PathBuf> = file_map
+      .file_paths()
+      .iter()
+      .filter_map(|path| Path::new(path).parent().map(Path::to_path_buf))
+      .collect();
+    let Ok(mut dirs) = self.dirs.lock() else {
+      return;
+    };
+    dirs.retain(|wd, dir| {
+      if wanted.remove(dir) {
+        return true;
+      }
+      if let Err(err) = self.inotify.rm_watch(*wd) {
+        debug!("Failed to stop watching {}: {err}", dir.display());
+      }
+      false
+    });
+    for dir in wanted {
+      if dirs.len() >= MAX_WATCHED_DIRS {
+        warn!(
+          "Not watching {}, {MAX_WATCHED_DIRS} directories are watched already",
+          dir.display()
+        );
+        continue;
+      }
+      // Completed writes and files moved into place. Creation is left out, it fires before the content is written.
+      match self.inotify.add_watch(&dir, AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO) {
+        Ok(wd) => {
+          dirs.insert(wd, dir);
+        }
+        Err(err) => warn!("Failed to watch {}: {err}", dir.display()),
+      }
+    }
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl FileWatcher {
+  pub fn new() -> Result<Self> { anyhow::bail!("Watching files is only supported on Linux") }
+
+  pub fn start(&self, _app: SharedAppState) {}
+
+  pub fn sync(&self, _file_map: &FileMapStorage) {}
+}
@@ -0,0 +1,257 @@
+use std::{
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+  time::Duration,
+};
+
+use anyhow::Result;
+use futures_util::future::join_all;
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, select};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  daemon::{
+    cli::StartupArgs,
+    discovery::{Memberships, rejoin_interval},
+  },
+  discovery::ipv6_interfaces,
+  protocol::{
+    mdns::{
+      MDNS_GROUP_V4, MDNS_GROUP_V6, MDNS_PORT, Message, Question, RData, Record, SERVICE_TYPE, SERVICES_META_QUERY,
+    },
+    messaging::PROTOCOL_VERSION,
+  },
+  utils::{cert::cert_fingerprint, hash::sha2_256_for_str},
+};
+
+/// How long the advertised records may be cached, in seconds
+const TTL: u32 = 120;
+/// Queries from other ports than 5353 come from simple resolvers, which must not cache answers for long
+/// (RFC 6762 section 6.7)
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+/// DNS-SD advertisement of the controller as a `_mxlite._tcp` service over multicast DNS.
+///
+/// The TXT record carries the protocol version and the fingerprint of the controller key. If HTTPS is enabled, it
+/// also carries its port and the fingerprint of its certificate, and `tls` is `1`, or `required` if agents cannot
+/// connect over plain HTTP at the port of the SRV record. Link-local IPv6 addresses are not advertised, agents find
+/// those through the UDP discovery.
+#[derive(Clone)]
+pub struct MdnsAdvertisement {
+  /// Service instance name, `<name>._mxlite._tcp.local`
+  instance: String,
+  /// Host name the SRV record points at, `<name>.local`
+  host: String,
+  http_port: u16,
+  priority: u16,
+  txt: Vec<String>,
+}
+
+impl MdnsAdvertisement {
+  pub fn new(args: &StartupArgs) -> Result<Self> {
+    let fingerprint = sha2_256_for_str(&args.key_pair.0)?;
+    // Named after the key, so that several controllers on a network do not collide
+    let name = format!("mxd-{}", &fingerprint[..12]);
+    let mut txt = vec!["txtvers=1".to_string(), format!("version={PROTOCOL_VERSION}"), format!("fp={fingerprint}")];
+    match &args.https_args {
+      Some(https) => {
+        txt.push(if args.require_tls { "tls=required" } else { "tls=1" }.to_string());
+        txt.push(format!("https_port={}", https.port));
+        txt.push(format!("cert={}", cert_fingerprint(&https.cert)?));
+      }
+      None => txt.push("tls=0".to_string()),
+    }
+    Ok(MdnsAdvertisement {
+      instance: format!("{name}.{SERVICE_TYPE}"),
+      host: format!("{name}.local"),
+      http_port: args.http_port,
+      priority: args.discovery_priority,
+      txt,
+    })
+  }
+
+  fn records(&self, ttl: u32) -> Vec<Record> {
+    let mut records = vec![
+      Record::new(SERVICE_TYPE, ttl, RData::Ptr(self.instance.clone())),
+      Record::new(SERVICES_META_QUERY, ttl, RData::Ptr(SERVICE_TYPE.to_string())),
+      Record::new(
+        &self.instance,
+        ttl,
+        RData::Srv {
+          priority: self.priority,
+          port: self.http_port,
+          target: self.host.clone(),
+        },
+      ),
+      Record::new(&self.instance, ttl, RData::Txt(self.txt.clone())),
+    ];
+    match if_addrs::get_if_addrs() {
+      Ok(interfaces) => {
+        for if_ in interfaces.iter().filter(|if_| !if_.is_loopback()) {
+          let data = match if_.ip() {
+            IpAddr::V4(ip) => RData::A(ip),
+            IpAddr::V6(ip) if ip.is_unicast_link_local() => continue,
+            IpAddr::V6(ip) => RData::Aaaa(ip),
+          };
+          records.push(Record::new(&self.host, ttl, data));
+        }
+      }
+      Err(err) => error!("Failed to list network interfaces: {err}"),
+    }
+    records
+  }
+
+  /// Records answering `questions`, and the other records of the service to resolve them without asking again.
+  fn answer(&self, questions: &[Question], ttl: u32) -> (Vec<Record>, Vec<Record>) {
+    let (answers, others): (Vec<Record>, Vec<Record>) =
+      self.records(ttl).into_iter().partition(|record| questions.iter().any(|q| record.answers(q)));
+    if answers.is_empty() {
+      return (answers, vec![]);
+    }
+    let additionals = others.into_iter().filter(|record| !matches!(record.data, RData::Ptr(_))).collect();
+    (answers, additionals)
+  }
+
+  /// Send all records unsolicited, to announce the service, or to withdraw it with a TTL of 0.
+  async fn announce(&self, socket: &UdpSocket, ttl: u32) {
+    let msg = Message {
+      response: true,
+      answers: self.records(ttl),
+      ..Default::default()
+    };
+    let msg = match msg.encode() {
+      Ok(msg) => msg,
+      Err(err) => {
+        error!("Failed to encode mDNS announcement: {err}");
+        return;
+      }
+    };
+    let dests = match socket.local_addr() {
+      Ok(SocketAddr::V4(_)) => vec![SocketAddr::from((MDNS_GROUP_V4, MDNS_PORT))],
+      Ok(SocketAddr::V6(_)) => ipv6_interfaces()
+        .into_iter()
+        .map(|index| SocketAddr::V6(SocketAddrV6::new(MDNS_GROUP_V6, MDNS_PORT, 0, index)))
+        .collect(),
+      Err(_) => vec![],
+    };
+    for dest in dests {
+      if let Err(err) = socket.send_to(&msg, dest).await {
+        debug!("Failed to send mDNS announcement to {dest}: {err}");
+      }
+    }
+  }
+
+  async fn handle_query(&self, socket: &UdpSocket, msg: &[u8], addr: SocketAddr) -> Result<()> {
+    let query = Message::decode(msg)?;
+    if query.response {
+      return Ok(());
+    }
+    let legacy = addr.port() != MDNS_PORT;
+    let (answers, additionals) = self.answer(&query.questions, if legacy { LEGACY_UNICAST_TTL } else { TTL });
+    if answers.is_empty() {
+      return Ok(());
+    }
+    let resp = Message {
+      id: if legacy { query.id } else { 0 },
+      response: true,
+      questions: if legacy { query.questions.clone() } else { vec![] },
+      answers,
+      additionals,
+    };
+    let dest = if legacy || query.questions.iter().any(|q| q.unicast) {
+      addr
+    } else {
+      // IPv4 multicast leaves through the default interface, IPv6 through the one the query came in on
+      match addr {
+        SocketAddr::V4(_) => SocketAddr::from((MDNS_GROUP_V4, MDNS_PORT)),
+        SocketAddr::V6(addr) => SocketAddr::V6(SocketAddrV6::new(MDNS_GROUP_V6, MDNS_PORT, 0, addr.scope_id())),
+      }
+    };
+    socket.send_to(&resp.encode()?, dest).await?;
+    debug!("Answered mDNS query from {addr}");
+    Ok(())
+  }
+
+  async fn serve(&self, ct: &CancellationToken, socket: &UdpSocket, memberships: &mut Memberships) {
+    let mut buf = [0u8; 9000];
+    let mut rejoin = rejoin_interval();
+    loop {
+      select! {
+          _ = ct.cancelled() => break,
+          _ = rejoin.tick() => memberships.update(socket),
+          r = socket.recv_from(&mut buf) => match r {
+            Ok((size, addr)) => {
+              if let Err(err) = self.handle_query(socket, &buf[..size], addr).await {
+                debug!("Failed to handle mDNS message from {addr}: {err}");
+              }
+            }
+            Err(err) => error!("Failed to receive mDNS message: {err}"),
+          }
+      }
+    }
+  }
+
+  /// Answer queries for the service until `ct` is cancelled, then withdraw it.
+  pub async fn run(&self, ct: &CancellationToken) {
+    let mut sockets = vec![];
+    match bind_v4() {
+      Ok(socket) => sockets.push(socket),
+      Err(err) => warn!("Failed to start mDNS responder over IPv4: {err}"),
+    }
+    match bind_v6() {
+      Ok(socket) => sockets.push(socket),
+      Err(err) => warn!("Failed to start mDNS responder over IPv6: {err}"),
+    }
+    if sockets.is_empty() {
+      error!("Failed to start mDNS responder");
+      return;
+    }
+    info!("Advertising {} over mDNS", self.instance);
+    // Announced twice, a second apart (RFC 6762 section 8.3)
+    for (socket, _) in &sockets {
+      self.announce(socket, TTL).await;
+    }
+    select! {
+        _ = ct.cancelled() => {}
+        _ = tokio::time::sleep(Duration::from_secs(1)) => {
+          for (socket, _) in &sockets {
+            self.announce(socket, TTL).await;
+          }
+        }
+    }
+    join_all(sockets.iter_mut().map(|(socket, memberships)| self.serve(ct, socket, memberships))).await;
+    for (socket, _) in &sockets {
+      self.announce(socket, 0).await;
+    }
+    info!("mDNS responder stopped");
+  }
+}
+
+/// Sockets on port 5353, shared with other responders running on the host.
+fn shared_socket(domain: Domain) -> Result<Socket> {
+  let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+  socket.set_reuse_address(true)?;
+  #[cfg(unix)]
+  socket.set_reuse_port(true)?;
+  Ok(socket)
+}
+
+fn bind_v4() -> Result<(UdpSocket, Memberships)> {
+  let socket = shared_socket(Domain::IPV4)?;
+  socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), MDNS_PORT).into())?;
+  socket.set_nonblocking(true)?;
+  let socket = UdpSocket::from_std(socket.into())?;
+  let memberships = Memberships::join(&socket, IpAddr::V4(MDNS_GROUP_V4));
+  Ok((socket, memberships))
+}
+
+fn bind_v6() -> Result<(UdpSocket, Memberships)> {
+  let socket = shared_socket(Domain::IPV6)?;
+  socket.set_only_v6(true)?;
+  socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), MDNS_PORT).into())?;
+  socket.set_nonblocking(true)?;
+  let socket = UdpSocket::from_std(socket.into())?;
+  let memberships = Memberships::join(&socket, IpAddr::V6(MDNS_GROUP_V6));
+  Ok((socket, memberships))
+}
@@ -1,4 +1,10 @@
+pub mod api_token;
+pub mod audit;
 pub mod cli;
 pub mod discovery;
+pub mod enrollment;
+pub mod file_map_config;
+pub mod file_watcher;
+pub mod mdns;
 pub mod server;
 pub mod states;
@@ -0,0 +1,79 @@
+use axum::{Extension, Json, Router, extract::State, http::StatusCode, routing::method_routing};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  daemon::{
+    api_token::Caller,
+    audit::{AuditEvent, AuditRecord},
+    states::SharedAppState,
+  },
+  utils::cert::generate_client_cert,
+};
+
+use super::ERR_REASON_INTERNAL_ERROR;
+
+const ERR_REASON_MTLS_DISABLED: &str = "MTLS_DISABLED";
+
+#[derive(Deserialize)]
+struct PostRequest {
+  host_id: String,
+}
+
+#[derive(Serialize)]
+struct PostResponse {
+  ok: bool,
+  /// PEM encoded client certificate for the agent
+  cert: Option<String>,
+  /// PEM encoded private key of the certificate
+  key: Option<String>,
+  /// PEM encoded CA certificate the agent should pin
+  ca_cert: Option<String>,
+  reason: Option<String>,
+}
+
+fn failed(status: StatusCode, reason: &str) -> (StatusCode, Json<PostResponse>) {
+  (
+    status,
+    Json(PostResponse {
+      ok: false,
+      cert: None,
+      key: None,
+      ca_cert: None,
+      reason: Some(reason.to_string()),
+    }),
+  )
+}
+
+/// Issue a client certificate that lets the agent `host_id` connect when mutual TLS is required.
+async fn post(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<PostRequest>,
+) -> (StatusCode, Json<PostResponse>) {
+  let Some(ca) = app.startup_args.client_ca() else {
+    return failed(StatusCode::NOT_FOUND, ERR_REASON_MTLS_DISABLED);
+  };
+  match generate_client_cert(&ca.cert, &ca.key, &params.host_id, ca.validity) {
+    Ok((cert, key)) => {
+      info!("Issued client certificate for {} to {caller}", params.host_id);
+      app.audit(AuditRecord::new(AuditEvent::AgentCertIssued).by(&caller).host(&params.host_id));
+      (
+        StatusCode::OK,
+        Json(PostResponse {
+          ok: true,
+          cert: Some(cert),
+          key: Some(key),
+          ca_cert: Some(ca.cert.clone()),
+          reason: None,
+        }),
+      )
+    }
+    Err(err) => {
+      warn!("Failed to issue client certificate for {}: {err}", params.host_id);
+      failed(StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR)
+    }
+  }
+}
+
+pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
+  Router::new().with_state(app).route("/", method_routing::post(post))
+}
@@ -1,22 +1,55 @@
 use axum::{
-  Json, Router,
+  Extension, Json, Router,
   extract::{Query, State},
+  http::StatusCode,
   routing::method_routing,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{daemon::states::SharedAppState, utils::states::States as _};
+use crate::{
+  daemon::{api_token::Caller, states::SharedAppState},
+  protocol::messaging::TransferProgress,
+  utils::states::States as _,
+};
 
 #[derive(Deserialize)]
 struct GetParams {
   host: String,
 }
 
-async fn get(State(app): State<SharedAppState>, params: Query<GetParams>) -> Json<Vec<u32>> {
+#[derive(Serialize)]
+struct TaskSummary {
+  id: u32,
+  /// Whether the final response has been received
+  completed: bool,
+  /// Latest progress reported by a running transfer
+  progress: Option<TransferProgress>,
+}
+
+async fn get(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, params: Query<GetParams>,
+) -> (StatusCode, Json<Vec<TaskSummary>>) {
+  if !caller.may_target(&params.host) {
+    return (StatusCode::FORBIDDEN, Json(vec![]));
+  }
   let Some(session) = app.host_session.get_arc(&params.host) else {
-    return Json(vec![]);
+    return (StatusCode::OK, Json(vec![]));
   };
-  Json(session.tasks.list())
+  let tasks = session
+    .tasks
+    .list()
+    .into_iter()
+    .filter_map(|id| {
+      let task = session.tasks.get_arc(&id)?;
+      let resp = task.as_ref().as_ref();
+      Some(TaskSummary {
+        id,
+        completed: resp.is_some_and(|r| !r.status.is_partial()),
+        progress: resp.and_then(|r| r.progress()).cloned(),
+      })
+    })
+    .collect();
+  (StatusCode::OK, Json(tasks))
 }
 
 pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
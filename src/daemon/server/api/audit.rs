@@ -0,0 +1,62 @@
+use axum::{
+  Json, Router,
+  extract::{Query, State},
+  http::StatusCode,
+  routing::method_routing,
+};
+use log::warn;
+use serde::Serialize;
+
+use crate::daemon::{
+  audit::{AuditFilter, AuditRecord},
+  states::SharedAppState,
+};
+
+use super::ERR_REASON_INTERNAL_ERROR;
+
+const ERR_REASON_AUDIT_LOG_DISABLED: &str = "AUDIT_LOG_DISABLED";
+
+#[derive(Serialize)]
+struct GetResponse {
+  ok: bool,
+  records: Vec<AuditRecord>,
+  reason: Option<String>,
+}
+
+async fn get(State(app): State<SharedAppState>, Query(filter): Query<AuditFilter>) -> (StatusCode, Json<GetResponse>) {
+  let Some(log) = &app.audit_log else {
+    return (
+      StatusCode::NOT_FOUND,
+      Json(GetResponse {
+        ok: false,
+        records: vec![],
+        reason: Some(ERR_REASON_AUDIT_LOG_DISABLED.to_string()),
+      }),
+    );
+  };
+  match log.query(&filter).await {
+    Ok(records) => (
+      StatusCode::OK,
+      Json(GetResponse {
+        ok: true,
+        records,
+        reason: None,
+      }),
+    ),
+    Err(err) => {
+      warn!("Failed to read audit log: {err}");
+      (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(GetResponse {
+          ok: false,
+          records: vec![],
+          reason: Some(ERR_REASON_INTERNAL_ERROR.to_string()),
+        }),
+      )
+    }
+  }
+}
+
+pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
+  Router::new().with_state(app).route("/", method_routing::get(get))
+}
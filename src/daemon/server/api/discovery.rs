@@ -1,7 +1,11 @@
-use axum::{Json, Router, extract::State, routing::method_routing};
+use axum::{Extension, Json, Router, extract::State, routing::method_routing};
 use serde::{Deserialize, Serialize};
 
-use crate::daemon::states::SharedAppState;
+use crate::daemon::{
+  api_token::Caller,
+  audit::{AuditEvent, AuditRecord},
+  states::SharedAppState,
+};
 
 #[derive(Serialize)]
 struct GetResponse {
@@ -30,7 +34,9 @@ struct PostResponse {
   state: GetResponse,
 }
 
-async fn post(State(app): State<SharedAppState>, Json(params): Json<PostRequest>) -> Json<PostResponse> {
+async fn post(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<PostRequest>,
+) -> Json<PostResponse> {
   let (ok, enabled) = if let Some(ds) = app.discovery_service.as_ref() {
     let mut ds = ds.lock().await;
     let ok = if params.start {
@@ -51,6 +57,13 @@ async fn post(State(app): State<SharedAppState>, Json(params): Json<PostRequest>
     log::warn!("Discovery service is not available");
     (false, false)
   };
+  app.audit(
+    AuditRecord::new(AuditEvent::DiscoveryChanged {
+      start: params.start,
+      ok,
+    })
+    .by(&caller),
+  );
   Json(PostResponse {
     ok,
     state: GetResponse {
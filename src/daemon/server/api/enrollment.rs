@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use axum::{
+  Extension, Json, Router,
+  extract::{Query, State},
+  http::StatusCode,
+  routing::method_routing,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::daemon::{
+  api_token::Caller,
+  audit::{AuditEvent, AuditRecord},
+  enrollment::{DEFAULT_TOKEN_TTL, EnrolledAgent, PendingEnrollment},
+  states::SharedAppState,
+};
+
+use super::{ERR_REASON_ENROLLMENT_DISABLED, ERR_REASON_INTERNAL_ERROR};
+
+const ERR_REASON_NOT_FOUND: &str = "NOT_FOUND";
+
+#[derive(Serialize)]
+struct ActionResponse {
+  ok: bool,
+  reason: Option<String>,
+}
+
+fn action_result(result: anyhow::Result<bool>) -> (StatusCode, Json<ActionResponse>) {
+  let (status, reason) = match result {
+    Ok(true) => (StatusCode::OK, None),
+    Ok(false) => (StatusCode::NOT_FOUND, Some(ERR_REASON_NOT_FOUND)),
+    Err(err) => {
+      warn!("Failed to update enrolled agents: {err}");
+      (StatusCode::INTERNAL_SERVER_ERROR, Some(ERR_REASON_INTERNAL_ERROR))
+    }
+  };
+  (
+    status,
+    Json(ActionResponse {
+      ok: reason.is_none(),
+      reason: reason.map(str::to_string),
+    }),
+  )
+}
+
+fn disabled() -> (StatusCode, Json<ActionResponse>) {
+  (
+    StatusCode::NOT_FOUND,
+    Json(ActionResponse {
+      ok: false,
+      reason: Some(ERR_REASON_ENROLLMENT_DISABLED.to_string()),
+    }),
+  )
+}
+
+#[derive(Serialize)]
+struct GetResponse {
+  ok: bool,
+  agents: Vec<EnrolledAgent>,
+  pending: Vec<PendingEnrollment>,
+  reason: Option<String>,
+}
+
+/// List the enrolled agents and the requests waiting for approval.
+async fn get(State(app): State<SharedAppState>) -> (StatusCode, Json<GetResponse>) {
+  let Some(enrollment) = &app.enrollment else {
+    return (
+      StatusCode::NOT_FOUND,
+      Json(GetResponse {
+        ok: false,
+        agents: vec![],
+        pending: vec![],
+        reason: Some(ERR_REASON_ENROLLMENT_DISABLED.to_string()),
+      }),
+    );
+  };
+  (
+    StatusCode::OK,
+    Json(GetResponse {
+      ok: true,
+      agents: enrollment.agents(),
+      pending: enrollment.pending(),
+      reason: None,
+    }),
+  )
+}
+
+#[derive(Deserialize)]
+struct HostRequest {
+  host_id: String,
+}
+
+/// A pending request, identified by the fingerprint of its key as logged by the agent.
+#[derive(Deserialize)]
+struct PendingRequest {
+  host_id: String,
+  fingerprint: String,
+}
+
+/// Revoke the enrolled key of a host. The agent has to enroll again to connect.
+async fn delete(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Query(params): Query<HostRequest>,
+) -> (StatusCode, Json<ActionResponse>) {
+  let Some(enrollment) = &app.enrollment else {
+    return disabled();
+  };
+  let result = enrollment.revoke(&params.host_id);
+  if let Ok(true) = result {
+    info!("Revoked enrollment of {} by {caller}", params.host_id);
+    app.audit(AuditRecord::new(AuditEvent::AgentRevoked).by(&caller).host(&params.host_id));
+  }
+  action_result(result)
+}
+
+/// Approve the pending request of a host for the key with the given fingerprint, which the operator compared with the
+/// one the agent logged.
+async fn post_approve(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<PendingRequest>,
+) -> (StatusCode, Json<ActionResponse>) {
+  let Some(enrollment) = &app.enrollment else {
+    return disabled();
+  };
+  let pubkey = enrollment
+    .pending()
+    .into_iter()
+    .find(|p| p.host_id == params.host_id && p.fingerprint == params.fingerprint)
+    .map(|p| p.pubkey);
+  let result = enrollment.approve(&params.host_id, &params.fingerprint);
+  if let (Ok(true), Some(pubkey)) = (&result, pubkey) {
+    info!(
+      "Approved enrollment of {} with key {} by {caller}",
+      params.host_id, params.fingerprint
+    );
+    app.audit(
+      AuditRecord::new(AuditEvent::AgentEnrolled { pubkey, auto: false })
+        .by(&caller)
+        .host(&params.host_id),
+    );
+  }
+  action_result(result)
+}
+
+async fn post_reject(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<PendingRequest>,
+) -> (StatusCode, Json<ActionResponse>) {
+  let Some(enrollment) = &app.enrollment else {
+    return disabled();
+  };
+  let rejected = enrollment.reject(&params.host_id, &params.fingerprint);
+  if rejected {
+    info!(
+      "Rejected enrollment of {} with key {} by {caller}",
+      params.host_id, params.fingerprint
+    );
+    app.audit(AuditRecord::new(AuditEvent::EnrollmentRejected).by(&caller).host(&params.host_id));
+  }
+  action_result(Ok(rejected))
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+  /// Lifetime in seconds
+  ttl: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+  ok: bool,
+  token: Option<String>,
+  /// Seconds since the Unix epoch
+  expires: Option<u64>,
+  reason: Option<String>,
+}
+
+/// Mint a one-time bootstrap token that enrolls the first agent presenting it without approval.
+async fn post_token(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<TokenRequest>,
+) -> (StatusCode, Json<TokenResponse>) {
+  let Some(enrollment) = &app.enrollment else {
+    return (
+      StatusCode::NOT_FOUND,
+      Json(TokenResponse {
+        ok: false,
+        token: None,
+        expires: None,
+        reason: Some(ERR_REASON_ENROLLMENT_DISABLED.to_string()),
+      }),
+    );
+  };
+  let ttl = params.ttl.map_or(DEFAULT_TOKEN_TTL, Duration::from_secs);
+  let (token, expires) = enrollment.mint_token(ttl);
+  info!("Minted a bootstrap token for {caller}");
+  app.audit(AuditRecord::new(AuditEvent::BootstrapTokenMinted { expires }).by(&caller));
+  (
+    StatusCode::OK,
+    Json(TokenResponse {
+      ok: true,
+      token: Some(token),
+      expires: Some(expires),
+      reason: None,
+    }),
+  )
+}
+
+pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
+  Router::new()
+    .with_state(app)
+    .route("/", method_routing::get(get).delete(delete))
+    .route("/approve", method_routing::post(post_approve))
+    .route("/reject", method_routing::post(post_reject))
+    .route("/tokens", method_routing::post(post_token))
+}
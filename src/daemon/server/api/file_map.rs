@@ -1,12 +1,19 @@
 use axum::{
-  Json, Router,
+  Extension, Json, Router,
   extract::{Query, State},
   http::StatusCode,
   routing::method_routing,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{daemon::states::SharedAppState, utils::states::States};
+use crate::{
+  daemon::{
+    api_token::Caller,
+    audit::{AuditEvent, AuditRecord},
+    states::SharedAppState,
+  },
+  utils::states::States,
+};
 
 #[derive(Deserialize)]
 struct PostRequestMapInner {
@@ -32,30 +39,51 @@ struct PostResponse {
   result: Vec<PostResponseErrInner>,
 }
 
-async fn post(State(app): State<SharedAppState>, Json(params): Json<PostRequest>) -> (StatusCode, Json<PostResponse>) {
+async fn post(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<PostRequest>,
+) -> (StatusCode, Json<PostResponse>) {
   let mut result = Vec::with_capacity(params.maps.len());
   for map in params.maps {
     if map.isdir.unwrap_or(false) {
-      if let Err(e) = app.file_map.add_dir_map(map.path, map.name.clone()) {
+      if let Err(e) = app.file_map.add_dir_map(map.path.clone(), map.name.clone()) {
         result.push(PostResponseErrInner {
           ok: false,
           err: Some(e),
           name: map.name,
         });
       } else {
+        app.audit(
+          AuditRecord::new(AuditEvent::FileMapAdded {
+            name: map.name.clone(),
+            path: map.path,
+            isdir: true,
+          })
+          .by(&caller),
+        );
         result.push(PostResponseErrInner {
           ok: true,
           err: None,
           name: map.name,
         });
       }
-    } else if let Err(e) = app.file_map.add_file_map(map.path, map.name.clone()) {
+    } else if let Err(e) = app.file_map.add_file_map(map.path.clone(), map.name.clone()) {
       result.push(PostResponseErrInner {
         ok: false,
         err: Some(e),
         name: map.name,
       });
     } else {
+      // Files are only found by hash once it is cached
+      let (hashing, name) = (app.clone(), map.name.clone());
+      tokio::spawn(async move { hashing.file_map.precompute_hashes(&name).await });
+      app.audit(
+        AuditRecord::new(AuditEvent::FileMapAdded {
+          name: map.name.clone(),
+          path: map.path,
+          isdir: false,
+        })
+        .by(&caller),
+      );
       result.push(PostResponseErrInner {
         ok: true,
         err: None,
@@ -63,6 +91,9 @@ async fn post(State(app): State<SharedAppState>, Json(params): Json<PostRequest>
       });
     }
   }
+  if let Some(watcher) = &app.file_watcher {
+    watcher.sync(&app.file_map);
+  }
   (
     if result.iter().all(|i| i.ok) {
       StatusCode::OK
@@ -89,8 +120,21 @@ struct DeleteRequest {
   publish_name: String,
 }
 
-async fn delete(State(app): State<SharedAppState>, Query(params): Query<DeleteRequest>) -> StatusCode {
-  app.file_map.remove(&params.publish_name);
+async fn delete(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Query(params): Query<DeleteRequest>,
+) -> StatusCode {
+  if app.file_map.get_arc(&params.publish_name).is_some() {
+    app.file_map.remove(&params.publish_name);
+    if let Some(watcher) = &app.file_watcher {
+      watcher.sync(&app.file_map);
+    }
+    app.audit(
+      AuditRecord::new(AuditEvent::FileMapRemoved {
+        name: params.publish_name,
+      })
+      .by(&caller),
+    );
+  }
   StatusCode::OK
 }
 
@@ -1,10 +1,14 @@
-use std::fs::{metadata, read_dir, symlink_metadata};
+use std::{
+  fs::{Metadata, canonicalize, read_dir, read_link, symlink_metadata},
+  path::{Component, Path, PathBuf},
+  time::UNIX_EPOCH,
+};
 
 use anyhow::Result;
 use axum::{
   Json, Router,
   body::Body,
-  extract::Query,
+  extract::{Query, State},
   http::StatusCode,
   response::{IntoResponse, Response},
   routing::get,
@@ -19,6 +23,50 @@ pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
   Router::new().with_state(app).route("/lsdir", get(get_lsdir)).route("/read", get(get_read))
 }
 
+/// Where a requested path leads with respect to the roots of the API.
+enum Confined {
+  /// Canonical path below one of the roots
+  Allowed(PathBuf),
+  /// The path does not exist, but would be below one of the roots
+  Missing,
+  /// The path, or a symlink along it, leads outside of the roots
+  Outside,
+}
+
+/// Whether `path` is below one of `roots`. Nothing is, without roots.
+fn is_below_roots(roots: &[PathBuf], path: &Path) -> bool { roots.iter().any(|root| path.starts_with(root)) }
+
+/// Make `path` absolute and drop `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+  let mut normalized = if path.is_absolute() {
+    PathBuf::new()
+  } else {
+    std::env::current_dir().unwrap_or_default()
+  };
+  for component in path.components() {
+    match component {
+      Component::ParentDir => {
+        normalized.pop();
+      }
+      Component::CurDir => {}
+      c => normalized.push(c),
+    }
+  }
+  normalized
+}
+
+/// Resolve `path`, following symlinks, and check that it stays below `roots`.
+///
+/// Paths that cannot be resolved are checked lexically, so nothing is revealed about what exists outside the roots.
+fn confine(roots: &[PathBuf], path: &str) -> Confined {
+  match canonicalize(path) {
+    Ok(canonical) if is_below_roots(roots, &canonical) => Confined::Allowed(canonical),
+    Ok(_) => Confined::Outside,
+    Err(_) if is_below_roots(roots, &normalize(Path::new(path))) => Confined::Missing,
+    Err(_) => Confined::Outside,
+  }
+}
+
 #[derive(Deserialize)]
 struct GetLsdirParams {
   path: String,
@@ -32,29 +80,59 @@ struct GetLsdirResponse {
   result: Option<LsdirResult>,
 }
 
-async fn get_lsdir(Query(params): Query<GetLsdirParams>) -> Json<GetLsdirResponse> {
+async fn get_lsdir(
+  State(app): State<SharedAppState>, Query(params): Query<GetLsdirParams>,
+) -> (StatusCode, Json<GetLsdirResponse>) {
   debug!("retrieve dir info: {:?}", params.path);
-  if !std::fs::exists(&params.path).unwrap_or(false) {
-    return Json(GetLsdirResponse {
-      ok: false,
-      error: Some("Path does not exist".to_string()),
-      existed: false,
-      result: None,
-    });
-  }
-  match lsdir(&params.path) {
-    Ok(result) => Json(GetLsdirResponse {
-      ok: true,
-      error: None,
-      existed: true,
-      result: Some(result),
-    }),
-    Err(err) => Json(GetLsdirResponse {
-      ok: false,
-      error: Some(err.to_string()),
-      existed: true,
-      result: None,
-    }),
+  let roots = &app.startup_args.fs_roots;
+  let canonical = match confine(roots, &params.path) {
+    Confined::Allowed(canonical) => canonical,
+    Confined::Missing => {
+      return (
+        StatusCode::OK,
+        Json(GetLsdirResponse {
+          ok: false,
+          error: Some("Path does not exist".to_string()),
+          existed: false,
+          result: None,
+        }),
+      );
+    }
+    Confined::Outside => {
+      warn!(
+        "Rejected listing of {} outside of the filesystem API roots",
+        params.path
+      );
+      return (
+        StatusCode::FORBIDDEN,
+        Json(GetLsdirResponse {
+          ok: false,
+          error: Some("Path is outside of the allowed roots".to_string()),
+          existed: false,
+          result: None,
+        }),
+      );
+    }
+  };
+  match lsdir(roots, Path::new(&params.path), &canonical) {
+    Ok(result) => (
+      StatusCode::OK,
+      Json(GetLsdirResponse {
+        ok: true,
+        error: None,
+        existed: true,
+        result: Some(result),
+      }),
+    ),
+    Err(err) => (
+      StatusCode::OK,
+      Json(GetLsdirResponse {
+        ok: false,
+        error: Some(err.to_string()),
+        existed: true,
+        result: None,
+      }),
+    ),
   }
 }
 
@@ -65,13 +143,64 @@ struct LsdirResult {
   is_file: bool,
   is_symlink: bool,
   size: u64,
+  /// Target of the symlink as stored in the link
+  symlink_target: Option<String>,
+  #[serde(flatten)]
+  props: FileProps,
+}
+
+#[derive(Serialize, Default)]
+struct FileProps {
+  /// Permission bits
+  mode: Option<u32>,
+  /// Modification time in seconds since the Unix epoch
+  mtime: Option<u64>,
+  uid: Option<u32>,
+  gid: Option<u32>,
+  /// Name of the owning user, if it is known
+  owner: Option<String>,
+  /// Name of the owning group, if it is known
+  group: Option<String>,
 }
 
-fn lsdir(path: &String) -> Result<LsdirResult> {
+fn file_props(meta: &Metadata) -> FileProps {
+  #[allow(unused_mut)]
+  let mut props = FileProps {
+    mtime: meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+    ..Default::default()
+  };
+  #[cfg(unix)]
+  {
+    use nix::unistd::{Gid, Group, Uid, User};
+    use std::os::unix::fs::MetadataExt as _;
+
+    props.mode = Some(meta.mode() & 0o7777);
+    props.uid = Some(meta.uid());
+    props.gid = Some(meta.gid());
+    props.owner = User::from_uid(Uid::from_raw(meta.uid())).ok().flatten().map(|u| u.name);
+    props.group = Group::from_gid(Gid::from_raw(meta.gid())).ok().flatten().map(|g| g.name);
+  }
+  props
+}
+
+/// Describe `path`, which resolves to `canonical`. Symlinks in a listed directory are only followed within `roots`.
+///
+/// Only the resolved paths are looked at, without following symlinks, so a path replaced by a symlink since it was
+/// resolved is not followed out of the roots. `path` is only checked for being a symlink if it is below the roots too.
+fn lsdir(roots: &[PathBuf], path: &Path, canonical: &Path) -> Result<LsdirResult> {
   let mut files = vec![];
   let mut subdirs = vec![];
-  let mut meta = symlink_metadata(path)?;
-  let is_symlink = meta.is_symlink();
+  let link = normalize(path);
+  let is_symlink = is_below_roots(roots, &link) && symlink_metadata(&link).is_ok_and(|meta| meta.is_symlink());
+  let symlink_target = if is_symlink {
+    read_link(&link).ok().map(|target| target.to_string_lossy().to_string())
+  } else {
+    None
+  };
+  let meta = symlink_metadata(canonical)?;
+  if meta.is_symlink() {
+    anyhow::bail!("Path changed while it was listed");
+  }
   debug!(
     "lsdir: {:?}, is_symlink: {}, is_dir: {}, is_file: {}",
     path,
@@ -79,22 +208,24 @@ fn lsdir(path: &String) -> Result<LsdirResult> {
     meta.is_dir(),
     meta.is_file()
   );
-  if is_symlink {
-    meta = metadata(path)?;
-  }
-  if meta.is_dir() {
-    if let Ok(entries) = read_dir(path) {
-      for entry in entries.flatten() {
-        if let Some(name) = entry.file_name().to_str() {
-          let ft = entry.file_type().inspect_err(|e| {
-            warn!("Failed to get file type: {:?}; path: {:?}", e, entry.path());
-          })?;
-          if ft.is_dir() {
-            subdirs.push(name.to_string());
-          } else if ft.is_file() {
-            files.push(name.to_string());
-          } else if ft.is_symlink() {
-            match metadata(entry.path()) {
+  if meta.is_dir() &&
+    let Ok(entries) = read_dir(canonical)
+  {
+    for entry in entries.flatten() {
+      if let Some(name) = entry.file_name().to_str() {
+        let ft = entry.file_type().inspect_err(|e| {
+          warn!("Failed to get file type: {:?}; path: {:?}", e, entry.path());
+        })?;
+        if ft.is_dir() {
+          subdirs.push(name.to_string());
+        } else if ft.is_file() {
+          files.push(name.to_string());
+        } else if ft.is_symlink() {
+          match canonicalize(entry.path()) {
+            Ok(target) if !is_below_roots(roots, &target) => {
+              debug!("Skipping symlink leading outside of the roots: {:?}", entry.path());
+            }
+            Ok(target) => match symlink_metadata(target) {
               Ok(target) => {
                 if target.is_dir() {
                   subdirs.push(name.to_string());
@@ -107,27 +238,24 @@ fn lsdir(path: &String) -> Result<LsdirResult> {
               Err(e) => {
                 warn!("Failed to get symlink target: {:?}; path: {:?}", e, entry.path());
               }
+            },
+            Err(e) => {
+              warn!("Failed to get symlink target: {:?}; path: {:?}", e, entry.path());
             }
           }
         }
       }
     }
-    Ok(LsdirResult {
-      files,
-      subdirs,
-      is_file: false,
-      is_symlink,
-      size: meta.len(),
-    })
-  } else {
-    Ok(LsdirResult {
-      files,
-      subdirs,
-      is_file: true,
-      is_symlink,
-      size: meta.len(),
-    })
   }
+  Ok(LsdirResult {
+    files,
+    subdirs,
+    is_file: !meta.is_dir(),
+    is_symlink,
+    size: meta.len(),
+    symlink_target,
+    props: file_props(&meta),
+  })
 }
 
 #[derive(Deserialize)]
@@ -136,26 +264,56 @@ struct GetReadParams {
   max_size: Option<u64>,
 }
 
-async fn get_read(Query(params): Query<GetReadParams>) -> Response {
+/// Open the resolved `path` for reading, refusing a symlink that was put in its place since.
+async fn open_resolved(path: &Path) -> std::io::Result<tokio::fs::File> {
+  let mut opts = tokio::fs::OpenOptions::new();
+  opts.read(true);
+  #[cfg(unix)]
+  opts.custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits());
+  opts.open(path).await
+}
+
+async fn get_read(State(app): State<SharedAppState>, Query(params): Query<GetReadParams>) -> Response {
   debug!("get_read: {:?}", params.path);
-  if let Some(size) = metadata(&params.path).ok().and_then(|meta| {
-    if meta.is_file() {
-      Some(meta.len())
-    } else {
-      debug!("get_read: Path is not a file: {:?}", params.path);
-      None
+  let path = match confine(&app.startup_args.fs_roots, &params.path) {
+    Confined::Allowed(path) => path,
+    Confined::Missing => return StatusCode::NOT_FOUND.into_response(),
+    Confined::Outside => {
+      warn!("Rejected read of {} outside of the filesystem API roots", params.path);
+      return StatusCode::FORBIDDEN.into_response();
     }
-  }) {
-    if params.max_size.map(|s| s >= size).unwrap_or(true) {
-      tokio::fs::File::open(&params.path)
-        .await
-        .map(|file| Body::from_stream(ReaderStream::new(file)).into_response())
-        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
-    } else {
-      debug!("get_read: File size exceeds max size: {:?}", params.path);
-      StatusCode::IM_A_TEAPOT.into_response()
-    }
-  } else {
-    StatusCode::NOT_FOUND.into_response()
+  };
+  let Ok(file) = open_resolved(&path).await else {
+    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+  };
+  // Check the opened file rather than the path, which may have changed in between.
+  let Some(size) = file.metadata().await.ok().filter(|meta| meta.is_file()).map(|meta| meta.len()) else {
+    debug!("get_read: Path is not a file: {:?}", params.path);
+    return StatusCode::NOT_FOUND.into_response();
+  };
+  if params.max_size.is_some_and(|max| max < size) {
+    debug!("get_read: File size exceeds max size: {:?}", params.path);
+    return StatusCode::IM_A_TEAPOT.into_response();
+  }
+  Body::from_stream(ReaderStream::new(file)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_confine_missing_paths() {
+    let roots = [PathBuf::from("/nonexistent-root")];
+    assert!(matches!(confine(&roots, "/nonexistent-root/a/../b"), Confined::Missing));
+    assert!(matches!(
+      confine(&roots, "/nonexistent-root/../etc/x"),
+      Confined::Outside
+    ));
+    assert!(matches!(confine(&roots, "/nonexistent-rootless/x"), Confined::Outside));
+    assert!(matches!(confine(&roots, "/"), Confined::Outside));
+    // Nothing is exposed without roots
+    assert!(matches!(confine(&[], "/"), Confined::Outside));
+    assert!(matches!(confine(&[], "/nonexistent-root"), Confined::Outside));
   }
 }
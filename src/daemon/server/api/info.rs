@@ -1,5 +1,5 @@
 use axum::{
-  Json, Router,
+  Extension, Json, Router,
   extract::{Query, State},
   http::StatusCode,
   routing::method_routing,
@@ -7,7 +7,10 @@ use axum::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
-  daemon::states::{SharedAppState, host_session::ExtraInfo},
+  daemon::{
+    api_token::Caller,
+    states::{SharedAppState, host_session::ExtraInfo},
+  },
   utils::states::States as _,
 };
 
@@ -23,7 +26,19 @@ struct GetResponse {
   info: Option<ExtraInfo>,
 }
 
-async fn get(State(app): State<SharedAppState>, params: Query<GetParams>) -> (StatusCode, Json<GetResponse>) {
+async fn get(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, params: Query<GetParams>,
+) -> (StatusCode, Json<GetResponse>) {
+  if !caller.may_target(&params.host) {
+    return (
+      StatusCode::FORBIDDEN,
+      Json(GetResponse {
+        ok: false,
+        host: params.host.clone(),
+        info: None,
+      }),
+    );
+  }
   if let Some(info) = app.host_session.get_arc(&params.host).map(|s| s.extra.clone()) {
     (
       StatusCode::OK,
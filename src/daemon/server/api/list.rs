@@ -1,7 +1,10 @@
-use axum::{Json, Router, extract::State, routing::method_routing};
+use axum::{Extension, Json, Router, extract::State, routing::method_routing};
 use serde::Serialize;
 
-use crate::{daemon::states::SharedAppState, utils::states::States as _};
+use crate::{
+  daemon::{api_token::Caller, states::SharedAppState},
+  utils::states::States as _,
+};
 
 #[derive(Serialize)]
 struct GetResponse {
@@ -9,11 +12,11 @@ struct GetResponse {
   sessions: Vec<String>,
 }
 
-async fn get(State(app): State<SharedAppState>) -> Json<GetResponse> {
-  Json(GetResponse {
-    ok: true,
-    sessions: app.host_session.list(),
-  })
+/// List the hosts connected, of those the caller may target.
+async fn get(State(app): State<SharedAppState>, Extension(caller): Extension<Caller>) -> Json<GetResponse> {
+  let mut sessions = app.host_session.list();
+  sessions.retain(|host| caller.may_target(host));
+  Json(GetResponse { ok: true, sessions })
 }
 
 pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
@@ -1,9 +1,12 @@
-use axum::{Json, Router, extract::State, routing::method_routing};
+use axum::{Extension, Json, Router, extract::State, routing::method_routing};
 use futures_util::future::join_all;
 use serde::Serialize;
 
 use crate::{
-  daemon::states::{SharedAppState, host_session::ExtraInfo},
+  daemon::{
+    api_token::Caller,
+    states::{SharedAppState, host_session::ExtraInfo},
+  },
   utils::states::States as _,
 };
 
@@ -19,8 +22,10 @@ struct GetResponse {
   hosts: Vec<GetRespInner>,
 }
 
-async fn get(State(app): State<SharedAppState>) -> Json<GetResponse> {
-  let hosts = join_all(app.host_session.list().iter().map(async |s| GetRespInner {
+async fn get(State(app): State<SharedAppState>, Extension(caller): Extension<Caller>) -> Json<GetResponse> {
+  let mut sessions = app.host_session.list();
+  sessions.retain(|host| caller.may_target(host));
+  let hosts = join_all(sessions.iter().map(async |s| GetRespInner {
     host: s.clone(),
     info: app.host_session.get_arc(s).map(|s| s.extra.clone()),
   }))
@@ -1,5 +1,8 @@
+mod agent_cert;
 mod all_task;
+mod audit;
 mod discovery;
+mod enrollment;
 mod file_map;
 mod fs;
 mod info;
@@ -8,6 +11,7 @@ mod list_info;
 mod relative_url;
 mod result;
 mod task;
+mod uploads;
 
 use axum::Router;
 
@@ -18,20 +22,31 @@ use super::utils::auth_middleware;
 const ERR_REASON_SESSION_NOT_FOUND: &str = "SESSION_NOT_FOUND";
 const ERR_REASON_TASK_NOT_FOUND: &str = "TASK_NOT_FOUND";
 const ERR_REASON_TASK_NOT_COMPLETED: &str = "TASK_NOT_COMPLETED";
-const ERR_REASON_INTERNAL_ERROR: &str = "INTERNAL_ERROR";
+const ERR_REASON_HOST_NOT_PERMITTED: &str = "HOST_NOT_PERMITTED";
+// Shared with the routes agents call outside of the API
+pub(super) const ERR_REASON_INTERNAL_ERROR: &str = "INTERNAL_ERROR";
+pub(super) const ERR_REASON_UPLOADS_DISABLED: &str = "UPLOADS_DISABLED";
+pub(super) const ERR_REASON_INVALID_PATH: &str = "INVALID_PATH";
+pub(super) const ERR_REASON_ENROLLMENT_DISABLED: &str = "ENROLLMENT_DISABLED";
 
 pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
-  let router = Router::new()
+  let mut router = Router::new()
     .with_state(app.clone())
+    .nest("/agent-cert", self::agent_cert::build(app.clone()))
     .nest("/all-tasks", self::all_task::build(app.clone()))
+    .nest("/audit", self::audit::build(app.clone()))
     .nest("/discovery", self::discovery::build(app.clone()))
+    .nest("/enrollment", self::enrollment::build(app.clone()))
     .nest("/file-map", self::file_map::build(app.clone()))
-    .nest("/fs", self::fs::build(app.clone()))
     .nest("/list", self::list::build(app.clone()))
     .nest("/list-info", self::list_info::build(app.clone()))
     .nest("/info", self::info::build(app.clone()))
     .nest("/relative-url", self::relative_url::build(app.clone()))
     .nest("/result", self::result::build(app.clone()))
-    .nest("/task", self::task::build(app.clone()));
-  auth_middleware(router, app.startup_args.apikey.clone())
+    .nest("/task", self::task::build(app.clone()))
+    .nest("/uploads", self::uploads::build(app.clone()));
+  if !app.startup_args.disable_fs_api {
+    router = router.nest("/fs", self::fs::build(app.clone()));
+  }
+  auth_middleware(router, app)
 }
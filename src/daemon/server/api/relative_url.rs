@@ -11,8 +11,13 @@ use if_addrs::IfAddr;
 use log::error;
 use serde::{Deserialize, Serialize};
 
+use url::Url;
+
 use crate::{
-  daemon::states::{SharedAppState, host_session::ExtraInfo},
+  daemon::{
+    server::signed_url::sign_url,
+    states::{SharedAppState, host_session::ExtraInfo},
+  },
   utils::states::States as _,
 };
 
@@ -56,7 +61,7 @@ async fn get_by_host(
 ) -> (StatusCode, Json<GetUrlSubResponse>) {
   if let Some(info) = app.host_session.get_arc(&params.host).map(|s| s.extra.clone()) {
     let mut url = info.controller_url.clone();
-    let success = if params.https.unwrap_or(false) {
+    let success = if https_requested(&app, params.https) {
       if let Some(https) = app.startup_args.https_args.as_ref() {
         url.set_scheme("https").is_ok() && url.set_port(Some(https.port)).is_ok()
       } else {
@@ -74,6 +79,7 @@ async fn get_by_host(
     };
     if success {
       url.set_path(&params.path.unwrap_or("".to_string()));
+      sign_url(app.startup_args.file_url_signing.as_ref(), &mut url, Some(&params.host));
       (
         StatusCode::OK,
         Json(GetUrlSubResponse {
@@ -107,7 +113,7 @@ async fn get_by_host(
 async fn get_by_host_ip(
   State(app): State<SharedAppState>, Query(params): Query<GetUrlSubByHostParams>,
 ) -> (StatusCode, Json<GetUrlSubResponse>) {
-  let (schema, port) = if params.https.unwrap_or(false) {
+  let (schema, port) = if https_requested(&app, params.https) {
     if let Some(https) = app.startup_args.https_args.as_ref() {
       ("https", https.port)
     } else {
@@ -133,7 +139,10 @@ async fn get_by_host_ip(
           Json(GetUrlSubResponse {
             ok: true,
             error: None,
-            urls: format_urls(schema, ips, port, params.path),
+            urls: format_urls(schema, ips, port, params.path)
+              .into_iter()
+              .map(|url| sign_url_str(&app, url, Some(&params.host)))
+              .collect(),
           }),
         )
       }
@@ -164,7 +173,7 @@ async fn get_by_host_ip(
 async fn get_by_ip(
   State(app): State<SharedAppState>, Query(params): Query<GetUrlSubByIpParams>,
 ) -> (StatusCode, Json<GetUrlSubResponse>) {
-  let (schema, port) = if params.https.unwrap_or(false) {
+  let (schema, port) = if https_requested(&app, params.https) {
     if let Some(https) = app.startup_args.https_args.as_ref() {
       ("https", https.port)
     } else {
@@ -212,7 +221,10 @@ async fn get_by_ip(
     Json(GetUrlSubResponse {
       ok: true,
       error: None,
-      urls: format_urls(schema, ips, port, params.path),
+      urls: format_urls(schema, ips, port, params.path)
+        .into_iter()
+        .map(|url| sign_url_str(&app, url, None))
+        .collect(),
     }),
   )
 }
@@ -259,6 +271,23 @@ async fn get_remote_ip_by_host_ip(
   }
 }
 
+/// Whether to build HTTPS URLs: if asked for, and always if the controller only serves files over TLS.
+fn https_requested(app: &SharedAppState, https: Option<bool>) -> bool {
+  app.startup_args.require_tls || https.unwrap_or(false)
+}
+
+/// Sign a URL built by [`format_urls`] if file URLs require a signature.
+fn sign_url_str(app: &SharedAppState, url: String, host: Option<&str>) -> String {
+  let signing = app.startup_args.file_url_signing.as_ref();
+  match Url::parse(&url) {
+    Ok(mut parsed) if signing.is_some() => {
+      sign_url(signing, &mut parsed, host);
+      parsed.to_string()
+    }
+    _ => url,
+  }
+}
+
 #[inline]
 fn format_urls(schema: &str, ips: Vec<u32>, port: u16, path: Option<String>) -> Vec<String> {
   let path = path
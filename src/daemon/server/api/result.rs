@@ -1,15 +1,20 @@
-use crate::{protocol::messaging::AgentResponse, utils::states::States};
+use crate::{
+  protocol::messaging::{AgentResponse, TransferProgress},
+  utils::states::States,
+};
 use axum::{
-  Json, Router,
+  Extension, Json, Router,
   extract::{Query, State},
   http::StatusCode,
   routing::method_routing,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::daemon::states::SharedAppState;
+use crate::daemon::{api_token::Caller, states::SharedAppState};
 
-use super::{ERR_REASON_SESSION_NOT_FOUND, ERR_REASON_TASK_NOT_COMPLETED, ERR_REASON_TASK_NOT_FOUND};
+use super::{
+  ERR_REASON_HOST_NOT_PERMITTED, ERR_REASON_SESSION_NOT_FOUND, ERR_REASON_TASK_NOT_COMPLETED, ERR_REASON_TASK_NOT_FOUND,
+};
 
 #[derive(Deserialize)]
 struct GetParams {
@@ -22,9 +27,25 @@ struct GetResponse {
   ok: bool,
   payload: Option<AgentResponse>,
   reason: Option<String>,
+  /// Latest progress reported by a task that has not completed yet
+  progress: Option<TransferProgress>,
 }
 
-async fn get(State(app): State<SharedAppState>, params: Query<GetParams>) -> (StatusCode, Json<GetResponse>) {
+async fn get(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, params: Query<GetParams>,
+) -> (StatusCode, Json<GetResponse>) {
+  if !caller.may_target(&params.host) {
+    return (
+      StatusCode::FORBIDDEN,
+      Json(GetResponse {
+        ok: false,
+        payload: None,
+        reason: Some(ERR_REASON_HOST_NOT_PERMITTED.to_string()),
+        progress: None,
+      }),
+    );
+  }
+
   let Some(session) = app.host_session.get_arc(&params.host) else {
     return (
       StatusCode::NOT_FOUND,
@@ -32,17 +53,19 @@ async fn get(State(app): State<SharedAppState>, params: Query<GetParams>) -> (St
         ok: false,
         payload: None,
         reason: Some(ERR_REASON_SESSION_NOT_FOUND.to_string()),
+        progress: None,
       }),
     );
   };
 
-  let Some(task) = session.tasks.take_if(params.task_id, |v| v.is_some()) else {
+  let Some(task) = session.tasks.take_if(params.task_id, |v| v.as_ref().is_some_and(|r| !r.status.is_partial())) else {
     return (
       StatusCode::NOT_FOUND,
       Json(GetResponse {
         ok: false,
         payload: None,
         reason: Some(ERR_REASON_TASK_NOT_FOUND.to_string()),
+        progress: None,
       }),
     );
   };
@@ -54,16 +77,30 @@ async fn get(State(app): State<SharedAppState>, params: Query<GetParams>) -> (St
         ok: false,
         payload: None,
         reason: Some(ERR_REASON_TASK_NOT_COMPLETED.to_string()),
+        progress: None,
       }),
     );
   };
 
+  if resp.status.is_partial() {
+    return (
+      StatusCode::NOT_FOUND,
+      Json(GetResponse {
+        ok: false,
+        payload: None,
+        reason: Some(ERR_REASON_TASK_NOT_COMPLETED.to_string()),
+        progress: resp.progress().cloned(),
+      }),
+    );
+  }
+
   (
     StatusCode::OK,
     Json(GetResponse {
       ok: true,
       payload: Some(resp.clone()),
       reason: None,
+      progress: None,
     }),
   )
 }
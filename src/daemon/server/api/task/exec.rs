@@ -1,8 +1,8 @@
 use crate::protocol::messaging::CommandExecutionRequest;
-use axum::{Json, Router, extract::State, http::StatusCode, routing::method_routing};
+use axum::{Extension, Json, Router, extract::State, http::StatusCode, routing::method_routing};
 use serde::Deserialize;
 
-use crate::daemon::states::SharedAppState;
+use crate::daemon::{api_token::Caller, states::SharedAppState};
 
 use super::utils::{SendReqResponse, send_req_helper};
 
@@ -16,10 +16,11 @@ struct PostRequest {
 }
 
 async fn post(
-  State(app): State<SharedAppState>, Json(params): Json<PostRequest>,
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<PostRequest>,
 ) -> (StatusCode, Json<SendReqResponse>) {
   send_req_helper(
     app,
+    &caller,
     params.host,
     CommandExecutionRequest {
       command: params.cmd,
@@ -1,41 +1,192 @@
-use crate::protocol::messaging::{FileDownloadParams, FileUploadParams};
-use axum::{Json, Router, extract::State, http::StatusCode, routing::method_routing};
+use std::path::Path;
+
+use crate::{
+  daemon::server::{
+    api::{ERR_REASON_INTERNAL_ERROR, ERR_REASON_SESSION_NOT_FOUND, ERR_REASON_UPLOADS_DISABLED},
+    signed_url::sign_url,
+  },
+  protocol::messaging::{DirDownloadParams, DirUploadParams, FileDownloadParams, FileUploadParams, ImageWriteParams},
+  utils::{compression::Compression, hash::HashAlgorithm, states::States as _},
+};
+use axum::{Extension, Json, Router, extract::State, http::StatusCode, routing::method_routing};
 use serde::Deserialize;
+use url::{Host, Url};
+
+use crate::daemon::{api_token::Caller, states::SharedAppState};
 
-use crate::daemon::states::SharedAppState;
+use super::utils::{SendReqResponse, reject_req, send_req_helper};
 
-use super::utils::{SendReqResponse, send_req_helper};
+const ERR_REASON_MISSING_URL: &str = "MISSING_URL";
+const ERR_REASON_FILE_NOT_FOUND: &str = "FILE_NOT_FOUND";
+const ERR_REASON_LINK_LOCAL_SESSION: &str = "LINK_LOCAL_SESSION";
 
 #[derive(Deserialize)]
 #[serde(rename_all = "lowercase")]
 enum FileOperation {
   Download,
   Upload,
+  /// Stream a possibly compressed disk image to a block device or file
+  Image,
+  /// Extract a tar stream into a directory
+  DownloadDir,
+  /// Pack a directory as a tar stream and upload it
+  UploadDir,
 }
 
 #[derive(Deserialize)]
 struct PostRequest {
-  url: String,
+  /// Source or destination URL. Uploads without one go to the upload directory of this daemon, downloads without one
+  /// fetch the published file matching `expected_hash`.
+  url: Option<String>,
   path: String,
   host: String,
   op: FileOperation,
+  expected_hash: Option<String>,
+  hash_algorithm: Option<HashAlgorithm>,
+  resume: Option<bool>,
+  segments: Option<u32>,
+  skip_existing: Option<bool>,
+  compression: Option<Compression>,
+  preserve_owner: Option<bool>,
+}
+
+type Rejection = (StatusCode, &'static str);
+
+/// Base URL of this daemon as reachable by `host`: the address and port the agent connected to, over HTTPS if it
+/// connected over TLS.
+///
+/// Agents connected through a link-local address cannot transfer files, URLs cannot carry the interface the address
+/// is scoped to.
+fn controller_url(app: &SharedAppState, host: &str) -> Result<Url, Rejection> {
+  let Some(info) = app.host_session.get_arc(&host.to_string()).map(|s| s.extra.clone()) else {
+    return Err((StatusCode::NOT_FOUND, ERR_REASON_SESSION_NOT_FOUND));
+  };
+  if matches!(info.controller_url.host(), Some(Host::Ipv6(ip)) if ip.is_unicast_link_local()) {
+    return Err((StatusCode::BAD_REQUEST, ERR_REASON_LINK_LOCAL_SESSION));
+  }
+  let mut url = info.controller_url.clone();
+  let scheme = if info.socket_info.tls { "https" } else { "http" };
+  if url.set_scheme(scheme).is_err() {
+    return Err((StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR));
+  }
+  url.set_path("");
+  url.set_query(None);
+  Ok(url)
+}
+
+/// Build a single-use URL for `host` to upload `name` into the upload directory of this daemon.
+fn upload_url(app: &SharedAppState, host: &str, name: &str) -> Result<String, Rejection> {
+  if app.startup_args.upload_dir.is_none() {
+    return Err((StatusCode::BAD_REQUEST, ERR_REASON_UPLOADS_DISABLED));
+  }
+  let mut url = controller_url(app, host)?;
+  url
+    .path_segments_mut()
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR))?
+    .extend(["files", "upload", host, name]);
+  url.query_pairs_mut().append_pair("token", &app.upload_grants.grant(host, name));
+  Ok(url.to_string())
+}
+
+/// Build a URL for `host` to download the published file with the given hash.
+async fn by_hash_url(
+  app: &SharedAppState, host: &str, algorithm: HashAlgorithm, digest: &str,
+) -> Result<String, Rejection> {
+  if app.file_map.find_by_hash(algorithm, digest, &[]).await.is_none() {
+    return Err((StatusCode::NOT_FOUND, ERR_REASON_FILE_NOT_FOUND));
+  }
+  let mut url = controller_url(app, host)?;
+  url
+    .path_segments_mut()
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR))?
+    .extend(["files", "by-hash", algorithm.name(), digest]);
+  sign_url(Some(&app.startup_args.by_hash_signing), &mut url, Some(host));
+  Ok(url.to_string())
 }
 
 async fn post(
-  State(app): State<SharedAppState>, Json(params): Json<PostRequest>,
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(mut params): Json<PostRequest>,
 ) -> (StatusCode, Json<SendReqResponse>) {
+  let url = match (params.url.take(), &params.op) {
+    (Some(url), _) => url,
+    (None, FileOperation::Upload | FileOperation::UploadDir) => {
+      let base = Path::new(&params.path).file_name().map(|n| n.to_string_lossy().to_string());
+      let name = match (base, &params.op) {
+        (Some(base), FileOperation::UploadDir) => {
+          format!("{base}.{}", params.compression.unwrap_or_default().tar_extension())
+        }
+        (Some(base), _) => base,
+        (None, _) => {
+          return reject_req(
+            &app,
+            &caller,
+            &params.host,
+            StatusCode::BAD_REQUEST,
+            ERR_REASON_MISSING_URL,
+          );
+        }
+      };
+      match upload_url(&app, &params.host, &name) {
+        Ok(url) => url,
+        Err((status, reason)) => return reject_req(&app, &caller, &params.host, status, reason),
+      }
+    }
+    (None, FileOperation::Download | FileOperation::Image) if params.expected_hash.is_some() => {
+      let digest = params.expected_hash.as_deref().unwrap_or_default();
+      match by_hash_url(&app, &params.host, params.hash_algorithm.unwrap_or_default(), digest).await {
+        Ok(url) => url,
+        Err((status, reason)) => return reject_req(&app, &caller, &params.host, status, reason),
+      }
+    }
+    (None, _) => {
+      return reject_req(
+        &app,
+        &caller,
+        &params.host,
+        StatusCode::BAD_REQUEST,
+        ERR_REASON_MISSING_URL,
+      );
+    }
+  };
   send_req_helper(
     app,
+    &caller,
     params.host,
     match params.op {
       FileOperation::Download => FileDownloadParams {
-        src_url: params.url,
+        src_url: url,
+        dest_path: params.path,
+        expected_hash: params.expected_hash,
+        hash_algorithm: params.hash_algorithm,
+        resume: params.resume,
+        segments: params.segments,
+        skip_existing: params.skip_existing,
+      }
+      .into(),
+      FileOperation::Image => ImageWriteParams {
+        src_url: url,
+        dest_path: params.path,
+        compression: params.compression,
+        expected_hash: params.expected_hash,
+        hash_algorithm: params.hash_algorithm,
+      }
+      .into(),
+      FileOperation::DownloadDir => DirDownloadParams {
+        src_url: url,
         dest_path: params.path,
+        compression: params.compression,
+        preserve_owner: params.preserve_owner,
+      }
+      .into(),
+      FileOperation::UploadDir => DirUploadParams {
+        src_path: params.path,
+        dest_url: url,
+        compression: params.compression,
       }
       .into(),
       FileOperation::Upload => FileUploadParams {
         src_path: params.path,
-        dest_url: params.url,
+        dest_url: url,
       }
       .into(),
     },
@@ -1,8 +1,8 @@
 use crate::protocol::messaging::ScriptEvalRequest;
-use axum::{Json, Router, extract::State, http::StatusCode, routing::method_routing};
+use axum::{Extension, Json, Router, extract::State, http::StatusCode, routing::method_routing};
 use serde::Deserialize;
 
-use crate::daemon::states::SharedAppState;
+use crate::daemon::{api_token::Caller, states::SharedAppState};
 
 use super::utils::{SendReqResponse, send_req_helper};
 
@@ -13,9 +13,15 @@ struct PostRequest {
 }
 
 async fn post(
-  State(app): State<SharedAppState>, Json(params): Json<PostRequest>,
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Json(params): Json<PostRequest>,
 ) -> (StatusCode, Json<SendReqResponse>) {
-  send_req_helper(app, params.host, ScriptEvalRequest { script: params.script }.into()).await
+  send_req_helper(
+    app,
+    &caller,
+    params.host,
+    ScriptEvalRequest { script: params.script }.into(),
+  )
+  .await
 }
 
 pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
@@ -1,12 +1,14 @@
 use crate::{
   daemon::{
-    server::api::{ERR_REASON_INTERNAL_ERROR, ERR_REASON_SESSION_NOT_FOUND},
+    api_token::Caller,
+    audit::{AuditEvent, AuditRecord},
+    server::api::{ERR_REASON_HOST_NOT_PERMITTED, ERR_REASON_INTERNAL_ERROR, ERR_REASON_SESSION_NOT_FOUND},
     states::{SharedAppState, host_session::HostSessionStorageExt as _},
   },
   protocol::messaging::ControllerRequestPayload,
 };
 use axum::{Json, http::StatusCode};
-use log::error;
+use log::{error, info, warn};
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -17,38 +19,70 @@ pub(super) struct SendReqResponse {
 }
 
 pub(super) async fn send_req_helper(
-  app: SharedAppState, host: String, req: ControllerRequestPayload,
+  app: SharedAppState, caller: &Caller, host: String, req: ControllerRequestPayload,
 ) -> (StatusCode, Json<SendReqResponse>) {
+  let audited = app.audit_log.is_some().then(|| req.clone());
+  if !caller.may_target(&host) {
+    warn!("Rejected task for {host} by {caller}, which may not target it");
+    audit_rejected(&app, caller, &host, ERR_REASON_HOST_NOT_PERMITTED, audited);
+    return failed(StatusCode::FORBIDDEN, ERR_REASON_HOST_NOT_PERMITTED);
+  }
   if let Some(r) = app.host_session.send_request(&host, req).await {
     match r {
-      Ok(req_id) => (
-        StatusCode::OK,
-        Json(SendReqResponse {
-          ok: true,
-          task_id: Some(req_id),
-          reason: None,
-        }),
-      ),
-      Err(e) => {
-        error!("Failed to pass internal message to host session: {} {:?}", &host, e);
+      Ok(req_id) => {
+        info!("Sent task {req_id} to {host} for {caller}");
+        if let Some(payload) = audited {
+          app.audit(AuditRecord::new(AuditEvent::TaskSent { payload }).by(caller).host(&host).task(req_id));
+        }
         (
-          StatusCode::INTERNAL_SERVER_ERROR,
+          StatusCode::OK,
           Json(SendReqResponse {
-            ok: false,
-            task_id: None,
-            reason: Some(ERR_REASON_INTERNAL_ERROR.to_string()),
+            ok: true,
+            task_id: Some(req_id),
+            reason: None,
           }),
         )
       }
+      Err(e) => {
+        error!("Failed to pass internal message to host session: {} {:?}", &host, e);
+        audit_rejected(&app, caller, &host, ERR_REASON_INTERNAL_ERROR, audited);
+        failed(StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR)
+      }
     }
   } else {
-    (
-      StatusCode::NOT_FOUND,
-      Json(SendReqResponse {
-        ok: false,
-        task_id: None,
-        reason: Some(ERR_REASON_SESSION_NOT_FOUND.to_string()),
-      }),
-    )
+    audit_rejected(&app, caller, &host, ERR_REASON_SESSION_NOT_FOUND, audited);
+    failed(StatusCode::NOT_FOUND, ERR_REASON_SESSION_NOT_FOUND)
   }
 }
+
+/// Reject a task request for `host` before it is sent to the host.
+pub(super) fn reject_req(
+  app: &SharedAppState, caller: &Caller, host: &str, status: StatusCode, reason: &str,
+) -> (StatusCode, Json<SendReqResponse>) {
+  audit_rejected(app, caller, host, reason, None);
+  failed(status, reason)
+}
+
+fn audit_rejected(
+  app: &SharedAppState, caller: &Caller, host: &str, reason: &str, payload: Option<ControllerRequestPayload>,
+) {
+  app.audit(
+    AuditRecord::new(AuditEvent::TaskRejected {
+      reason: reason.to_string(),
+      payload,
+    })
+    .by(caller)
+    .host(host),
+  );
+}
+
+fn failed(status: StatusCode, reason: &str) -> (StatusCode, Json<SendReqResponse>) {
+  (
+    status,
+    Json(SendReqResponse {
+      ok: false,
+      task_id: None,
+      reason: Some(reason.to_string()),
+    }),
+  )
+}
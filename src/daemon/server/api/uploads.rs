@@ -0,0 +1,162 @@
+use std::{
+  path::{Path, PathBuf},
+  time::UNIX_EPOCH,
+};
+
+use axum::{
+  Extension, Json, Router,
+  extract::{Query, Request, State},
+  http::StatusCode,
+  response::{IntoResponse, Response},
+  routing::get,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  daemon::{
+    api_token::Caller,
+    server::files::gen_file_response,
+    states::{SharedAppState, upload::join_below},
+  },
+  utils::fs::is_temp_path,
+};
+
+use super::{
+  ERR_REASON_HOST_NOT_PERMITTED, ERR_REASON_INTERNAL_ERROR, ERR_REASON_INVALID_PATH, ERR_REASON_UPLOADS_DISABLED,
+};
+
+pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
+  Router::new().with_state(app).route("/", get(get_uploads)).route("/file", get(get_upload_file))
+}
+
+#[derive(Deserialize)]
+struct GetUploadsParams {
+  host: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UploadEntry {
+  host: String,
+  /// Path relative to the host's upload directory
+  path: String,
+  size: u64,
+  /// Seconds since the Unix epoch
+  modified: u64,
+}
+
+#[derive(Serialize)]
+struct GetUploadsResponse {
+  ok: bool,
+  uploads: Vec<UploadEntry>,
+  reason: Option<String>,
+}
+
+impl GetUploadsResponse {
+  fn failed(reason: &str) -> Json<Self> {
+    Json(GetUploadsResponse {
+      ok: false,
+      uploads: vec![],
+      reason: Some(reason.to_string()),
+    })
+  }
+}
+
+/// List the files received from agents, optionally only those of one host. Only hosts the caller may target are
+/// listed.
+async fn get_uploads(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Query(params): Query<GetUploadsParams>,
+) -> Json<GetUploadsResponse> {
+  let Some(upload_dir) = app.startup_args.upload_dir.clone() else {
+    return GetUploadsResponse::failed(ERR_REASON_UPLOADS_DISABLED);
+  };
+  let upload_dir = PathBuf::from(upload_dir);
+  let dirs = match &params.host {
+    Some(host) if !caller.may_target(host) => return GetUploadsResponse::failed(ERR_REASON_HOST_NOT_PERMITTED),
+    Some(host) => match join_below(&upload_dir, host).filter(|_| !host.contains('/')) {
+      Some(dir) => vec![(host.clone(), dir)],
+      None => return GetUploadsResponse::failed(ERR_REASON_INVALID_PATH),
+    },
+    None => match std::fs::read_dir(&upload_dir) {
+      Ok(entries) => entries
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|e| (e.file_name().to_string_lossy().to_string(), e.path()))
+        .filter(|(host, _)| caller.may_target(host))
+        .collect(),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+      Err(err) => {
+        warn!("Failed to read upload directory {}: {err}", upload_dir.display());
+        return GetUploadsResponse::failed(ERR_REASON_INTERNAL_ERROR);
+      }
+    },
+  };
+  let uploads = tokio::task::spawn_blocking(move || {
+    let mut uploads = vec![];
+    for (host, dir) in dirs {
+      collect_files(&host, &dir, &dir, &mut uploads);
+    }
+    uploads
+  })
+  .await;
+  match uploads {
+    Ok(uploads) => Json(GetUploadsResponse {
+      ok: true,
+      uploads,
+      reason: None,
+    }),
+    Err(err) => {
+      warn!("Failed to list uploads: {err}");
+      GetUploadsResponse::failed(ERR_REASON_INTERNAL_ERROR)
+    }
+  }
+}
+
+fn collect_files(host: &str, root: &Path, dir: &Path, out: &mut Vec<UploadEntry>) {
+  let Ok(entries) = std::fs::read_dir(dir) else {
+    return;
+  };
+  for entry in entries.flatten() {
+    let path = entry.path();
+    let Ok(meta) = entry.metadata() else {
+      continue;
+    };
+    if meta.is_dir() {
+      collect_files(host, root, &path, out);
+    } else if meta.is_file() && !is_temp_path(&path) {
+      out.push(UploadEntry {
+        host: host.to_string(),
+        path: path.strip_prefix(root).unwrap_or(&path).to_string_lossy().to_string(),
+        size: meta.len(),
+        modified: meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs()),
+      });
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct GetUploadFileParams {
+  host: String,
+  path: String,
+}
+
+/// Download a file received from an agent.
+async fn get_upload_file(
+  State(app): State<SharedAppState>, Extension(caller): Extension<Caller>, Query(params): Query<GetUploadFileParams>,
+  req: Request,
+) -> Response {
+  let Some(upload_dir) = &app.startup_args.upload_dir else {
+    return (StatusCode::NOT_FOUND, ERR_REASON_UPLOADS_DISABLED).into_response();
+  };
+  if !caller.may_target(&params.host) {
+    return (StatusCode::FORBIDDEN, ERR_REASON_HOST_NOT_PERMITTED).into_response();
+  }
+  let path = join_below(Path::new(upload_dir), &params.host)
+    .filter(|_| !params.host.contains('/'))
+    .and_then(|dir| join_below(&dir, &params.path))
+    .filter(|path| path.is_file() && !is_temp_path(path));
+  match path {
+    Some(path) => gen_file_response(&path.to_string_lossy(), None, req).await.0,
+    None => (StatusCode::NOT_FOUND, ERR_REASON_INVALID_PATH).into_response(),
+  }
+}
@@ -5,16 +5,43 @@ use crate::{
 use log::{debug, info};
 use std::sync::Arc;
 
-use crate::daemon::states::host_session::HostSession;
+use crate::daemon::{
+  audit::{AuditEvent, AuditRecord},
+  states::{SharedAppState, host_session::HostSession},
+};
 
-pub(super) async fn handle_msg(msg: Message, session: Arc<HostSession>) {
+pub(super) async fn handle_msg(msg: Message, app: SharedAppState, session: Arc<HostSession>) {
   debug!("Received message: {msg:?}");
   if let Message::AgentResponse(response) = msg {
-    handle_resp(response, session.clone()).await;
+    handle_resp(response, app, session.clone()).await;
   }
 }
 
-async fn handle_resp(response: AgentResponse, session: Arc<HostSession>) {
-  info!("Task Completed: {} {}", session.host_id, response.id);
+async fn handle_resp(response: AgentResponse, app: SharedAppState, session: Arc<HostSession>) {
+  if response.status.is_partial() {
+    // Messages are handled concurrently, so a late partial response must not replace the final one.
+    if session
+      .tasks
+      .get_arc(&response.id)
+      .is_some_and(|task| task.as_ref().as_ref().is_some_and(|r| !r.status.is_partial()))
+    {
+      return;
+    }
+    debug!(
+      "Task Progress: {} {} {:?}",
+      session.host_id, response.id, response.status
+    );
+  } else {
+    info!("Task Completed: {} {}", session.host_id, response.id);
+    app.audit(
+      AuditRecord::new(AuditEvent::TaskCompleted {
+        status: response.status.clone(),
+        code: response.exit_code(),
+      })
+      .client(session.extra.socket_info.remote_addr)
+      .host(&session.host_id)
+      .task(response.id),
+    );
+  }
   session.tasks.insert(response.id, Some(response));
 }
@@ -0,0 +1,141 @@
+use axum::{
+  Json,
+  extract::{ConnectInfo, State},
+  http::{HeaderMap, StatusCode},
+};
+use log::{info, warn};
+
+use crate::{
+  daemon::{
+    audit::{AuditEvent, AuditRecord},
+    enrollment::Decision,
+    states::SharedAppState,
+  },
+  protocol::enrollment::{EnrollRequest, EnrollResponse, EnrollStatus},
+  utils::{cert::sign_client_csr, hash::sha2_256_for_str},
+};
+
+use super::{
+  SocketConnectInfo,
+  api::{ERR_REASON_ENROLLMENT_DISABLED, ERR_REASON_INTERNAL_ERROR},
+  utils::verified_agent_auth,
+};
+
+const ERR_REASON_UNAUTHORIZED: &str = "UNAUTHORIZED";
+const ERR_REASON_TLS_REQUIRED: &str = "TLS_REQUIRED";
+const ERR_REASON_INVALID_CSR: &str = "INVALID_CSR";
+const ERR_REASON_TOO_MANY_PENDING: &str = "TOO_MANY_PENDING";
+
+fn failed(status: StatusCode, reason: &str) -> (StatusCode, Json<EnrollResponse>) {
+  (
+    status,
+    Json(EnrollResponse {
+      ok: false,
+      status: None,
+      cert: None,
+      ca_cert: None,
+      reason: Some(reason.to_string()),
+    }),
+  )
+}
+
+fn decided(status: EnrollStatus, cert: Option<String>, ca_cert: Option<String>) -> (StatusCode, Json<EnrollResponse>) {
+  let code = match status {
+    EnrollStatus::Approved => StatusCode::OK,
+    EnrollStatus::Pending => StatusCode::ACCEPTED,
+    EnrollStatus::Rejected => StatusCode::FORBIDDEN,
+  };
+  (
+    code,
+    Json(EnrollResponse {
+      ok: status == EnrollStatus::Approved,
+      status: Some(status),
+      cert,
+      ca_cert,
+      reason: None,
+    }),
+  )
+}
+
+/// Enroll the key an agent signs its connections with.
+///
+/// Agents keep asking while their request is pending. Once approved, and if the controller requires mutual TLS, the
+/// agent's certificate signing request is answered with a client certificate. Bootstrap tokens are only accepted over
+/// TLS, a token sent over plain HTTP is discarded.
+pub(super) async fn post_enroll(
+  State(app): State<SharedAppState>, ConnectInfo(socket_info): ConnectInfo<SocketConnectInfo>, headers: HeaderMap,
+  Json(params): Json<EnrollRequest>,
+) -> (StatusCode, Json<EnrollResponse>) {
+  let Some(enrollment) = &app.enrollment else {
+    return failed(StatusCode::NOT_FOUND, ERR_REASON_ENROLLMENT_DISABLED);
+  };
+  if app.startup_args.require_tls && !socket_info.tls {
+    warn!("Rejected enrollment of {} over plain HTTP", params.host_id);
+    return failed(StatusCode::FORBIDDEN, ERR_REASON_TLS_REQUIRED);
+  }
+  if let Some(token) = &params.token &&
+    !socket_info.tls
+  {
+    warn!("Rejected bootstrap token of {} sent over plain HTTP", params.host_id);
+    enrollment.discard_token(token);
+    return failed(StatusCode::FORBIDDEN, ERR_REASON_TLS_REQUIRED);
+  }
+  let Some(auth) = verified_agent_auth(&headers).filter(|auth| params.verify(auth)) else {
+    warn!(
+      "Rejected enrollment of {} without a valid agent signature",
+      params.host_id
+    );
+    return failed(StatusCode::UNAUTHORIZED, ERR_REASON_UNAUTHORIZED);
+  };
+  let pubkey = auth.encoded_pubkey();
+  let host_id = &params.host_id;
+  let was_enrolled = enrollment.is_enrolled(host_id, &pubkey);
+  let decision = match enrollment.request(host_id, &pubkey, params.token.as_deref(), socket_info.remote_addr) {
+    Ok(decision) => decision,
+    Err(err) => {
+      warn!("Failed to enroll {host_id}: {err}");
+      return failed(StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR);
+    }
+  };
+  match decision {
+    Decision::Pending => {
+      info!(
+        "Enrollment of {host_id} with key {} from {:?} is waiting for approval",
+        sha2_256_for_str(&pubkey).unwrap_or_default(),
+        socket_info.remote_addr
+      );
+      return decided(EnrollStatus::Pending, None, None);
+    }
+    Decision::Rejected => return decided(EnrollStatus::Rejected, None, None),
+    Decision::QueueFull => {
+      warn!("Rejected enrollment of {host_id}, too many requests are waiting for approval");
+      return failed(StatusCode::SERVICE_UNAVAILABLE, ERR_REASON_TOO_MANY_PENDING);
+    }
+    Decision::Approved if !was_enrolled => {
+      info!("Enrolled {host_id} with a bootstrap token");
+      app.audit(
+        AuditRecord::new(AuditEvent::AgentEnrolled {
+          pubkey: pubkey.clone(),
+          auto: true,
+        })
+        .client(socket_info.remote_addr)
+        .host(host_id),
+      );
+    }
+    Decision::Approved => {}
+  }
+  let (Some(ca), Some(csr)) = (app.startup_args.client_ca(), &params.csr) else {
+    return decided(EnrollStatus::Approved, None, None);
+  };
+  match sign_client_csr(&ca.cert, &ca.key, host_id, csr, ca.validity) {
+    Ok(cert) => {
+      info!("Issued client certificate for enrolled agent {host_id}");
+      app.audit(AuditRecord::new(AuditEvent::AgentCertIssued).client(socket_info.remote_addr).host(host_id));
+      decided(EnrollStatus::Approved, Some(cert), Some(ca.cert.clone()))
+    }
+    Err(err) => {
+      warn!("Failed to sign certificate request of {host_id}: {err}");
+      failed(StatusCode::BAD_REQUEST, ERR_REASON_INVALID_CSR)
+    }
+  }
+}
@@ -1,4 +1,10 @@
-use std::fs::metadata;
+use std::{
+  collections::HashMap,
+  fs::{Metadata, metadata},
+  io::SeekFrom,
+  ops::RangeInclusive,
+  time::UNIX_EPOCH,
+};
 
 use axum::{
   Json, Router,
@@ -6,10 +12,13 @@ use axum::{
   extract::{Query, Request, State},
   http::{HeaderMap, HeaderValue, StatusCode, header},
   response::{IntoResponse, Response},
-  routing::get,
+  routing::{get, put},
 };
+use bytes::Bytes;
+use futures_util::{StreamExt as _, TryStreamExt as _, future, stream};
+use http_range_header::{EndPosition, ParsedRanges, StartPosition, parse_range_header};
 use httpdate::HttpDate;
-use log::{debug, error, warn};
+use log::{debug, error};
 use serde::Deserialize;
 use tokio::{
   fs::File,
@@ -17,14 +26,33 @@ use tokio::{
 };
 use tokio_util::io::ReaderStream;
 
-use crate::daemon::states::{SharedAppState, file_map::FileMap};
+use crate::{
+  daemon::states::{SharedAppState, file_map::FileMap},
+  utils::{archive::pack_dir, compression::Compression, hash::HashAlgorithm, util::random_str},
+};
 use axum::extract::Path;
 
+use super::{signed_url::signature_middleware, upload::put_upload, utils::tls_middleware};
+
 pub(super) fn build(app: SharedAppState) -> Router<SharedAppState> {
-  Router::new()
+  let downloads = Router::new()
     .with_state(app.clone())
     .route("/_/{dir}/{*path}", get(get_dir_child).head(head_dir_child))
-    .route("/{name}", get(get_file).head(head_file))
+    .route("/_tar/{dir}", get(get_dir_tar))
+    .route("/{name}", get(get_file).head(head_file));
+  // URLs by hash are only handed out through the API, so they are always signed
+  let by_hash = Router::new().route(
+    "/by-hash/{algo}/{digest}",
+    get(get_file_by_hash).head(head_file_by_hash),
+  );
+  let files = signature_middleware(downloads, app.startup_args.file_url_signing.clone(), app.clone())
+    .merge(signature_middleware(
+      by_hash,
+      Some(app.startup_args.by_hash_signing.clone()),
+      app.clone(),
+    ))
+    .route("/upload/{host}/{*path}", put(put_upload).post(put_upload));
+  tls_middleware(files, app.startup_args.require_tls)
 }
 
 macro_rules! add_header {
@@ -33,127 +61,337 @@ macro_rules! add_header {
   };
 }
 
-#[derive(Deserialize)]
-struct GetFileParams {
-  xxh3: Option<bool>,
-  md5: Option<bool>,
-  sha1: Option<bool>,
-  sha256: Option<bool>,
-  sha512: Option<bool>,
+/// Hash algorithms requested with query parameters like `?sha256=true`.
+fn requested_hashes(params: &HashMap<String, String>) -> Vec<HashAlgorithm> {
+  params
+    .iter()
+    .filter(|(_, enabled)| enabled.parse().unwrap_or(false))
+    .filter_map(|(name, _)| name.parse().ok())
+    .collect()
 }
 
-async fn gen_file_response(file_path: &str, req: Request) -> (Response, bool) {
-  match File::open(file_path).await {
-    Ok(file) => match file.metadata().await {
-      Ok(meta) => {
-        let mut builder = Response::builder();
-        let headers = builder.headers_mut();
-        if headers.is_none() {
-          return (StatusCode::INTERNAL_SERVER_ERROR.into_response(), false);
-        }
-        let headers = headers.unwrap();
-        add_header!(headers, header::CONTENT_TYPE, "application/octet-stream");
-        add_header!(headers, header::ACCEPT_RANGES, "bytes");
-        add_header!(
-          headers,
-          header::LAST_MODIFIED,
-          HttpDate::from(meta.modified().unwrap()).to_string()
-        );
-        let range = req
-          .headers()
-          .get(header::RANGE)
-          .and_then(|v| v.to_str().ok())
-          .map(|v| http_range_header::parse_range_header(v).and_then(|v| v.validate(meta.len())));
-        match range {
-          Some(Ok(range)) => {
-            debug!("Range header: {range:?}");
-            if range.len() > 1 {
-              warn!("Range header contains multiple ranges: {range:?}");
-              return (StatusCode::IM_A_TEAPOT.into_response(), false); // FIXME: Not supported range
-            }
-            let start = *range[0].start();
-            let end = *range[0].end();
-            let len = end - start + 1;
-            let builder = builder
-              .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, meta.len()))
-              .header(header::CONTENT_LENGTH, len.to_string())
-              .status(StatusCode::PARTIAL_CONTENT);
-            let mut file = file;
-            file.seek(std::io::SeekFrom::Start(start)).await.unwrap();
-            let stream2 = ReaderStream::with_capacity(file.take(len), 64 * 1024);
-            match builder.body(Body::from_stream(stream2)) {
-              Ok(response) => (response, true),
-              Err(err) => (
-                (
-                  StatusCode::INTERNAL_SERVER_ERROR,
-                  Json(format!("Failed to create response: {err}")),
-                )
-                  .into_response(),
-                false,
-              ),
-            }
-          }
-          Some(Err(err)) => (
-            (
-              StatusCode::RANGE_NOT_SATISFIABLE,
-              Json(format!("Invalid range header: {err}")),
-            )
-              .into_response(),
-            false,
-          ),
-          None => {
-            add_header!(headers, header::CONTENT_LENGTH, meta.len().to_string());
-            match builder.body(Body::from_stream(ReaderStream::new(file))) {
-              Ok(response) => (response, true),
-              Err(err) => (
-                (
-                  StatusCode::INTERNAL_SERVER_ERROR,
-                  Json(format!("Failed to create response: {err}")),
-                )
-                  .into_response(),
-                false,
-              ),
-            }
-          }
-        }
+/// Get a published file with the requested hashes.
+///
+/// A file whose xxh3 is not cached yet, because it was just published or changed, is served with a weak `ETag` and
+/// hashed in the background, so that later requests get a strong one.
+async fn published_file(app: &SharedAppState, name: &String, params: &HashMap<String, String>) -> Option<FileMap> {
+  let map = app.file_map.get_file_with_optional_props(name, &requested_hashes(params)).await?;
+  if map.hash(HashAlgorithm::Xxh3).is_none() {
+    let (app, name) = (app.clone(), name.clone());
+    tokio::spawn(async move { app.file_map.precompute_hashes(&name).await });
+  }
+  Some(map)
+}
+
+/// Most ranges answered in one response. Requests for more get the whole file instead.
+const MAX_RANGES: usize = 16;
+
+/// Resolve the ranges of a `Range` header against a file of `size` bytes.
+///
+/// Ranges that start past the end of the file are dropped, the others are sorted and merged where they overlap or
+/// are adjacent, so that no byte is sent twice.
+fn resolve_ranges(parsed: &ParsedRanges, size: u64) -> Result<Vec<RangeInclusive<u64>>, String> {
+  let mut ranges = Vec::with_capacity(parsed.ranges.len());
+  for range in &parsed.ranges {
+    let start = match range.start {
+      StartPosition::Index(i) => i,
+      StartPosition::FromLast(i) => size.saturating_sub(i),
+    };
+    let end = match range.end {
+      EndPosition::Index(i) if i < start => return Err(format!("range {start}-{i} is reversed")),
+      EndPosition::Index(i) => i.min(size.saturating_sub(1)),
+      EndPosition::LastByte => size.saturating_sub(1),
+    };
+    if start < size {
+      ranges.push(start..=end);
+    }
+  }
+  ranges.sort_by_key(|range| *range.start());
+  let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+  for range in ranges {
+    match merged.last_mut() {
+      Some(last) if *range.start() <= *last.end() + 1 => *last = *last.start()..=*last.end().max(range.end()),
+      _ => merged.push(range),
+    }
+  }
+  if merged.is_empty() {
+    return Err(format!("no range starts within the {size} bytes of the file"));
+  }
+  Ok(merged)
+}
+
+/// Validators of a file, used to answer conditional requests.
+struct Validators {
+  etag: String,
+  last_modified: HttpDate,
+}
+
+impl Validators {
+  /// Use the cached xxh3 as a strong ETag if there is one, otherwise a weak ETag from size and mtime.
+  fn new(meta: &Metadata, xxh3: Option<&str>) -> Self {
+    let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+    let etag = match xxh3 {
+      Some(hash) => format!("\"{hash}\""),
+      None => format!(
+        "W/\"{:x}-{:x}\"",
+        meta.len(),
+        modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+      ),
+    };
+    Validators {
+      etag,
+      last_modified: HttpDate::from(modified),
+    }
+  }
+
+  fn is_strong(&self) -> bool { !self.etag.starts_with("W/") }
+
+  /// Whether the client's copy is still current, according to `If-None-Match` or else `If-Modified-Since`.
+  fn not_modified(&self, headers: &HeaderMap) -> bool {
+    if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+      return tags
+        .to_str()
+        .map(|tags| tags.split(',').map(str::trim).any(|tag| tag == "*" || weak_eq(tag, &self.etag)))
+        .unwrap_or(false);
+    }
+    headers
+      .get(header::IF_MODIFIED_SINCE)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.parse::<HttpDate>().ok())
+      .is_some_and(|since| self.last_modified <= since)
+  }
+
+  /// Whether a `Range` header should be honored, according to `If-Range`.
+  fn range_applies(&self, headers: &HeaderMap) -> bool {
+    let Some(if_range) = headers.get(header::IF_RANGE) else {
+      return true;
+    };
+    let Ok(if_range) = if_range.to_str().map(str::trim) else {
+      return false;
+    };
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+      self.is_strong() && if_range == self.etag
+    } else {
+      if_range.parse::<HttpDate>().is_ok_and(|date| date == self.last_modified)
+    }
+  }
+}
+
+#[inline]
+fn weak_eq(a: &str, b: &str) -> bool { a.trim_start_matches("W/") == b.trim_start_matches("W/") }
+
+#[inline]
+fn finish_response(r: Result<Response, http::Error>) -> (Response, bool) {
+  match r {
+    Ok(response) => (response, true),
+    Err(err) => (
+      (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(format!("Failed to create response: {err}")),
+      )
+        .into_response(),
+      false,
+    ),
+  }
+}
+
+/// Build a `multipart/byteranges` body for several ranges of a file.
+///
+/// Returns the exact length of the body along with it.
+fn multipart_body(file_path: &str, ranges: Vec<RangeInclusive<u64>>, size: u64, boundary: &str) -> (u64, Body) {
+  let mut length = 0u64;
+  let mut parts = Vec::with_capacity(ranges.len());
+  for range in ranges {
+    let (start, end) = (*range.start(), *range.end());
+    let head = format!(
+      "\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {start}-{end}/{size}\r\n\r\n"
+    );
+    length += head.len() as u64 + end - start + 1;
+    parts.push((Bytes::from(head), start, end - start + 1));
+  }
+  let tail = format!("\r\n--{boundary}--\r\n");
+  length += tail.len() as u64;
+
+  let file_path = file_path.to_string();
+  let stream = stream::iter(parts)
+    .then(move |(head, start, len)| {
+      let file_path = file_path.clone();
+      async move {
+        let mut file = File::open(&file_path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok::<_, std::io::Error>(
+          stream::once(future::ready(Ok(head))).chain(ReaderStream::with_capacity(file.take(len), 64 * 1024)),
+        )
       }
-      Err(err) => (
+    })
+    .try_flatten()
+    .chain(stream::once(future::ready(Ok(Bytes::from(tail)))));
+  (length, Body::from_stream(stream))
+}
+
+pub(super) async fn gen_file_response(file_path: &str, xxh3: Option<&str>, req: Request) -> (Response, bool) {
+  let mut file = match File::open(file_path).await {
+    Ok(file) => file,
+    Err(err) => {
+      return (
+        (
+          StatusCode::INTERNAL_SERVER_ERROR,
+          Json(format!("Failed to open file: {err}")),
+        )
+          .into_response(),
+        false,
+      );
+    }
+  };
+  let meta = match file.metadata().await {
+    Ok(meta) => meta,
+    Err(err) => {
+      return (
         (
           StatusCode::INTERNAL_SERVER_ERROR,
           Json(format!("Failed to get file metadata: {err}")),
         )
           .into_response(),
         false,
-      ),
-    },
-    Err(err) => (
-      (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Json(format!("Failed to open file: {err}")),
+      );
+    }
+  };
+  let validators = Validators::new(&meta, xxh3);
+  let builder = Response::builder()
+    .header(header::ACCEPT_RANGES, "bytes")
+    .header(header::LAST_MODIFIED, validators.last_modified.to_string())
+    .header(header::ETAG, &validators.etag);
+  if validators.not_modified(req.headers()) {
+    debug!("File not modified: {file_path}");
+    return finish_response(builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()));
+  }
+
+  let range = if validators.range_applies(req.headers()) {
+    req
+      .headers()
+      .get(header::RANGE)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| match parse_range_header(v) {
+        Ok(parsed) if parsed.ranges.len() > MAX_RANGES => {
+          debug!("{} ranges requested, sending the whole file", parsed.ranges.len());
+          None
+        }
+        Ok(parsed) => Some(resolve_ranges(&parsed, meta.len())),
+        Err(err) => Some(Err(err.to_string())),
+      })
+  } else {
+    debug!("If-Range does not match, ignoring Range header");
+    None
+  };
+  match range {
+    Some(Ok(ranges)) if ranges.len() == 1 => {
+      debug!("Range header: {ranges:?}");
+      let start = *ranges[0].start();
+      let end = *ranges[0].end();
+      let len = end - start + 1;
+      let builder = builder
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, meta.len()))
+        .header(header::CONTENT_LENGTH, len.to_string())
+        .status(StatusCode::PARTIAL_CONTENT);
+      if let Err(err) = file.seek(SeekFrom::Start(start)).await {
+        error!("Failed to seek file: {err}");
+        return (StatusCode::INTERNAL_SERVER_ERROR.into_response(), false);
+      }
+      let stream = ReaderStream::with_capacity(file.take(len), 64 * 1024);
+      finish_response(builder.body(Body::from_stream(stream)))
+    }
+    Some(Ok(ranges)) => {
+      debug!("Multiple ranges: {ranges:?}");
+      let boundary = random_str(24);
+      let (len, body) = multipart_body(file_path, ranges, meta.len(), &boundary);
+      finish_response(
+        builder
+          .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={boundary}"),
+          )
+          .header(header::CONTENT_LENGTH, len.to_string())
+          .status(StatusCode::PARTIAL_CONTENT)
+          .body(body),
       )
-        .into_response(),
-      false,
+    }
+    Some(Err(err)) => {
+      let mut resp = (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        Json(format!("Invalid range header: {err}")),
+      )
+        .into_response();
+      add_header!(
+        resp.headers_mut(),
+        header::CONTENT_RANGE,
+        format!("bytes */{}", meta.len())
+      );
+      (resp, false)
+    }
+    None => finish_response(
+      builder
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, meta.len().to_string())
+        .body(Body::from_stream(ReaderStream::new(file))),
     ),
   }
 }
 
 async fn get_file(
-  State(app): State<SharedAppState>, Path(name): Path<String>, Query(params): Query<GetFileParams>, req: Request,
+  State(app): State<SharedAppState>, Path(name): Path<String>, Query(params): Query<HashMap<String, String>>,
+  req: Request,
 ) -> Response {
   debug!("get file: {name}");
-  if let Some(map) = app
+  let map = published_file(&app, &name, &params).await;
+  serve_file(map, req).await
+}
+
+async fn head_file(
+  State(app): State<SharedAppState>, Path(name): Path<String>, Query(params): Query<HashMap<String, String>>,
+) -> Response {
+  debug!("head file: {name}");
+  let map = published_file(&app, &name, &params).await;
+  serve_file_head(map)
+}
+
+/// Find the published file with the hash `digest` in the algorithm named `algo`.
+async fn find_by_hash(
+  app: &SharedAppState, algo: &str, digest: &str, params: &HashMap<String, String>,
+) -> Result<FileMap, StatusCode> {
+  let algorithm: HashAlgorithm = algo.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+  app
     .file_map
-    .get_file_with_optional_props(
-      &name,
-      params.xxh3.unwrap_or(false),
-      params.md5.unwrap_or(false),
-      params.sha1.unwrap_or(false),
-      params.sha256.unwrap_or(false),
-      params.sha512.unwrap_or(false),
-    )
+    .find_by_hash(algorithm, digest, &requested_hashes(params))
     .await
-  {
-    let (mut resp, ok) = gen_file_response(&map.file_path, req).await;
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_file_by_hash(
+  State(app): State<SharedAppState>, Path((algo, digest)): Path<(String, String)>,
+  Query(params): Query<HashMap<String, String>>, req: Request,
+) -> Response {
+  debug!("get file by hash: {algo} {digest}");
+  match find_by_hash(&app, &algo, &digest, &params).await {
+    Ok(map) => serve_file(Some(map), req).await,
+    Err(status) => status.into_response(),
+  }
+}
+
+async fn head_file_by_hash(
+  State(app): State<SharedAppState>, Path((algo, digest)): Path<(String, String)>,
+  Query(params): Query<HashMap<String, String>>,
+) -> Response {
+  debug!("head file by hash: {algo} {digest}");
+  match find_by_hash(&app, &algo, &digest, &params).await {
+    Ok(map) => serve_file_head(Some(map)),
+    Err(status) => status.into_response(),
+  }
+}
+
+async fn serve_file(map: Option<FileMap>, req: Request) -> Response {
+  if let Some(map) = map {
+    let (mut resp, ok) = gen_file_response(&map.file_path, map.hash(HashAlgorithm::Xxh3), req).await;
     if ok {
       let headers = resp.headers_mut();
       apply_hash_headers(headers, map);
@@ -164,21 +402,7 @@ async fn get_file(
   }
 }
 
-async fn head_file(
-  State(app): State<SharedAppState>, Path(name): Path<String>, Query(params): Query<GetFileParams>,
-) -> Response {
-  debug!("head file: {name}");
-  let map = app
-    .file_map
-    .get_file_with_optional_props(
-      &name,
-      params.xxh3.unwrap_or(false),
-      params.md5.unwrap_or(false),
-      params.sha1.unwrap_or(false),
-      params.sha256.unwrap_or(false),
-      params.sha512.unwrap_or(false),
-    )
-    .await;
+fn serve_file_head(map: Option<FileMap>) -> Response {
   if map.is_none() {
     return StatusCode::NOT_FOUND.into_response();
   }
@@ -189,8 +413,10 @@ async fn head_file(
     return StatusCode::INTERNAL_SERVER_ERROR.into_response();
   }
   let meta = meta.unwrap();
+  let validators = Validators::new(&meta, map.hash(HashAlgorithm::Xxh3));
   let mut response = StatusCode::OK.into_response();
   let headers = response.headers_mut();
+  add_header!(headers, header::ETAG, validators.etag);
   apply_hash_headers(headers, map);
   add_header!(headers, header::CONTENT_LENGTH, meta.len().to_string());
   add_header!(headers, header::CONTENT_TYPE, "application/octet-stream");
@@ -205,20 +431,10 @@ async fn head_file(
 
 #[inline]
 fn apply_hash_headers(headers: &mut HeaderMap, map: FileMap) {
-  if let Some(hash) = map.xxh3 {
-    add_header!(headers, "X-Hash-Xxh3", hash);
-  }
-  if let Some(hash) = map.md5 {
-    add_header!(headers, "X-Hash-Md5", hash);
-  }
-  if let Some(hash) = map.sha1 {
-    add_header!(headers, "X-Hash-Sha1", hash);
-  }
-  if let Some(hash) = map.sha256 {
-    add_header!(headers, "X-Hash-Sha256", hash);
-  }
-  if let Some(hash) = map.sha512 {
-    add_header!(headers, "X-Hash-Sha512", hash);
+  for (algorithm, hash) in map.hashes {
+    for name in algorithm.header_names() {
+      add_header!(headers, *name, &hash);
+    }
   }
 }
 
@@ -235,7 +451,7 @@ async fn get_dir_child(
     return StatusCode::FORBIDDEN.into_response();
   }
   if let Some(path) = app.file_map.get_dir_child_path(&dir, &path) {
-    let (resp, _) = gen_file_response(&path, req).await;
+    let (resp, _) = gen_file_response(&path, None, req).await;
     resp
   } else {
     StatusCode::NOT_FOUND.into_response()
@@ -255,8 +471,10 @@ async fn head_dir_child(State(app): State<SharedAppState>, Path((dir, path)): Pa
       return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     }
     let meta = meta.unwrap();
+    let validators = Validators::new(&meta, None);
     let mut response = StatusCode::OK.into_response();
     let headers = response.headers_mut();
+    add_header!(headers, header::ETAG, validators.etag);
     add_header!(headers, header::CONTENT_LENGTH, meta.len().to_string());
     add_header!(headers, header::CONTENT_TYPE, "application/octet-stream");
     add_header!(headers, header::ACCEPT_RANGES, "bytes");
@@ -270,3 +488,81 @@ async fn head_dir_child(State(app): State<SharedAppState>, Path((dir, path)): Pa
     StatusCode::NOT_FOUND.into_response()
   }
 }
+
+#[derive(Deserialize)]
+struct GetDirTarParams {
+  compression: Option<Compression>,
+}
+
+/// Stream a published directory as a tar archive, built while it is sent.
+///
+/// The size is not known in advance, so the response has no `Content-Length` and does not support ranges.
+async fn get_dir_tar(
+  State(app): State<SharedAppState>, Path(dir): Path<String>, Query(params): Query<GetDirTarParams>,
+) -> Response {
+  debug!("get dir tar: {dir}");
+  let Some(path) = app.file_map.get_dir_path(&dir) else {
+    return StatusCode::NOT_FOUND.into_response();
+  };
+  let compression = params.compression.unwrap_or_default();
+  let content_type = match compression {
+    Compression::None => "application/x-tar",
+    Compression::Gzip => "application/gzip",
+    Compression::Zstd => "application/zstd",
+    Compression::Xz => "application/x-xz",
+  };
+  let (resp, _) = finish_response(
+    Response::builder()
+      .header(header::CONTENT_TYPE, content_type)
+      .header(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{dir}.{}\"", compression.tar_extension()),
+      )
+      .body(Body::from_stream(ReaderStream::new(pack_dir(path.into(), compression)))),
+  );
+  resp
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn resolve(header: &str, size: u64) -> Result<Vec<RangeInclusive<u64>>, String> {
+    resolve_ranges(&parse_range_header(header).unwrap(), size)
+  }
+
+  #[test]
+  fn test_requested_hashes() {
+    let params = HashMap::from([
+      ("sha512".to_string(), "true".to_string()),
+      ("sha2-512".to_string(), "true".to_string()),
+      ("md5".to_string(), "false".to_string()),
+    ]);
+    let mut requested = requested_hashes(&params);
+    requested.sort_by_key(|algorithm| algorithm.name());
+    // `sha512` keeps requesting SHA3-512
+    assert_eq!(requested, [HashAlgorithm::Sha512, HashAlgorithm::Sha3_512]);
+    // and means the same in tasks and by-hash URLs
+    assert_eq!(
+      serde_json::from_str::<HashAlgorithm>(r#""sha512""#).unwrap(),
+      HashAlgorithm::Sha3_512
+    );
+    assert_eq!("SHA512".parse(), Ok(HashAlgorithm::Sha3_512));
+  }
+
+  #[test]
+  fn test_resolve_ranges() {
+    assert_eq!(resolve("bytes=0-9", 100), Ok(vec![0..=9]));
+    // Overlapping and adjacent ranges are merged, in order
+    assert_eq!(
+      resolve("bytes=50-59, 0-15, 10-20, 21-30", 100),
+      Ok(vec![0..=30, 50..=59])
+    );
+    assert_eq!(resolve("bytes=-10, 95-", 100), Ok(vec![90..=99]));
+    assert_eq!(resolve("bytes=-200", 100), Ok(vec![0..=99]));
+    // Unsatisfiable ranges are dropped
+    assert_eq!(resolve("bytes=0-9, 100-", 100), Ok(vec![0..=9]));
+    assert!(resolve("bytes=100-", 100).is_err());
+    assert!(resolve("bytes=0-", 0).is_err());
+  }
+}
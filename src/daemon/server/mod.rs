@@ -1,5 +1,5 @@
 use std::{
-  net::{IpAddr, Ipv4Addr, SocketAddr},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
   sync::Arc,
   time::Duration,
 };
@@ -9,12 +9,13 @@ use axum::{
   Router,
   extract::connect_info::Connected,
   http::StatusCode,
-  routing::get,
+  routing::{get, post},
   serve::{IncomingStream, Listener},
 };
 use futures::future::join3;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
   net::{TcpListener, TcpStream},
   select,
@@ -23,22 +24,26 @@ use tokio::{
 use tokio_rustls::{
   TlsAcceptor,
   rustls::{
-    ServerConfig,
+    RootCertStore, ServerConfig,
     pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
   },
   server::TlsStream,
 };
 use tokio_util::sync::CancellationToken;
 use tower_http::services::ServeDir;
 
-use crate::daemon::states::SharedAppState;
+use crate::{daemon::states::SharedAppState, protocol::enrollment::ENROLL_PATH};
 
-use crate::utils::signal::ctrl_c;
+use crate::utils::{cert::cert_common_name, signal::ctrl_c};
 
 mod api;
 mod collector;
+mod enroll;
 mod files;
 mod net;
+mod signed_url;
+mod upload;
 mod utils;
 
 struct TlsListener {
@@ -73,20 +78,46 @@ impl Listener for TlsListener {
   fn local_addr(&self) -> tokio::io::Result<Self::Addr> { self.listener.local_addr() }
 }
 
+/// Listen on `port` over both IPv6 and IPv4, or over IPv4 only on hosts without IPv6.
+async fn bind_dual_stack(port: u16) -> Result<TcpListener> {
+  let socket = match Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)) {
+    Ok(socket) => socket,
+    Err(err) => {
+      warn!("IPv6 is unavailable, listening on IPv4 only: {err}");
+      return Ok(TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?);
+    }
+  };
+  socket.set_only_v6(false)?;
+  socket.set_reuse_address(true)?;
+  socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+  socket.listen(1024)?;
+  socket.set_nonblocking(true)?;
+  Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses, report them as plain IPv4.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr { SocketAddr::new(addr.ip().to_canonical(), addr.port()) }
+
 #[derive(Clone, Debug, Serialize)]
 pub struct SocketConnectInfo {
   pub local_addr: Option<SocketAddr>,
   pub remote_addr: Option<SocketAddr>,
+  /// Host id in the verified client certificate of a mutual TLS connection
+  pub client_cert_host: Option<String>,
+  /// Whether the connection is over HTTPS
+  pub tls: bool,
 }
 
 impl Connected<IncomingStream<'_, TcpListener>> for SocketConnectInfo {
   fn connect_info(target: IncomingStream<'_, TcpListener>) -> Self {
     let io = target.io();
-    let local_addr = io.local_addr().ok();
-    let remote_addr = io.peer_addr().ok();
+    let local_addr = io.local_addr().ok().map(canonical_addr);
+    let remote_addr = io.peer_addr().ok().map(canonical_addr);
     SocketConnectInfo {
       local_addr,
       remote_addr,
+      client_cert_host: None,
+      tls: false,
     }
   }
 }
@@ -94,11 +125,15 @@ impl Connected<IncomingStream<'_, TcpListener>> for SocketConnectInfo {
 impl Connected<IncomingStream<'_, TlsListener>> for SocketConnectInfo {
   fn connect_info(target: IncomingStream<'_, TlsListener>) -> Self {
     let io = target.io().get_ref();
-    let local_addr = io.0.local_addr().ok();
-    let remote_addr = io.0.peer_addr().ok();
+    let local_addr = io.0.local_addr().ok().map(canonical_addr);
+    let remote_addr = io.0.peer_addr().ok().map(canonical_addr);
+    // Only certificates issued by the client CA get past the handshake, so their common name can be trusted.
+    let client_cert_host = io.1.peer_certificates().and_then(|certs| cert_common_name(certs.first()?));
     SocketConnectInfo {
       local_addr,
       remote_addr,
+      client_cert_host,
+      tls: true,
     }
   }
 }
@@ -112,6 +147,7 @@ pub(crate) async fn main(state: SharedAppState) -> Result<()> {
 
   let mut route = Router::new()
     .route("/ws", get(self::net::handle_ws).head(async || StatusCode::OK))
+    .route(ENROLL_PATH, post(self::enroll::post_enroll))
     .nest("/api", self::api::build(state.clone()))
     .nest("/files", self::files::build(state.clone()));
   if let Some(static_path) = &config.static_path {
@@ -121,7 +157,7 @@ pub(crate) async fn main(state: SharedAppState) -> Result<()> {
 
   let halt_signal2 = halt_signal.clone();
   let http_port = config.http_port;
-  let http_listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), http_port)).await?;
+  let http_listener = bind_dual_stack(http_port).await?;
   let http_serve = axum::serve(http_listener, route_srv.clone()).with_graceful_shutdown(async move {
     select! {
         _ = halt_http.cancelled() => {
@@ -137,11 +173,21 @@ pub(crate) async fn main(state: SharedAppState) -> Result<()> {
 
   let halt_signal2 = halt_signal.clone();
   let https_serve = if let Some(https) = &config.https_args {
-    let tls_config = ServerConfig::builder().with_no_client_auth().with_single_cert(
+    let tls_config = ServerConfig::builder();
+    let tls_config = if let Some(ca) = &https.client_ca {
+      let mut roots = RootCertStore::empty();
+      roots.add(CertificateDer::from_pem_slice(ca.cert.as_bytes())?)?;
+      // API clients keep connecting without a certificate, `/ws` checks for one itself.
+      let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).allow_unauthenticated().build()?;
+      tls_config.with_client_cert_verifier(verifier)
+    } else {
+      tls_config.with_no_client_auth()
+    };
+    let tls_config = tls_config.with_single_cert(
       vec![CertificateDer::from_pem_slice(https.cert.as_bytes())?],
       PrivateKeyDer::from_pem_slice(https.key.as_bytes())?,
     )?;
-    let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), https.port)).await?;
+    let listener = bind_dual_stack(https.port).await?;
     let acceptor = TlsAcceptor::from(Arc::new(tls_config));
     let tls_listener = TlsListener { listener, acceptor };
     let serve = axum::serve(tls_listener, route_srv).with_graceful_shutdown(async move {
@@ -200,3 +246,18 @@ async fn lifecycle_helper(_app: SharedAppState, halt_signal: CancellationToken)
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_canonical_addr() {
+    let mapped: SocketAddr = "[::ffff:192.0.2.1]:8080".parse().unwrap();
+    assert_eq!(canonical_addr(mapped), "192.0.2.1:8080".parse().unwrap());
+    for addr in ["192.0.2.1:8080", "[2001:db8::1]:8080", "[::1]:8080"] {
+      let addr: SocketAddr = addr.parse().unwrap();
+      assert_eq!(canonical_addr(addr), addr);
+    }
+  }
+}
@@ -6,7 +6,8 @@ use std::{
 
 use crate::{
   protocol::{
-    handshake::{CONNECT_HANDSHAKE_HEADER_KEY, ConnectHandshake},
+    auth::AuthRequest,
+    handshake::{CONNECT_CONTROLLER_AUTH_HEADER_KEY, CONNECT_HANDSHAKE_HEADER_KEY, ConnectHandshake},
     messaging::Message as ProtocolMessage,
   },
   utils::states::States as _,
@@ -31,7 +32,7 @@ use crate::daemon::states::{
   host_session::{ExtraInfo, HostSession},
 };
 
-use super::SocketConnectInfo;
+use super::{SocketConnectInfo, utils::verified_agent_auth};
 
 pub(super) async fn handle_ws(
   State(app): State<SharedAppState>, ConnectInfo(socket_info): ConnectInfo<SocketConnectInfo>, headers: HeaderMap,
@@ -56,7 +57,40 @@ async fn handle_ws_inner(
     headers.get(CONNECT_HANDSHAKE_HEADER_KEY).ok_or(anyhow!("Missing handshake header"))?.to_str()?,
   )?;
   let host_id = params.host_id.clone();
-  let resp = ws.on_upgrade(async move |socket| {
+  if app.startup_args.require_tls && !socket_info.tls {
+    warn!(
+      "Rejected agent {host_id} from {:?}: TLS is required",
+      socket_info.remote_addr
+    );
+    return Ok(StatusCode::FORBIDDEN.into_response());
+  }
+  if app.startup_args.client_ca().is_some() && socket_info.client_cert_host.as_ref() != Some(&host_id) {
+    warn!(
+      "Rejected agent {host_id} from {:?}: client certificate is for {:?}",
+      socket_info.remote_addr, socket_info.client_cert_host
+    );
+    return Ok(StatusCode::FORBIDDEN.into_response());
+  }
+  let agent_auth = verified_agent_auth(&headers);
+  if let Some(enrollment) = &app.enrollment &&
+    !agent_auth.as_ref().is_some_and(|auth| enrollment.is_enrolled(&host_id, &auth.encoded_pubkey()))
+  {
+    warn!(
+      "Rejected agent {host_id} from {:?}: not enrolled",
+      socket_info.remote_addr
+    );
+    return Ok(StatusCode::FORBIDDEN.into_response());
+  }
+  // Agents check the signature against the controllers they trust. It covers the nonce the agent sent and the
+  // session it opens, so that it cannot be replayed to another agent.
+  let sign = match &agent_auth {
+    Some(auth) => Some(AuthRequest::new_with_privkey_string_and_challenge(
+      &app.startup_args.key_pair.1,
+      &params.controller_challenge(&auth.nonce),
+    )?),
+    None => None,
+  };
+  let mut resp = ws.on_upgrade(async move |socket| {
     let host_id = params.host_id.clone();
     if let Err(e) = handle_connection(socket, params.clone(), socket_info, app.clone(), ct).await {
       error!("Failed to handle WebSocket connection for host {}: {}", &host_id, e);
@@ -65,6 +99,9 @@ async fn handle_ws_inner(
     }
     app.host_session.remove(&host_id); // usually it should remove the closing session
   });
+  if let Some(sign) = sign {
+    resp.headers_mut().insert(CONNECT_CONTROLLER_AUTH_HEADER_KEY, sign.encode().parse()?);
+  }
   info!("WebSocket connection established for id: {}", &host_id);
   Ok(resp)
 }
@@ -98,6 +135,8 @@ async fn handle_connection(
     session.notify.notify_waiters();
     return Err(anyhow!("Session ID mismatch"));
   }
+  // Agents keep their session id across restarts, a connection still open for it is stale
+  session.notify.notify_waiters();
   let mut last_seen = Instant::now();
   loop {
     select! {
@@ -118,7 +157,7 @@ async fn handle_connection(
                 break;
             }
         }
-        r = handle_recv(&mut ws, session.clone()) => {
+        r = handle_recv(&mut ws, app.clone(), session.clone()) => {
             last_seen = Instant::now();
             match r {
                 Ok(true) => continue,
@@ -148,14 +187,14 @@ async fn handle_connection(
   Ok(())
 }
 
-async fn handle_recv(ws: &mut WebSocket, session: Arc<HostSession>) -> Result<bool> {
+async fn handle_recv(ws: &mut WebSocket, app: SharedAppState, session: Arc<HostSession>) -> Result<bool> {
   if let Some(msg) = ws.recv().await {
     let msg = msg?;
     match msg {
       Message::Text(data) => {
         let data = data.to_string();
         let msg = ProtocolMessage::try_from(data.as_str())?;
-        tokio::spawn(super::collector::handle_msg(msg, session));
+        tokio::spawn(super::collector::handle_msg(msg, app, session));
         Ok(true)
       }
       Message::Binary(_) => Err(anyhow!("Binary message not supported")), // Not supported yet
@@ -0,0 +1,165 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+  Router,
+  extract::{ConnectInfo, OriginalUri, Query, Request, State},
+  http::StatusCode,
+  middleware::{self, Next},
+  response::IntoResponse as _,
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac as _};
+use log::warn;
+use serde::Deserialize;
+use sha2::Sha256;
+use url::Url;
+
+use crate::{
+  daemon::{cli::FileUrlSigning, states::SharedAppState},
+  utils::states::States as _,
+};
+
+use super::SocketConnectInfo;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Query parameters of a signature, replaced when a URL is signed again
+const SIGNATURE_PARAMS: [&str; 3] = ["expires", "host", "sig"];
+
+#[derive(Deserialize)]
+struct SignatureParams {
+  expires: u64,
+  host: Option<String>,
+  sig: String,
+}
+
+fn now_secs() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) }
+
+fn mac_for(secret: &str, path: &str, expires: u64, host: Option<&str>) -> HmacSha256 {
+  let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+  mac.update(format!("{path}\n{expires}\n{}", host.unwrap_or_default()).as_bytes());
+  mac
+}
+
+/// Build the query string that authorizes a download of the percent-encoded `path`, optionally bound to `host`.
+pub(super) fn sign_path(signing: &FileUrlSigning, path: &str, host: Option<&str>) -> String {
+  let expires = now_secs() + signing.ttl;
+  let sig = URL_SAFE_NO_PAD.encode(mac_for(&signing.secret, path, expires, host).finalize().into_bytes());
+  let mut query = url::form_urlencoded::Serializer::new(String::new());
+  query.append_pair("expires", &expires.to_string());
+  if let Some(host) = host {
+    query.append_pair("host", host);
+  }
+  query.append_pair("sig", &sig);
+  query.finish()
+}
+
+/// Add a signature to `url` if signing is enabled and it points into `/files`. The rest of its query is kept.
+pub(super) fn sign_url(signing: Option<&FileUrlSigning>, url: &mut Url, host: Option<&str>) {
+  if let Some(signing) = signing &&
+    url.path().starts_with("/files/")
+  {
+    let signature = sign_path(signing, url.path(), host);
+    let kept: Vec<_> = url
+      .query_pairs()
+      .filter(|(name, _)| !SIGNATURE_PARAMS.contains(&name.as_ref()))
+      .map(|(name, value)| (name.into_owned(), value.into_owned()))
+      .collect();
+    let query = match kept.is_empty() {
+      true => signature,
+      false => format!(
+        "{}&{signature}",
+        url::form_urlencoded::Serializer::new(String::new()).extend_pairs(kept).finish()
+      ),
+    };
+    url.set_query(Some(&query));
+  }
+}
+
+fn verify(signing: &FileUrlSigning, path: &str, params: &SignatureParams) -> bool {
+  if params.expires < now_secs() {
+    return false;
+  }
+  let Ok(sig) = URL_SAFE_NO_PAD.decode(&params.sig) else {
+    return false;
+  };
+  mac_for(&signing.secret, path, params.expires, params.host.as_deref()).verify_slice(&sig).is_ok()
+}
+
+/// Whether `request` comes from the host `host`: by its client certificate if it presented one, otherwise from the
+/// address the host's session is connected from.
+fn from_host(app: &SharedAppState, request: &Request, host: &str) -> bool {
+  let Some(ConnectInfo(info)) = request.extensions().get::<ConnectInfo<SocketConnectInfo>>() else {
+    return false;
+  };
+  if let Some(cert_host) = &info.client_cert_host {
+    return cert_host == host;
+  }
+  let Some(session) = app.host_session.get_arc(&host.to_string()) else {
+    return false;
+  };
+  match (info.remote_addr, session.extra.socket_info.remote_addr) {
+    (Some(remote), Some(agent)) => remote.ip() == agent.ip(),
+    _ => false,
+  }
+}
+
+/// Reject requests to the routes of `router` that do not carry a valid signature for their path, or that come from
+/// another host than the one the URL was signed for.
+///
+/// The router is returned unchanged if signing is disabled.
+pub(super) fn signature_middleware<T: Clone + Send + Sync + 'static>(
+  router: Router<T>, signing: Option<FileUrlSigning>, app: SharedAppState,
+) -> Router<T> {
+  let Some(signing) = signing else {
+    return router;
+  };
+  router.route_layer(middleware::from_fn_with_state(
+    (signing, app),
+    async |State((signing, app)): State<(FileUrlSigning, SharedAppState)>, request: Request, next: Next| {
+      let path = match request.extensions().get::<OriginalUri>() {
+        Some(uri) => uri.path().to_string(),
+        None => request.uri().path().to_string(),
+      };
+      match Query::<SignatureParams>::try_from_uri(request.uri()) {
+        Ok(Query(params)) if verify(&signing, &path, &params) => match &params.host {
+          Some(host) if !from_host(&app, &request, host) => {
+            warn!("Rejected request for {path}, signed for {host} but not made by it");
+            StatusCode::FORBIDDEN.into_response()
+          }
+          _ => next.run(request).await,
+        },
+        Ok(_) => {
+          warn!("Rejected request for {path} with an invalid or expired signature");
+          StatusCode::FORBIDDEN.into_response()
+        }
+        Err(_) => StatusCode::FORBIDDEN.into_response(),
+      }
+    },
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_signature() {
+    let signing = FileUrlSigning {
+      secret: "secret".to_string(),
+      ttl: 60,
+    };
+    let query = sign_path(&signing, "/files/a%20b", Some("host"));
+    let Query(params) = Query::<SignatureParams>::try_from_uri(&format!("/?{query}").parse().unwrap()).unwrap();
+    assert!(verify(&signing, "/files/a%20b", &params));
+    assert!(!verify(&signing, "/files/other", &params));
+    let unbound = SignatureParams { host: None, ..params };
+    assert!(!verify(&signing, "/files/a%20b", &unbound));
+
+    // The query of the URL is kept, a previous signature is replaced
+    let mut url = Url::parse("http://controller/files/a?sha256=true&sig=old").unwrap();
+    sign_url(Some(&signing), &mut url, None);
+    let names: Vec<_> = url.query_pairs().map(|(name, _)| name.into_owned()).collect();
+    assert_eq!(names, ["sha256", "expires", "sig"]);
+  }
+}
@@ -0,0 +1,191 @@
+use std::path::Path as FsPath;
+
+use axum::{
+  Json,
+  body::Body,
+  extract::{Path, Query, Request, State},
+  http::{HeaderMap, StatusCode, header},
+};
+use futures_util::StreamExt as _;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+  fs::{self, File},
+  io::AsyncWriteExt as _,
+};
+
+use crate::{
+  daemon::{
+    api_token::Scope,
+    states::{SharedAppState, upload::join_below},
+  },
+  utils::{
+    fs::{create_parent_dirs, temp_path_for},
+    hash::{HashAlgorithm, HashError, StreamHasher, hash_eq},
+  },
+};
+
+use super::api::{ERR_REASON_INTERNAL_ERROR, ERR_REASON_INVALID_PATH, ERR_REASON_UPLOADS_DISABLED};
+
+const ERR_REASON_UNAUTHORIZED: &str = "UNAUTHORIZED";
+const ERR_REASON_TOO_LARGE: &str = "TOO_LARGE";
+const ERR_REASON_HASH_MISMATCH: &str = "HASH_MISMATCH";
+
+#[derive(Debug, Error)]
+enum ReceiveError {
+  #[error("Upload exceeds {0} bytes")]
+  TooLarge(u64),
+  #[error("Io error: {0}")]
+  IoError(#[from] std::io::Error),
+  #[error("Hash error: {0}")]
+  HashError(#[from] HashError),
+  #[error("Hash mismatch: expected {0}, got {1}")]
+  HashMismatch(String, String),
+}
+
+#[derive(Deserialize)]
+pub(super) struct PutParams {
+  token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(super) struct PutResponse {
+  ok: bool,
+  size: u64,
+  xxh3: Option<String>,
+  reason: Option<String>,
+}
+
+fn failed(status: StatusCode, reason: &str) -> (StatusCode, Json<PutResponse>) {
+  (
+    status,
+    Json(PutResponse {
+      ok: false,
+      size: 0,
+      xxh3: None,
+      reason: Some(reason.to_string()),
+    }),
+  )
+}
+
+/// An upload is allowed with a token minted for this host and path, or with an API token allowed file tasks on the
+/// host. The token is only consumed once the upload succeeded.
+fn is_authorized(app: &SharedAppState, host: &str, path: &str, token: Option<&str>, headers: &HeaderMap) -> bool {
+  if let Some(token) = token {
+    return app.upload_grants.allows(token, host, path);
+  }
+  app
+    .api_tokens
+    .authenticate(headers.get(header::AUTHORIZATION).map(|v| v.as_bytes()))
+    .is_some_and(|caller| caller.allows(Scope::TaskFile) && caller.may_target(host))
+}
+
+/// Receive a file from an agent into `<upload_dir>/<host>/<path>`.
+///
+/// The body is written to a temporary file and only renamed into place once it is complete. If the agent sends an
+/// `X-Hash-Xxh3` header, the received data must match it.
+pub(super) async fn put_upload(
+  State(app): State<SharedAppState>, Path((host, path)): Path<(String, String)>, Query(params): Query<PutParams>,
+  req: Request,
+) -> (StatusCode, Json<PutResponse>) {
+  let Some(upload_dir) = &app.startup_args.upload_dir else {
+    return failed(StatusCode::NOT_FOUND, ERR_REASON_UPLOADS_DISABLED);
+  };
+  if !is_authorized(&app, &host, &path, params.token.as_deref(), req.headers()) {
+    warn!("Rejected unauthorized upload of {path} for {host}");
+    return failed(StatusCode::FORBIDDEN, ERR_REASON_UNAUTHORIZED);
+  }
+  let Some(dest) = join_below(FsPath::new(upload_dir), &host)
+    .filter(|_| !host.contains('/'))
+    .and_then(|dir| join_below(&dir, &path))
+  else {
+    return failed(StatusCode::BAD_REQUEST, ERR_REASON_INVALID_PATH);
+  };
+  let limit = app.startup_args.upload_max_size;
+  let headers = req.headers();
+  if headers
+    .get(header::CONTENT_LENGTH)
+    .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+    .is_some_and(|len| len > limit)
+  {
+    return failed(StatusCode::PAYLOAD_TOO_LARGE, ERR_REASON_TOO_LARGE);
+  }
+  let expected = headers.get("X-Hash-Xxh3").and_then(|v| v.to_str().ok()).map(str::to_string);
+
+  if let Err(err) = create_parent_dirs(&dest).await {
+    warn!("Failed to create upload directory for {}: {err}", dest.display());
+    return failed(StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR);
+  }
+  let tmp = temp_path_for(&dest);
+  let r = receive(req.into_body(), &tmp, limit).await;
+  let r = r.and_then(|(size, hash)| match expected {
+    Some(expected) if !hash_eq(HashAlgorithm::Xxh3, &expected, &hash) => {
+      Err(ReceiveError::HashMismatch(expected, hash))
+    }
+    _ => Ok((size, hash)),
+  });
+  let (size, hash) = match r {
+    Ok(received) => received,
+    Err(err) => {
+      warn!("Failed to receive upload of {path} for {host}: {err}");
+      remove_temp(&tmp).await;
+      return match err {
+        ReceiveError::TooLarge(_) => failed(StatusCode::PAYLOAD_TOO_LARGE, ERR_REASON_TOO_LARGE),
+        ReceiveError::HashMismatch(..) => failed(StatusCode::UNPROCESSABLE_ENTITY, ERR_REASON_HASH_MISMATCH),
+        _ => failed(StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR),
+      };
+    }
+  };
+  // A concurrent upload with the same token may have succeeded meanwhile
+  if let Some(token) = &params.token &&
+    !app.upload_grants.redeem(token, &host, &path)
+  {
+    warn!("Rejected upload of {path} for {host}, its token was used meanwhile");
+    remove_temp(&tmp).await;
+    return failed(StatusCode::FORBIDDEN, ERR_REASON_UNAUTHORIZED);
+  }
+  if let Err(err) = fs::rename(&tmp, &dest).await {
+    warn!("Failed to move upload into {}: {err}", dest.display());
+    remove_temp(&tmp).await;
+    return failed(StatusCode::INTERNAL_SERVER_ERROR, ERR_REASON_INTERNAL_ERROR);
+  }
+  info!(
+    "Received upload of {size} bytes from {host} into {}. xxh3: {hash}",
+    dest.display()
+  );
+  (
+    StatusCode::OK,
+    Json(PutResponse {
+      ok: true,
+      size,
+      xxh3: Some(hash),
+      reason: None,
+    }),
+  )
+}
+
+/// Stream `body` into `tmp`, giving up once it exceeds `limit` bytes. Returns the size and xxh3 of the data.
+async fn receive(body: Body, tmp: &FsPath, limit: u64) -> Result<(u64, String), ReceiveError> {
+  let mut file = File::create(tmp).await?;
+  let mut hasher = StreamHasher::new(HashAlgorithm::Xxh3);
+  let mut size = 0u64;
+  let mut stream = body.into_data_stream();
+  while let Some(chunk) = stream.next().await {
+    let chunk = chunk.map_err(std::io::Error::other)?;
+    size += chunk.len() as u64;
+    if size > limit {
+      return Err(ReceiveError::TooLarge(limit));
+    }
+    hasher.update(&chunk);
+    file.write_all(&chunk).await?;
+  }
+  file.sync_all().await?;
+  Ok((size, hasher.finalize()?))
+}
+
+async fn remove_temp(tmp: &FsPath) {
+  if let Err(err) = fs::remove_file(tmp).await {
+    warn!("Failed to remove temporary upload {}: {err}", tmp.display());
+  }
+}
@@ -1,31 +1,87 @@
 use axum::{
   Router,
-  extract::{Request, State},
-  http::StatusCode,
+  extract::{ConnectInfo, Request, State},
+  http::{HeaderMap, StatusCode, header},
   middleware::{self, Next},
   response::IntoResponse as _,
 };
+use log::{debug, info, warn};
 
-#[derive(Clone)]
-struct ApiState {
-  apikey: Option<String>,
+use crate::{
+  daemon::{
+    api_token::Scope,
+    audit::{AuditEvent, AuditRecord},
+    server::SocketConnectInfo,
+    states::SharedAppState,
+  },
+  protocol::{auth::AuthRequest, handshake::CONNECT_AGENT_AUTH_HEADER_KEY},
+};
+
+/// Authentication header of the agent that signed the request, if it is present and valid.
+pub(super) fn verified_agent_auth(headers: &HeaderMap) -> Option<AuthRequest> {
+  let auth = AuthRequest::decode(headers.get(CONNECT_AGENT_AUTH_HEADER_KEY)?.to_str().ok()?).ok()?;
+  auth.verify().then_some(auth)
+}
+
+/// Reject requests to the routes of `router` that do not come over TLS, for controllers started with `--require-tls`.
+///
+/// The router is returned unchanged if TLS is not required.
+pub(super) fn tls_middleware<T: Clone + Send + Sync + 'static>(router: Router<T>, require_tls: bool) -> Router<T> {
+  if !require_tls {
+    return router;
+  }
+  router.layer(middleware::from_fn(async |request: Request, next: Next| {
+    let tls = request.extensions().get::<ConnectInfo<SocketConnectInfo>>().is_some_and(|info| info.tls);
+    if !tls {
+      warn!(
+        "Rejected {} {} over plain HTTP, TLS is required",
+        request.method(),
+        request.uri().path()
+      );
+      return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+  }))
 }
 
-pub(super) fn auth_middleware<T: Clone + Send + Sync + 'static>(router: Router<T>, key: Option<String>) -> Router<T> {
+/// Authenticate API requests and check that their token has the scope the route needs.
+///
+/// The caller is added to the request extensions for handlers that check hosts. Requests refused for lack of scope are
+/// audited.
+pub(super) fn auth_middleware<T: Clone + Send + Sync + 'static>(router: Router<T>, app: SharedAppState) -> Router<T> {
   router.layer(middleware::from_fn_with_state(
-    ApiState {
-      apikey: key.map(|sk| format!("Bearer {sk}")),
-    },
-    async |State(state): State<ApiState>, request: Request, next: Next| {
-      if let Some(sk) = state.apikey {
-        if let Some(key) = request.headers().get("Authorization") {
-          if key != &sk {
-            return (StatusCode::FORBIDDEN).into_response();
-          }
+    app,
+    async |State(app): State<SharedAppState>, mut request: Request, next: Next| {
+      let authorization = request.headers().get(header::AUTHORIZATION).map(|v| v.as_bytes());
+      let Some(mut caller) = app.api_tokens.authenticate(authorization) else {
+        return if authorization.is_some() {
+          StatusCode::FORBIDDEN.into_response()
         } else {
-          return (StatusCode::UNAUTHORIZED).into_response();
-        }
+          StatusCode::UNAUTHORIZED.into_response()
+        };
+      };
+      caller.remote_addr =
+        request.extensions().get::<ConnectInfo<SocketConnectInfo>>().and_then(|info| info.remote_addr);
+      let method = request.method().clone();
+      let path = request.uri().path().to_string();
+      let scope = Scope::required_for(&method, &path);
+      if !caller.allows(scope) {
+        warn!("Rejected {method} {path} by {caller}, which lacks the {scope:?} scope");
+        app.audit(
+          AuditRecord::new(AuditEvent::RequestDenied {
+            method: method.to_string(),
+            path,
+          })
+          .by(&caller),
+        );
+        return StatusCode::FORBIDDEN.into_response();
+      }
+      if scope == Scope::ReadOnly || scope == Scope::FsRead {
+        debug!("{method} {path} by {caller}");
+      } else {
+        info!("{method} {path} by {caller}");
       }
+      request.extensions_mut().insert(caller);
       next.run(request).await
     },
   ))
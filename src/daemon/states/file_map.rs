@@ -1,18 +1,55 @@
-use std::path::Path;
+use std::{
+  collections::{BTreeSet, HashMap, HashSet},
+  fs::Metadata,
+  path::Path,
+  sync::{Arc, Mutex, RwLock},
+  time::SystemTime,
+};
+
+use log::warn;
 
 use crate::utils::{
-  hash,
-  states::{StateMap, States as _},
+  hash::{self, HashAlgorithm, hash_eq},
+  states::{StateMap, States},
 };
 
 #[derive(Debug, Clone)]
 pub struct FileMap {
   pub file_path: String,
-  pub xxh3: Option<String>,
-  pub md5: Option<String>,
-  pub sha1: Option<String>,
-  pub sha256: Option<String>,
-  pub sha512: Option<String>,
+  /// Hashes of the file computed so far
+  pub hashes: HashMap<HashAlgorithm, String>,
+  /// Algorithms the file was requested with, computed again ahead of requests when the file changes
+  pub algorithms: HashSet<HashAlgorithm>,
+  /// State of the file when the hashes above were computed
+  pub stamp: Option<FileStamp>,
+}
+
+/// Size, modification time and inode of a file, used to tell whether its cached hashes are still valid.
+///
+/// The inode catches files replaced by a rename, which may keep the size and mtime of the original.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStamp {
+  size: u64,
+  mtime: Option<SystemTime>,
+  inode: u64,
+}
+
+impl FileStamp {
+  pub fn of(meta: &Metadata) -> Self {
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(meta);
+    #[cfg(not(unix))]
+    let inode = 0;
+    FileStamp {
+      size: meta.len(),
+      mtime: meta.modified().ok(),
+      inode,
+    }
+  }
+}
+
+impl FileMap {
+  pub fn hash(&self, algorithm: HashAlgorithm) -> Option<&str> { self.hashes.get(&algorithm).map(String::as_str) }
 }
 
 #[derive(Debug, Clone)]
@@ -21,9 +58,64 @@ pub enum MapItem {
   Dir(String),
 }
 
-pub type FileMapStorage = StateMap<String, MapItem>;
+/// Form of a digest the index is keyed by, so that lookups match like [`hash_eq`].
+fn index_key(algorithm: HashAlgorithm, digest: &str) -> Option<String> {
+  match algorithm {
+    HashAlgorithm::Xxh3 => u64::from_str_radix(digest, 16).ok().map(|h| format!("{h:016x}")),
+    _ => Some(digest.to_ascii_lowercase()),
+  }
+}
+
+/// Published files and directories by name, with an index of the hashes cached for the files.
+///
+/// Lookups by hash only read the index, so an unauthenticated request cannot make the daemon hash files.
+#[derive(Clone, Default)]
+pub struct FileMapStorage {
+  maps: StateMap<String, MapItem>,
+  /// Names of the published files by cached hash
+  by_hash: Arc<RwLock<HashMap<(HashAlgorithm, String), BTreeSet<String>>>>,
+  /// Locks of the files being hashed by name, so that concurrent requests for a file wait for a single pass over it
+  hashing: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl States<String, MapItem> for FileMapStorage {
+  /// Publish `item` under `key`, indexing the hashes cached for it in place of those of the item it replaces.
+  fn insert(&self, key: String, item: MapItem) -> bool {
+    let Ok(mut index) = self.by_hash.write() else {
+      return false;
+    };
+    index.retain(|_, names| {
+      names.remove(&key);
+      !names.is_empty()
+    });
+    if let MapItem::File(map) = &item {
+      for (algorithm, digest) in &map.hashes {
+        if let Some(digest) = index_key(*algorithm, digest) {
+          index.entry((*algorithm, digest)).or_default().insert(key.clone());
+        }
+      }
+    }
+    self.maps.insert(key, item)
+  }
+
+  fn get_arc(&self, key: &String) -> Option<Arc<MapItem>> { self.maps.get_arc(key) }
+
+  fn remove(&self, key: &String) {
+    if let Ok(mut index) = self.by_hash.write() {
+      index.retain(|_, names| {
+        names.remove(key);
+        !names.is_empty()
+      });
+    }
+    self.maps.remove(key);
+  }
+
+  fn list(&self) -> Vec<String> { self.maps.list() }
+}
 
 impl FileMapStorage {
+  pub fn new() -> Self { Self::default() }
+
   pub fn add_file_map(&self, file_path: String, publish_name: String) -> Result<(), String> {
     let path = Path::new(&file_path);
     if !path.exists() {
@@ -40,11 +132,9 @@ impl FileMapStorage {
       publish_name,
       MapItem::File(FileMap {
         file_path: new_path,
-        xxh3: None,
-        md5: None,
-        sha1: None,
-        sha256: None,
-        sha512: None,
+        hashes: HashMap::new(),
+        algorithms: HashSet::new(),
+        stamp: None,
       }),
     );
     Ok(())
@@ -66,47 +156,133 @@ impl FileMapStorage {
     Ok(())
   }
 
+  /// Get a published file, computing those of `algorithms` that are not cached yet in one pass over the file.
+  ///
+  /// Cached hashes are dropped first if the file changed since they were computed. Concurrent calls for the same file
+  /// wait for the one hashing it, and only hash what it did not.
   pub(crate) async fn get_file_with_optional_props(
-    &self, publish_name: &String, ensure_xxh3: bool, ensure_md5: bool, ensure_sha1: bool, ensure_sha256: bool,
-    ensure_sha512: bool,
+    &self, publish_name: &String, algorithms: &[HashAlgorithm],
   ) -> Option<FileMap> {
-    if let Some(file_map) = self.get_arc(publish_name) &&
-      let MapItem::File(mut new_inner) = (*file_map).clone()
+    let map = self.current_file(publish_name).await?;
+    if algorithms.iter().all(|algorithm| map.hashes.contains_key(algorithm)) {
+      return Some(map);
+    }
+    let lock = self.hashing.lock().ok()?.entry(publish_name.clone()).or_default().clone();
+    let guard = lock.lock().await;
+    let map = self.hash_missing(publish_name, algorithms).await;
+    drop(guard);
+    if let Ok(mut hashing) = self.hashing.lock() &&
+      Arc::strong_count(&lock) <= 2
     {
-      if ensure_xxh3 &&
-        new_inner.xxh3.is_none() &&
-        let Ok(hash) = hash::xxh3_for_file(&new_inner.file_path).await
+      hashing.remove(publish_name);
+    }
+    map
+  }
+
+  /// The published file `publish_name`, without its cached hashes if it changed since they were computed.
+  async fn current_file(&self, publish_name: &String) -> Option<FileMap> {
+    let MapItem::File(mut map) = (*self.get_arc(publish_name)?).clone() else {
+      return None;
+    };
+    let stamp = tokio::fs::metadata(&map.file_path).await.ok().map(|m| FileStamp::of(&m));
+    if map.stamp != stamp {
+      map.hashes.clear();
+      map.stamp = stamp;
+    }
+    Some(map)
+  }
+
+  async fn hash_missing(&self, publish_name: &String, algorithms: &[HashAlgorithm]) -> Option<FileMap> {
+    // Hashes computed while waiting for the lock are cached by now
+    let mut map = self.current_file(publish_name).await?;
+    let missing: Vec<_> = algorithms.iter().copied().filter(|algorithm| !map.hashes.contains_key(algorithm)).collect();
+    if missing.is_empty() {
+      return Some(map);
+    }
+    match hash::hashes_for_file(&map.file_path, &missing).await {
+      Ok(hashes) => map.hashes.extend(hashes),
+      Err(err) => warn!("Failed to hash {}: {err}", map.file_path),
+    }
+    map.algorithms.extend(missing);
+    // The map may have been removed or replaced while hashing.
+    if self.is_published(publish_name, &map.file_path, false) {
+      self.insert(publish_name.clone(), MapItem::File(map.clone()));
+    }
+    Some(map)
+  }
+
+  /// Find a published file whose hash in `algorithm` is `digest`, with the hashes in `algorithms` computed too.
+  ///
+  /// Only files whose hash is cached are found, the hashes of published files are computed once they are added and
+  /// whenever they change if they are watched. A file that changed since is checked again, and skipped if it no
+  /// longer matches.
+  pub(crate) async fn find_by_hash(
+    &self, algorithm: HashAlgorithm, digest: &str, algorithms: &[HashAlgorithm],
+  ) -> Option<FileMap> {
+    let key = index_key(algorithm, digest)?;
+    let names = self.by_hash.read().ok()?.get(&(algorithm, key)).cloned().unwrap_or_default();
+    let mut algorithms = algorithms.to_vec();
+    algorithms.push(algorithm);
+    for name in names {
+      if let Some(map) = self.get_file_with_optional_props(&name, &algorithms).await &&
+        map.hash(algorithm).is_some_and(|h| hash_eq(algorithm, h, digest))
       {
-        new_inner.xxh3 = Some(hash);
+        return Some(map);
       }
-      if ensure_md5 || ensure_sha1 || ensure_sha256 || ensure_sha512 {
-        let calc_md5 = ensure_md5 && new_inner.sha1.is_none();
-        let calc_sha1 = ensure_sha1 && new_inner.sha1.is_none();
-        let calc_sha256 = ensure_sha256 && new_inner.sha256.is_none();
-        let calc_sha512 = ensure_sha512 && new_inner.sha512.is_none();
-        if let Ok((md5, sha1, sha256, sha512)) =
-          hash::hashes_for_file(&new_inner.file_path, calc_md5, calc_sha1, calc_sha256, calc_sha512).await
-        {
-          if calc_md5 {
-            new_inner.md5 = md5;
-          }
-          if calc_sha1 {
-            new_inner.sha1 = sha1;
-          }
-          if calc_sha256 {
-            new_inner.sha256 = sha256;
-          }
-          if calc_sha512 {
-            new_inner.sha512 = sha512;
-          }
-        }
-      }
-      self.insert(publish_name.clone(), MapItem::File(new_inner.clone()));
-      return Some(new_inner);
     }
     None
   }
 
+  /// Compute the hashes of a published file ahead of requests for it: the default one, which files are served and
+  /// found with, and those it was requested with before.
+  pub(crate) async fn precompute_hashes(&self, publish_name: &String) {
+    let Some(MapItem::File(map)) = self.get_arc(publish_name).as_deref().cloned() else {
+      return;
+    };
+    let mut algorithms: Vec<_> = map.algorithms.into_iter().collect();
+    if !algorithms.contains(&HashAlgorithm::default()) {
+      algorithms.push(HashAlgorithm::default());
+    }
+    self.get_file_with_optional_props(publish_name, &algorithms).await;
+  }
+
+  /// Canonical paths of all published files.
+  pub(crate) fn file_paths(&self) -> Vec<String> {
+    self
+      .list()
+      .into_iter()
+      .filter_map(|name| match self.get_arc(&name).as_deref() {
+        Some(MapItem::File(map)) => Some(map.file_path.clone()),
+        _ => None,
+      })
+      .collect()
+  }
+
+  /// Names of the published files at the canonical `path`.
+  pub(crate) fn names_for_path(&self, path: &str) -> Vec<String> {
+    self
+      .list()
+      .into_iter()
+      .filter(|name| matches!(self.get_arc(name).as_deref(), Some(MapItem::File(map)) if map.file_path == path))
+      .collect()
+  }
+
+  /// Whether `publish_name` already publishes the canonical `path`, as a directory if `is_dir`.
+  pub(crate) fn is_published(&self, publish_name: &String, path: &str, is_dir: bool) -> bool {
+    match self.get_arc(publish_name).as_deref() {
+      Some(MapItem::File(map)) => !is_dir && map.file_path == path,
+      Some(MapItem::Dir(dir)) => is_dir && dir == path,
+      None => false,
+    }
+  }
+
+  pub(crate) fn get_dir_path(&self, publish_name: &String) -> Option<String> {
+    match self.get_arc(publish_name).as_deref() {
+      Some(MapItem::Dir(path)) => Some(path.clone()),
+      _ => None,
+    }
+  }
+
   pub(crate) fn get_dir_child_path(&self, publish_name: &String, subpath: &String) -> Option<String> {
     if let Some(map_item) = self.get_arc(publish_name) &&
       let MapItem::Dir(path) = (*map_item).clone()
@@ -120,3 +296,67 @@ impl FileMapStorage {
     None
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::TempPath;
+
+  #[tokio::test]
+  async fn test_find_by_hash() {
+    let path = TempPath::new("mxd-file-map");
+    std::fs::write(&path, b"published").unwrap();
+    let storage = FileMapStorage::new();
+    storage.add_file_map(path.to_string_lossy().to_string(), "a".to_string()).unwrap();
+    let digest = hash::hashes_for_file(&path.to_string_lossy(), &[HashAlgorithm::Sha256]).await.unwrap()
+      [&HashAlgorithm::Sha256]
+      .clone();
+
+    // Not found before its hash is cached
+    assert!(storage.find_by_hash(HashAlgorithm::Sha256, &digest, &[]).await.is_none());
+    storage.get_file_with_optional_props(&"a".to_string(), &[HashAlgorithm::Sha256]).await;
+    let map = storage.find_by_hash(HashAlgorithm::Sha256, &digest.to_uppercase(), &[]).await.unwrap();
+    assert_eq!(map.file_path, path.canonicalize().unwrap().to_string_lossy());
+
+    // A changed file no longer matches its old hash
+    std::fs::write(&path, b"changed").unwrap();
+    assert!(storage.find_by_hash(HashAlgorithm::Sha256, &digest, &[]).await.is_none());
+
+    storage.precompute_hashes(&"a".to_string()).await;
+    storage.remove(&"a".to_string());
+    assert!(storage.by_hash.read().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_precompute_hashes() {
+    let path = TempPath::new("mxd-file-map");
+    std::fs::write(&path, b"published").unwrap();
+    let storage = FileMapStorage::new();
+    let name = "a".to_string();
+    storage.add_file_map(path.to_string_lossy().to_string(), name.clone()).unwrap();
+
+    // Only the default hash ahead of the first request
+    storage.precompute_hashes(&name).await;
+    let map = storage.get_file_with_optional_props(&name, &[]).await.unwrap();
+    assert_eq!(map.hashes.keys().collect::<Vec<_>>(), [&HashAlgorithm::Xxh3]);
+
+    // Concurrent requests hash the file once and all get the hash
+    let (a, b) = tokio::join!(
+      storage.get_file_with_optional_props(&name, &[HashAlgorithm::Sha256]),
+      storage.get_file_with_optional_props(&name, &[HashAlgorithm::Sha256]),
+    );
+    assert_eq!(
+      a.unwrap().hash(HashAlgorithm::Sha256),
+      b.unwrap().hash(HashAlgorithm::Sha256)
+    );
+    assert!(storage.hashing.lock().unwrap().is_empty());
+
+    // The requested hash is computed again along with the default one once the file changes
+    std::fs::write(&path, b"changed").unwrap();
+    storage.precompute_hashes(&name).await;
+    let map = storage.get_file_with_optional_props(&name, &[]).await.unwrap();
+    let mut algorithms: Vec<_> = map.hashes.keys().map(|a| a.name()).collect();
+    algorithms.sort();
+    assert_eq!(algorithms, ["sha256", "xxh3"]);
+  }
+}
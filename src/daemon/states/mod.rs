@@ -1,5 +1,6 @@
 pub mod file_map;
 pub mod host_session;
+pub mod upload;
 
 use std::sync::Arc;
 
@@ -7,8 +8,14 @@ use file_map::FileMapStorage;
 use host_session::HostSessionStorage;
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
+use upload::UploadGrantStorage;
 
-use crate::daemon::cli::StartupArgs;
+use crate::daemon::{
+  api_token::ApiTokens,
+  audit::{AuditLog, AuditRecord},
+  cli::StartupArgs,
+  enrollment::Enrollment,
+};
 
 pub struct AppState {
   pub host_session: HostSessionStorage,
@@ -16,16 +23,33 @@ pub struct AppState {
   pub cancel_signal: CancellationToken,
   pub startup_args: StartupArgs,
   pub discovery_service: Option<Mutex<crate::daemon::discovery::DiscoveryService>>,
+  pub upload_grants: UploadGrantStorage,
+  pub file_watcher: Option<crate::daemon::file_watcher::FileWatcher>,
+  pub api_tokens: Arc<ApiTokens>,
+  pub audit_log: Option<AuditLog>,
+  pub enrollment: Option<Enrollment>,
 }
 
 impl AppState {
-  pub fn new(startup_args: StartupArgs) -> Self {
+  pub fn new(startup_args: StartupArgs, api_tokens: ApiTokens) -> Self {
     AppState {
       host_session: HostSessionStorage::new(),
       file_map: FileMapStorage::new(),
       cancel_signal: CancellationToken::new(),
       startup_args,
       discovery_service: None,
+      upload_grants: UploadGrantStorage::new(),
+      file_watcher: None,
+      api_tokens: Arc::new(api_tokens),
+      audit_log: None,
+      enrollment: None,
+    }
+  }
+
+  /// Append `record` to the audit log, if there is one.
+  pub fn audit(&self, record: AuditRecord) {
+    if let Some(log) = &self.audit_log {
+      log.record(record);
     }
   }
 }
@@ -0,0 +1,70 @@
+use std::{
+  path::{Component, Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+use crate::utils::{
+  states::{StateMap, States as _},
+  util::random_str,
+};
+
+/// How long an upload URL handed to an agent stays valid.
+const GRANT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Permission for one host to upload one file, handed out as the token of an upload URL.
+pub struct UploadGrant {
+  pub host_id: String,
+  pub path: String,
+  pub expires: SystemTime,
+}
+
+pub type UploadGrantStorage = StateMap<String, UploadGrant>;
+
+impl UploadGrantStorage {
+  /// Mint a single-use token that allows `host_id` to upload to `path`.
+  pub fn grant(&self, host_id: &str, path: &str) -> String {
+    self.purge_expired();
+    let token = random_str(32);
+    self.insert(
+      token.clone(),
+      UploadGrant {
+        host_id: host_id.to_string(),
+        path: path.to_string(),
+        expires: SystemTime::now() + GRANT_TTL,
+      },
+    );
+    token
+  }
+
+  /// Whether `token` allows `host_id` to upload to `path`, without consuming it.
+  pub fn allows(&self, token: &str, host_id: &str, path: &str) -> bool {
+    self
+      .get_arc(&token.to_string())
+      .is_some_and(|grant| grant.host_id == host_id && grant.path == path && grant.expires > SystemTime::now())
+  }
+
+  /// Consume `token` if it allows `host_id` to upload to `path`.
+  pub fn redeem(&self, token: &str, host_id: &str, path: &str) -> bool {
+    self
+      .remove_if(&token.to_string(), |grant| {
+        grant.host_id == host_id && grant.path == path
+      })
+      .is_some_and(|grant| grant.expires > SystemTime::now())
+  }
+
+  fn purge_expired(&self) {
+    let now = SystemTime::now();
+    for token in self.list() {
+      self.remove_if(&token, |grant| grant.expires <= now);
+    }
+  }
+}
+
+/// Join the relative path `sub` to `base`, rejecting absolute paths and `..` so the result stays below `base`.
+pub fn join_below(base: &Path, sub: &str) -> Option<PathBuf> {
+  let sub = Path::new(sub);
+  if sub.as_os_str().is_empty() || !sub.components().all(|c| matches!(c, Component::Normal(_))) {
+    return None;
+  }
+  Some(base.join(sub))
+}
@@ -0,0 +1,244 @@
+//! Lookup of controllers advertised as `_mxlite._tcp` DNS-SD services over multicast DNS.
+//!
+//! Queries are sent from an ephemeral port, so responders answer them directly by unicast (RFC 6762 section 6.7)
+//! and no responder needs to run on the agent. The key fingerprint in the TXT record is not authenticated, it only
+//! filters out other controllers early. It is kept with the resolved URLs, and the agent only accepts a controller
+//! found this way once the handshake proved it holds that key.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use log::{debug, info, warn};
+use tokio::{net::UdpSocket, select};
+
+use super::{Advertisement, RESPONSE_WINDOW, ipv6_interfaces};
+use crate::protocol::mdns::{
+  MDNS_GROUP_V4, MDNS_GROUP_V6, MDNS_PORT, Message, Question, RData, Record, SERVICE_TYPE, TYPE_A, TYPE_AAAA, TYPE_PTR,
+  TYPE_SRV, TYPE_TXT, name_eq, txt_value,
+};
+
+/// Records received so far, with the interface the response came in through if it was over IPv6
+type Records = Vec<(Record, Option<u32>)>;
+
+fn find<'a>(records: &'a Records, name: &'a str, rtype: u16) -> impl Iterator<Item = &'a (Record, Option<u32>)> {
+  records
+    .iter()
+    .filter(move |(record, _)| record.data.rtype() == rtype && name_eq(&record.name, name))
+}
+
+/// Service instances of controllers found so far.
+fn instances(records: &Records) -> Vec<&str> {
+  let mut instances: Vec<&str> = find(records, SERVICE_TYPE, TYPE_PTR)
+    .filter_map(|(record, _)| match &record.data {
+      RData::Ptr(instance) => Some(instance.as_str()),
+      _ => None,
+    })
+    .collect();
+  instances.dedup();
+  instances
+}
+
+/// Priority, port and target host of an instance.
+fn srv<'a>(records: &'a Records, instance: &'a str) -> Option<(u16, u16, &'a str)> {
+  find(records, instance, TYPE_SRV).find_map(|(record, _)| match &record.data {
+    RData::Srv { priority, port, target } => Some((*priority, *port, target.as_str())),
+    _ => None,
+  })
+}
+
+fn txt<'a>(records: &'a Records, instance: &'a str) -> &'a [String] {
+  find(records, instance, TYPE_TXT)
+    .find_map(|(record, _)| match &record.data {
+      RData::Txt(txt) => Some(txt.as_slice()),
+      _ => None,
+    })
+    .unwrap_or_default()
+}
+
+/// Query for the service, and for the records of instances that responders did not send along with it.
+fn query(records: &Records) -> Message {
+  let mut query = Message::query(SERVICE_TYPE, TYPE_PTR);
+  let mut ask = |name: &str, qtype| {
+    query.questions.push(Question {
+      name: name.to_string(),
+      qtype,
+      unicast: false,
+    })
+  };
+  for instance in instances(records) {
+    if find(records, instance, TYPE_TXT).next().is_none() {
+      ask(instance, TYPE_TXT);
+    }
+    match srv(records, instance) {
+      None => ask(instance, TYPE_SRV),
+      Some((_, _, target))
+        if find(records, target, TYPE_A).chain(find(records, target, TYPE_AAAA)).next().is_none() =>
+      {
+        ask(target, TYPE_A);
+        ask(target, TYPE_AAAA);
+      }
+      Some(_) => {}
+    }
+  }
+  query
+}
+
+async fn send_query(socket: Option<&UdpSocket>, socket_v6: Option<&UdpSocket>, query: &[u8]) {
+  if let Some(socket) = socket &&
+    let Err(err) = socket.send_to(query, SocketAddr::from((MDNS_GROUP_V4, MDNS_PORT))).await
+  {
+    debug!("Failed to send mDNS query over IPv4: {err}");
+  }
+  if let Some(socket_v6) = socket_v6 {
+    for index in ipv6_interfaces() {
+      if let Err(err) = socket_v6.send_to(query, SocketAddrV6::new(MDNS_GROUP_V6, MDNS_PORT, 0, index)).await {
+        debug!("Failed to send mDNS query on interface {index}: {err}");
+      }
+    }
+  }
+}
+
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+  match socket {
+    Some(socket) => socket.recv_from(buf).await,
+    None => std::future::pending().await,
+  }
+}
+
+fn handle_response(msg: &[u8], addr: SocketAddr, records: &mut Records) {
+  let resp = match Message::decode(msg) {
+    Ok(resp) if resp.response => resp,
+    Ok(_) => return,
+    Err(err) => {
+      debug!("Invalid mDNS response from {addr}: {err}");
+      return;
+    }
+  };
+  let scope_id = match addr {
+    SocketAddr::V6(addr) if addr.scope_id() != 0 => Some(addr.scope_id()),
+    _ => None,
+  };
+  // Records with a TTL of 0 withdraw a service that is going away
+  for record in resp.answers.into_iter().chain(resp.additionals).filter(|record| record.ttl > 0) {
+    if !records.iter().any(|(r, _)| r.name == record.name && r.data == record.data) {
+      records.push((record, scope_id));
+    }
+  }
+}
+
+/// Collect the responses that come in within [`RESPONSE_WINDOW`].
+async fn collect(socket: Option<&UdpSocket>, socket_v6: Option<&UdpSocket>, records: &mut Records) {
+  let mut buf = [0u8; 9000];
+  let mut buf_v6 = [0u8; 9000];
+  let collect = async {
+    loop {
+      let (r, msg) = select! {
+          r = recv_from(socket, &mut buf) => (r, &buf[..]),
+          r = recv_from(socket_v6, &mut buf_v6) => (r, &buf_v6[..]),
+      };
+      match r {
+        Ok((size, addr)) => handle_response(&msg[..size], addr, records),
+        Err(err) => debug!("Failed to receive mDNS response: {err}"),
+      }
+    }
+  };
+  let _ = tokio::time::timeout(RESPONSE_WINDOW, collect).await;
+}
+
+/// Discovery over mDNS. Records are kept across rounds, so that each query only asks for those still missing.
+pub(super) struct MdnsLookup {
+  socket: Option<UdpSocket>,
+  socket_v6: Option<UdpSocket>,
+  records: Records,
+}
+
+impl MdnsLookup {
+  /// Returns `None` if no socket can be opened.
+  pub(super) async fn new() -> Option<Self> {
+    let socket = match UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await {
+      Ok(socket) => Some(socket),
+      Err(err) => {
+        debug!("Looking up controllers over mDNS without IPv4: {err}");
+        None
+      }
+    };
+    let socket_v6 = match UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)).await {
+      Ok(socket) => Some(socket),
+      Err(err) => {
+        debug!("Looking up controllers over mDNS without IPv6: {err}");
+        None
+      }
+    };
+    if socket.is_none() && socket_v6.is_none() {
+      warn!("Failed to look up controllers over mDNS");
+      return None;
+    }
+    Some(MdnsLookup {
+      socket,
+      socket_v6,
+      records: vec![],
+    })
+  }
+
+  /// Send a query and collect the responses for [`RESPONSE_WINDOW`]. Returns the URLs of the controllers resolved so
+  /// far whose claimed fingerprint is in `trusted`, or all of them if it is empty.
+  pub(super) async fn round(&mut self, trusted: &[String]) -> Vec<Advertisement> {
+    match query(&self.records).encode() {
+      Ok(query) => {
+        debug!("Sending mDNS query for {SERVICE_TYPE}");
+        send_query(self.socket.as_ref(), self.socket_v6.as_ref(), &query).await;
+      }
+      Err(err) => warn!("Failed to encode mDNS query: {err}"),
+    }
+    collect(self.socket.as_ref(), self.socket_v6.as_ref(), &mut self.records).await;
+    let advertisements = resolve(&self.records, trusted);
+    if !advertisements.is_empty() {
+      info!("Resolved {} controller URLs over mDNS", advertisements.len());
+    }
+    advertisements
+  }
+}
+
+fn resolve(records: &Records, trusted: &[String]) -> Vec<Advertisement> {
+  let mut advertisements = vec![];
+  for instance in instances(records) {
+    let Some((priority, port, target)) = srv(records, instance) else {
+      continue;
+    };
+    let txt = txt(records, instance);
+    let fingerprint = txt_value(txt, "fp");
+    if !trusted.is_empty() && !fingerprint.is_some_and(|fp| trusted.iter().any(|t| t == fp)) {
+      warn!("Ignoring mDNS advertisement of untrusted controller {instance}");
+      continue;
+    }
+    // `wss://` URLs on the HTTPS port, and `ws://` ones on the port of the SRV record unless TLS is required
+    let tls = txt_value(txt, "tls").unwrap_or("0");
+    let https_port = txt_value(txt, "https_port").and_then(|port| port.parse::<u16>().ok()).filter(|_| tls != "0");
+    let mut urls = vec![];
+    if let Some(https_port) = https_port {
+      urls.push(("wss", https_port));
+    }
+    if tls != "required" {
+      urls.push(("ws", port));
+    }
+    for (record, scope_id) in find(records, target, TYPE_A).chain(find(records, target, TYPE_AAAA)) {
+      let (host, scope_id) = match record.data {
+        RData::A(ip) => (ip.to_string(), None),
+        RData::Aaaa(ip) if ip.is_unicast_link_local() => (format!("[{ip}]"), *scope_id),
+        RData::Aaaa(ip) => (format!("[{ip}]"), None),
+        _ => continue,
+      };
+      for (scheme, port) in &urls {
+        advertisements.push(Advertisement {
+          url: format!("{scheme}://{host}:{port}/ws"),
+          scope_id,
+          fingerprint: fingerprint.map(str::to_string),
+          verified: false,
+          version: txt_value(txt, "version").and_then(|v| v.parse().ok()),
+          priority,
+          cert_fingerprint: txt_value(txt, "cert").map(str::to_string),
+        });
+      }
+    }
+  }
+  advertisements
+}
@@ -0,0 +1,630 @@
+mod mdns;
+
+use crate::{
+  protocol::{
+    discovery::{
+      DISCOVERY_MULTICAST_V6, DISCOVERY_PORT, DiscoveryRequest, DiscoveryResponse, MAGIC_REQUEST, MAGIC_RESPONSE,
+      PROTOCOL_REV,
+    },
+    messaging::PROTOCOL_VERSION,
+  },
+  utils::util::random_str,
+};
+use futures_util::future::join_all;
+use log::{debug, error, info, warn};
+use reqwest::Url;
+use std::{
+  fmt::{self, Display},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+  str::{self, FromStr},
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+  join,
+  net::{TcpStream, UdpSocket, lookup_host},
+  select,
+};
+use url::Host;
+
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+  #[error("No controller found")]
+  NoControllerFound,
+  #[error("Io Error: {0}")]
+  IoError(#[from] std::io::Error),
+  #[error("Decode Error: {0}")]
+  DecodeError(#[from] std::str::Utf8Error),
+  #[error("Protocol Error: {0}")]
+  ProtocolError(&'static str),
+  #[error("Deserialization Error: {0}")]
+  DeserializationError(#[from] serde_json::Error),
+}
+
+/// URL of a controller, with the interface it was discovered on if it is a link-local IPv6 address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+  pub url: Url,
+  /// Index of the local interface link-local addresses are reached through
+  pub scope_id: Option<u32>,
+  /// Fingerprint of the key the controller was advertised with, which the handshake has to prove it holds
+  pub fingerprint: Option<String>,
+  /// SHA-256 of the certificate a trusted controller advertised in a signed response for a `wss://` URL
+  pub cert_fingerprint: Option<String>,
+}
+
+impl Endpoint {
+  pub fn new(url: Url) -> Self {
+    Endpoint {
+      url,
+      scope_id: None,
+      fingerprint: None,
+      cert_fingerprint: None,
+    }
+  }
+
+  /// Whether the controller is reached over TLS.
+  pub fn is_secure(&self) -> bool { self.url.scheme() == "wss" }
+
+  /// Whether the host is a link-local IPv6 address, only reachable through the interface in `scope_id`.
+  pub fn is_link_local(&self) -> bool { matches!(self.url.host(), Some(Host::Ipv6(ip)) if ip.is_unicast_link_local()) }
+
+  /// Resolve the addresses to connect to.
+  pub async fn socket_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{msg}: {}", self.url));
+    let port = self.url.port_or_known_default().ok_or_else(|| invalid("No port in URL"))?;
+    match self.url.host() {
+      Some(Host::Ipv4(ip)) => Ok(vec![SocketAddr::new(IpAddr::V4(ip), port)]),
+      Some(Host::Ipv6(ip)) => {
+        let scope_id = if ip.is_unicast_link_local() {
+          self.scope_id.unwrap_or(0)
+        } else {
+          0
+        };
+        Ok(vec![SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))])
+      }
+      Some(Host::Domain(domain)) => Ok(lookup_host((domain, port)).await?.collect()),
+      None => Err(invalid("No host in URL")),
+    }
+  }
+}
+
+impl Display for Endpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.scope_id {
+      Some(scope_id) if self.is_link_local() => write!(f, "{} (interface {scope_id})", self.url),
+      _ => write!(f, "{}", self.url),
+    }
+  }
+}
+
+/// Indexes of the interfaces with an IPv6 address, where discovery runs over multicast.
+pub fn ipv6_interfaces() -> Vec<u32> {
+  let interfaces = match if_addrs::get_if_addrs() {
+    Ok(interfaces) => interfaces,
+    Err(err) => {
+      error!("Failed to list network interfaces: {err}");
+      return vec![];
+    }
+  };
+  let mut indexes: Vec<u32> = interfaces
+    .iter()
+    .filter(|if_| !if_.is_loopback() && if_.ip().is_ipv6())
+    .filter_map(|if_| if_.index)
+    .collect();
+  indexes.sort_unstable();
+  indexes.dedup();
+  indexes
+}
+
+/// Send the request to the multicast group on every IPv6 interface. Returns on how many it was sent.
+async fn send_multicast(socket: &UdpSocket, req: &[u8]) -> usize {
+  let mut sent = 0;
+  for index in ipv6_interfaces() {
+    match socket.send_to(req, SocketAddrV6::new(DISCOVERY_MULTICAST_V6, DISCOVERY_PORT, 0, index)).await {
+      Ok(_) => sent += 1,
+      Err(err) => debug!("Failed to send discovery request on interface {index}: {err}"),
+    }
+  }
+  sent
+}
+
+/// How long responses are collected after each request
+const RESPONSE_WINDOW: Duration = Duration::from_secs(3);
+
+/// A controller URL from a discovery response or an mDNS advertisement, with what it is ranked by.
+#[derive(Debug, Clone)]
+struct Advertisement {
+  url: String,
+  /// Interface the advertisement came in through, for link-local URLs
+  scope_id: Option<u32>,
+  /// Fingerprint of the controller key, only claimed over mDNS
+  fingerprint: Option<String>,
+  /// Whether `fingerprint` is that of the key a discovery response was signed with
+  verified: bool,
+  /// Version of the messaging protocol the controller speaks
+  version: Option<u32>,
+  /// Preference advertised by the controller, lower values first
+  priority: u16,
+  /// SHA-256 of the certificate of a `wss://` URL, as unauthenticated as `fingerprint` unless `verified`
+  cert_fingerprint: Option<String>,
+}
+
+/// A reachable controller.
+struct Candidate {
+  endpoint: Endpoint,
+  /// Time the reachability check took
+  rtt: Duration,
+  advertisement: Advertisement,
+}
+
+impl Candidate {
+  /// Candidates are tried in the order of this key: trusted controllers first, then those with a verified key,
+  /// then those speaking the same protocol version, over TLS, by advertised priority, and the fastest to respond
+  /// first. Link-local addresses are a last resort among controllers of the same trust: file tasks do not work over
+  /// them, since HTTP clients cannot reach an address without the interface it is scoped to.
+  ///
+  /// Only fingerprints of signed responses count, anyone can claim a trusted fingerprint and a low priority in an
+  /// unsigned response or over mDNS.
+  fn rank(&self, trusted: &[String]) -> (u8, bool, u8, bool, u16, Duration) {
+    let trust = match &self.advertisement.fingerprint {
+      Some(fingerprint) if self.advertisement.verified && trusted.contains(fingerprint) => 0,
+      Some(_) if self.advertisement.verified => 1,
+      _ => 2,
+    };
+    let version = match self.advertisement.version {
+      Some(PROTOCOL_VERSION) => 0,
+      None => 1,
+      Some(_) => 2,
+    };
+    (
+      trust,
+      self.endpoint.is_link_local(),
+      version,
+      !self.endpoint.is_secure(),
+      self.advertisement.priority,
+      self.rtt,
+    )
+  }
+}
+
+/// Check which advertised URLs are reachable, each once.
+///
+/// The certificate of a `wss://` URL is only pinned if a controller in `trusted` signed the response advertising it,
+/// other URLs have to pass the usual certificate checks.
+async fn check(mut advertisements: Vec<Advertisement>, trusted: &[String]) -> Vec<Candidate> {
+  let mut seen = vec![];
+  advertisements.retain(|ad| {
+    let key = (ad.url.clone(), ad.scope_id);
+    let new = !seen.contains(&key);
+    seen.push(key);
+    new
+  });
+  join_all(advertisements.into_iter().map(async |advertisement| {
+    let (mut endpoint, rtt) = handle_url(&advertisement.url, advertisement.scope_id).await?;
+    endpoint.fingerprint = advertisement.fingerprint.clone();
+    let trusted = advertisement.verified && advertisement.fingerprint.as_ref().is_some_and(|fp| trusted.contains(fp));
+    if endpoint.is_secure() && trusted {
+      endpoint.cert_fingerprint = advertisement.cert_fingerprint.clone();
+    }
+    Some(Candidate {
+      endpoint,
+      rtt,
+      advertisement,
+    })
+  }))
+  .await
+  .into_iter()
+  .flatten()
+  .collect()
+}
+
+/// Order the candidates by [`Candidate::rank`].
+fn rank(mut candidates: Vec<Candidate>, trusted: &[String]) -> Vec<Endpoint> {
+  candidates.sort_by_key(|candidate| candidate.rank(trusted));
+  for Candidate {
+    endpoint,
+    rtt,
+    advertisement,
+  } in &candidates
+  {
+    debug!(
+      "Controller candidate {endpoint}: key {}, protocol version {}, priority {}, responded in {rtt:?}",
+      advertisement.fingerprint.as_deref().unwrap_or("unknown"),
+      advertisement.version.map_or("unknown".to_string(), |v| v.to_string()),
+      advertisement.priority
+    );
+  }
+  candidates.into_iter().map(|candidate| candidate.endpoint).collect()
+}
+
+/// Look up the controllers on the local network, with the UDP discovery protocol and over mDNS/DNS-SD at once.
+/// Responses are collected for a few seconds, and the reachable controllers are returned in the order they should
+/// be tried in.
+///
+/// If `trusted` lists controller key fingerprints, only controllers advertised with one of those keys are returned.
+/// Keys advertised over mDNS are not authenticated, the handshake has to check that the controller holds the key of
+/// the returned [`Endpoint::fingerprint`].
+pub async fn discover_controller_once(trusted: &[String]) -> Result<Vec<Endpoint>, DiscoveryError> {
+  info!("Discovering controller");
+  let udp = UdpLookup::new().await?;
+  let mut mdns = mdns::MdnsLookup::new().await;
+  for _ in 0..10 {
+    let from_mdns = async {
+      match &mut mdns {
+        Some(mdns) => mdns.round(trusted).await,
+        None => vec![],
+      }
+    };
+    let (from_udp, from_mdns) = join!(udp.round(trusted), from_mdns);
+    let mut advertisements = match from_udp {
+      Ok(advertisements) => advertisements,
+      Err(err) if mdns.is_none() => return Err(err),
+      Err(err) => {
+        debug!("Discovering over mDNS only: {err}");
+        vec![]
+      }
+    };
+    // Signed responses first, so that their verified fingerprint is kept for URLs also advertised over mDNS
+    advertisements.extend(from_mdns);
+    if advertisements.is_empty() {
+      info!("Discovery timeout");
+      continue;
+    }
+    let candidates = check(advertisements, trusted).await;
+    info!("Discovered {} controllers", candidates.len());
+    if candidates.is_empty() {
+      warn!("No controllers found");
+    } else {
+      return Ok(rank(candidates, trusted));
+    }
+  }
+  warn!("No controller found");
+  Err(DiscoveryError::NoControllerFound)
+}
+
+/// Discovery with the UDP protocol, by IPv4 broadcast and IPv6 link-local multicast.
+struct UdpLookup {
+  socket: UdpSocket,
+  socket_v6: Option<UdpSocket>,
+  req: DiscoveryRequest,
+}
+
+impl UdpLookup {
+  async fn new() -> Result<Self, DiscoveryError> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    socket.set_broadcast(true)?;
+    let socket_v6 = match UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)).await {
+      Ok(socket) => Some(socket),
+      Err(err) => {
+        debug!("Discovering over IPv4 only: {err}");
+        None
+      }
+    };
+    let req = DiscoveryRequest {
+      magic: MAGIC_REQUEST.to_string(),
+      revision: PROTOCOL_REV,
+      nonce: Some(random_str(16)),
+    };
+    Ok(UdpLookup { socket, socket_v6, req })
+  }
+
+  /// Send the request and collect the responses for [`RESPONSE_WINDOW`]. Only responses signed by a key in `trusted`
+  /// are accepted, unless it is empty.
+  async fn round(&self, trusted: &[String]) -> Result<Vec<Advertisement>, DiscoveryError> {
+    let nonce = self.req.nonce.as_deref().unwrap_or_default();
+    let req_str = self.req.to_string();
+    let req_bin = req_str.as_bytes();
+    debug!("Sending discovery request: {req_str}");
+    let sent_v4 = self
+      .socket
+      .send_to(
+        req_bin,
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DISCOVERY_PORT),
+      )
+      .await;
+    let sent_v6 = match &self.socket_v6 {
+      Some(socket_v6) => send_multicast(socket_v6, req_bin).await,
+      None => 0,
+    };
+    if let Err(err) = sent_v4 {
+      // IPv6-only networks have no IPv4 route to broadcast on
+      if sent_v6 == 0 {
+        return Err(err.into());
+      }
+      debug!("Failed to send discovery request over IPv4: {err}");
+    }
+
+    let mut advertisements = vec![];
+    let collect = async {
+      loop {
+        let r = match &self.socket_v6 {
+          Some(socket_v6) => select! {
+            r = recv_pack(&self.socket, nonce, trusted) => r,
+            r = recv_pack(socket_v6, nonce, trusted) => r,
+          },
+          None => recv_pack(&self.socket, nonce, trusted).await,
+        };
+        match r {
+          Ok((resp, addr, fingerprint)) => advertisements.extend(handle_resp(resp, addr, fingerprint)),
+          Err(err) => error!("Failed to handle discovery message: {err}"),
+        }
+      }
+    };
+    let _ = tokio::time::timeout(RESPONSE_WINDOW, collect).await;
+    Ok(advertisements)
+  }
+}
+
+async fn recv_pack(
+  socket: &UdpSocket, nonce: &str, trusted: &[String],
+) -> Result<(DiscoveryResponse, SocketAddr, Option<String>), DiscoveryError> {
+  let mut buf = [0u8; 4096];
+  match socket.recv_from(&mut buf).await {
+    Ok((size, addr)) => {
+      info!("Received discovery response from {addr}");
+      let msg = str::from_utf8(&buf[..size])?;
+      let resp: DiscoveryResponse = DiscoveryResponse::from_str(msg)?;
+      if resp.magic != MAGIC_RESPONSE {
+        error!("Invalid magic: {}", resp.magic);
+        return Err(DiscoveryError::ProtocolError("Invalid magic"));
+      }
+      let fingerprint = check_signature(&resp, nonce, trusted)?;
+      Ok((resp, addr, fingerprint))
+    }
+    Err(err) => {
+      error!("Failed to receive data: {err}");
+      Err(err.into())
+    }
+  }
+}
+
+/// Returns the fingerprint of the key the response is signed with, if it is.
+fn check_signature(
+  resp: &DiscoveryResponse, nonce: &str, trusted: &[String],
+) -> Result<Option<String>, DiscoveryError> {
+  if !resp.is_signed() {
+    if !trusted.is_empty() {
+      return Err(DiscoveryError::ProtocolError("Unsigned response"));
+    }
+    debug!("Discovery response is not signed");
+    return Ok(None);
+  }
+  let Some(fingerprint) = resp.verify(nonce) else {
+    return Err(DiscoveryError::ProtocolError("Invalid or stale signature"));
+  };
+  if !trusted.is_empty() && !trusted.contains(&fingerprint) {
+    warn!("Ignoring discovery response of untrusted controller {fingerprint}");
+    return Err(DiscoveryError::ProtocolError("Untrusted controller"));
+  }
+  info!("Discovery response signed by controller {fingerprint}");
+  Ok(Some(fingerprint))
+}
+
+fn handle_resp(resp: DiscoveryResponse, addr: SocketAddr, fingerprint: Option<String>) -> Vec<Advertisement> {
+  // Link-local addresses in the response are on the link the response came in through
+  let scope_id = match addr {
+    SocketAddr::V6(addr) if addr.scope_id() != 0 => Some(addr.scope_id()),
+    _ => None,
+  };
+  resp
+    .ws
+    .into_iter()
+    .map(|url| {
+      // Other URLs are the same whichever link the response came in through
+      let link_local = Url::from_str(&url).is_ok_and(|url| Endpoint::new(url).is_link_local());
+      Advertisement {
+        url,
+        scope_id: scope_id.filter(|_| link_local),
+        verified: fingerprint.is_some(),
+        fingerprint: fingerprint.clone(),
+        version: resp.version,
+        priority: resp.priority.unwrap_or_default(),
+        cert_fingerprint: resp.cert_fingerprint.clone(),
+      }
+    })
+    .collect()
+}
+
+async fn https_ping(url: Url) -> bool {
+  let r = match reqwest::Client::builder()
+    .danger_accept_invalid_certs(true) // Controller may use self-signed certs, and we don't care because it's not being used to authenticate
+    .build()
+  {
+    Ok(v) => v,
+    Err(e) => {
+      error!("Failed to create HTTP client: {e}");
+      return false;
+    }
+  };
+  let r = match r.head(url.clone()).timeout(Duration::from_secs(5)).send().await {
+    Ok(v) => v,
+    Err(e) => {
+      warn!("Failed to send request to {url}: {e}");
+      return false;
+    }
+  };
+  r.status().is_success()
+}
+
+/// Check that a controller on a link-local address accepts connections, which HTTP clients cannot test.
+async fn tcp_ping(endpoint: &Endpoint) -> bool {
+  let addrs = match endpoint.socket_addrs().await {
+    Ok(addrs) => addrs,
+    Err(e) => {
+      warn!("Failed to resolve {endpoint}: {e}");
+      return false;
+    }
+  };
+  match tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(&*addrs)).await {
+    Ok(Ok(_)) => true,
+    Ok(Err(e)) => {
+      warn!("Failed to connect to {endpoint}: {e}");
+      false
+    }
+    Err(_) => {
+      warn!("Timed out connecting to {endpoint}");
+      false
+    }
+  }
+}
+
+async fn test_url(endpoint: &Endpoint) -> bool {
+  let mut url = endpoint.url.clone();
+  match url.scheme() {
+    "ws" => {
+      if url.set_scheme("http").is_err() {
+        error!("Failed to set scheme for {url}");
+        return false;
+      }
+    }
+    "wss" => {
+      if url.set_scheme("https").is_err() {
+        error!("Failed to set scheme for {url}");
+        return false;
+      }
+    }
+    "http" | "https" => {}
+    _ => {
+      error!("Unsupported URL scheme");
+      return false;
+    }
+  }
+  if endpoint.is_link_local() {
+    return tcp_ping(endpoint).await;
+  }
+  https_ping(url).await
+}
+
+/// Check that the controller at `url` is reachable. Returns its endpoint and the time the check took.
+async fn handle_url(url: &str, scope_id: Option<u32>) -> Option<(Endpoint, Duration)> {
+  let Ok(url) = Url::from_str(url) else {
+    warn!("Invalid URL: {url}");
+    return None;
+  };
+  let mut endpoint = Endpoint {
+    scope_id,
+    ..Endpoint::new(url)
+  };
+  if endpoint.is_link_local() && scope_id.is_none() {
+    warn!(
+      "Ignoring link-local controller URL received over IPv4: {}",
+      endpoint.url
+    );
+    return None;
+  }
+  let start = Instant::now();
+  if !test_url(&endpoint).await {
+    warn!("Invalid controller URL: {endpoint}");
+    return None;
+  }
+  let rtt = start.elapsed();
+  let url = &mut endpoint.url;
+  match url.scheme() {
+    "ws" | "wss" => {}
+    "http" => {
+      if url.set_scheme("ws").is_err() {
+        error!("Failed to set scheme for {url}");
+        return None;
+      }
+    }
+    "https" => {
+      if url.set_scheme("wss").is_err() {
+        error!("Failed to set scheme for {url}");
+        return None;
+      }
+    }
+    _ => {
+      unreachable!() // This should never happen because we check the scheme in test_url
+    }
+  }
+  Some((endpoint, rtt))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn candidate(
+    url: &str, fingerprint: Option<&str>, verified: bool, version: u32, priority: u16, rtt: u64,
+  ) -> Candidate {
+    Candidate {
+      endpoint: Endpoint::new(Url::parse(url).unwrap()),
+      rtt: Duration::from_millis(rtt),
+      advertisement: Advertisement {
+        url: url.to_string(),
+        scope_id: None,
+        fingerprint: fingerprint.map(str::to_string),
+        verified,
+        version: Some(version),
+        priority,
+        cert_fingerprint: None,
+      },
+    }
+  }
+
+  #[tokio::test]
+  async fn test_endpoint() {
+    let endpoint = |url: &str, scope_id| Endpoint {
+      scope_id,
+      ..Endpoint::new(Url::parse(url).unwrap())
+    };
+
+    let link_local = endpoint("ws://[fe80::1]:8080/ws", Some(3));
+    assert!(link_local.is_link_local());
+    assert_eq!(
+      link_local.socket_addrs().await.unwrap(),
+      [SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 8080, 0, 3))]
+    );
+
+    // The scope only applies to link-local addresses
+    let global = endpoint("wss://[2001:db8::1]/ws", Some(3));
+    assert!(!global.is_link_local());
+    assert_eq!(
+      global.socket_addrs().await.unwrap(),
+      [SocketAddr::V6(SocketAddrV6::new("2001:db8::1".parse().unwrap(), 443, 0, 0))]
+    );
+
+    let v4 = endpoint("ws://192.0.2.1:8080/ws", None);
+    assert!(!v4.is_link_local());
+    assert_eq!(v4.socket_addrs().await.unwrap(), ["192.0.2.1:8080".parse().unwrap()]);
+    assert!(!endpoint("ws://localhost:8080/ws", None).is_link_local());
+  }
+
+  #[test]
+  fn test_rank() {
+    let trusted = vec!["t".to_string()];
+    let candidates = vec![
+      candidate("ws://10.0.0.1:8080/ws", Some("t"), false, PROTOCOL_VERSION, 0, 1),
+      candidate("ws://10.0.0.2:8080/ws", Some("t"), true, PROTOCOL_VERSION, 9, 9),
+      candidate("ws://10.0.0.3:8080/ws", Some("o"), true, PROTOCOL_VERSION, 0, 1),
+      candidate("ws://10.0.0.4:8080/ws", None, false, PROTOCOL_VERSION - 1, 0, 1),
+      candidate("wss://10.0.0.5:8443/ws", None, false, PROTOCOL_VERSION, 5, 9),
+      candidate("ws://10.0.0.6:8080/ws", None, false, PROTOCOL_VERSION, 1, 1),
+      candidate("ws://[fe80::1]:8080/ws", None, false, PROTOCOL_VERSION, 2, 1),
+      candidate("ws://10.0.0.7:8080/ws", None, false, PROTOCOL_VERSION, 2, 5),
+      candidate("ws://10.0.0.8:8080/ws", None, false, PROTOCOL_VERSION, 2, 3),
+    ];
+    let hosts: Vec<String> = rank(candidates, &trusted)
+      .iter()
+      .map(|endpoint| endpoint.url.host_str().unwrap().to_string())
+      .collect();
+    assert_eq!(
+      hosts,
+      [
+        // Verified and trusted, then verified
+        "10.0.0.2",
+        "10.0.0.3",
+        // A claimed trusted fingerprint counts for nothing, TLS first, then by priority and RTT
+        "10.0.0.5",
+        "10.0.0.1",
+        "10.0.0.6",
+        "10.0.0.8",
+        "10.0.0.7",
+        // Older protocol version, then link-local last
+        "10.0.0.4",
+        "[fe80::1]",
+      ]
+    );
+  }
+}
@@ -43,6 +43,35 @@ fn verify(data: &[u8], pubkey: [u8; 32], signature: &[u8; 64]) -> bool {
   verifying_key.verify(data, &sig).is_ok()
 }
 
+fn decode_privkey(privkey: &str) -> Result<[u8; 32]> {
+  let privkey = base64::engine::general_purpose::STANDARD
+    .decode(privkey)
+    .map_err(|e| anyhow::anyhow!("Failed to decode private key: {}", e))?;
+  privkey.try_into().map_err(|_| anyhow::anyhow!("Invalid private key length"))
+}
+
+/// Sign `data` with a base64 encoded private key. Returns the base64 encoded public key and signature.
+pub fn sign_with_privkey_string(data: &[u8], privkey: &str) -> Result<(String, String)> {
+  let privkey = decode_privkey(privkey)?;
+  let signature = sign(data, &privkey)?;
+  Ok((
+    base64::engine::general_purpose::STANDARD.encode(derive_pubkey(&privkey)),
+    base64::engine::general_purpose::STANDARD.encode(signature),
+  ))
+}
+
+/// Verify a signature made by [`sign_with_privkey_string`].
+pub fn verify_with_pubkey_string(data: &[u8], pubkey: &str, signature: &str) -> bool {
+  let engine = base64::engine::general_purpose::STANDARD;
+  let (Ok(Ok(pubkey)), Ok(Ok(signature))) = (
+    engine.decode(pubkey).map(<[u8; 32]>::try_from),
+    engine.decode(signature).map(<[u8; 64]>::try_from),
+  ) else {
+    return false;
+  };
+  verify(data, pubkey, &signature)
+}
+
 #[derive(Debug, Clone)]
 pub struct AuthRequest {
   pub rev: u32,
@@ -53,16 +82,17 @@ pub struct AuthRequest {
 }
 
 impl AuthRequest {
-  pub fn new(privkey: [u8; 32]) -> Result<Self> {
+  pub fn new(privkey: [u8; 32]) -> Result<Self> { Self::new_with_challenge(privkey, &[]) }
+
+  /// Sign `challenge` along with the request, so that it only authenticates the exchange the challenge came from.
+  pub fn new_with_challenge(privkey: [u8; 32], challenge: &[u8]) -> Result<Self> {
     let timestamp = std::time::UNIX_EPOCH.elapsed()?.as_secs();
     let nonce = rand::random::<[u8; 16]>();
-    let mut buf = BytesMut::with_capacity(4 + 8 + 16 + 32);
     let pubkey = derive_pubkey(&privkey);
-    buf.put_u32_le(PROTOCOL_REV);
-    buf.put_u64_le(timestamp);
-    buf.put_slice(&nonce);
-    buf.put_slice(&pubkey);
-    let signature = sign(&buf, &privkey)?;
+    let signature = sign(
+      &Self::signed_data(PROTOCOL_REV, timestamp, &nonce, &pubkey, challenge),
+      &privkey,
+    )?;
     Ok(Self {
       rev: PROTOCOL_REV,
       timestamp,
@@ -72,17 +102,26 @@ impl AuthRequest {
     })
   }
 
-  pub fn new_with_privkey_string(privkey: &str) -> Result<Self> {
-    let privkey = base64::engine::general_purpose::STANDARD
-      .decode(privkey)
-      .map_err(|e| anyhow::anyhow!("Failed to decode private key: {}", e))?;
-    let Ok(privkey) = privkey.try_into() else {
-      return Err(anyhow::anyhow!("Invalid private key length"));
-    };
-    Self::new(privkey)
+  pub fn new_with_privkey_string(privkey: &str) -> Result<Self> { Self::new(decode_privkey(privkey)?) }
+
+  pub fn new_with_privkey_string_and_challenge(privkey: &str, challenge: &[u8]) -> Result<Self> {
+    Self::new_with_challenge(decode_privkey(privkey)?, challenge)
   }
 
-  pub fn verify(&self) -> bool {
+  fn signed_data(rev: u32, timestamp: u64, nonce: &[u8; 16], pubkey: &[u8; 32], challenge: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(4 + 8 + 16 + 32 + challenge.len());
+    buf.put_u32_le(rev);
+    buf.put_u64_le(timestamp);
+    buf.put_slice(nonce);
+    buf.put_slice(pubkey);
+    buf.put_slice(challenge);
+    buf
+  }
+
+  pub fn verify(&self) -> bool { self.verify_challenge(&[]) }
+
+  /// Verify a request made by [`AuthRequest::new_with_challenge`] for `challenge`.
+  pub fn verify_challenge(&self, challenge: &[u8]) -> bool {
     if self.rev != PROTOCOL_REV {
       return false; // Unsupported protocol revision
     }
@@ -94,11 +133,7 @@ impl AuthRequest {
     if i64::abs(self.timestamp as i64 - timestamp as i64) > 3 {
       return false; // Allow a 3 seconds clock skew
     }
-    let mut buf = BytesMut::with_capacity(4 + 8 + 16 + 32);
-    buf.put_u32_le(self.rev);
-    buf.put_u64_le(self.timestamp);
-    buf.put_slice(&self.nonce);
-    buf.put_slice(&self.pubkey);
+    let buf = Self::signed_data(self.rev, self.timestamp, &self.nonce, &self.pubkey, challenge);
     verify(&buf, self.pubkey, &self.signature)
   }
 
@@ -156,6 +191,12 @@ mod tests {
     let decoded = AuthRequest::decode(&encoded).unwrap();
     assert!(decoded.verify());
 
+    let req = AuthRequest::new_with_challenge(privkey, b"challenge").unwrap();
+    let decoded = AuthRequest::decode(&req.encode()).unwrap();
+    assert!(decoded.verify_challenge(b"challenge"));
+    assert!(!decoded.verify_challenge(b"other"));
+    assert!(!decoded.verify());
+
     println!("pubkey: {:?}", decoded.encoded_pubkey());
   }
 }
@@ -1,21 +1,133 @@
-use std::str::FromStr;
+use std::{net::Ipv6Addr, str::FromStr};
 
+use anyhow::Result;
+use bytes::{BufMut as _, BytesMut};
 use serde::{Deserialize, Serialize};
 
+use super::{
+  auth::{sign_with_privkey_string, verify_with_pubkey_string},
+  messaging::PROTOCOL_VERSION,
+};
+use crate::utils::hash::sha2_256_for_str;
+
 pub const PROTOCOL_REV: u32 = 1;
 pub const MAGIC_REQUEST: &str = "MXA-DISCOVER";
 pub const MAGIC_RESPONSE: &str = "MXA-RESPONSE";
 
+/// Largest difference between the clocks of the controller and the agent accepted on signed responses, in seconds
+const MAX_CLOCK_SKEW: u64 = 30;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoveryRequest {
   pub magic: String,
   pub revision: u32,
+  /// Random value the controller echoes in its signed response, so responses cannot be replayed
+  #[serde(default)]
+  pub nonce: Option<String>,
 }
 
+/// Answer of a controller to a [`DiscoveryRequest`].
+///
+/// Controllers sign the response with the key they authenticate to agents with. Responses of older controllers
+/// carry no signature.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoveryResponse {
   pub magic: String,
   pub ws: Vec<String>,
+  /// Version of the messaging protocol the controller speaks
+  #[serde(default)]
+  pub version: Option<u32>,
+  /// Preference of the controller when several answer, lower values first as in DNS SRV records
+  #[serde(default)]
+  pub priority: Option<u16>,
+  /// SHA-256 of the DER encoded certificate the `wss://` URLs are served with
+  #[serde(default)]
+  pub cert_fingerprint: Option<String>,
+  /// Seconds since the Unix epoch when the response was signed
+  #[serde(default)]
+  pub timestamp: Option<u64>,
+  /// Nonce of the request being answered
+  #[serde(default)]
+  pub nonce: Option<String>,
+  /// Base64 encoded public key of the controller
+  #[serde(default)]
+  pub pubkey: Option<String>,
+  /// Base64 encoded Ed25519 signature
+  #[serde(default)]
+  pub signature: Option<String>,
+}
+
+impl DiscoveryResponse {
+  pub fn new(ws: Vec<String>, priority: u16) -> Self {
+    DiscoveryResponse {
+      magic: MAGIC_RESPONSE.to_string(),
+      ws,
+      version: Some(PROTOCOL_VERSION),
+      priority: Some(priority),
+      cert_fingerprint: None,
+      timestamp: None,
+      nonce: None,
+      pubkey: None,
+      signature: None,
+    }
+  }
+
+  fn signed_data(&self) -> BytesMut {
+    fn put_str(buf: &mut BytesMut, s: &str) {
+      buf.put_u32_le(s.len() as u32);
+      buf.put_slice(s.as_bytes());
+    }
+    let mut buf = BytesMut::new();
+    put_str(&mut buf, &self.magic);
+    put_str(&mut buf, self.nonce.as_deref().unwrap_or_default());
+    for ws in &self.ws {
+      put_str(&mut buf, ws);
+    }
+    buf.put_u64_le(self.timestamp.unwrap_or_default());
+    // Only appended when present, so that responses of controllers without them still verify
+    if let Some(version) = self.version {
+      buf.put_u32_le(version);
+    }
+    if let Some(priority) = self.priority {
+      buf.put_u16_le(priority);
+    }
+    if let Some(cert_fingerprint) = &self.cert_fingerprint {
+      put_str(&mut buf, cert_fingerprint);
+    }
+    buf
+  }
+
+  /// Sign the response to the request carrying `nonce` with a base64 encoded private key.
+  pub fn sign(&mut self, nonce: Option<String>, privkey: &str) -> Result<()> {
+    self.nonce = nonce;
+    self.timestamp = Some(std::time::UNIX_EPOCH.elapsed()?.as_secs());
+    let (pubkey, signature) = sign_with_privkey_string(&self.signed_data(), privkey)?;
+    self.pubkey = Some(pubkey);
+    self.signature = Some(signature);
+    Ok(())
+  }
+
+  /// Whether the response carries a signature at all.
+  pub fn is_signed(&self) -> bool { self.signature.is_some() }
+
+  /// Check that the response answers the request carrying `nonce`, was signed recently, and that the signature is
+  /// valid. Returns the fingerprint of the signing key.
+  pub fn verify(&self, nonce: &str) -> Option<String> {
+    let (Some(timestamp), Some(pubkey), Some(signature)) = (self.timestamp, &self.pubkey, &self.signature) else {
+      return None;
+    };
+    if self.nonce.as_deref() != Some(nonce) {
+      return None;
+    }
+    let now = std::time::UNIX_EPOCH.elapsed().ok()?.as_secs();
+    if now.abs_diff(timestamp) > MAX_CLOCK_SKEW {
+      return None;
+    }
+    if !verify_with_pubkey_string(&self.signed_data(), pubkey, signature) {
+      return None;
+    }
+    sha2_256_for_str(pubkey).ok()
+  }
 }
 
 impl FromStr for DiscoveryResponse {
@@ -41,3 +153,30 @@ impl ToString for DiscoveryRequest {
 }
 
 pub const DISCOVERY_PORT: u16 = 11451;
+/// Link-local multicast group controllers join on every interface to receive discovery requests over IPv6
+pub const DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x2cbb);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protocol::auth::generate_keypair_str;
+
+  #[test]
+  fn test_signed_response() {
+    let (pubkey, privkey) = generate_keypair_str();
+    let mut resp = DiscoveryResponse::new(vec!["ws://192.0.2.1:8080/ws".to_string()], 0);
+    assert!(resp.verify("nonce").is_none());
+    resp.sign(Some("nonce".to_string()), &privkey).unwrap();
+    let resp = DiscoveryResponse::from_str(&resp.to_string()).unwrap();
+    assert_eq!(resp.verify("nonce"), Some(sha2_256_for_str(&pubkey).unwrap()));
+    assert!(resp.verify("other").is_none());
+
+    let mut forged = resp.clone();
+    forged.ws = vec!["ws://198.51.100.1:8080/ws".to_string()];
+    assert!(forged.verify("nonce").is_none());
+
+    let mut forged = resp.clone();
+    forged.priority = Some(10);
+    assert!(forged.verify("nonce").is_none());
+  }
+}
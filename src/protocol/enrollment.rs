@@ -0,0 +1,76 @@
+use anyhow::Result;
+use bytes::{BufMut as _, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use super::auth::{AuthRequest, sign_with_privkey_string, verify_with_pubkey_string};
+
+/// Path of the controller endpoint agents enroll with.
+///
+/// Requests carry the agent authentication header, proving the agent holds the key it enrolls, and are signed with
+/// that key so that the header cannot be reused with another host id, token or CSR.
+pub const ENROLL_PATH: &str = "/enroll";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollRequest {
+  pub host_id: String,
+  /// One-time bootstrap token. Without it, an operator has to approve the agent.
+  pub token: Option<String>,
+  /// PEM encoded certificate signing request, to be issued a client certificate when the controller requires
+  /// mutual TLS
+  pub csr: Option<String>,
+  /// Base64 encoded signature of the request and the nonce of the authentication header
+  #[serde(default)]
+  pub signature: String,
+}
+
+impl EnrollRequest {
+  fn signed_data(&self, auth: &AuthRequest) -> BytesMut {
+    fn put_str(buf: &mut BytesMut, s: Option<&str>) {
+      match s {
+        Some(s) => {
+          buf.put_u8(1);
+          buf.put_u32_le(s.len() as u32);
+          buf.put_slice(s.as_bytes());
+        }
+        None => buf.put_u8(0),
+      }
+    }
+    let mut buf = BytesMut::new();
+    buf.put_slice(&auth.nonce);
+    put_str(&mut buf, Some(&self.host_id));
+    put_str(&mut buf, self.token.as_deref());
+    put_str(&mut buf, self.csr.as_deref());
+    buf
+  }
+
+  /// Sign the request, to be sent along with `auth`, with a base64 encoded private key.
+  pub fn sign(&mut self, auth: &AuthRequest, privkey: &str) -> Result<()> {
+    self.signature = sign_with_privkey_string(&self.signed_data(auth), privkey)?.1;
+    Ok(())
+  }
+
+  /// Whether the request was signed by the key of `auth`, which must have been verified.
+  pub fn verify(&self, auth: &AuthRequest) -> bool {
+    verify_with_pubkey_string(&self.signed_data(auth), &auth.encoded_pubkey(), &self.signature)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnrollStatus {
+  Approved,
+  /// Waiting for an operator, the agent should ask again later
+  Pending,
+  Rejected,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollResponse {
+  pub ok: bool,
+  pub status: Option<EnrollStatus>,
+  /// PEM encoded client certificate, if one was requested and the controller requires mutual TLS
+  pub cert: Option<String>,
+  /// PEM encoded CA certificate of the controller, to pin
+  pub ca_cert: Option<String>,
+  pub reason: Option<String>,
+}
@@ -17,6 +17,19 @@ pub struct ConnectHandshake {
   pub system_info: SystemInfo,
 }
 
+impl ConnectHandshake {
+  /// What the controller signs in its authentication header: the nonce of the agent's authentication header and the
+  /// session the agent opens, so that the header cannot be relayed to another agent or connection.
+  pub fn controller_challenge(&self, agent_nonce: &[u8]) -> Vec<u8> {
+    let mut challenge = agent_nonce.to_vec();
+    for s in [&self.host_id, &self.session_id] {
+      challenge.extend_from_slice(&(s.len() as u32).to_le_bytes());
+      challenge.extend_from_slice(s.as_bytes());
+    }
+    challenge
+  }
+}
+
 #[allow(clippy::to_string_trait_impl)]
 impl ToString for ConnectHandshake {
   fn to_string(&self) -> String {
@@ -0,0 +1,361 @@
+//! The subset of DNS messages used to advertise and look up controllers with DNS-SD over multicast DNS
+//! (RFC 6762, RFC 6763).
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Result, bail};
+use bytes::{BufMut as _, BytesMut};
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+/// DNS-SD service type controllers are advertised as
+pub const SERVICE_TYPE: &str = "_mxlite._tcp.local";
+/// Name browsers query to enumerate the service types on the network
+pub const SERVICES_META_QUERY: &str = "_services._dns-sd._udp.local";
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Top bit of the class, cache-flush on records and unicast-response on questions
+const CLASS_FLAG: u16 = 0x8000;
+/// Authoritative response
+const FLAGS_RESPONSE: u16 = 0x8400;
+const FLAG_QR: u16 = 0x8000;
+
+/// Whether two domain names are the same. Names compare case-insensitively.
+pub fn name_eq(a: &str, b: &str) -> bool { a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.')) }
+
+/// Value of `key` in the `key=value` strings of a TXT record.
+pub fn txt_value<'a>(txt: &'a [String], key: &str) -> Option<&'a str> {
+  txt
+    .iter()
+    .find_map(|entry| entry.split_once('=').filter(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+  A(Ipv4Addr),
+  Aaaa(Ipv6Addr),
+  Ptr(String),
+  /// Target host and port of a service, with the priority of this target, lower values first
+  Srv {
+    priority: u16,
+    port: u16,
+    target: String,
+  },
+  Txt(Vec<String>),
+  /// Record of a type not used here
+  Other(u16),
+}
+
+impl RData {
+  pub fn rtype(&self) -> u16 {
+    match self {
+      RData::A(_) => TYPE_A,
+      RData::Aaaa(_) => TYPE_AAAA,
+      RData::Ptr(_) => TYPE_PTR,
+      RData::Srv { .. } => TYPE_SRV,
+      RData::Txt(_) => TYPE_TXT,
+      RData::Other(rtype) => *rtype,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+  pub name: String,
+  /// Seconds the record may be cached, 0 to withdraw it
+  pub ttl: u32,
+  pub data: RData,
+}
+
+impl Record {
+  pub fn new(name: impl Into<String>, ttl: u32, data: RData) -> Self {
+    Record {
+      name: name.into(),
+      ttl,
+      data,
+    }
+  }
+
+  /// Whether the record answers `question`.
+  pub fn answers(&self, question: &Question) -> bool {
+    name_eq(&self.name, &question.name) && (question.qtype == TYPE_ANY || question.qtype == self.data.rtype())
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+  pub name: String,
+  pub qtype: u16,
+  /// Whether the querier asks for a unicast response
+  pub unicast: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+  pub id: u16,
+  pub response: bool,
+  pub questions: Vec<Question>,
+  pub answers: Vec<Record>,
+  pub additionals: Vec<Record>,
+}
+
+impl Message {
+  pub fn query(name: &str, qtype: u16) -> Self {
+    Message {
+      questions: vec![Question {
+        name: name.to_string(),
+        qtype,
+        unicast: false,
+      }],
+      ..Default::default()
+    }
+  }
+
+  pub fn encode(&self) -> Result<Vec<u8>> {
+    let mut buf = BytesMut::with_capacity(512);
+    buf.put_u16(self.id);
+    buf.put_u16(if self.response { FLAGS_RESPONSE } else { 0 });
+    buf.put_u16(self.questions.len() as u16);
+    buf.put_u16(self.answers.len() as u16);
+    buf.put_u16(0);
+    buf.put_u16(self.additionals.len() as u16);
+    for question in &self.questions {
+      put_name(&mut buf, &question.name)?;
+      buf.put_u16(question.qtype);
+      buf.put_u16(if question.unicast {
+        CLASS_IN | CLASS_FLAG
+      } else {
+        CLASS_IN
+      });
+    }
+    for record in self.answers.iter().chain(&self.additionals) {
+      put_record(&mut buf, record)?;
+    }
+    Ok(buf.to_vec())
+  }
+
+  /// Parse a message. Authority records are skipped.
+  pub fn decode(msg: &[u8]) -> Result<Self> {
+    let mut reader = Reader { msg, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+    let mut message = Message {
+      id,
+      response: flags & FLAG_QR != 0,
+      ..Default::default()
+    };
+    for _ in 0..counts[0] {
+      let name = reader.name()?;
+      let qtype = reader.u16()?;
+      let class = reader.u16()?;
+      message.questions.push(Question {
+        name,
+        qtype,
+        unicast: class & CLASS_FLAG != 0,
+      });
+    }
+    for _ in 0..counts[1] {
+      message.answers.push(reader.record()?);
+    }
+    for _ in 0..counts[2] {
+      reader.record()?;
+    }
+    for _ in 0..counts[3] {
+      message.additionals.push(reader.record()?);
+    }
+    Ok(message)
+  }
+}
+
+fn put_name(buf: &mut BytesMut, name: &str) -> Result<()> {
+  for label in name.trim_end_matches('.').split('.') {
+    if label.is_empty() || label.len() > 63 {
+      bail!("Invalid DNS name: {name}");
+    }
+    buf.put_u8(label.len() as u8);
+    buf.put_slice(label.as_bytes());
+  }
+  buf.put_u8(0);
+  Ok(())
+}
+
+fn put_record(buf: &mut BytesMut, record: &Record) -> Result<()> {
+  let mut data = BytesMut::new();
+  match &record.data {
+    RData::A(ip) => data.put_slice(&ip.octets()),
+    RData::Aaaa(ip) => data.put_slice(&ip.octets()),
+    RData::Ptr(name) => put_name(&mut data, name)?,
+    RData::Srv { priority, port, target } => {
+      data.put_u16(*priority);
+      data.put_u16(0);
+      data.put_u16(*port);
+      put_name(&mut data, target)?;
+    }
+    RData::Txt(entries) => {
+      for entry in entries {
+        if entry.len() > 255 {
+          bail!("TXT entry too long: {entry}");
+        }
+        data.put_u8(entry.len() as u8);
+        data.put_slice(entry.as_bytes());
+      }
+      // A TXT record holds at least one string
+      if entries.is_empty() {
+        data.put_u8(0);
+      }
+    }
+    RData::Other(rtype) => bail!("Cannot encode DNS record of type {rtype}"),
+  }
+  put_name(buf, &record.name)?;
+  buf.put_u16(record.data.rtype());
+  // Shared records (PTR) may have several answers from different responders, the others are unique to this one
+  let shared = matches!(record.data, RData::Ptr(_));
+  buf.put_u16(if shared { CLASS_IN } else { CLASS_IN | CLASS_FLAG });
+  buf.put_u32(record.ttl);
+  buf.put_u16(data.len() as u16);
+  buf.put_slice(&data);
+  Ok(())
+}
+
+struct Reader<'a> {
+  msg: &'a [u8],
+  pos: usize,
+}
+
+impl Reader<'_> {
+  fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+    let Some(bytes) = self.msg.get(self.pos..self.pos + len) else {
+      bail!("Truncated DNS message");
+    };
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8> { Ok(self.bytes(1)?[0]) }
+
+  fn u16(&mut self) -> Result<u16> { Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?)) }
+
+  fn u32(&mut self) -> Result<u32> { Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?)) }
+
+  /// Read a name, following compression pointers.
+  fn name(&mut self) -> Result<String> {
+    let mut labels = vec![];
+    // Position to continue at once the name is read, set at the first pointer
+    let mut resume = None;
+    let mut jumps = 0;
+    loop {
+      let len = self.u8()? as usize;
+      match len & 0xc0 {
+        0x00 if len == 0 => break,
+        0x00 => labels.push(String::from_utf8_lossy(self.bytes(len)?).into_owned()),
+        0xc0 => {
+          let offset = ((len & 0x3f) << 8) | self.u8()? as usize;
+          jumps += 1;
+          if jumps > 32 {
+            bail!("Too many compression pointers in DNS name");
+          }
+          resume.get_or_insert(self.pos);
+          self.pos = offset;
+        }
+        _ => bail!("Unsupported DNS label type"),
+      }
+    }
+    if let Some(resume) = resume {
+      self.pos = resume;
+    }
+    Ok(labels.join("."))
+  }
+
+  fn record(&mut self) -> Result<Record> {
+    let name = self.name()?;
+    let rtype = self.u16()?;
+    let _class = self.u16()?;
+    let ttl = self.u32()?;
+    let len = self.u16()? as usize;
+    let end = self.pos + len;
+    if end > self.msg.len() {
+      bail!("Truncated DNS record");
+    }
+    let data = match rtype {
+      TYPE_A => RData::A(<[u8; 4]>::try_from(self.bytes(len)?)?.into()),
+      TYPE_AAAA => RData::Aaaa(<[u8; 16]>::try_from(self.bytes(len)?)?.into()),
+      TYPE_PTR => RData::Ptr(self.name()?),
+      TYPE_SRV => {
+        let priority = self.u16()?;
+        let _weight = self.u16()?;
+        let port = self.u16()?;
+        RData::Srv {
+          priority,
+          port,
+          target: self.name()?,
+        }
+      }
+      TYPE_TXT => {
+        let mut entries = vec![];
+        while self.pos < end {
+          let len = self.u8()? as usize;
+          if len > 0 {
+            entries.push(String::from_utf8_lossy(self.bytes(len)?).into_owned());
+          }
+        }
+        RData::Txt(entries)
+      }
+      _ => RData::Other(rtype),
+    };
+    self.pos = end;
+    Ok(Record { name, ttl, data })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_message() {
+    let instance = format!("mxd-test.{SERVICE_TYPE}");
+    let message = Message {
+      response: true,
+      answers: vec![Record::new(SERVICE_TYPE, 120, RData::Ptr(instance.clone()))],
+      additionals: vec![
+        Record::new(
+          &instance,
+          120,
+          RData::Srv {
+            priority: 0,
+            port: 8080,
+            target: "mxd-test.local".to_string(),
+          },
+        ),
+        Record::new(&instance, 120, RData::Txt(vec!["fp=abc".to_string()])),
+        Record::new("mxd-test.local", 120, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+      ],
+      ..Default::default()
+    };
+    let decoded = Message::decode(&message.encode().unwrap()).unwrap();
+    assert_eq!(decoded, message);
+    assert_eq!(txt_value(&["fp=abc".to_string()], "FP"), Some("abc"));
+
+    // The PTR target points back at the question name with a compression pointer
+    let mut msg = vec![0, 0, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+    let mut name = BytesMut::new();
+    put_name(&mut name, SERVICE_TYPE).unwrap();
+    msg.extend_from_slice(&name);
+    msg.extend_from_slice(&[0, 12, 0, 1]);
+    msg.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1, 0, 0, 0, 120, 0, 11, 8]);
+    msg.extend_from_slice(b"mxd-test");
+    msg.extend_from_slice(&[0xc0, 12]);
+    let decoded = Message::decode(&msg).unwrap();
+    assert!(decoded.response);
+    assert!(decoded.answers[0].answers(&decoded.questions[0]));
+    assert_eq!(decoded.answers[0].data, RData::Ptr(instance));
+  }
+}
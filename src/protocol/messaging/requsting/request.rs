@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::utils::{compression::Compression, hash::HashAlgorithm};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CommandExecutionRequest {
   pub command: String,
@@ -23,6 +25,16 @@ pub struct FileUploadParams {
 pub struct FileDownloadParams {
   pub src_url: String,
   pub dest_path: String,
+  /// Digest the downloaded file must match. The file is deleted on mismatch.
+  pub expected_hash: Option<String>,
+  /// Algorithm of `expected_hash` and of the reported hash. Defaults to xxh3.
+  pub hash_algorithm: Option<HashAlgorithm>,
+  /// Resume from a partial file left by an interrupted download. Defaults to `true`.
+  pub resume: Option<bool>,
+  /// Number of concurrent range requests for large files. Defaults to 1.
+  pub segments: Option<u32>,
+  /// Skip the download if `dest_path` already has `expected_hash`. Defaults to `true`.
+  pub skip_existing: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -35,6 +47,55 @@ pub struct FileReadParams {
 pub struct FileWriteParams {
   pub content: String,
   pub dest_path: String,
+  /// Write to a temporary file and rename it into place. Defaults to `true`.
+  pub atomic: Option<bool>,
+  /// Append to the existing file instead of replacing it.
+  pub append: Option<bool>,
+  /// Create missing parent directories.
+  pub create_parents: Option<bool>,
+  /// Permission bits, e.g. `0o644`.
+  pub mode: Option<u32>,
+  /// User name or numeric uid.
+  pub owner: Option<String>,
+  /// Group name or numeric gid.
+  pub group: Option<String>,
+  /// Only write if the xxh3 of the current file matches. Used for compare-and-swap edits.
+  pub expected_hash: Option<String>,
+  /// Keep the previous contents at `<dest_path>.bak`.
+  pub backup: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImageWriteParams {
+  pub src_url: String,
+  /// Block device or regular file to write the decompressed image to.
+  pub dest_path: String,
+  /// Compression of the source. Detected from the stream header if not set.
+  pub compression: Option<Compression>,
+  /// Digest the downloaded stream must match, before decompression.
+  pub expected_hash: Option<String>,
+  /// Algorithm of `expected_hash` and of the reported hashes. Defaults to xxh3.
+  pub hash_algorithm: Option<HashAlgorithm>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirDownloadParams {
+  /// URL of a tar stream, e.g. `/files/_tar/{dir}` on the controller
+  pub src_url: String,
+  /// Directory to extract into. Created if missing.
+  pub dest_path: String,
+  /// Compression of the stream. Detected from the stream header if not set.
+  pub compression: Option<Compression>,
+  /// Restore the owner of every entry. Defaults to `true` when the agent runs as root.
+  pub preserve_owner: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirUploadParams {
+  pub src_path: String,
+  pub dest_url: String,
+  /// Compression applied to the tar stream. Defaults to none.
+  pub compression: Option<Compression>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -44,6 +105,9 @@ pub enum FileTransferRequest {
   Upload(FileUploadParams),
   Read(FileReadParams),
   Write(FileWriteParams),
+  WriteImage(ImageWriteParams),
+  DownloadDir(DirDownloadParams),
+  UploadDir(DirUploadParams),
 }
 
 impl From<FileDownloadParams> for FileTransferRequest {
@@ -58,6 +122,15 @@ impl From<FileReadParams> for FileTransferRequest {
 impl From<FileWriteParams> for FileTransferRequest {
   fn from(value: FileWriteParams) -> Self { FileTransferRequest::Write(value) }
 }
+impl From<ImageWriteParams> for FileTransferRequest {
+  fn from(value: ImageWriteParams) -> Self { FileTransferRequest::WriteImage(value) }
+}
+impl From<DirDownloadParams> for FileTransferRequest {
+  fn from(value: DirDownloadParams) -> Self { FileTransferRequest::DownloadDir(value) }
+}
+impl From<DirUploadParams> for FileTransferRequest {
+  fn from(value: DirUploadParams) -> Self { FileTransferRequest::UploadDir(value) }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
@@ -88,6 +161,15 @@ impl From<FileReadParams> for ControllerRequestPayload {
 impl From<FileWriteParams> for ControllerRequestPayload {
   fn from(value: FileWriteParams) -> Self { ControllerRequestPayload::FileTransferRequest(value.into()) }
 }
+impl From<ImageWriteParams> for ControllerRequestPayload {
+  fn from(value: ImageWriteParams) -> Self { ControllerRequestPayload::FileTransferRequest(value.into()) }
+}
+impl From<DirDownloadParams> for ControllerRequestPayload {
+  fn from(value: DirDownloadParams) -> Self { ControllerRequestPayload::FileTransferRequest(value.into()) }
+}
+impl From<DirUploadParams> for ControllerRequestPayload {
+  fn from(value: DirUploadParams) -> Self { ControllerRequestPayload::FileTransferRequest(value.into()) }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ControllerRequest {
@@ -104,6 +186,11 @@ fn test_controller_request_serialization() {
     payload: ControllerRequestPayload::FileTransferRequest(FileTransferRequest::Download(FileDownloadParams {
       src_url: "http://example.com/file.txt".to_string(),
       dest_path: "/tmp/file.txt".to_string(),
+      expected_hash: None,
+      hash_algorithm: None,
+      resume: None,
+      segments: None,
+      skip_existing: None,
     })),
   };
   let serialized = serde_json::to_string(&request).unwrap();
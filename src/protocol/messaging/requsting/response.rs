@@ -17,6 +17,10 @@ pub struct ScriptEvalResponse {
 pub struct FileDownloadResult {
   pub ok: bool,
   pub hash: Option<String>,
+  pub reason: Option<String>,
+  /// The destination already had the expected hash, so nothing was downloaded.
+  #[serde(default)]
+  pub skipped: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -35,6 +39,31 @@ pub struct FileReadResult {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FileWriteResult {
   pub ok: bool,
+  /// xxh3 of the file after writing
+  pub hash: Option<String>,
+  /// xxh3 of the file found on disk when `expected_hash` did not match
+  pub current_hash: Option<String>,
+  pub backup_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImageWriteResult {
+  pub ok: bool,
+  /// Bytes written to the target after decompression
+  pub written: u64,
+  /// Hash of the downloaded stream
+  pub hash: Option<String>,
+  /// Hash of the data written to the target
+  pub image_hash: Option<String>,
+  pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirTransferResult {
+  pub ok: bool,
+  /// Bytes of the tar stream transferred
+  pub size: u64,
+  pub reason: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -44,6 +73,9 @@ pub enum FileOperationResponse {
   Upload(FileUploadResult),
   Read(FileReadResult),
   Write(FileWriteResult),
+  WriteImage(ImageWriteResult),
+  DownloadDir(DirTransferResult),
+  UploadDir(DirTransferResult),
 }
 
 impl From<FileDownloadResult> for FileOperationResponse {
@@ -58,6 +90,20 @@ impl From<FileReadResult> for FileOperationResponse {
 impl From<FileWriteResult> for FileOperationResponse {
   fn from(value: FileWriteResult) -> Self { FileOperationResponse::Write(value) }
 }
+impl From<ImageWriteResult> for FileOperationResponse {
+  fn from(value: ImageWriteResult) -> Self { FileOperationResponse::WriteImage(value) }
+}
+
+/// Progress of a running transfer, sent as a partial response.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransferProgress {
+  /// Bytes transferred so far
+  pub transferred: u64,
+  /// Total bytes to transfer, if known
+  pub total: Option<u64>,
+  /// Throughput since the previous report in bytes per second
+  pub rate: u64,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ErrorResponse {
@@ -71,6 +117,7 @@ pub enum AgentResponsePayload {
   CommandExecutionResponse(CommandExecutionResponse),
   ScriptEvalResponse(ScriptEvalResponse),
   FileOperationResponse(FileOperationResponse),
+  Progress(TransferProgress),
   Error(ErrorResponse),
 }
 
@@ -83,6 +130,9 @@ impl From<ScriptEvalResponse> for AgentResponsePayload {
 impl From<FileOperationResponse> for AgentResponsePayload {
   fn from(value: FileOperationResponse) -> Self { AgentResponsePayload::FileOperationResponse(value) }
 }
+impl From<TransferProgress> for AgentResponsePayload {
+  fn from(value: TransferProgress) -> Self { AgentResponsePayload::Progress(value) }
+}
 impl From<ErrorResponse> for AgentResponsePayload {
   fn from(value: ErrorResponse) -> Self { AgentResponsePayload::Error(value) }
 }
@@ -99,6 +149,11 @@ pub enum Status {
   NotAccepted,            // Task was not accepted by executor
 }
 
+impl Status {
+  /// Whether more responses will follow for the same task.
+  pub fn is_partial(&self) -> bool { matches!(self, Status::PartialOk(_) | Status::PartialFail(_)) }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AgentResponse {
   pub id: u32,
@@ -106,6 +161,24 @@ pub struct AgentResponse {
   pub payload: AgentResponsePayload,
 }
 
+impl AgentResponse {
+  /// Transfer progress carried by a partial response.
+  pub fn progress(&self) -> Option<&TransferProgress> {
+    match &self.payload {
+      AgentResponsePayload::Progress(progress) if self.status.is_partial() => Some(progress),
+      _ => None,
+    }
+  }
+
+  /// Exit code of an executed command.
+  pub fn exit_code(&self) -> Option<i32> {
+    match &self.payload {
+      AgentResponsePayload::CommandExecutionResponse(resp) => Some(resp.code),
+      _ => None,
+    }
+  }
+}
+
 #[test]
 fn test_agent_response_serialization() {
   let response = AgentResponse {
@@ -114,6 +187,8 @@ fn test_agent_response_serialization() {
     payload: AgentResponsePayload::FileOperationResponse(FileOperationResponse::Download(FileDownloadResult {
       ok: true,
       hash: Some("dummy_hash".to_string()),
+      reason: None,
+      skipped: false,
     })),
   };
   let serialized = serde_json::to_string(&response).unwrap();
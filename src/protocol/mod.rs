@@ -1,4 +1,6 @@
 pub mod auth;
 pub mod discovery;
+pub mod enrollment;
 pub mod handshake;
+pub mod mdns;
 pub mod messaging;
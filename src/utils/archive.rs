@@ -0,0 +1,153 @@
+use std::{
+  io::{self, Write},
+  path::PathBuf,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+  },
+};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use futures_util::{TryStreamExt as _, stream};
+use log::{info, warn};
+use reqwest::Body;
+use tokio::{io::BufReader, sync::mpsc, task::JoinHandle};
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+
+use crate::{
+  protocol::messaging::TransferProgress,
+  utils::{
+    compression::{BoxedReader, Compression, compress, decompress},
+    http::client_for,
+    progress::{ProgressReporter, wait_with_progress},
+  },
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Sends everything written to it as chunks over a channel, for producing a stream on a blocking thread.
+struct ChannelWriter {
+  tx: mpsc::Sender<io::Result<Bytes>>,
+  buf: BytesMut,
+}
+
+impl Write for ChannelWriter {
+  fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+    self.buf.extend_from_slice(data);
+    if self.buf.len() >= CHUNK_SIZE {
+      self.flush()?;
+    }
+    Ok(data.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    if !self.buf.is_empty() {
+      let chunk = self.buf.split().freeze();
+      self
+        .tx
+        .blocking_send(Ok(chunk))
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "archive receiver was dropped"))?;
+    }
+    Ok(())
+  }
+}
+
+/// Stream a tar archive of `dir`, built on a blocking thread.
+///
+/// Entries are stored relative to `dir`, with their mode, ownership and mtime. Symlinks are archived as links.
+/// A failure while reading the directory ends the stream with an error, so a truncated archive is never mistaken for
+/// a complete one.
+pub fn pack_dir(dir: PathBuf, compression: Compression) -> BoxedReader<'static> {
+  let (tx, mut rx) = mpsc::channel(16);
+  let writer = ChannelWriter {
+    tx: tx.clone(),
+    buf: BytesMut::with_capacity(CHUNK_SIZE),
+  };
+  tokio::task::spawn_blocking(move || {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    let r = builder.append_dir_all(".", &dir).and_then(|_| builder.into_inner()).and_then(|mut w| w.flush());
+    if let Err(err) = r {
+      warn!("Failed to archive {}: {err}", dir.display());
+      let _ = tx.blocking_send(Err(err));
+    }
+  });
+  let reader = StreamReader::new(stream::poll_fn(move |cx| rx.poll_recv(cx)));
+  compress(reader, compression)
+}
+
+/// Extract a tar stream into `dest` on a blocking thread.
+///
+/// Permission bits and mtimes are always restored. Numeric ownership is restored if `preserve_owner` is set, which
+/// requires root. Entries that would land outside of `dest` are skipped.
+pub fn unpack_tar(reader: BoxedReader<'static>, dest: PathBuf, preserve_owner: bool) -> JoinHandle<io::Result<()>> {
+  let reader = SyncIoBridge::new(reader);
+  tokio::task::spawn_blocking(move || {
+    std::fs::create_dir_all(&dest)?;
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(preserve_owner);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+    archive.unpack(&dest)
+  })
+}
+
+/// Download a tar stream from `url` and extract it into `dest`.
+///
+/// The compression is detected from the stream if not given. Returns the number of bytes downloaded.
+pub async fn download_dir(
+  url: &str, dest: &str, compression: Option<Compression>, preserve_owner: bool, on_progress: impl Fn(TransferProgress),
+) -> Result<u64> {
+  info!("Extracting archive from {url} into {dest}");
+  let resp = client_for(url).get(url).send().await?.error_for_status()?;
+  let progress = ProgressReporter::new(resp.content_length(), on_progress);
+  let received = Arc::new(AtomicU64::new(0));
+  let counter = received.clone();
+  let stream = resp
+    .bytes_stream()
+    .inspect_ok(move |chunk| {
+      counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+    })
+    .map_err(io::Error::other);
+  let reader = BufReader::new(StreamReader::new(Box::pin(stream)));
+  let task = unpack_tar(
+    decompress(reader, compression).await?,
+    PathBuf::from(dest),
+    preserve_owner,
+  );
+  wait_with_progress(task, &received, &progress).await??;
+  let received = received.load(Ordering::Relaxed);
+  info!("Extracted {received} bytes from {url} into {dest}");
+  Ok(received)
+}
+
+/// Pack the directory `src` as a tar stream and upload it to `url` with a `PUT` request.
+///
+/// Returns the number of bytes uploaded.
+pub async fn upload_dir(
+  url: &str, src: &str, compression: Compression, on_progress: impl Fn(TransferProgress),
+) -> Result<u64> {
+  info!("Uploading archive of {src} to {url}");
+  if !tokio::fs::metadata(src).await?.is_dir() {
+    anyhow::bail!("Not a directory: {src}");
+  }
+  let progress = ProgressReporter::new(None, on_progress);
+  let sent = Arc::new(AtomicU64::new(0));
+  let counter = sent.clone();
+  let body = ReaderStream::new(pack_dir(PathBuf::from(src), compression)).inspect_ok(move |chunk| {
+    counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+  });
+  let request = client_for(url).put(url).body(Body::wrap_stream(body)).send();
+  let response = wait_with_progress(request, &sent, &progress).await?;
+  if !response.status().is_success() {
+    anyhow::bail!(
+      "Failed to upload archive to {url}. Server returned {}",
+      response.status()
+    );
+  }
+  let sent = sent.load(Ordering::Relaxed);
+  info!("Uploaded {sent} bytes of {src} to {url}");
+  Ok(sent)
+}
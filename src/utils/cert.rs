@@ -2,10 +2,13 @@ use std::fs::exists;
 
 use anyhow::Result;
 use rcgen::{
-  BasicConstraints, CertificateParams, DistinguishedName, ExtendedKeyUsagePurpose, Ia5String, IsCa, KeyPair,
-  KeyUsagePurpose, SanType,
+  BasicConstraints, CertificateParams, CertificateSigningRequestParams, DistinguishedName, DnType, DnValue,
+  ExtendedKeyUsagePurpose, Ia5String, IsCa, KeyPair, KeyUsagePurpose, PublicKeyData, SanType,
 };
 use time::OffsetDateTime;
+use tokio_rustls::rustls::pki_types::{CertificateDer, pem::PemObject as _};
+
+use crate::utils::hash::sha2_256_for_bytes;
 
 /// Generates a self-signed CA certificate and its private key.
 /// Returns the PEM encoded certificate and private key.
@@ -65,6 +68,99 @@ pub fn generate_signed_cert(
   Ok((cert_pem, key_pem))
 }
 
+/// Generates a TLS client certificate for an agent, signed by the provided CA and valid for `validity`.
+/// The common name is the agent's host id, which the controller checks against the id it connects with.
+/// Returns the PEM encoded client certificate and its private key.
+pub fn generate_client_cert(
+  ca_cert_pem: &str, ca_key_pem: &str, host_id: &str, validity: time::Duration,
+) -> Result<(String, String)> {
+  let key_pair = KeyPair::generate()?;
+  let cert_pem = sign_client_cert(ca_cert_pem, ca_key_pem, host_id, &key_pair, validity)?;
+  let key_pem = key_pair.serialize_pem();
+
+  Ok((cert_pem, key_pem))
+}
+
+/// Signs a TLS client certificate for the public key of an agent's certificate signing request.
+/// The request's signature is verified, everything else in it is ignored and replaced like [`generate_client_cert`].
+/// Returns the PEM encoded client certificate.
+pub fn sign_client_csr(
+  ca_cert_pem: &str, ca_key_pem: &str, host_id: &str, csr_pem: &str, validity: time::Duration,
+) -> Result<String> {
+  let csr = CertificateSigningRequestParams::from_pem(csr_pem)?;
+  sign_client_cert(ca_cert_pem, ca_key_pem, host_id, &csr.public_key, validity)
+}
+
+fn sign_client_cert(
+  ca_cert_pem: &str, ca_key_pem: &str, host_id: &str, public_key: &impl PublicKeyData, validity: time::Duration,
+) -> Result<String> {
+  let ca_params = CertificateParams::from_ca_cert_pem(ca_cert_pem)?;
+  let mut params = ca_params.clone();
+
+  let ca_key_pair = KeyPair::from_pem(ca_key_pem)?;
+
+  params.subject_alt_names = vec![];
+  params.is_ca = IsCa::NoCa;
+  params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+  params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+
+  let mut distinguished_name = DistinguishedName::new();
+  distinguished_name.push(rcgen::DnType::OrganizationName, "MxLite Agent");
+  distinguished_name.push(rcgen::DnType::CommonName, host_id);
+  params.distinguished_name = distinguished_name;
+
+  params.not_before = OffsetDateTime::now_utc();
+  params.not_after = params.not_before + validity;
+
+  let cert = params.signed_by(public_key, &ca_params, &ca_key_pair)?;
+  Ok(cert.pem())
+}
+
+/// Generates a private key and a certificate signing request for it, to have a client certificate issued for
+/// `host_id` without the key leaving the agent.
+/// Returns the PEM encoded request and private key.
+pub fn generate_client_csr(host_id: &str) -> Result<(String, String)> {
+  let mut params = CertificateParams::default();
+  let mut distinguished_name = DistinguishedName::new();
+  distinguished_name.push(rcgen::DnType::CommonName, host_id);
+  params.distinguished_name = distinguished_name;
+
+  let key_pair = KeyPair::generate()?;
+  let csr = params.serialize_request(&key_pair)?;
+
+  Ok((csr.pem()?, key_pair.serialize_pem()))
+}
+
+/// Returns the common name of a DER encoded certificate, if it has one.
+pub fn cert_common_name(der: &[u8]) -> Option<String> {
+  let params = CertificateParams::from_ca_cert_der(&der.into()).ok()?;
+  match params.distinguished_name.get(&DnType::CommonName)? {
+    DnValue::Utf8String(name) => Some(name.clone()),
+    DnValue::PrintableString(name) => Some(name.as_str().to_string()),
+    DnValue::Ia5String(name) => Some(name.as_str().to_string()),
+    _ => None,
+  }
+}
+
+/// Whether the first certificate in `cert_pem` has less than a third of its validity left, or expired already.
+/// Unreadable certificates need renewal too.
+pub fn needs_renewal(cert_pem: &str) -> bool {
+  let Ok(params) = CertificateDer::from_pem_slice(cert_pem.as_bytes())
+    .map_err(anyhow::Error::from)
+    .and_then(|der| Ok(CertificateParams::from_ca_cert_der(&der)?))
+  else {
+    return true;
+  };
+  let lifetime = params.not_after - params.not_before;
+  params.not_after - OffsetDateTime::now_utc() < lifetime / 3
+}
+
+/// Returns the SHA-256 of the DER encoding of the first certificate in `cert_pem`, the fingerprint agents pin it by.
+pub fn cert_fingerprint(cert_pem: &str) -> Result<String> {
+  let der = CertificateDer::from_pem_slice(cert_pem.as_bytes())?;
+  Ok(sha2_256_for_bytes(&der)?)
+}
+
 /// Reads a certificate and private key from the specified file paths.
 ///
 /// If the files do not exist, it generates a self-signed certificate using the provided CA certificate and key paths.
@@ -126,3 +222,24 @@ pub fn get_cert_from_file(
     Err(anyhow::anyhow!("Certificate and key paths must be provided"))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_client_cert_renewal() {
+    let (ca_cert, ca_key) = generate_ca_cert().unwrap();
+    let (csr, _) = generate_client_csr("host").unwrap();
+
+    let cert = sign_client_csr(&ca_cert, &ca_key, "host", &csr, time::Duration::days(90)).unwrap();
+    let params = CertificateParams::from_ca_cert_pem(&cert).unwrap();
+    assert_eq!(params.not_after - params.not_before, time::Duration::days(90));
+    assert!(!needs_renewal(&cert));
+
+    // Expired, or about to
+    let (cert, _) = generate_client_cert(&ca_cert, &ca_key, "host", time::Duration::ZERO).unwrap();
+    assert!(needs_renewal(&cert));
+    assert!(needs_renewal("not a certificate"));
+  }
+}
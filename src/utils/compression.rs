@@ -0,0 +1,82 @@
+use std::pin::Pin;
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, XzDecoder, XzEncoder, ZstdDecoder, ZstdEncoder};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt as _, AsyncRead};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+  #[default]
+  None,
+  Gzip,
+  Zstd,
+  Xz,
+}
+
+impl Compression {
+  /// Detect the compression from the magic bytes at the start of a stream.
+  pub fn detect(header: &[u8]) -> Self {
+    if header.starts_with(&[0x1f, 0x8b]) {
+      Compression::Gzip
+    } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+      Compression::Zstd
+    } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+      Compression::Xz
+    } else {
+      Compression::None
+    }
+  }
+
+  /// File extension of a tar archive with this compression.
+  pub fn tar_extension(self) -> &'static str {
+    match self {
+      Compression::None => "tar",
+      Compression::Gzip => "tar.gz",
+      Compression::Zstd => "tar.zst",
+      Compression::Xz => "tar.xz",
+    }
+  }
+}
+
+pub type BoxedReader<'a> = Pin<Box<dyn AsyncRead + Send + 'a>>;
+
+/// Decompress `reader`, detecting the compression from its first bytes if `compression` is not given.
+///
+/// Concatenated gzip members and zstd frames, as written by parallel compressors, are decoded as one stream.
+pub async fn decompress<'a>(
+  mut reader: impl AsyncBufRead + Send + Unpin + 'a, compression: Option<Compression>,
+) -> std::io::Result<BoxedReader<'a>> {
+  let compression = match compression {
+    Some(compression) => compression,
+    None => Compression::detect(reader.fill_buf().await?),
+  };
+  Ok(match compression {
+    Compression::None => Box::pin(reader),
+    Compression::Gzip => {
+      let mut decoder = GzipDecoder::new(reader);
+      decoder.multiple_members(true);
+      Box::pin(decoder)
+    }
+    Compression::Zstd => {
+      let mut decoder = ZstdDecoder::new(reader);
+      decoder.multiple_members(true);
+      Box::pin(decoder)
+    }
+    Compression::Xz => {
+      let mut decoder = XzDecoder::new(reader);
+      decoder.multiple_members(true);
+      Box::pin(decoder)
+    }
+  })
+}
+
+/// Compress `reader` with the default level of `compression`.
+pub fn compress<'a>(reader: impl AsyncBufRead + Send + 'a, compression: Compression) -> BoxedReader<'a> {
+  match compression {
+    Compression::None => Box::pin(reader),
+    Compression::Gzip => Box::pin(GzipEncoder::new(reader)),
+    Compression::Zstd => Box::pin(ZstdEncoder::new(reader)),
+    Compression::Xz => Box::pin(XzEncoder::new(reader)),
+  }
+}
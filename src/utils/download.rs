@@ -0,0 +1,949 @@
+use std::{
+  io::SeekFrom,
+  path::{Path, PathBuf},
+};
+
+use futures_util::{StreamExt, future::try_join_all};
+use log::{debug, error, info, warn};
+use reqwest::{Client, Response, StatusCode, header};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+  fs::{self, File, OpenOptions},
+  io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _},
+  sync::Mutex,
+};
+
+use crate::{
+  protocol::messaging::TransferProgress,
+  utils::{
+    fs::partial_path_for,
+    hash::{HashAlgorithm, HashError, StreamHasher, hash_eq},
+    http::client_for,
+    progress::ProgressReporter,
+    retry::{Retry, RetryResult, async_with_retry},
+  },
+};
+
+const DOWNLOAD_RETRIES: i32 = 5;
+/// Segments smaller than this are not worth a separate connection.
+const MIN_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+const MAX_SEGMENTS: u32 = 32;
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+  #[error("Request error: {0}")]
+  RequestError(#[from] reqwest::Error),
+  #[error("Io error: {0}")]
+  IoError(#[from] std::io::Error),
+  #[error("Hash error: {0}")]
+  HashError(#[from] HashError),
+  #[error("Server returned {0}")]
+  StatusError(StatusCode),
+  #[error("Hash mismatch: expected {0}, got {1}")]
+  HashMismatch(String, String),
+  #[error("Incomplete response: {0}")]
+  Incomplete(String),
+  /// The file changed on the server since the partial file was started, which was discarded to start over
+  #[error("File changed on the server: {0}")]
+  Changed(String),
+  #[error("Giving up after {0} attempts")]
+  RetriesExhausted(i32),
+  #[error("Download cancelled")]
+  Cancelled,
+}
+
+impl DownloadError {
+  /// Whether the download may succeed when attempted again.
+  fn is_transient(&self) -> bool {
+    match self {
+      DownloadError::RequestError(_) | DownloadError::Incomplete(_) => true,
+      DownloadError::StatusError(status) => {
+        status.is_server_error() ||
+          *status == StatusCode::REQUEST_TIMEOUT ||
+          *status == StatusCode::TOO_MANY_REQUESTS ||
+          *status == StatusCode::RANGE_NOT_SATISFIABLE
+      }
+      _ => false,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+  /// Digest the downloaded file must match, in `hash_algorithm`.
+  pub expected_hash: Option<String>,
+  pub hash_algorithm: HashAlgorithm,
+  /// Continue from a partial file left by an interrupted download.
+  pub resume: bool,
+  /// Number of concurrent range requests. Values below 2 download with a single stream.
+  pub segments: u32,
+}
+
+impl Default for DownloadOptions {
+  fn default() -> Self {
+    DownloadOptions {
+      expected_hash: None,
+      hash_algorithm: HashAlgorithm::Xxh3,
+      resume: true,
+      segments: 1,
+    }
+  }
+}
+
+/// Download a file from the given URL and save it to the given path.
+///
+/// The file is written to `<path>.part` and renamed into place once it is complete and verified.
+/// Transient failures are retried with backoff, resuming from the partial file when the server supports ranges.
+/// The validator of the response the partial file came from is kept in `<path>.part.meta`, and sent in `If-Range`
+/// so that a file changed on the server is downloaded again from the start.
+///
+/// With `opts.segments` above 1 and a server that accepts ranges, the file is fetched by several concurrent range
+/// requests instead. Each segment is retried on its own and checked against the xxh3 of the bytes received for it,
+/// and the whole file is hashed once all segments are done. The progress of the segments is kept with the partial
+/// file, a later download of the same version of the file continues it.
+///
+/// `on_progress` is called periodically with the bytes of the file downloaded so far.
+///
+/// Returns the hash of the file in `opts.hash_algorithm`.
+pub async fn download_file(
+  url: &str, path: &str, opts: &DownloadOptions, on_progress: impl Fn(TransferProgress) + Sync,
+) -> Result<String, DownloadError> {
+  info!("Downloading file from {url} to {path}");
+  let client = client_for(url);
+  let path = Path::new(path);
+  let part = partial_path_for(path);
+  let progress = ProgressReporter::new(None, on_progress);
+  // The partial file is discarded when the file changed or did not match once resumed, the next attempt probes the
+  // file again and starts over.
+  for attempt in 1..=DOWNLOAD_RETRIES {
+    if opts.segments < 2 {
+      break;
+    }
+    let Some((size, validator)) = probe(&client, url).await.filter(|(size, _)| *size >= 2 * MIN_SEGMENT_SIZE) else {
+      info!("Not downloading {url} in segments, falling back to a single stream");
+      break;
+    };
+    progress.set_total(Some(size));
+    let resuming = opts.resume && fs::try_exists(&part).await.unwrap_or(false);
+    match download_segmented(&client, url, &part, size, validator.as_deref(), opts, &progress).await {
+      Ok(hash) => {
+        fs::rename(&part, path).await?;
+        PartMeta::remove(&part).await;
+        info!(
+          "Downloaded file from {url} to {}. {:?}: {hash}",
+          path.display(),
+          opts.hash_algorithm
+        );
+        return Ok(hash);
+      }
+      Err(DownloadError::HashMismatch(expected, actual)) if resuming => {
+        warn!("Hash mismatch after resuming download of {url}, expected {expected}, got {actual}. Restarting");
+      }
+      Err(DownloadError::Changed(reason)) if attempt < DOWNLOAD_RETRIES => {
+        warn!("{url} changed on the server during the download ({reason}), starting over");
+      }
+      Err(err) => return Err(err),
+    }
+  }
+  let r = async_with_retry(
+    async || {
+      let resuming = opts.resume && fs::try_exists(&part).await.unwrap_or(false);
+      match download_once(&client, url, &part, opts, &progress).await {
+        Ok(hash) => Retry::Return(Ok(hash)),
+        Err(DownloadError::HashMismatch(expected, actual)) if resuming => {
+          warn!("Hash mismatch after resuming download of {url}, expected {expected}, got {actual}. Restarting");
+          Retry::RetryImmediate
+        }
+        Err(DownloadError::Changed(reason)) => {
+          warn!("{url} changed on the server during the download ({reason}), starting over");
+          Retry::RetryImmediate
+        }
+        Err(err) if err.is_transient() => {
+          warn!("Failed to download file from {url}: {err}");
+          Retry::RetryWithDelay
+        }
+        Err(err) => Retry::Return(Err(err)),
+      }
+    },
+    DOWNLOAD_RETRIES,
+  )
+  .await;
+  let hash = match r {
+    RetryResult::Return(r) => r?,
+    RetryResult::Break => return Err(DownloadError::Cancelled),
+    RetryResult::NoResult => {
+      error!("Failed to download file from {url} after {DOWNLOAD_RETRIES} attempts");
+      return Err(DownloadError::RetriesExhausted(DOWNLOAD_RETRIES));
+    }
+  };
+  fs::rename(&part, path).await?;
+  PartMeta::remove(&part).await;
+  info!(
+    "Downloaded file from {url} to {}. {:?}: {hash}",
+    path.display(),
+    opts.hash_algorithm
+  );
+  Ok(hash)
+}
+
+/// What is known about a `.part` file, kept next to it to resume the download from.
+#[derive(Debug, Serialize, Deserialize)]
+struct PartMeta {
+  /// Strong `ETag`, or else `Last-Modified`, of the response the downloaded bytes came from
+  validator: String,
+  /// Progress of a segmented download. Empty for a single stream, which wrote the file up to its length.
+  #[serde(default)]
+  segments: Vec<Segment>,
+}
+
+impl PartMeta {
+  fn path(part: &Path) -> PathBuf {
+    let mut p = part.as_os_str().to_owned();
+    p.push(".meta");
+    PathBuf::from(p)
+  }
+
+  async fn load(part: &Path) -> Option<Self> {
+    let content = fs::read(Self::path(part)).await.ok()?;
+    serde_json::from_slice(&content)
+      .inspect_err(|err| warn!("Ignoring unreadable {}: {err}", Self::path(part).display()))
+      .ok()
+  }
+
+  async fn save(&self, part: &Path) -> Result<(), DownloadError> {
+    let content = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+    fs::write(Self::path(part), content).await?;
+    Ok(())
+  }
+
+  async fn remove(part: &Path) {
+    if let Err(err) = fs::remove_file(Self::path(part)).await &&
+      err.kind() != std::io::ErrorKind::NotFound
+    {
+      warn!("Failed to remove {}: {err}", Self::path(part).display());
+    }
+  }
+}
+
+/// Remove a partial file and what is known about it.
+async fn discard(part: &Path) -> Result<(), DownloadError> {
+  PartMeta::remove(part).await;
+  match fs::remove_file(part).await {
+    Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+    _ => Ok(()),
+  }
+}
+
+/// What a response can be resumed with in `If-Range`: its strong `ETag`, or else its `Last-Modified` date.
+fn validator(response: &Response) -> Option<String> {
+  let headers = response.headers();
+  let value = |name| headers.get(name).and_then(|v: &header::HeaderValue| v.to_str().ok());
+  value(header::ETAG)
+    .filter(|etag| !etag.starts_with("W/"))
+    .or_else(|| value(header::LAST_MODIFIED))
+    .map(str::to_string)
+}
+
+async fn download_once(
+  client: &Client, url: &str, part: &Path, opts: &DownloadOptions,
+  progress: &ProgressReporter<impl Fn(TransferProgress)>,
+) -> Result<String, DownloadError> {
+  // Only a partial file of a known version of the file can be continued
+  let meta = match opts.resume {
+    true => PartMeta::load(part).await.filter(|meta| meta.segments.is_empty()),
+    false => None,
+  };
+  let offset = match &meta {
+    Some(_) => fs::metadata(part).await.map(|m| m.len()).unwrap_or(0),
+    None => 0,
+  };
+  let mut req = client.get(url);
+  if let Some(meta) = &meta &&
+    offset > 0
+  {
+    req = req.header(header::RANGE, format!("bytes={offset}-")).header(header::IF_RANGE, &meta.validator);
+  }
+  let response = req.send().await?;
+  let mut hasher = StreamHasher::new(opts.hash_algorithm);
+  let length = response.content_length();
+  let mut out = match response.status() {
+    StatusCode::PARTIAL_CONTENT if offset > 0 && content_range_start(&response) == Some(offset) => {
+      info!("Resuming download of {url} from {offset} bytes");
+      hash_partial(part, &mut hasher).await?;
+      progress.set_total(length.map(|len| offset + len));
+      progress.set(offset);
+      OpenOptions::new().append(true).open(part).await?
+    }
+    StatusCode::PARTIAL_CONTENT if offset > 0 => {
+      discard(part).await?;
+      return Err(DownloadError::Changed(format!(
+        "asked for bytes from {offset}, got {:?}",
+        content_range_start(&response)
+      )));
+    }
+    StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+      warn!("Server rejected resuming download of {url} from {offset} bytes");
+      discard(part).await?;
+      return Err(DownloadError::StatusError(StatusCode::RANGE_NOT_SATISFIABLE));
+    }
+    status if status.is_success() && status != StatusCode::PARTIAL_CONTENT => {
+      if offset > 0 {
+        info!("{url} changed since it was partially downloaded, starting over");
+      }
+      progress.set_total(length);
+      progress.set(0);
+      let out = File::create(part).await?;
+      match validator(&response) {
+        Some(validator) => {
+          PartMeta {
+            validator,
+            segments: vec![],
+          }
+          .save(part)
+          .await?
+        }
+        None => PartMeta::remove(part).await,
+      }
+      out
+    }
+    status => {
+      error!("Failed to download file from {url}. Server returned {status}");
+      return Err(DownloadError::StatusError(status));
+    }
+  };
+
+  let mut body = response.bytes_stream();
+  while let Some(chunk) = body.next().await {
+    let chunk = chunk?;
+    hasher.update(&chunk);
+    out.write_all(&chunk).await?;
+    progress.add(chunk.len() as u64);
+  }
+  out.sync_all().await?;
+  drop(out);
+
+  let hash = hasher.finalize()?;
+  if let Some(expected) = &opts.expected_hash &&
+    !hash_eq(opts.hash_algorithm, expected, &hash)
+  {
+    error!("Hash mismatch for {url}: expected {expected}, got {hash}");
+    discard(part).await?;
+    return Err(DownloadError::HashMismatch(expected.clone(), hash));
+  }
+  Ok(hash)
+}
+
+/// Get the size and the validator of the file at `url`, if the server accepts range requests for it.
+async fn probe(client: &Client, url: &str) -> Option<(u64, Option<String>)> {
+  let response = client.head(url).send().await.inspect_err(|err| warn!("Failed to probe {url}: {err}")).ok()?;
+  if !response.status().is_success() {
+    return None;
+  }
+  let headers = response.headers();
+  let accepts_ranges = headers
+    .get(header::ACCEPT_RANGES)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.split(',').any(|unit| unit.trim() == "bytes"));
+  if !accepts_ranges {
+    return None;
+  }
+  let size = headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()?;
+  Some((size, validator(&response)))
+}
+
+/// Inclusive byte range of a segmented download, and how much of it was written.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Segment {
+  start: u64,
+  end: u64,
+  written: u64,
+}
+
+impl Segment {
+  fn len(&self) -> u64 { self.end - self.start + 1 }
+}
+
+struct SegmentState {
+  segment: Segment,
+  /// Start of the bytes `hasher` has seen, those received since the download was started or resumed
+  hashed_from: u64,
+  hasher: StreamHasher,
+}
+
+/// Segments to download the `size` bytes of `url` in, continuing those of a `.part` file downloaded from the same
+/// version of the file. A partial file of a single stream is continued from its length.
+async fn plan_segments(
+  url: &str, part: &Path, size: u64, validator: Option<&str>, opts: &DownloadOptions,
+) -> Result<Vec<Segment>, DownloadError> {
+  let meta = match validator {
+    Some(validator) if opts.resume => PartMeta::load(part).await.filter(|meta| meta.validator == validator),
+    _ => None,
+  };
+  let len = fs::metadata(part).await.map(|m| m.len()).ok();
+  if let Some(meta) = &meta &&
+    !meta.segments.is_empty() &&
+    len == Some(size) &&
+    meta.segments.iter().all(|s| s.end < size && s.written <= s.len())
+  {
+    info!("Resuming segmented download of {url}");
+    return Ok(meta.segments.clone());
+  }
+  let done = match (&meta, len) {
+    (Some(meta), Some(len)) if meta.segments.is_empty() && len < size => len,
+    _ => 0,
+  };
+  let file = if done > 0 {
+    info!("Resuming download of {url} from {done} bytes in segments");
+    OpenOptions::new().write(true).open(part).await?
+  } else {
+    File::create(part).await?
+  };
+  file.set_len(size).await?;
+  drop(file);
+
+  let remaining = size - done;
+  let count = opts.segments.min(MAX_SEGMENTS).min(remaining.div_ceil(MIN_SEGMENT_SIZE).max(1) as u32) as u64;
+  let segment_size = remaining.div_ceil(count);
+  let segments: Vec<_> = (0..count)
+    .map(|i| done + i * segment_size)
+    .take_while(|start| *start < size)
+    .map(|start| Segment {
+      start,
+      end: (start + segment_size).min(size) - 1,
+      written: 0,
+    })
+    .collect();
+  info!(
+    "Downloading {url} in {} segments of {segment_size} bytes",
+    segments.len()
+  );
+  match validator {
+    Some(validator) => {
+      PartMeta {
+        validator: validator.to_string(),
+        segments: segments.clone(),
+      }
+      .save(part)
+      .await?
+    }
+    None => PartMeta::remove(part).await,
+  }
+  Ok(segments)
+}
+
+/// Download `size` bytes from `url` into a preallocated `part` file with concurrent range requests.
+///
+/// If a segment fails for good, the progress of all of them is kept to resume from, unless the file changed on the
+/// server meanwhile.
+async fn download_segmented(
+  client: &Client, url: &str, part: &Path, size: u64, validator: Option<&str>, opts: &DownloadOptions,
+  progress: &ProgressReporter<impl Fn(TransferProgress)>,
+) -> Result<String, DownloadError> {
+  let segments = plan_segments(url, part, size, validator, opts).await?;
+  let remaining: u64 = segments.iter().map(|s| s.len() - s.written).sum();
+  progress.set(size - remaining);
+  let states: Vec<_> = segments
+    .into_iter()
+    .map(|segment| {
+      Mutex::new(SegmentState {
+        segment,
+        hashed_from: segment.start + segment.written,
+        hasher: StreamHasher::new(HashAlgorithm::Xxh3),
+      })
+    })
+    .collect();
+  let downloads = states
+    .iter()
+    .enumerate()
+    .map(|(index, state)| download_segment(client, url, validator, part, index, state, progress));
+  if let Err(err) = try_join_all(downloads).await {
+    error!("Failed to download {url} in segments: {err}");
+    let resumable = err.is_transient() || matches!(err, DownloadError::RetriesExhausted(_) | DownloadError::Cancelled);
+    match validator {
+      Some(validator) if resumable => {
+        let mut segments = Vec::with_capacity(states.len());
+        for state in &states {
+          segments.push(state.lock().await.segment);
+        }
+        PartMeta {
+          validator: validator.to_string(),
+          segments,
+        }
+        .save(part)
+        .await?;
+      }
+      _ => discard(part).await?,
+    }
+    return Err(err);
+  }
+
+  let mut hasher = StreamHasher::new(opts.hash_algorithm);
+  hash_partial(part, &mut hasher).await?;
+  File::open(part).await?.sync_all().await?;
+  let hash = hasher.finalize()?;
+  if let Some(expected) = &opts.expected_hash &&
+    !hash_eq(opts.hash_algorithm, expected, &hash)
+  {
+    error!("Hash mismatch for {url}: expected {expected}, got {hash}");
+    discard(part).await?;
+    return Err(DownloadError::HashMismatch(expected.clone(), hash));
+  }
+  Ok(hash)
+}
+
+/// Download a segment of `url` into the same range of `part`, retrying failed attempts with backoff.
+///
+/// A failed attempt is continued from the last byte written. Once complete, the bytes written are read back and
+/// compared with the xxh3 of those received, a segment that does not match is downloaded again.
+async fn download_segment(
+  client: &Client, url: &str, validator: Option<&str>, part: &Path, index: usize, state: &Mutex<SegmentState>,
+  progress: &ProgressReporter<impl Fn(TransferProgress)>,
+) -> Result<(), DownloadError> {
+  let r = async_with_retry(
+    async || {
+      let mut state = state.lock().await;
+      let r = match fetch_range(client, url, validator, part, &mut state, progress).await {
+        Ok(()) => verify_segment(part, &mut state).await,
+        Err(err) => Err(err),
+      };
+      match r {
+        Ok(()) => Retry::Return(Ok(())),
+        Err(err) if err.is_transient() => {
+          warn!("Failed to download segment {index} of {url}: {err}");
+          Retry::RetryWithDelay
+        }
+        Err(DownloadError::HashMismatch(expected, actual)) => {
+          warn!("Segment {index} of {url} was written as {actual}, expected {expected}. Downloading it again");
+          let segment = &mut state.segment;
+          progress.sub(segment.written);
+          segment.written = 0;
+          state.hashed_from = segment.start;
+          state.hasher = StreamHasher::new(HashAlgorithm::Xxh3);
+          Retry::RetryImmediate
+        }
+        Err(err) => Retry::Return(Err(err)),
+      }
+    },
+    DOWNLOAD_RETRIES,
+  )
+  .await;
+  match r {
+    RetryResult::Return(r) => r,
+    RetryResult::Break => Err(DownloadError::Cancelled),
+    RetryResult::NoResult => Err(DownloadError::RetriesExhausted(DOWNLOAD_RETRIES)),
+  }
+}
+
+/// Compare the xxh3 of the bytes received for a complete segment with that of the bytes written to `part`.
+async fn verify_segment(part: &Path, state: &mut SegmentState) -> Result<(), DownloadError> {
+  let Segment { start, end, .. } = state.segment;
+  let expected = std::mem::replace(&mut state.hasher, StreamHasher::new(HashAlgorithm::Xxh3)).finalize()?;
+  let mut fd = File::open(part).await?;
+  fd.seek(SeekFrom::Start(state.hashed_from)).await?;
+  let mut hasher = StreamHasher::new(HashAlgorithm::Xxh3);
+  let mut remaining = end + 1 - state.hashed_from;
+  let mut buf = vec![0u8; 64 * 1024];
+  while remaining > 0 {
+    let n = fd.read(&mut buf[..remaining.min(64 * 1024) as usize]).await?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[..n]);
+    remaining -= n as u64;
+  }
+  let actual = hasher.finalize()?;
+  if !hash_eq(HashAlgorithm::Xxh3, &expected, &actual) {
+    return Err(DownloadError::HashMismatch(expected, actual));
+  }
+  debug!("Verified segment {start}-{end}, xxh3 {actual}");
+  Ok(())
+}
+
+async fn fetch_range(
+  client: &Client, url: &str, validator: Option<&str>, part: &Path, state: &mut SegmentState,
+  progress: &ProgressReporter<impl Fn(TransferProgress)>,
+) -> Result<(), DownloadError> {
+  let Segment { start, end, written } = state.segment;
+  let len = end - start + 1;
+  let from = start + written;
+  if written >= len {
+    return Ok(());
+  }
+  let mut req = client.get(url).header(header::RANGE, format!("bytes={from}-{end}"));
+  if let Some(validator) = validator {
+    req = req.header(header::IF_RANGE, validator);
+  }
+  let response = req.send().await?;
+  // A full response to a range with `If-Range` means the file changed, the segments cannot be continued
+  if response.status() == StatusCode::OK && validator.is_some() {
+    return Err(DownloadError::Changed(format!(
+      "range {from}-{end} was answered with the whole file"
+    )));
+  }
+  if response.status() == StatusCode::PARTIAL_CONTENT && content_range_start(&response) != Some(from) {
+    return Err(DownloadError::Changed(format!(
+      "asked for bytes from {from}, got {:?}",
+      content_range_start(&response)
+    )));
+  }
+  if response.status() != StatusCode::PARTIAL_CONTENT {
+    error!(
+      "Server did not honor range {from}-{end} of {url}: {}",
+      response.status()
+    );
+    return Err(DownloadError::StatusError(response.status()));
+  }
+
+  let mut out = OpenOptions::new().write(true).open(part).await?;
+  out.seek(SeekFrom::Start(from)).await?;
+  let mut body = response.bytes_stream();
+  while let Some(chunk) = body.next().await {
+    let chunk = chunk?;
+    if state.segment.written + chunk.len() as u64 > len {
+      return Err(DownloadError::Incomplete(format!("range {start}-{end} overflowed")));
+    }
+    out.write_all(&chunk).await?;
+    state.hasher.update(&chunk);
+    state.segment.written += chunk.len() as u64;
+    progress.add(chunk.len() as u64);
+  }
+  out.flush().await?;
+  if state.segment.written < len {
+    return Err(DownloadError::Incomplete(format!(
+      "range {start}-{end} ended after {} bytes",
+      state.segment.written
+    )));
+  }
+  Ok(())
+}
+
+/// Feed the already downloaded part of a file into the hasher.
+async fn hash_partial(part: &Path, hasher: &mut StreamHasher) -> Result<(), DownloadError> {
+  let mut fd = File::open(part).await?;
+  let mut buf = vec![0u8; 64 * 1024];
+  loop {
+    let n = fd.read(&mut buf).await?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[..n]);
+  }
+  Ok(())
+}
+
+/// Get the first byte position from a `Content-Range: bytes <start>-<end>/<size>` header.
+fn content_range_start(response: &Response) -> Option<u64> {
+  let value = response.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+  debug!("Content-Range: {value}");
+  value.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex as StdMutex};
+
+  use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, Method},
+    response::IntoResponse as _,
+    routing::get,
+  };
+  use tokio::net::TcpListener;
+
+  use super::*;
+  use crate::utils::testing::TempPath;
+
+  /// What the test server serves, and how it misbehaves.
+  #[derive(Default)]
+  struct Served {
+    content: Vec<u8>,
+    etag: String,
+    /// Cut the body of the next response after this many bytes
+    truncate: Option<usize>,
+    /// Content and `ETag` to switch to after the next `HEAD` request, as if the file changed during a download
+    next_version: Option<(Vec<u8>, String)>,
+    /// Answer the next range request with a `Content-Range` that does not match it
+    misplace_range: bool,
+    /// `Range` headers of the `GET` requests received
+    ranges: Vec<Option<String>>,
+  }
+
+  type Server = Arc<StdMutex<Served>>;
+
+  fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let end = match end {
+      "" => len - 1,
+      end => end.parse::<usize>().ok()?.min(len - 1),
+    };
+    Some((start.parse().ok()?, end))
+  }
+
+  async fn serve(State(server): State<Server>, method: Method, headers: HeaderMap) -> axum::response::Response {
+    let mut served = server.lock().unwrap();
+    let (content, etag) = (served.content.clone(), served.etag.clone());
+    if method == Method::HEAD {
+      if let Some((content, etag)) = served.next_version.take() {
+        served.content = content;
+        served.etag = etag;
+      }
+    } else {
+      let range = headers.get(header::RANGE).map(|v| v.to_str().unwrap().to_string());
+      served.ranges.push(range);
+    }
+    let len = content.len();
+    let if_range = headers.get(header::IF_RANGE).is_none_or(|v| v.to_str().unwrap() == etag);
+    let range = headers.get(header::RANGE).filter(|_| if_range).and_then(|v| parse_range(v.to_str().unwrap(), len));
+    let (status, start, end) = match range {
+      Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end),
+      None => (StatusCode::OK, 0, len - 1),
+    };
+    let body = Bytes::copy_from_slice(&content[start..=end]);
+    let body_len = body.len();
+    let mut response = match served.truncate.take() {
+      // Fail once the first bytes had time to reach the client
+      Some(n) => Body::from_stream(futures_util::stream::once(async move { Ok(body.slice(..n)) }).chain(
+        futures_util::stream::once(async {
+          tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+          Err(std::io::Error::other("connection lost"))
+        }),
+      ))
+      .into_response(),
+      None => Body::from(body).into_response(),
+    };
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, etag.parse().unwrap());
+    headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+    headers.insert(header::CONTENT_LENGTH, body_len.into());
+    if status == StatusCode::PARTIAL_CONTENT {
+      let shown = if std::mem::take(&mut served.misplace_range) {
+        start + 1
+      } else {
+        start
+      };
+      headers.insert(
+        header::CONTENT_RANGE,
+        format!("bytes {shown}-{end}/{len}").parse().unwrap(),
+      );
+    }
+    response
+  }
+
+  async fn start_server(content: Vec<u8>, etag: &str) -> (Server, String) {
+    let server = Arc::new(StdMutex::new(Served {
+      content,
+      etag: etag.to_string(),
+      ..Default::default()
+    }));
+    let app = Router::new().route("/file", get(serve)).with_state(server.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/file", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (server, url)
+  }
+
+  /// `len` bytes that differ between `seed`s.
+  fn content(len: usize, seed: u8) -> Vec<u8> { (0..len).map(|i| (i % 251) as u8 ^ seed).collect() }
+
+  fn xxh3(data: &[u8]) -> String {
+    let mut hasher = StreamHasher::new(HashAlgorithm::Xxh3);
+    hasher.update(data);
+    hasher.finalize().unwrap()
+  }
+
+  fn new_dir() -> TempPath {
+    let dir = TempPath::new("mxa-download");
+    std::fs::create_dir(&dir).unwrap();
+    dir
+  }
+
+  #[tokio::test]
+  async fn test_part_meta() {
+    let dir = new_dir();
+    let part = dir.join("file.part");
+    assert!(PartMeta::load(&part).await.is_none());
+    PartMeta {
+      validator: "\"v1\"".to_string(),
+      segments: vec![Segment {
+        start: 0,
+        end: 9,
+        written: 4,
+      }],
+    }
+    .save(&part)
+    .await
+    .unwrap();
+    assert_eq!(PartMeta::path(&part), dir.join("file.part.meta"));
+    let meta = PartMeta::load(&part).await.unwrap();
+    assert_eq!(meta.validator, "\"v1\"");
+    assert_eq!((meta.segments[0].end, meta.segments[0].written), (9, 4));
+    PartMeta::remove(&part).await;
+    assert!(PartMeta::load(&part).await.is_none());
+    // Unreadable metadata is ignored rather than trusted
+    std::fs::write(PartMeta::path(&part), b"{").unwrap();
+    assert!(PartMeta::load(&part).await.is_none());
+  }
+
+  #[tokio::test]
+  async fn test_plan_segments() {
+    let dir = new_dir();
+    let part = dir.join("file.part");
+    let size = 3 * MIN_SEGMENT_SIZE;
+    let opts = DownloadOptions {
+      segments: 4,
+      ..Default::default()
+    };
+    // Segments are at least MIN_SEGMENT_SIZE and cover the file
+    let segments = plan_segments("url", &part, size, Some("v1"), &opts).await.unwrap();
+    let bounds: Vec<_> = segments.iter().map(|s| (s.start, s.end, s.written)).collect();
+    assert_eq!(
+      bounds,
+      [
+        (0, MIN_SEGMENT_SIZE - 1, 0),
+        (MIN_SEGMENT_SIZE, 2 * MIN_SEGMENT_SIZE - 1, 0),
+        (2 * MIN_SEGMENT_SIZE, size - 1, 0)
+      ]
+    );
+    assert_eq!(std::fs::metadata(&part).unwrap().len(), size);
+
+    // The progress of the same version of the file is continued
+    let mut progressed = segments.clone();
+    progressed[1].written = 100;
+    PartMeta {
+      validator: "v1".to_string(),
+      segments: progressed,
+    }
+    .save(&part)
+    .await
+    .unwrap();
+    let resumed = plan_segments("url", &part, size, Some("v1"), &opts).await.unwrap();
+    assert_eq!(resumed[1].written, 100);
+    // but not that of another version
+    let replanned = plan_segments("url", &part, size, Some("v2"), &opts).await.unwrap();
+    assert!(replanned.iter().all(|s| s.written == 0));
+
+    // A partial file of a single stream is continued from its length
+    PartMeta {
+      validator: "v2".to_string(),
+      segments: vec![],
+    }
+    .save(&part)
+    .await
+    .unwrap();
+    File::create(&part).await.unwrap().set_len(MIN_SEGMENT_SIZE).await.unwrap();
+    let continued = plan_segments("url", &part, size, Some("v2"), &opts).await.unwrap();
+    assert_eq!(continued.first().map(|s| s.start), Some(MIN_SEGMENT_SIZE));
+    assert_eq!(continued.last().map(|s| s.end), Some(size - 1));
+  }
+
+  #[tokio::test]
+  async fn test_verify_segment() {
+    let dir = new_dir();
+    let part = dir.join("file.part");
+    std::fs::write(&part, b"0123456789").unwrap();
+    let state = |received: &[u8]| {
+      let mut hasher = StreamHasher::new(HashAlgorithm::Xxh3);
+      hasher.update(received);
+      SegmentState {
+        segment: Segment {
+          start: 2,
+          end: 7,
+          written: 6,
+        },
+        hashed_from: 4,
+        hasher,
+      }
+    };
+    // Only the bytes received since `hashed_from` are compared
+    verify_segment(&part, &mut state(b"4567")).await.unwrap();
+    assert!(matches!(
+      verify_segment(&part, &mut state(b"4568")).await,
+      Err(DownloadError::HashMismatch(..))
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_resume_after_short_read() {
+    let data = content(256 * 1024, 1);
+    let (server, url) = start_server(data.clone(), "\"v1\"").await;
+    server.lock().unwrap().truncate = Some(64 * 1024);
+    let dir = new_dir();
+    let part = dir.join("file.part");
+    let (client, opts) = (Client::new(), DownloadOptions::default());
+    let progress = ProgressReporter::new(None, |_| {});
+
+    assert!(download_once(&client, &url, &part, &opts, &progress).await.unwrap_err().is_transient());
+    let offset = std::fs::metadata(&part).unwrap().len();
+    assert!(offset > 0 && offset < data.len() as u64);
+    assert_eq!(
+      download_once(&client, &url, &part, &opts, &progress).await.unwrap(),
+      xxh3(&data)
+    );
+    assert_eq!(std::fs::read(&part).unwrap(), data);
+    assert_eq!(server.lock().unwrap().ranges, [None, Some(format!("bytes={offset}-"))]);
+  }
+
+  #[tokio::test]
+  async fn test_restart_changed_file() {
+    let (old, new) = (content(256 * 1024, 1), content(256 * 1024, 2));
+    let (server, url) = start_server(new.clone(), "\"v2\"").await;
+    let dir = new_dir();
+    let path = dir.join("file");
+    let part = partial_path_for(&path);
+    let path = path.to_string_lossy().to_string();
+
+    // A partial file of an older version is not continued
+    std::fs::write(&part, &old[..1000]).unwrap();
+    PartMeta {
+      validator: "\"v1\"".to_string(),
+      segments: vec![],
+    }
+    .save(&part)
+    .await
+    .unwrap();
+    let hash = download_file(&url, &path, &DownloadOptions::default(), |_| {}).await.unwrap();
+    assert_eq!((hash, std::fs::read(&path).unwrap()), (xxh3(&new), new.clone()));
+
+    // Nor is one answered with another range than asked for
+    std::fs::write(&part, &new[..1000]).unwrap();
+    PartMeta {
+      validator: "\"v2\"".to_string(),
+      segments: vec![],
+    }
+    .save(&part)
+    .await
+    .unwrap();
+    server.lock().unwrap().misplace_range = true;
+    let hash = download_file(&url, &path, &DownloadOptions::default(), |_| {}).await.unwrap();
+    assert_eq!((hash, std::fs::read(&path).unwrap()), (xxh3(&new), new));
+    assert!(!part.exists() && !PartMeta::path(&part).exists());
+    // The range answered wrongly was discarded and fetched again from the start
+    let requests = server.lock().unwrap().ranges.clone();
+    assert_eq!(requests[requests.len() - 2..], [Some("bytes=1000-".to_string()), None]);
+  }
+
+  #[tokio::test]
+  async fn test_segmented_download_restarts_changed_file() {
+    let size = 2 * MIN_SEGMENT_SIZE as usize;
+    let (old, new) = (content(size, 1), content(size, 2));
+    let (server, url) = start_server(old, "\"v1\"").await;
+    // The file changes right after the download is planned
+    server.lock().unwrap().next_version = Some((new.clone(), "\"v2\"".to_string()));
+    let dir = new_dir();
+    let path = dir.join("file").to_string_lossy().to_string();
+    let opts = DownloadOptions {
+      segments: 2,
+      ..Default::default()
+    };
+
+    let hash = download_file(&url, &path, &opts, |_| {}).await.unwrap();
+    assert_eq!(hash, xxh3(&new));
+    assert_eq!(std::fs::read(&path).unwrap(), new);
+    // Both segments were requested again after the first answer showed the change
+    assert!(server.lock().unwrap().ranges.len() >= 3);
+  }
+}
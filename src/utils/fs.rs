@@ -0,0 +1,233 @@
+use std::{
+  fs::Metadata,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use log::{debug, warn};
+use tokio::{
+  fs::{self, OpenOptions},
+  io::AsyncWriteExt as _,
+};
+
+use crate::utils::util::random_str;
+
+const TEMP_SUFFIX: &str = ".mxa-tmp";
+
+/// Permission bits and ownership to apply to a file.
+#[derive(Debug, Clone, Default)]
+pub struct FileAttrs {
+  pub mode: Option<u32>,
+  /// User name or numeric uid
+  pub owner: Option<String>,
+  /// Group name or numeric gid
+  pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+  /// Write to a temporary file in the same directory, then rename it into place.
+  pub atomic: bool,
+  /// Append to the existing contents instead of replacing them.
+  pub append: bool,
+  /// Create missing parent directories.
+  pub create_parents: bool,
+  /// Copy the previous contents to `<path>.bak` before writing.
+  pub backup: bool,
+  pub attrs: FileAttrs,
+}
+
+/// Get a path for a temporary file next to `path`.
+///
+/// The temporary file lives in the same directory, so it can be renamed over `path` atomically.
+pub fn temp_path_for(path: &Path) -> PathBuf {
+  let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  path.with_file_name(format!(".{name}.{}{TEMP_SUFFIX}", random_str(8)))
+}
+
+/// Whether `path` is a temporary file created by [`temp_path_for`].
+pub fn is_temp_path(path: &Path) -> bool { path.to_string_lossy().ends_with(TEMP_SUFFIX) }
+
+/// Get the path where the previous contents of `path` are kept.
+pub fn backup_path_for(path: &Path) -> PathBuf {
+  let mut p = path.as_os_str().to_owned();
+  p.push(".bak");
+  PathBuf::from(p)
+}
+
+/// Get the path where a download to `path` is kept until it is complete.
+///
+/// The path is stable, so an interrupted download can be resumed.
+pub fn partial_path_for(path: &Path) -> PathBuf {
+  let mut p = path.as_os_str().to_owned();
+  p.push(".part");
+  PathBuf::from(p)
+}
+
+/// Create all missing parent directories of `path`.
+pub async fn create_parent_dirs(path: &Path) -> Result<()> {
+  if let Some(parent) = path.parent() &&
+    !parent.as_os_str().is_empty()
+  {
+    fs::create_dir_all(parent).await?;
+  }
+  Ok(())
+}
+
+/// Apply ownership and permission bits to `path`.
+///
+/// Ownership is changed first, since `chown` may clear setuid and setgid bits.
+pub async fn apply_attrs(path: &Path, attrs: &FileAttrs) -> Result<()> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    if attrs.owner.is_some() || attrs.group.is_some() {
+      let uid = attrs.owner.as_deref().map(resolve_uid).transpose()?;
+      let gid = attrs.group.as_deref().map(resolve_gid).transpose()?;
+      nix::unistd::chown(path, uid, gid)?;
+    }
+    if let Some(mode) = attrs.mode {
+      fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    }
+  }
+  #[cfg(not(unix))]
+  {
+    if attrs.mode.is_some() || attrs.owner.is_some() || attrs.group.is_some() {
+      anyhow::bail!("Setting file mode or owner is not supported on this platform");
+    }
+  }
+  debug!("Applied attributes to {}: {attrs:?}", path.display());
+  Ok(())
+}
+
+#[cfg(unix)]
+fn resolve_uid(owner: &str) -> Result<nix::unistd::Uid> {
+  if let Ok(uid) = owner.parse::<u32>() {
+    return Ok(nix::unistd::Uid::from_raw(uid));
+  }
+  match nix::unistd::User::from_name(owner)? {
+    Some(user) => Ok(user.uid),
+    None => anyhow::bail!("Unknown user: {owner}"),
+  }
+}
+
+#[cfg(unix)]
+fn resolve_gid(group: &str) -> Result<nix::unistd::Gid> {
+  if let Ok(gid) = group.parse::<u32>() {
+    return Ok(nix::unistd::Gid::from_raw(gid));
+  }
+  match nix::unistd::Group::from_name(group)? {
+    Some(group) => Ok(group.gid),
+    None => anyhow::bail!("Unknown group: {group}"),
+  }
+}
+
+/// Fill attributes that were not requested explicitly from the file being replaced.
+///
+/// Ownership is only inherited when running as root, as nobody else may give a file away.
+fn inherit_attrs(attrs: &FileAttrs, previous: Option<&Metadata>) -> FileAttrs {
+  let mut attrs = attrs.clone();
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+
+    if let Some(meta) = previous {
+      if attrs.mode.is_none() {
+        attrs.mode = Some(meta.permissions().mode() & 0o7777);
+      }
+      if nix::unistd::geteuid().is_root() {
+        if attrs.owner.is_none() {
+          attrs.owner = Some(meta.uid().to_string());
+        }
+        if attrs.group.is_none() {
+          attrs.group = Some(meta.gid().to_string());
+        }
+      }
+    }
+  }
+  #[cfg(not(unix))]
+  let _ = previous;
+  attrs
+}
+
+/// Write `content` to `path` according to `opts`.
+///
+/// Returns the path of the backup if one was made.
+pub async fn write_file(path: &Path, content: &[u8], opts: &WriteOptions) -> Result<Option<PathBuf>> {
+  if opts.create_parents {
+    create_parent_dirs(path).await?;
+  }
+  let previous = fs::metadata(path).await.ok().filter(|m| m.is_file());
+  let backup = if opts.backup && previous.is_some() {
+    let backup = backup_path_for(path);
+    fs::copy(path, &backup).await?;
+    debug!("Backed up {} to {}", path.display(), backup.display());
+    Some(backup)
+  } else {
+    None
+  };
+
+  if opts.atomic {
+    let tmp = temp_path_for(path);
+    if let Err(err) = write_and_rename(path, &tmp, content, opts, previous.as_ref()).await {
+      if let Err(e) = fs::remove_file(&tmp).await &&
+        e.kind() != std::io::ErrorKind::NotFound
+      {
+        warn!("Failed to remove temporary file {}: {e}", tmp.display());
+      }
+      return Err(err);
+    }
+  } else {
+    let mut file = OpenOptions::new()
+      .write(true)
+      .create(true)
+      .append(opts.append)
+      .truncate(!opts.append)
+      .open(path)
+      .await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    apply_attrs(path, &opts.attrs).await?;
+  }
+  Ok(backup)
+}
+
+async fn write_and_rename(
+  path: &Path, tmp: &Path, content: &[u8], opts: &WriteOptions, previous: Option<&Metadata>,
+) -> Result<()> {
+  if opts.append && previous.is_some() {
+    fs::copy(path, tmp).await?;
+  }
+  let mut file = OpenOptions::new().create(true).append(true).open(tmp).await?;
+  file.write_all(content).await?;
+  file.sync_all().await?;
+  drop(file);
+  apply_attrs(tmp, &inherit_attrs(&opts.attrs, previous)).await?;
+  fs::rename(tmp, path).await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::testing::TempPath;
+
+  #[tokio::test]
+  async fn test_write_file() {
+    let dir = TempPath::new("mxlite-test");
+    let path = dir.join("sub/file.txt");
+    let mut opts = WriteOptions {
+      atomic: true,
+      create_parents: true,
+      ..Default::default()
+    };
+    assert!(write_file(&path, b"hello", &opts).await.unwrap().is_none());
+
+    opts.append = true;
+    opts.backup = true;
+    let backup = write_file(&path, b" world", &opts).await.unwrap().unwrap();
+    assert_eq!(fs::read(&path).await.unwrap(), b"hello world");
+    assert_eq!(fs::read(&backup).await.unwrap(), b"hello");
+  }
+}
@@ -1,8 +1,9 @@
-use std::{hash::Hasher, io::Error};
+use std::{collections::HashMap, hash::Hasher, io::Error, str::FromStr};
 use thiserror::Error;
 
 use base16ct::lower;
 use digest::{Digest, DynDigest};
+use serde::{Deserialize, Serialize};
 use tokio::{fs::File, io::AsyncReadExt};
 use xxhash_rust::xxh3::Xxh3;
 
@@ -103,85 +104,168 @@ pub async fn sha3_512_for_file(path: &str) -> Result<String, HashError> {
   digest_for_file(path, Box::new(<sha3::Sha3_512 as Digest>::new())).await
 }
 
-/// Calculate digests for a file at the given path using the provided hashers.
-///
-/// Returns a vector of hashes in base16 format.
-pub async fn digests_for_file(
-  path: &str, mut hashers: Vec<Box<dyn DynDigest + Send + Unpin>>,
-) -> Result<Vec<String>, HashError> {
-  let mut fd = File::open(path).await?;
-  // for files smaller than 1MB we can read the whole file into memory
-  let metadata = fd.metadata().await.map_err(HashError::IoError)?;
-  if metadata.len() < 1024 * 1024 {
-    let mut buf = Vec::new();
-    fd.read_to_end(&mut buf).await?;
-    for hasher in &mut hashers {
-      hasher.update(&buf);
-    }
-  } else {
-    let mut buf = [0u8; 4 * 1024];
+/// Calculate the hashes of a file in each of `algorithms`, reading it once.
+pub async fn hashes_for_file(
+  path: &str, algorithms: &[HashAlgorithm],
+) -> Result<HashMap<HashAlgorithm, String>, HashError> {
+  let mut hashers: Vec<_> = algorithms.iter().map(|&algorithm| (algorithm, StreamHasher::new(algorithm))).collect();
+  if !hashers.is_empty() {
+    let mut fd = File::open(path).await?;
+    let mut buf = vec![0u8; 64 * 1024];
     loop {
       let n = fd.read(&mut buf).await?;
       if n == 0 {
         break;
       }
-      for hasher in &mut hashers {
+      for (_, hasher) in &mut hashers {
         hasher.update(&buf[..n]);
       }
     }
   }
+  hashers.into_iter().map(|(algorithm, hasher)| Ok((algorithm, hasher.finalize()?))).collect()
+}
 
-  let mut hashes = Vec::with_capacity(hashers.len());
-  for hasher in &mut hashers {
-    let hash = hasher.finalize_reset();
-    let mut buf = vec![0u8; hash.len() * 2];
-    hashes.push(lower::encode_str(&hash, buf.as_mut_slice()).map_err(HashError::Base16Error)?.to_string());
-  }
-  Ok(hashes)
+pub fn sha2_256_for_str(input: &str) -> Result<String, HashError> { sha2_256_for_bytes(input.as_bytes()) }
+
+pub fn sha2_256_for_bytes(input: &[u8]) -> Result<String, HashError> {
+  let mut hasher = sha2::Sha256::new();
+  Digest::update(&mut hasher, input);
+  let hash = hasher.finalize();
+  let mut buf = vec![0u8; hash.len() * 2];
+  Ok(lower::encode_str(&hash, buf.as_mut_slice()).map_err(HashError::Base16Error)?.to_string())
 }
 
-/// Calculate multiple hashes for a file at the given path.
-pub async fn hashes_for_file(
-  path: &str, calc_md5: bool, calc_sha1: bool, calc_sha2_256: bool, calc_sha3_512: bool,
-) -> Result<(Option<String>, Option<String>, Option<String>, Option<String>), HashError> {
-  let mut hashers: Vec<Box<dyn DynDigest + Send + Unpin>> = Vec::new();
-  if calc_md5 {
-    hashers.push(Box::new(<md5::Md5 as Digest>::new()));
-  }
-  if calc_sha1 {
-    hashers.push(Box::new(<sha1::Sha1 as Digest>::new()));
-  }
-  if calc_sha2_256 {
-    hashers.push(Box::new(<sha2::Sha256 as Digest>::new()));
+/// Hash algorithms that can be used to verify transferred files.
+///
+/// `sha512` has always named SHA3-512 in the `X-Hash-Sha512` header and the `?sha512` query parameter of published
+/// files. It is kept as a deprecated alias of `sha3-512` everywhere, SHA-512 is `sha2-512`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+  #[default]
+  Xxh3,
+  Md5,
+  Sha1,
+  Sha256,
+  #[serde(rename = "sha2-512")]
+  Sha512,
+  #[serde(rename = "sha3-256")]
+  Sha3_256,
+  #[serde(rename = "sha3-512", alias = "sha512")]
+  Sha3_512,
+  Blake3,
+}
+
+impl HashAlgorithm {
+  pub const ALL: [HashAlgorithm; 8] = [
+    HashAlgorithm::Xxh3,
+    HashAlgorithm::Md5,
+    HashAlgorithm::Sha1,
+    HashAlgorithm::Sha256,
+    HashAlgorithm::Sha512,
+    HashAlgorithm::Sha3_256,
+    HashAlgorithm::Sha3_512,
+    HashAlgorithm::Blake3,
+  ];
+
+  /// Name of the algorithm, as used in requests and query parameters.
+  pub fn name(self) -> &'static str {
+    match self {
+      HashAlgorithm::Xxh3 => "xxh3",
+      HashAlgorithm::Md5 => "md5",
+      HashAlgorithm::Sha1 => "sha1",
+      HashAlgorithm::Sha256 => "sha256",
+      HashAlgorithm::Sha512 => "sha2-512",
+      HashAlgorithm::Sha3_256 => "sha3-256",
+      HashAlgorithm::Sha3_512 => "sha3-512",
+      HashAlgorithm::Blake3 => "blake3",
+    }
   }
-  if calc_sha3_512 {
-    hashers.push(Box::new(<sha3::Sha3_512 as Digest>::new()));
+
+  /// Names of the response headers carrying a file's hash in this algorithm. SHA3-512 is also sent in the deprecated
+  /// `X-Hash-Sha512`.
+  pub fn header_names(self) -> &'static [&'static str] {
+    match self {
+      HashAlgorithm::Xxh3 => &["X-Hash-Xxh3"],
+      HashAlgorithm::Md5 => &["X-Hash-Md5"],
+      HashAlgorithm::Sha1 => &["X-Hash-Sha1"],
+      HashAlgorithm::Sha256 => &["X-Hash-Sha256"],
+      HashAlgorithm::Sha512 => &["X-Hash-Sha2-512"],
+      HashAlgorithm::Sha3_256 => &["X-Hash-Sha3-256"],
+      HashAlgorithm::Sha3_512 => &["X-Hash-Sha3-512", "X-Hash-Sha512"],
+      HashAlgorithm::Blake3 => &["X-Hash-Blake3"],
+    }
   }
-  let mut hashes = digests_for_file(path, hashers).await?;
-  let mut result: (Option<String>, Option<String>, Option<String>, Option<String>) = (None, None, None, None);
-  if calc_md5 {
-    let hash = hashes.remove(0);
-    result.0 = Some(hash);
+}
+
+impl FromStr for HashAlgorithm {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.eq_ignore_ascii_case("sha512") {
+      return Ok(HashAlgorithm::Sha3_512);
+    }
+    HashAlgorithm::ALL
+      .into_iter()
+      .find(|algorithm| algorithm.name().eq_ignore_ascii_case(s))
+      .ok_or_else(|| format!("Unknown hash algorithm: {s}"))
   }
-  if calc_sha1 {
-    let hash = hashes.remove(0);
-    result.1 = Some(hash);
+}
+
+/// Incremental hasher over one of the [`HashAlgorithm`]s, for data that arrives in chunks.
+pub enum StreamHasher {
+  Xxh3(Box<Xxh3>),
+  Blake3(Box<blake3::Hasher>),
+  Digest(Box<dyn DynDigest + Send>),
+}
+
+impl StreamHasher {
+  pub fn new(algorithm: HashAlgorithm) -> Self {
+    match algorithm {
+      HashAlgorithm::Xxh3 => StreamHasher::Xxh3(Box::new(Xxh3::new())),
+      HashAlgorithm::Md5 => StreamHasher::Digest(Box::new(<md5::Md5 as Digest>::new())),
+      HashAlgorithm::Sha1 => StreamHasher::Digest(Box::new(<sha1::Sha1 as Digest>::new())),
+      HashAlgorithm::Sha256 => StreamHasher::Digest(Box::new(<sha2::Sha256 as Digest>::new())),
+      HashAlgorithm::Sha512 => StreamHasher::Digest(Box::new(<sha2::Sha512 as Digest>::new())),
+      HashAlgorithm::Sha3_256 => StreamHasher::Digest(Box::new(<sha3::Sha3_256 as Digest>::new())),
+      HashAlgorithm::Sha3_512 => StreamHasher::Digest(Box::new(<sha3::Sha3_512 as Digest>::new())),
+      HashAlgorithm::Blake3 => StreamHasher::Blake3(Box::new(blake3::Hasher::new())),
+    }
   }
-  if calc_sha2_256 {
-    let hash = hashes.remove(0);
-    result.2 = Some(hash);
+
+  pub fn update(&mut self, data: &[u8]) {
+    match self {
+      StreamHasher::Xxh3(hasher) => hasher.update(data),
+      StreamHasher::Blake3(hasher) => {
+        hasher.update(data);
+      }
+      StreamHasher::Digest(hasher) => hasher.update(data),
+    }
   }
-  if calc_sha3_512 {
-    let hash = hashes.remove(0);
-    result.3 = Some(hash);
+
+  /// Returns the hash in base16 format, in the same format as the `*_for_file` functions.
+  pub fn finalize(self) -> Result<String, HashError> {
+    match self {
+      StreamHasher::Xxh3(hasher) => Ok(format!("{:x}", hasher.digest())),
+      StreamHasher::Blake3(hasher) => Ok(hasher.finalize().to_hex().to_string()),
+      StreamHasher::Digest(hasher) => {
+        let hash = hasher.finalize();
+        let mut buf = vec![0u8; hash.len() * 2];
+        Ok(lower::encode_str(&hash, buf.as_mut_slice()).map_err(HashError::Base16Error)?.to_string())
+      }
+    }
   }
-  Ok(result)
 }
 
-pub fn sha2_256_for_str(input: &str) -> Result<String, HashError> {
-  let mut hasher = sha2::Sha256::new();
-  Digest::update(&mut hasher, input.as_bytes());
-  let hash = hasher.finalize();
-  let mut buf = vec![0u8; hash.len() * 2];
-  Ok(lower::encode_str(&hash, buf.as_mut_slice()).map_err(HashError::Base16Error)?.to_string())
+/// Compare two base16 hashes of the given algorithm.
+///
+/// xxh3 hashes are compared numerically, since they are not zero-padded.
+pub fn hash_eq(algorithm: HashAlgorithm, a: &str, b: &str) -> bool {
+  match algorithm {
+    HashAlgorithm::Xxh3 => matches!(
+      (u64::from_str_radix(a, 16), u64::from_str_radix(b, 16)),
+      (Ok(a), Ok(b)) if a == b
+    ),
+    _ => a.eq_ignore_ascii_case(b),
+  }
 }
@@ -0,0 +1,43 @@
+use std::sync::{Arc, RwLock};
+
+use log::warn;
+use reqwest::Client;
+use tokio_rustls::rustls::ClientConfig;
+use url::{Origin, Url};
+
+/// Client for the URLs of the controller the agent is connected to, built with the TLS configuration the agent
+/// reaches it with, so that a pinned CA or certificate and the client certificate apply to file transfers too.
+static CONTROLLER_CLIENT: RwLock<Option<(Origin, Client)>> = RwLock::new(None);
+
+/// Use `tls_config` for HTTPS URLs of the controller reached at `ws_url`, or the default configuration if `None`.
+pub fn set_controller_tls(ws_url: &Url, tls_config: Option<Arc<ClientConfig>>) {
+  let mut url = ws_url.clone();
+  let scheme = if ws_url.scheme() == "wss" { "https" } else { "http" };
+  let client = match tls_config {
+    Some(tls_config) => Client::builder().use_preconfigured_tls((*tls_config).clone()).build(),
+    None => Ok(Client::new()),
+  };
+  let entry = match (url.set_scheme(scheme), client) {
+    (Ok(()), Ok(client)) => Some((url.origin(), client)),
+    (_, Err(err)) => {
+      warn!("Failed to build HTTP client for controller at {ws_url}: {err}");
+      None
+    }
+    _ => None,
+  };
+  if let Ok(mut controller) = CONTROLLER_CLIENT.write() {
+    *controller = entry;
+  }
+}
+
+/// HTTP client to fetch `url` with: the controller's one if it points at the controller, the default one otherwise.
+pub fn client_for(url: &str) -> Client {
+  let origin = Url::parse(url).map(|url| url.origin());
+  if let (Ok(origin), Ok(controller)) = (origin, CONTROLLER_CLIENT.read()) &&
+    let Some((controller_origin, client)) = controller.as_ref() &&
+    *controller_origin == origin
+  {
+    return client.clone();
+  }
+  Client::new()
+}
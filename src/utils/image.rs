@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+
+use futures_util::TryStreamExt as _;
+use log::{info, warn};
+use reqwest::StatusCode;
+use thiserror::Error;
+use tokio::{
+  fs::{self, File, OpenOptions},
+  io::{AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+};
+use tokio_util::io::StreamReader;
+
+use crate::{
+  protocol::messaging::TransferProgress,
+  system_info::{SystemInfo, collect_info},
+  utils::{
+    compression::{Compression, decompress},
+    fs::partial_path_for,
+    hash::{HashAlgorithm, HashError, StreamHasher, hash_eq},
+    http::client_for,
+    progress::ProgressReporter,
+  },
+};
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+  #[error("Request error: {0}")]
+  RequestError(#[from] reqwest::Error),
+  #[error("Io error: {0}")]
+  IoError(#[from] std::io::Error),
+  #[error("Hash error: {0}")]
+  HashError(#[from] HashError),
+  #[error("Server returned {0}")]
+  StatusError(StatusCode),
+  #[error("Hash mismatch: expected {0}, got {1}")]
+  HashMismatch(String, String),
+  #[error("Target is in use: {0}")]
+  TargetInUse(String),
+  #[error("Target is read-only: {0}")]
+  ReadOnly(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImageOptions {
+  /// Compression of the source. Detected from the stream header if not set.
+  pub compression: Option<Compression>,
+  /// Digest the downloaded stream must match, in `hash_algorithm`, before decompression.
+  pub expected_hash: Option<String>,
+  pub hash_algorithm: HashAlgorithm,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageOutcome {
+  /// Bytes written to the target
+  pub written: u64,
+  /// Hash of the downloaded stream
+  pub hash: String,
+  /// Hash of the data written to the target
+  pub image_hash: String,
+}
+
+/// Stream a disk image from `url` to `target`, decompressing it on the fly.
+///
+/// Block devices are written in place, after checking that neither the device nor any of its partitions is mounted.
+/// Regular files are written to `<target>.part` and renamed into place once the hash is verified. A device that
+/// fails verification has already been overwritten, so the error only tells the caller not to trust it.
+///
+/// `on_progress` receives the number of downloaded bytes, which can be compared to the `Content-Length`.
+pub async fn write_image(
+  url: &str, target: &Path, opts: &ImageOptions, on_progress: impl Fn(TransferProgress) + Sync,
+) -> Result<ImageOutcome, ImageError> {
+  info!("Writing image from {url} to {}", target.display());
+  let is_device = fs::metadata(target).await.is_ok_and(|m| is_block_device(&m));
+  if is_device {
+    let info = tokio::task::spawn_blocking(collect_info).await.map_err(std::io::Error::other)?;
+    ensure_not_in_use(target, &info)?;
+  }
+  let (out_path, out) = if is_device {
+    (target.to_path_buf(), open_device(target).await?)
+  } else {
+    let part = partial_path_for(target);
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(&part).await?;
+    (part, file)
+  };
+
+  let r = stream_image(url, out, opts, on_progress).await;
+  let r = r.and_then(|outcome| match &opts.expected_hash {
+    Some(expected) if !hash_eq(opts.hash_algorithm, expected, &outcome.hash) => {
+      Err(ImageError::HashMismatch(expected.clone(), outcome.hash))
+    }
+    _ => Ok(outcome),
+  });
+  match r {
+    Ok(outcome) => {
+      if !is_device {
+        fs::rename(&out_path, target).await?;
+      }
+      info!(
+        "Wrote {} bytes from {url} to {}. {:?}: {}",
+        outcome.written,
+        target.display(),
+        opts.hash_algorithm,
+        outcome.hash
+      );
+      Ok(outcome)
+    }
+    Err(err) => {
+      if !is_device && let Err(e) = fs::remove_file(&out_path).await {
+        warn!("Failed to remove partial image {}: {e}", out_path.display());
+      }
+      Err(err)
+    }
+  }
+}
+
+async fn stream_image(
+  url: &str, mut out: File, opts: &ImageOptions, on_progress: impl Fn(TransferProgress) + Sync,
+) -> Result<ImageOutcome, ImageError> {
+  let resp = client_for(url).get(url).send().await?;
+  if !resp.status().is_success() {
+    return Err(ImageError::StatusError(resp.status()));
+  }
+  let progress = ProgressReporter::new(resp.content_length(), on_progress);
+  let mut hasher = StreamHasher::new(opts.hash_algorithm);
+  let stream = resp
+    .bytes_stream()
+    .inspect_ok(|chunk| {
+      hasher.update(chunk);
+      progress.add(chunk.len() as u64);
+    })
+    .map_err(std::io::Error::other);
+  let reader = BufReader::with_capacity(BUFFER_SIZE, StreamReader::new(Box::pin(stream)));
+  let mut decoder = decompress(reader, opts.compression).await?;
+
+  let mut image_hasher = StreamHasher::new(opts.hash_algorithm);
+  let mut buf = vec![0u8; BUFFER_SIZE];
+  let mut written = 0u64;
+  loop {
+    let n = decoder.read(&mut buf).await?;
+    if n == 0 {
+      break;
+    }
+    out.write_all(&buf[..n]).await?;
+    image_hasher.update(&buf[..n]);
+    written += n as u64;
+  }
+  out.sync_all().await?;
+  drop(decoder);
+  Ok(ImageOutcome {
+    written,
+    hash: hasher.finalize()?,
+    image_hash: image_hasher.finalize()?,
+  })
+}
+
+fn is_block_device(meta: &std::fs::Metadata) -> bool {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::FileTypeExt as _;
+    meta.file_type().is_block_device()
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = meta;
+    false
+  }
+}
+
+async fn open_device(path: &Path) -> Result<File, ImageError> {
+  let mut opts = OpenOptions::new();
+  opts.write(true);
+  // The kernel refuses exclusive opens of block devices claimed by a filesystem, LVM or RAID.
+  #[cfg(target_os = "linux")]
+  opts.custom_flags(nix::fcntl::OFlag::O_EXCL.bits());
+  opts.open(path).await.map_err(|err| {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(nix::libc::EBUSY) {
+      return ImageError::TargetInUse(path.display().to_string());
+    }
+    err.into()
+  })
+}
+
+/// Check the mount table and block device list for reasons not to overwrite `target`.
+fn ensure_not_in_use(target: &Path, info: &SystemInfo) -> Result<(), ImageError> {
+  let target = target.canonicalize()?;
+  let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  for mnt in &info.mnts {
+    let Ok(dev) = Path::new(&mnt.device_name).canonicalize() else {
+      continue;
+    };
+    if dev.starts_with("/dev") &&
+      dev.file_name().is_some_and(|dev_name| is_same_or_partition(&name, &dev_name.to_string_lossy()))
+    {
+      return Err(ImageError::TargetInUse(format!(
+        "{} is mounted at {}",
+        mnt.device_name, mnt.mount_point
+      )));
+    }
+  }
+  let blk = info
+    .blks
+    .iter()
+    .find(|blk| blk.name == name || blk.path.as_deref().map(PathBuf::from).as_ref() == Some(&target));
+  if let Some(blk) = blk &&
+    blk.readonly
+  {
+    return Err(ImageError::ReadOnly(target.display().to_string()));
+  }
+  Ok(())
+}
+
+/// Whether `dev` is the disk `disk` or one of its partitions, e.g. `sda1` of `sda` or `nvme0n1p2` of `nvme0n1`.
+fn is_same_or_partition(disk: &str, dev: &str) -> bool {
+  let Some(rest) = dev.strip_prefix(disk) else {
+    return false;
+  };
+  match rest.strip_prefix('p') {
+    Some(num) => !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()),
+    None => rest.chars().all(|c| c.is_ascii_digit()),
+  }
+}
@@ -1,6 +1,15 @@
+pub mod archive;
 pub mod cert;
+pub mod compression;
+pub mod download;
+pub mod fs;
 pub mod hash;
+pub mod http;
+pub mod image;
+pub mod progress;
 pub mod retry;
 pub mod signal;
 pub mod states;
+#[cfg(test)]
+pub(crate) mod testing;
 pub mod util;
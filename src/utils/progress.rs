@@ -0,0 +1,102 @@
+use std::{
+  future::Future,
+  sync::{
+    Mutex,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::{Duration, Instant},
+};
+
+use crate::protocol::messaging::TransferProgress;
+
+/// Minimum time between two progress reports of the same transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+struct ReportState {
+  total: Option<u64>,
+  /// Time and byte count of the previous report, used for the throughput
+  last: Option<(Instant, u64)>,
+}
+
+/// Counts the bytes of a running transfer and turns them into periodic [`TransferProgress`] reports.
+///
+/// Shared by reference, so concurrent parts of the same transfer can count into one reporter.
+pub struct ProgressReporter<F: Fn(TransferProgress)> {
+  callback: F,
+  transferred: AtomicU64,
+  state: Mutex<ReportState>,
+}
+
+impl<F: Fn(TransferProgress)> ProgressReporter<F> {
+  pub fn new(total: Option<u64>, callback: F) -> Self {
+    ProgressReporter {
+      callback,
+      transferred: AtomicU64::new(0),
+      state: Mutex::new(ReportState { total, last: None }),
+    }
+  }
+
+  pub fn set_total(&self, total: Option<u64>) {
+    if let Ok(mut state) = self.state.lock() {
+      state.total = total;
+    }
+  }
+
+  /// Set the number of bytes transferred so far, e.g. when resuming from a partial file.
+  pub fn set(&self, transferred: u64) {
+    self.transferred.store(transferred, Ordering::Relaxed);
+    self.maybe_report(transferred);
+  }
+
+  /// Count `n` more bytes as transferred.
+  pub fn add(&self, n: u64) {
+    let transferred = self.transferred.fetch_add(n, Ordering::Relaxed) + n;
+    self.maybe_report(transferred);
+  }
+
+  /// Stop counting `n` bytes, of a part that has to be transferred again.
+  pub fn sub(&self, n: u64) {
+    let transferred = self.transferred.fetch_sub(n, Ordering::Relaxed).saturating_sub(n);
+    self.maybe_report(transferred);
+  }
+
+  /// Report `transferred` bytes, unless the previous report was sent less than [`PROGRESS_INTERVAL`] ago.
+  fn maybe_report(&self, transferred: u64) {
+    let Ok(mut state) = self.state.try_lock() else {
+      return;
+    };
+    let now = Instant::now();
+    let Some((last_time, last_bytes)) = state.last else {
+      state.last = Some((now, transferred));
+      return;
+    };
+    let elapsed = now.duration_since(last_time);
+    if elapsed < PROGRESS_INTERVAL {
+      return;
+    }
+    state.last = Some((now, transferred));
+    let progress = TransferProgress {
+      transferred,
+      total: state.total,
+      rate: (transferred.saturating_sub(last_bytes) as f64 / elapsed.as_secs_f64()) as u64,
+    };
+    drop(state);
+    (self.callback)(progress);
+  }
+}
+
+/// Wait for `fut` while periodically reporting the bytes counted in `transferred`.
+///
+/// Used where the transfer is driven by reqwest or a blocking task, which cannot borrow the reporter.
+pub async fn wait_with_progress<T>(
+  fut: impl Future<Output = T>, transferred: &AtomicU64, progress: &ProgressReporter<impl Fn(TransferProgress)>,
+) -> T {
+  tokio::pin!(fut);
+  let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+  loop {
+    tokio::select! {
+      r = &mut fut => break r,
+      _ = ticker.tick() => progress.set(transferred.load(Ordering::Relaxed)),
+    }
+  }
+}
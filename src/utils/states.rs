@@ -77,6 +77,16 @@ impl<Key: Ord + Clone, State> StateMap<Key, State> {
     }
   }
 
+  /// Remove and return the entry for `key` if it matches `predicate`, under a single write lock.
+  pub fn remove_if(&self, key: &Key, predicate: impl FnOnce(&State) -> bool) -> Option<Arc<State>> {
+    let mut guard = self._inner.write().ok()?;
+    if predicate(guard.get(key)?) {
+      guard.remove(key)
+    } else {
+      None
+    }
+  }
+
   pub fn take_if(&self, key: Key, predicate: impl FnOnce(&State) -> bool) -> Option<Arc<State>> {
     let v = self.get_arc(&key);
     if let Some(v) = v {
@@ -0,0 +1,39 @@
+use std::{
+  ops::Deref,
+  path::{Path, PathBuf},
+};
+
+use crate::utils::util::random_str;
+
+/// A unique path in the temporary directory, removed with everything below it when dropped, so that a failing test
+/// does not leave files behind.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+  /// A path starting with `prefix`, that does not exist yet.
+  pub(crate) fn new(prefix: &str) -> Self { TempPath(std::env::temp_dir().join(format!("{prefix}-{}", random_str(8)))) }
+}
+
+impl Deref for TempPath {
+  type Target = Path;
+
+  fn deref(&self) -> &Path { &self.0 }
+}
+
+impl AsRef<Path> for TempPath {
+  fn as_ref(&self) -> &Path { &self.0 }
+}
+
+impl Drop for TempPath {
+  fn drop(&mut self) {
+    match std::fs::symlink_metadata(&self.0) {
+      Ok(meta) if meta.is_dir() => {
+        let _ = std::fs::remove_dir_all(&self.0);
+      }
+      Ok(_) => {
+        let _ = std::fs::remove_file(&self.0);
+      }
+      Err(_) => {}
+    }
+  }
+}
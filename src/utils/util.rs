@@ -1,43 +1,55 @@
-use std::{hash::Hasher, process::Stdio};
+use std::{
+  process::Stdio,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+  },
+};
 
 use anyhow::Result;
-use futures_util::StreamExt;
+use futures_util::TryStreamExt as _;
 use log::{error, info};
 use rand::Rng;
+use reqwest::{Body, header::CONTENT_LENGTH};
 use tokio::{fs::File, io::AsyncWriteExt, process::Command, select};
-use xxhash_rust::xxh3::Xxh3;
+use tokio_util::io::ReaderStream;
 
-use crate::utils::signal::ctrl_c;
-
-/// Download a file from the given URL and save it to the given path. Return the xxh3 hash of the file.
-pub async fn download_file(url: &str, path: &str) -> Result<String> {
-  info!("Downloading file from {url} to {path}");
-  let response = reqwest::get(url).await?;
-  if response.status().is_success() {
-    let mut out = File::create(path).await?;
-    let mut body = response.bytes_stream();
-    let mut hasher = Xxh3::new();
-    while let Some(chunk) = body.next().await {
-      let chunk = chunk?;
-      hasher.write(&chunk);
-      out.write_all(&chunk).await?;
-    }
-    let hash = format!("{:x}", hasher.finish());
-    info!("Downloaded file from {url} to {path}. xxh3: {hash}");
-    Ok(hash)
-  } else {
-    error!("Failed to download file from {url}. Server returned an error.");
-    anyhow::bail!("Failed to download file from {}", url);
-  }
-}
+use crate::{
+  protocol::messaging::TransferProgress,
+  utils::{
+    hash::xxh3_for_file,
+    http::client_for,
+    progress::{ProgressReporter, wait_with_progress},
+    signal::ctrl_c,
+  },
+};
 
 /// Upload a file to the given URL.
-pub async fn upload_file(url: &str, path: &str) -> Result<()> {
+///
+/// `on_progress` is called periodically with the bytes of the file sent so far. The xxh3 of the file is sent in the
+/// `X-Hash-Xxh3` header so the receiver can verify it, and returned on success.
+pub async fn upload_file(url: &str, path: &str, on_progress: impl Fn(TransferProgress)) -> Result<String> {
   info!("Uploading file from {path} to {url}");
-  if reqwest::Client::new().put(url).body(File::open(path).await?).send().await?.status().is_success() {
-    Ok(())
+  let hash = xxh3_for_file(path).await?;
+  let file = File::open(path).await?;
+  let size = file.metadata().await?.len();
+  let progress = ProgressReporter::new(Some(size), on_progress);
+  let sent = Arc::new(AtomicU64::new(0));
+  let counter = sent.clone();
+  let body = ReaderStream::new(file).inspect_ok(move |chunk| {
+    counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+  });
+  let request = client_for(url)
+    .put(url)
+    .header(CONTENT_LENGTH, size)
+    .header("X-Hash-Xxh3", &hash)
+    .body(Body::wrap_stream(body))
+    .send();
+  let response = wait_with_progress(request, &sent, &progress).await?;
+  if response.status().is_success() {
+    Ok(hash)
   } else {
-    error!("Failed to upload file to {url}. Server returned an error.");
+    error!("Failed to upload file to {url}. Server returned {}", response.status());
     anyhow::bail!("Failed to upload file to {}", url);
   }
 }